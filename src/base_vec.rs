@@ -31,14 +31,16 @@
 //! type is fixed in size, the `SLOT_SIZE` is equal to the max size.
 //! Otherwise, the `SLOT_SIZE` is the max size plus the number of
 //! bytes required to represent integers up to that max size.
+use crate::export::{Exporter, ImportError, Importer, StructureKind};
 use crate::storable::{bounds, bytes_to_store_size_bounded};
 use crate::{
-    read_to_vec, read_u32, read_u64, safe_write, write, write_u32, write_u64, Address, GrowFailed,
-    Memory, Storable,
+    read_to_vec, read_u32, read_u64, safe_write, write, write_u32, write_u64, write_zeros, Address,
+    ClearProgress, GrowFailed, Memory, Storable,
 };
 use std::borrow::{Borrow, Cow};
 use std::cmp::min;
 use std::fmt;
+use std::io;
 use std::marker::PhantomData;
 use std::ops::Range;
 
@@ -202,11 +204,17 @@ impl<T: Storable, M: Memory> BaseVec<T, M> {
     ///
     /// Complexity: O(max_size(T))
     pub fn push(&self, item: &T) -> Result<(), GrowFailed> {
+        self.push_bytes(&item.to_bytes_checked())
+    }
+
+    /// Adds an already encoded item to the end of the vector.
+    ///
+    /// PRECONDITION: the item's size fits the bounds of `T`.
+    fn push_bytes(&self, bytes: &[u8]) -> Result<(), GrowFailed> {
         let index = self.len();
         let offset = DATA_OFFSET + slot_size::<T>() as u64 * index;
-        let bytes = item.to_bytes_checked();
         let data_offset = self.write_entry_size(offset, bytes.len() as u32)?;
-        safe_write(&self.memory, data_offset, bytes.borrow())?;
+        safe_write(&self.memory, data_offset, bytes)?;
         // NB. We update the size only after we ensure that the data
         // write succeeded.
         self.set_len(index + 1);
@@ -226,6 +234,59 @@ impl<T: Storable, M: Memory> BaseVec<T, M> {
         Some(value)
     }
 
+    /// Creates a new vector in the specified memory from a stream of the given kind.
+    ///
+    /// Complexity: O(n * max_size(T)), where n is the number of items in the stream.
+    pub fn import<R: io::Read>(
+        memory: M,
+        magic: [u8; 3],
+        reader: R,
+        kind: StructureKind,
+    ) -> Result<Self, ImportError> {
+        let mut importer = Importer::new(reader, kind, None, Some(&T::BOUND))?;
+        let vec = Self::new(memory, magic).expect("Failed to create a new vector");
+        for _ in 0..importer.len() {
+            let (_, value) = importer.read_entry()?;
+            vec.push_bytes(&value)
+                .expect("Failed to push item to the vector");
+        }
+        importer.finish()?;
+        Ok(vec)
+    }
+
+    /// Writes the items of the vector to `writer` as a stream of the given kind.
+    ///
+    /// Complexity: O(len * max_size(T))
+    pub fn export<W: io::Write>(&self, writer: W, kind: StructureKind) -> io::Result<()> {
+        let mut exporter = Exporter::new(writer, kind, None, Some(&T::BOUND), self.len())?;
+        let mut buf = vec![];
+        for index in 0..self.len() {
+            self.read_entry_to(index, &mut buf);
+            exporter.write_entry(&[], &buf)?;
+        }
+        exporter.finish()
+    }
+
+    /// Removes up to `max_items` items from the end of the vector and overwrites their
+    /// slots with zeros.
+    ///
+    /// Complexity: O(max_items * max_size(T))
+    pub fn clear_incremental(&self, max_items: u64) -> ClearProgress {
+        let len = self.len();
+        let removed = min(len, max_items);
+        let slot_size = slot_size::<T>() as u64;
+        write_zeros(
+            &self.memory,
+            DATA_OFFSET + slot_size * (len - removed),
+            slot_size * removed,
+        );
+        self.set_len(len - removed);
+        ClearProgress {
+            removed,
+            remaining: len - removed,
+        }
+    }
+
     pub fn iter(&self) -> Iter<'_, T, M> {
         Iter {
             vec: self,
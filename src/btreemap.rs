@@ -17,7 +17,13 @@
 //! ----------------------------------------
 //! Length (number of elements) ↕ 8 bytes
 //! ---------------------------------------- <- Address 28 (PACKED_HEADER_SIZE)
-//! Reserved space              ↕ 24 bytes
+//! Snapshots address           ↕ 8 bytes
+//! ----------------------------------------
+//! Generation                  ↕ 8 bytes
+//! ----------------------------------------
+//! Header extension marker     ↕ 4 bytes
+//! ----------------------------------------
+//! Reserved space              ↕ 4 bytes
 //! ---------------------------------------- <- Address 52 (ALLOCATOR_OFFSET)
 //! Allocator
 //! ----------------------------------------
@@ -25,6 +31,27 @@
 //! ----------------------------------------
 //! ```
 //!
+//! While a transaction is in progress, the snapshots address is that of the transaction's
+//! record, which is followed by the list of snapshots.
+//!
+//! The snapshots address and the generation are only valid if they're followed by the
+//! header extension marker. Maps saved by earlier versions of this library don't have the
+//! marker, and their snapshots address and generation are read as unset. The marker is only
+//! written once one of the fields is set, so a map that doesn't use them keeps the layout of
+//! earlier versions.
+//!
+//! # V3 layout
+//!
+//! Maps that support order statistics (see [`BTreeMap::new_counted`]) have the same layout
+//! as V2, with layout version 3 and the page size always stored as a direct value. Their
+//! nodes additionally store the number of entries in the subtree of each child.
+//!
+//! # V4 layout
+//!
+//! Maps with prefix-compressed keys (see [`BTreeMap::new_compressed`]) have the same layout
+//! as V2, with layout version 4. Their nodes store the prefix shared by all their keys
+//! only once.
+//!
 //! # V1 layout
 //!
 //! ```text
@@ -41,7 +68,13 @@
 //! ----------------------------------------
 //! Length (number of elements) ↕ 8 bytes
 //! ---------------------------------------- <- Address 28 (PACKED_HEADER_SIZE)
-//! Reserved space              ↕ 24 bytes
+//! Snapshots address           ↕ 8 bytes
+//! ----------------------------------------
+//! Generation                  ↕ 8 bytes
+//! ----------------------------------------
+//! Header extension marker     ↕ 4 bytes
+//! ----------------------------------------
+//! Reserved space              ↕ 4 bytes
 //! ---------------------------------------- <- Address 52 (ALLOCATOR_OFFSET)
 //! Allocator
 //! ----------------------------------------
@@ -49,20 +82,48 @@
 //! ----------------------------------------
 //! ```
 mod allocator;
+mod compaction;
+mod diff;
+mod entry;
+mod integrity;
 mod iter;
+mod join;
+mod large_value;
 mod node;
+mod node_cache;
+mod raw;
+mod snapshot;
+mod stats;
+mod transaction;
 use crate::btreemap::iter::{IterInternal, KeysIter, ValuesIter};
 use crate::{
-    storable::Bound as StorableBound,
+    export::{Exporter, ImportError, Importer, StructureKind},
+    storable::{Bound as StorableBound, TuplePrefix},
     types::{Address, NULL},
-    Memory, Storable,
+    ClearProgress, Memory, Storable,
 };
 use allocator::Allocator;
-pub use iter::Iter;
-use node::{DerivedPageSize, Entry, Node, NodeType, PageSize, Version};
+use compaction::Compaction;
+pub use diff::{Diff, DiffEvent};
+pub use entry::{Entry, OccupiedEntry, VacantEntry};
+pub use integrity::IntegrityError;
+pub use iter::{Cursor, CursorMut, ExtractIf, InvalidToken, Iter};
+pub use join::{JoinKind, Joined, MergeJoin};
+pub use large_value::LargeValueMap;
+use node::{DerivedPageSize, Node, NodeType, PageSize, Version};
+use node_cache::NodeCache;
+pub use node_cache::NodeCacheMetrics;
+pub use raw::{Lexicographic, RawBTreeMap, RawKey, RawKeyOrder};
+pub use snapshot::{Snapshot, SnapshotView};
+pub use stats::BTreeMapStats;
 use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::io;
 use std::marker::PhantomData;
 use std::ops::{Bound, RangeBounds};
+use transaction::Committed;
+pub use transaction::Transaction;
 
 #[cfg(test)]
 mod proptests;
@@ -70,8 +131,17 @@ mod proptests;
 const MAGIC: &[u8; 3] = b"BTR";
 const LAYOUT_VERSION: u8 = 1;
 const LAYOUT_VERSION_2: u8 = 2;
+const LAYOUT_VERSION_3: u8 = 3;
+const LAYOUT_VERSION_4: u8 = 4;
 // The sum of all the header fields, i.e. size of a packed header.
 const PACKED_HEADER_SIZE: usize = 28;
+// The offset of the address of the list of snapshots, right after the packed header.
+const SNAPSHOTS_OFFSET: usize = PACKED_HEADER_SIZE;
+// The offset of the map's generation, which changes whenever the map is cleared.
+const GENERATION_OFFSET: usize = SNAPSHOTS_OFFSET + 8;
+// The offset of the marker indicating that the snapshots address and generation are valid.
+const HEADER_EXTENSION_OFFSET: usize = GENERATION_OFFSET + 8;
+const HEADER_EXTENSION_MARKER: &[u8; 4] = b"BTRX";
 // The offset where the allocator begins.
 const ALLOCATOR_OFFSET: usize = 52;
 
@@ -248,6 +318,23 @@ where
     // The number of elements in the map.
     length: u64,
 
+    // A cache of decoded nodes. It isn't persisted, and is disabled by default.
+    node_cache: RefCell<NodeCache<K>>,
+
+    // The progress of an ongoing compaction. See `compact`.
+    compaction: Option<Compaction<K>>,
+
+    // The address of the first record in the list of snapshots, or `NULL` if the map has
+    // no snapshots. See `snapshot`.
+    snapshots: Address,
+
+    // The map's generation, which is saved in the header. See `generation`.
+    generation: u64,
+
+    // The root and the length saved in the header while a transaction is in progress, which
+    // are those of the tree the transaction started from. See `transaction`.
+    committed: Option<Committed>,
+
     // A marker to communicate to the Rust compiler that we own these types.
     _phantom: PhantomData<(K, V)>,
 }
@@ -324,7 +411,141 @@ where
     ///
     /// See `Allocator` for more details on its own memory layout.
     pub fn new(memory: M) -> Self {
-        let page_size = match (K::BOUND, V::BOUND) {
+        Self::new_with_version(memory, Version::V2(Self::default_page_size()))
+    }
+
+    /// Initializes a `BTreeMap` that supports order statistics.
+    ///
+    /// If the memory provided already contains a `BTreeMap`, then that map is loaded.
+    /// Otherwise, a new `BTreeMap` is created with [`new_counted`](Self::new_counted).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the memory contains a map that was not created with
+    /// [`new_counted`](Self::new_counted).
+    pub fn init_counted(memory: M) -> Self {
+        if memory.size() == 0 {
+            // Memory is empty. Create a new map.
+            return BTreeMap::new_counted(memory);
+        }
+
+        // Check if the magic in the memory corresponds to a BTreeMap.
+        let mut dst = vec![0; 3];
+        memory.read(0, &mut dst);
+        if dst != MAGIC {
+            // No BTreeMap found. Create a new instance.
+            return BTreeMap::new_counted(memory);
+        }
+
+        let btree = BTreeMap::load(memory);
+        assert!(
+            btree.is_counted(),
+            "The memory contains a BTreeMap that does not support order statistics."
+        );
+        btree
+    }
+
+    /// Creates a new instance of a `BTreeMap` that supports order statistics.
+    ///
+    /// In addition to the regular layout, every internal node stores the number of
+    /// entries in the subtree of each of its children. This makes [`nth`](Self::nth),
+    /// [`rank`](Self::rank) and counting the entries of a range run in `O(log n)`, at the
+    /// cost of slightly larger nodes and of updating the counts along the path of every
+    /// insertion and removal.
+    ///
+    /// Maps created with [`new`](Self::new) cannot be converted to maps with counts.
+    /// Their contents can be copied into a new map instead (e.g. with [`append`](Self::append)).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::new_counted(DefaultMemoryImpl::default());
+    ///
+    /// for i in 0..100 {
+    ///     map.insert(i * 10, i);
+    /// }
+    ///
+    /// assert_eq!(map.nth(42), Some((420, 42)));
+    /// assert_eq!(map.rank(&425), 43);
+    /// assert_eq!(map.range(100..200).count(), 10);
+    /// ```
+    pub fn new_counted(memory: M) -> Self {
+        Self::new_with_version(memory, Version::V3(Self::default_page_size()))
+    }
+
+    /// Initializes a `BTreeMap` that stores its keys with prefix compression.
+    ///
+    /// If the memory provided already contains a `BTreeMap`, then that map is loaded and
+    /// switched to prefix-compressed keys. The migration is lazy: new nodes use the
+    /// compressed layout right away, and existing nodes are rewritten in the compressed
+    /// layout the next time they're modified. Otherwise, a new `BTreeMap` is created with
+    /// [`new_compressed`](Self::new_compressed).
+    ///
+    /// Once migrated, the map can no longer be loaded by versions of this library that
+    /// don't support prefix-compressed keys.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the memory contains a map created with
+    /// [`new_counted`](Self::new_counted), as maps with subtree counts don't support
+    /// prefix-compressed keys.
+    pub fn init_compressed(memory: M) -> Self {
+        if memory.size() == 0 {
+            // Memory is empty. Create a new map.
+            return BTreeMap::new_compressed(memory);
+        }
+
+        // Check if the magic in the memory corresponds to a BTreeMap.
+        let mut dst = vec![0; 3];
+        memory.read(0, &mut dst);
+        if dst != MAGIC {
+            // No BTreeMap found. Create a new instance.
+            return BTreeMap::new_compressed(memory);
+        }
+
+        let mut btree = BTreeMap::load(memory);
+        btree.version = match btree.version {
+            Version::V2(page_size) | Version::V4(page_size) => Version::V4(page_size),
+            Version::V1(_) => unreachable!("Loaded maps are migrated to V2."),
+            Version::V3(_) => {
+                panic!("The memory contains a BTreeMap with subtree counts, which doesn't support prefix-compressed keys.")
+            }
+        };
+        btree.save_header();
+        btree
+    }
+
+    /// Creates a new instance of a `BTreeMap` that stores its keys with prefix compression.
+    ///
+    /// Every node stores the longest prefix shared by its keys once, followed by the rest
+    /// of each key. This saves memory, and overflow pages, when keys share long prefixes,
+    /// such as composite keys that start with the same principal. Looking up keys is as
+    /// fast as with [`new`](Self::new), while saving a node has to find the shared prefix
+    /// of its keys.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    /// let mut map: BTreeMap<(u64, u64), u64, _> =
+    ///     BTreeMap::new_compressed(DefaultMemoryImpl::default());
+    ///
+    /// // All the keys start with the same user ID.
+    /// let user_id = 42;
+    /// for i in 0..100 {
+    ///     map.insert((user_id, i), i);
+    /// }
+    ///
+    /// assert_eq!(map.get(&(user_id, 7)), Some(7));
+    /// ```
+    pub fn new_compressed(memory: M) -> Self {
+        Self::new_with_version(memory, Version::V4(Self::default_page_size()))
+    }
+
+    /// Returns the page size to use for new maps.
+    fn default_page_size() -> PageSize {
+        match (K::BOUND, V::BOUND) {
             // The keys and values are both bounded.
             (
                 StorableBound::Bounded {
@@ -347,8 +568,17 @@ where
             }
             // Use a default page size.
             _ => PageSize::Value(DEFAULT_PAGE_SIZE),
-        };
+        }
+    }
 
+    /// Creates a new map with the given version.
+    fn new_with_version(memory: M, version: Version) -> Self {
+        let page_size = version.page_size();
+        let generation = if Self::contains_map(&memory) {
+            Self::read_header_extension(&memory).1.wrapping_add(1)
+        } else {
+            0
+        };
         let btree = Self {
             root_addr: NULL,
             allocator: Allocator::new(
@@ -356,12 +586,19 @@ where
                 Address::from(ALLOCATOR_OFFSET as u64),
                 page_size.get().into(),
             ),
-            version: Version::V2(page_size),
+            version,
             length: 0,
+            node_cache: RefCell::new(NodeCache::new(0)),
+            compaction: None,
+            snapshots: NULL,
+            generation,
+            committed: None,
             _phantom: PhantomData,
         };
 
         btree.save_header();
+        // The iteration tokens of the map that was in the memory are invalidated.
+        btree.save_header_extension();
         btree
     }
 
@@ -372,6 +609,11 @@ where
     pub fn new_v1(memory: M) -> Self {
         let max_key_size = K::BOUND.max_size();
         let max_value_size = V::BOUND.max_size();
+        let generation = if Self::contains_map(&memory) {
+            Self::read_header_extension(&memory).1.wrapping_add(1)
+        } else {
+            0
+        };
 
         let btree = Self {
             root_addr: NULL,
@@ -385,10 +627,17 @@ where
                 max_value_size,
             }),
             length: 0,
+            node_cache: RefCell::new(NodeCache::new(0)),
+            compaction: None,
+            snapshots: NULL,
+            generation,
+            committed: None,
             _phantom: PhantomData,
         };
 
         btree.save_header();
+        // The iteration tokens of the map that was in the memory are invalidated.
+        btree.save_header_extension();
         btree
     }
 
@@ -397,6 +646,338 @@ where
         Self::load_helper(memory, true)
     }
 
+    /// Creates a new `BTreeMap` from an iterator of entries sorted by key.
+    ///
+    /// Rather than inserting the entries one at a time, the tree is built bottom-up:
+    /// nodes are filled to capacity and written once, in the order they are allocated.
+    /// This is significantly cheaper than repeated calls to [`insert`](Self::insert)
+    /// and produces a compact tree.
+    ///
+    /// As with [`new`](Self::new), the given `memory` is assumed to be exclusively reserved
+    /// for this data structure, and any data it contains is overwritten. The map is created
+    /// with the same node layout as [`new`](Self::new), so it doesn't support order
+    /// statistics or compress its keys. To bulk load such a map, create it with
+    /// [`new_counted`](Self::new_counted) or [`new_compressed`](Self::new_compressed) and
+    /// [`append`](Self::append) a map built with this function to it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the keys are not in strictly ascending order.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    ///
+    /// let map: BTreeMap<u64, u64, _> =
+    ///     BTreeMap::from_sorted_iter(DefaultMemoryImpl::default(), (0..1_000).map(|i| (i, i * i)));
+    ///
+    /// assert_eq!(map.len(), 1_000);
+    /// assert_eq!(map.get(&10), Some(100));
+    /// ```
+    pub fn from_sorted_iter<I>(memory: M, iter: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut btree = Self::new(memory);
+        btree.bulk_append(
+            iter.into_iter()
+                .map(|(key, value)| (key, value.into_bytes_checked())),
+        );
+        btree
+    }
+
+    /// Creates a new map in the given memory from a stream written by
+    /// [`export`](Self::export), overwriting any data the memory contains.
+    ///
+    /// The entries are appended in bulk, as in [`from_sorted_iter`](Self::from_sorted_iter).
+    /// If the import fails, the memory may contain a partially imported map.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    ///
+    /// let mut map: BTreeMap<u64, String, _> = BTreeMap::new(DefaultMemoryImpl::default());
+    /// map.insert(1, "one".to_string());
+    /// map.insert(2, "two".to_string());
+    ///
+    /// let mut stream = vec![];
+    /// map.export(&mut stream).unwrap();
+    ///
+    /// let copy: BTreeMap<u64, String, _> =
+    ///     BTreeMap::import(DefaultMemoryImpl::default(), stream.as_slice()).unwrap();
+    /// assert_eq!(copy.get(&2), Some("two".to_string()));
+    /// ```
+    pub fn import<R: io::Read>(memory: M, reader: R) -> Result<Self, ImportError> {
+        Self::import_as(memory, reader, StructureKind::BTreeMap, Some(&V::BOUND))
+    }
+
+    /// Writes the entries of the map to `writer`, in the [portable format](crate::export).
+    pub fn export<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        self.export_as(writer, StructureKind::BTreeMap, Some(&V::BOUND))
+    }
+
+    /// Imports a map from a stream of the given kind, which has no values if
+    /// `value_bound` is `None`.
+    pub(crate) fn import_as<R: io::Read>(
+        memory: M,
+        reader: R,
+        kind: StructureKind,
+        value_bound: Option<&StorableBound>,
+    ) -> Result<Self, ImportError> {
+        let mut importer = Importer::new(reader, kind, Some(&K::BOUND), value_bound)?;
+        let mut btree = Self::new(memory);
+
+        let mut error = None;
+        let mut remaining = importer.len();
+        let mut last_key: Option<K> = None;
+        let entries = std::iter::from_fn(|| {
+            if remaining == 0 {
+                return None;
+            }
+            remaining -= 1;
+            let entry = importer.read_entry().and_then(|(key, value)| {
+                let key = K::from_bytes(Cow::Owned(key));
+                if last_key.as_ref().is_some_and(|last_key| last_key >= &key) {
+                    return Err(importer.invalid_entry());
+                }
+                last_key = Some(key.clone());
+                Ok((key, value))
+            });
+            entry.map_err(|err| error = Some(err)).ok()
+        });
+        btree.bulk_append(entries);
+
+        match error {
+            Some(err) => Err(err),
+            None => importer.finish().map(|()| btree),
+        }
+    }
+
+    /// Exports the map as a stream of the given kind, without values if `value_bound` is
+    /// `None`.
+    pub(crate) fn export_as<W: io::Write>(
+        &self,
+        writer: W,
+        kind: StructureKind,
+        value_bound: Option<&StorableBound>,
+    ) -> io::Result<()> {
+        let mut exporter = Exporter::new(writer, kind, Some(&K::BOUND), value_bound, self.len())?;
+        let mut iter = self.range_internal(..);
+        while let Some((key, value)) = iter.next_encoded() {
+            exporter.write_entry(&key.to_bytes(), &value)?;
+        }
+        exporter.finish()
+    }
+
+    /// Appends the given sorted entries to the map, building the tree bottom-up along its
+    /// right-most path.
+    ///
+    /// The keys of the entries must be greater than all the keys already in the map.
+    fn bulk_append<I>(&mut self, entries: I)
+    where
+        I: IntoIterator<Item = node::Entry<K>>,
+    {
+        // The right-most path of the tree, from the leaf (at index 0) up to the root.
+        // Nodes that are not on this path are already saved.
+        let mut path = self.right_path();
+
+        for entry in entries {
+            if let Some(prev_key) = Self::bulk_load_last_key(&path, self.memory()) {
+                assert!(
+                    prev_key < &entry.0,
+                    "Keys must be sorted in strictly ascending order."
+                );
+            }
+
+            self.length += 1;
+
+            if path.is_empty() {
+                path.push(self.allocate_node(NodeType::Leaf));
+            }
+
+            if path[0].is_full() {
+                // The leaf is full. The entry becomes a separator in the leaf's parent.
+                self.bulk_load_push_separator(&mut path, entry);
+            } else {
+                path[0].push_entry(entry);
+            }
+        }
+
+        self.rebalance_right_path(path);
+    }
+
+    /// Loads the right-most path of the tree, from the leaf (at index 0) up to the root.
+    fn right_path(&self) -> Vec<Node<K>> {
+        let mut path = vec![];
+        if self.root_addr == NULL {
+            return path;
+        }
+
+        let mut node = self.load_node(self.root_addr);
+        while node.node_type() == NodeType::Internal {
+            let child = self.load_node(node.child(node.children_len() - 1));
+            path.push(node);
+            node = child;
+        }
+        path.push(node);
+        path.reverse();
+        path
+    }
+
+    /// Returns the largest key that was loaded so far, which is the last key in the lowest
+    /// non-empty node of the path.
+    fn bulk_load_last_key<'a>(path: &'a [Node<K>], memory: &M) -> Option<&'a K> {
+        path.iter()
+            .find(|node| node.entries_len() > 0)
+            .map(|node| node.key(node.entries_len() - 1, memory))
+    }
+
+    /// Pushes a separator entry above the full leaf of the path.
+    ///
+    /// Every full node on the path is saved and replaced by an empty sibling, until a node
+    /// that can hold the separator is reached. If all the nodes are full, a new root is
+    /// introduced.
+    ///
+    /// Example (values and children of the leaves are not included for brevity):
+    /// ```ignore
+    ///                   [ ... A ]                                   [ ... A   L ]
+    ///                          \                                           /   \
+    ///   [ B  C  D  E  F  G  H  I  J  K ]     +  L  =>     [ B ... J  K ]      [ ]
+    /// ```
+    fn bulk_load_push_separator(&mut self, path: &mut Vec<Node<K>>, separator: node::Entry<K>) {
+        let mut level = 0;
+        // The address of the newly created node one level below.
+        let mut new_child = NULL;
+
+        loop {
+            // Replace the full node with a new sibling.
+            let mut sibling = self.allocate_node(path[level].node_type());
+            if new_child != NULL {
+                sibling.push_child(new_child, 0);
+            }
+            let mut full_node = core::mem::replace(&mut path[level], sibling);
+            self.save_node(&mut full_node);
+            new_child = path[level].address();
+
+            if level + 1 == path.len() {
+                // The full node was the root. Introduce a new root.
+                let mut new_root = self.allocate_node(NodeType::Internal);
+                new_root.push_child(full_node.address(), full_node.subtree_len());
+                path.push(new_root);
+            } else {
+                // The full node won't change anymore, so its count in the parent is final.
+                let parent = &mut path[level + 1];
+                parent.set_child_count(parent.children_len() - 1, full_node.subtree_len());
+            }
+
+            let parent = &mut path[level + 1];
+            if !parent.is_full() {
+                parent.push_entry(separator);
+                parent.push_child(new_child, 0);
+                return;
+            }
+
+            // The parent is full as well. Move up a level.
+            level += 1;
+        }
+    }
+
+    /// Saves the nodes of a right-most path of the tree, rebalancing them where needed.
+    ///
+    /// All the nodes that aren't on the path are valid, but the nodes on the path can have
+    /// any number of entries (including zero). Empty roots are removed first. Then, starting
+    /// from the top, each node on the path that is at its minimum size is either merged into
+    /// its left sibling, if both fit into a single node, or refilled with entries rotated
+    /// from its left sibling through the parent.
+    ///
+    /// Each non-root node on the path ends up with at least `B` entries, so merging a node
+    /// one level below never leaves its parent with too few entries.
+    fn rebalance_right_path(&mut self, mut path: Vec<Node<K>>) {
+        // Update the subtree counts along the path, bottom-up.
+        for level in 1..path.len() {
+            let count = path[level - 1].subtree_len();
+            let parent = &mut path[level];
+            parent.set_child_count(parent.children_len() - 1, count);
+        }
+
+        // Remove empty roots. An empty internal root has a single child, which becomes the root.
+        while path.last().is_some_and(|root| root.entries_len() == 0) {
+            let root = path.pop().unwrap();
+            if path.is_empty() && root.node_type() == NodeType::Internal {
+                // The path ended at the root, so its child isn't on the path yet.
+                path.push(self.load_node(root.child(0)));
+            }
+            self.deallocate_node(root);
+        }
+
+        let mut level = path.len().saturating_sub(1);
+        while level > 0 {
+            let (lower, upper) = path.split_at_mut(level);
+            let (node, parent) = (&mut lower[level - 1], &mut upper[0]);
+
+            if node.at_minimum() {
+                let mut left_sibling = self.load_node(parent.child(parent.children_len() - 2));
+
+                if left_sibling.entries_len() + node.entries_len() < node::CAPACITY {
+                    // Merge the node into its left sibling.
+                    let separator = parent
+                        .pop_entry(self.memory())
+                        .expect("the parent must have a separator");
+                    parent.pop_child();
+                    left_sibling.push_entry(separator);
+                    while node.entries_len() > 0 {
+                        left_sibling.push_entry(node.remove_entry(0, self.memory()));
+                    }
+                    while node.children_len() > 0 {
+                        let (child, count) = node.remove_child(0);
+                        left_sibling.push_child(child, count);
+                    }
+                    parent.set_child_count(parent.children_len() - 1, left_sibling.subtree_len());
+
+                    let node = core::mem::replace(&mut path[level - 1], left_sibling);
+                    self.deallocate_node(node);
+
+                    if path[level].entries_len() == 0 {
+                        // Only the root can run out of entries. The merged node becomes the root.
+                        debug_assert_eq!(level, path.len() - 1);
+                        let root = path.pop().unwrap();
+                        self.deallocate_node(root);
+                        level -= 1;
+                        continue;
+                    }
+                } else {
+                    // Rotate entries (and children) from the left sibling through the parent.
+                    let separator_idx = parent.entries_len() - 1;
+                    while node.at_minimum() {
+                        let entry = left_sibling
+                            .pop_entry(self.memory())
+                            .expect("the left sibling must have spare entries");
+                        let separator = parent.swap_entry(separator_idx, entry, self.memory());
+                        node.insert_entry(0, separator);
+                        if let Some((child, count)) = left_sibling.pop_child() {
+                            node.insert_child(0, child, count);
+                        }
+                    }
+                    let last_idx = parent.children_len() - 1;
+                    parent.set_child_count(last_idx - 1, left_sibling.subtree_len());
+                    parent.set_child_count(last_idx, node.subtree_len());
+                    self.save_node(&mut left_sibling);
+                }
+            }
+
+            self.save_node(&mut path[level]);
+            level -= 1;
+        }
+
+        if let Some(node) = path.first_mut() {
+            self.save_node(node);
+        }
+        self.root_addr = path.last().map_or(NULL, |root| root.address());
+        self.save_header();
+    }
+
     /// Loads the map from memory, potentially migrating the map from V1 to V2.
     fn load_helper(memory: M, migrate_to_v2: bool) -> Self {
         // Read the header from memory.
@@ -429,13 +1010,21 @@ where
         };
 
         let allocator_addr = Address::from(ALLOCATOR_OFFSET as u64);
-        Self {
+        let (snapshots, generation) = Self::read_header_extension(&memory);
+        let mut btree = Self {
             root_addr: header.root_addr,
             allocator: Allocator::load(memory, allocator_addr),
             version,
             length: header.length,
+            node_cache: RefCell::new(NodeCache::new(0)),
+            compaction: None,
+            snapshots,
+            generation,
+            committed: None,
             _phantom: PhantomData,
-        }
+        };
+        btree.recover_transaction();
+        btree
     }
 
     /// Reads the header from the specified memory.
@@ -458,7 +1047,7 @@ where
                     length: u64::from_le_bytes(buf[20..28].try_into().unwrap()),
                 }
             }
-            LAYOUT_VERSION_2 => {
+            LAYOUT_VERSION_2 | LAYOUT_VERSION_4 => {
                 // Load the page size.
                 let page_size = {
                     // Page sizes can be stored either as a direct value or as max/value sizes.
@@ -479,7 +1068,22 @@ where
 
                 // Deserialize the fields
                 BTreeHeader {
-                    version: Version::V2(page_size),
+                    version: if buf[3] == LAYOUT_VERSION_4 {
+                        Version::V4(page_size)
+                    } else {
+                        Version::V2(page_size)
+                    },
+                    root_addr: Address::from(u64::from_le_bytes(buf[12..20].try_into().unwrap())),
+                    length: u64::from_le_bytes(buf[20..28].try_into().unwrap()),
+                }
+            }
+            LAYOUT_VERSION_3 => {
+                // The page size of a V3 map is always stored as a direct value.
+                let page_size = PageSize::Value(u32::from_le_bytes(buf[4..8].try_into().unwrap()));
+
+                // Deserialize the fields
+                BTreeHeader {
+                    version: Version::V3(page_size),
                     root_addr: Address::from(u64::from_le_bytes(buf[12..20].try_into().unwrap())),
                     length: u64::from_le_bytes(buf[20..28].try_into().unwrap()),
                 }
@@ -499,6 +1103,15 @@ where
     ///   value.to_bytes().len() <= max_size(Value)
     pub fn insert(&mut self, key: K, value: V) -> Option<V> {
         let value = value.into_bytes_checked();
+        self.insert_encoded(key, value)
+            .map(Cow::Owned)
+            .map(V::from_bytes)
+    }
+
+    /// Inserts a key and an already encoded value into the map, returning the
+    /// previous encoded value of the key, if present.
+    fn insert_encoded(&mut self, key: K, value: Vec<u8>) -> Option<Vec<u8>> {
+        self.unshare_path(&key);
 
         let root = if self.root_addr == NULL {
             // No root present. Allocate one.
@@ -513,9 +1126,7 @@ where
             // Check if the key already exists in the root.
             if let Ok(idx) = root.search(&key, self.memory()) {
                 // Key found, replace its value and return the old one.
-                return Some(V::from_bytes(Cow::Owned(
-                    self.update_value(&mut root, idx, value),
-                )));
+                return Some(self.update_value(&mut root, idx, value));
             }
 
             // If the root is full, we need to introduce a new node as the root.
@@ -528,7 +1139,7 @@ where
                 let mut new_root = self.allocate_node(NodeType::Internal);
 
                 // The new root has the old root as its only child.
-                new_root.push_child(self.root_addr);
+                new_root.push_child(self.root_addr, self.length);
 
                 // Update the root address.
                 self.root_addr = new_root.address();
@@ -544,8 +1155,6 @@ where
         };
 
         self.insert_nonfull(root, key, value)
-            .map(Cow::Owned)
-            .map(V::from_bytes)
     }
 
     /// Inserts an entry into a node that is *not full*.
@@ -579,6 +1188,7 @@ where
                     NodeType::Internal => {
                         // The node is an internal node.
                         // Load the child that we should add the entry to.
+                        let mut idx = idx;
                         let mut child = self.load_node(node.child(idx));
 
                         if child.is_full() {
@@ -593,14 +1203,20 @@ where
 
                             // The children have now changed. Search again for
                             // the child where we need to store the entry in.
-                            let idx = node.search(&key, self.memory()).unwrap_or_else(|idx| idx);
+                            idx = node.search(&key, self.memory()).unwrap_or_else(|idx| idx);
                             child = self.load_node(node.child(idx));
                         }
 
                         // The child should now be not full.
                         assert!(!child.is_full());
 
-                        self.insert_nonfull(child, key, value)
+                        let old_value = self.insert_nonfull(child, key, value);
+                        if old_value.is_none() && self.is_counted() {
+                            // The key was new, so the child's subtree gained an entry.
+                            node.set_child_count(idx, node.child_count(idx) + 1);
+                            self.save_node(&mut node);
+                        }
+                        old_value
                     }
                 }
             }
@@ -637,9 +1253,11 @@ where
         assert_eq!(sibling.node_type(), full_child.node_type());
 
         // Add sibling as a new child in the node.
-        node.insert_child(full_child_idx + 1, sibling.address());
+        node.insert_child(full_child_idx + 1, sibling.address(), 0);
 
         let (median_key, median_value) = full_child.split(&mut sibling, self.memory());
+        node.set_child_count(full_child_idx, full_child.subtree_len());
+        node.set_child_count(full_child_idx + 1, sibling.subtree_len());
 
         node.insert_entry(full_child_idx, (median_key, median_value));
 
@@ -660,29 +1278,140 @@ where
         .map(V::from_bytes)
     }
 
-    /// Returns true if the key exists.
-    pub fn contains_key(&self, key: &K) -> bool {
-        // An empty closure returns Some(()) if the key is found.
-        self.root_addr != NULL && self.traverse(self.root_addr, key, |_, _| ()).is_some()
-    }
+    /// Gets the given key's corresponding entry in the map for in-place manipulation.
+    ///
+    /// The lookup descends the tree once. The node where the key was found (or, for a
+    /// vacant entry, the leaf where it would be inserted) is kept in the entry so that
+    /// a subsequent write can reuse it instead of searching the tree again.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    /// use ic_stable_structures::btreemap::Entry;
+    ///
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::init(DefaultMemoryImpl::default());
+    ///
+    /// // Count occurrences.
+    /// for key in [1, 2, 1, 1] {
+    ///     map.entry(key).and_modify(|count| *count += 1).or_insert(1);
+    /// }
+    /// assert_eq!(map.get(&1), Some(3));
+    /// assert_eq!(map.get(&2), Some(1));
+    ///
+    /// if let Entry::Occupied(entry) = map.entry(2) {
+    ///     assert_eq!(entry.remove(), 1);
+    /// }
+    /// assert!(!map.contains_key(&2));
+    /// ```
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, M> {
+        // The entry can modify the nodes it finds, or remove the key.
+        self.unshare_path(&key);
 
-    /// Recursively traverses from `node_addr`, invoking `f` if `key` is found. Stops at a leaf if not.
-    fn traverse<F, R>(&self, node_addr: Address, key: &K, f: F) -> Option<R>
-    where
-        F: Fn(&mut Node<K>, usize) -> R,
-    {
-        let mut node = self.load_node(node_addr);
-        // Look for the key in the current node.
-        match node.search(key, self.memory()) {
-            Ok(idx) => Some(f(&mut node, idx)), // Key found: apply `f`.
-            Err(idx) => match node.node_type() {
-                NodeType::Leaf => None, // At a leaf: key not present.
-                NodeType::Internal => self.traverse(node.child(idx), key, f), // Continue search in child.
-            },
+        if self.root_addr == NULL {
+            return Entry::Vacant(VacantEntry {
+                map: self,
+                key,
+                leaf: None,
+            });
+        }
+
+        let mut node = self.load_node(self.root_addr);
+        loop {
+            match node.search(&key, self.memory()) {
+                Ok(idx) => {
+                    return Entry::Occupied(OccupiedEntry {
+                        map: self,
+                        key,
+                        node,
+                        idx,
+                    })
+                }
+                Err(idx) => match node.node_type() {
+                    NodeType::Leaf => {
+                        return Entry::Vacant(VacantEntry {
+                            map: self,
+                            key,
+                            leaf: Some((node, idx)),
+                        })
+                    }
+                    NodeType::Internal => node = self.load_node(node.child(idx)),
+                },
+            }
         }
     }
 
-    /// Returns `true` if the map contains no elements.
+    /// Updates the value of the key with `f`, which is given the current value of the key,
+    /// if any, and returns its new value. Returning `None` removes the key.
+    ///
+    /// The key is looked up once, as with [`entry`](Self::entry), and its current value is
+    /// decoded once. The update is written to the node the key was found in (or, for a
+    /// missing key, the leaf it belongs to) unless the tree needs rebalancing.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::init(DefaultMemoryImpl::default());
+    ///
+    /// // Increment a counter, removing it when it reaches 3.
+    /// let increment = |count: Option<u64>| match count.unwrap_or(0) + 1 {
+    ///     3 => None,
+    ///     count => Some(count),
+    /// };
+    /// map.update(&1, increment);
+    /// map.update(&1, increment);
+    /// assert_eq!(map.get(&1), Some(2));
+    /// map.update(&1, increment);
+    /// assert!(!map.contains_key(&1));
+    /// ```
+    pub fn update<F>(&mut self, key: &K, f: F)
+    where
+        F: FnOnce(Option<V>) -> Option<V>,
+    {
+        match self.entry(key.clone()) {
+            Entry::Occupied(mut entry) => match f(Some(entry.get())) {
+                Some(value) => {
+                    let encoded_value = value.into_bytes_checked();
+                    entry
+                        .map
+                        .update_value(&mut entry.node, entry.idx, encoded_value);
+                }
+                None => {
+                    entry.remove_encoded();
+                }
+            },
+            Entry::Vacant(entry) => {
+                if let Some(value) = f(None) {
+                    entry.insert(value);
+                }
+            }
+        }
+    }
+
+    /// Returns true if the key exists.
+    pub fn contains_key(&self, key: &K) -> bool {
+        // An empty closure returns Some(()) if the key is found.
+        self.root_addr != NULL && self.traverse(self.root_addr, key, |_, _| ()).is_some()
+    }
+
+    /// Recursively traverses from `node_addr`, invoking `f` if `key` is found. Stops at a leaf if not.
+    fn traverse<F, R>(&self, node_addr: Address, key: &K, f: F) -> Option<R>
+    where
+        F: Fn(&mut Node<K>, usize) -> R,
+    {
+        let mut node = self.load_node(node_addr);
+        // Look for the key in the current node.
+        match node.search(key, self.memory()) {
+            Ok(idx) => Some(f(&mut node, idx)), // Key found: apply `f`.
+            Err(idx) => match node.node_type() {
+                NodeType::Leaf => None, // At a leaf: key not present.
+                NodeType::Internal => self.traverse(node.child(idx), key, f), // Continue search in child.
+            },
+        }
+    }
+
+    /// Returns `true` if the map contains no elements.
     pub fn is_empty(&self) -> bool {
         self.length == 0
     }
@@ -692,6 +1421,175 @@ where
         self.length
     }
 
+    /// Returns true if the map supports order statistics, i.e. if it was created
+    /// with [`new_counted`](Self::new_counted).
+    pub fn is_counted(&self) -> bool {
+        matches!(self.version, Version::V3(_))
+    }
+
+    /// Enables a cache of up to `capacity` decoded nodes.
+    ///
+    /// Every lookup reads and decodes the nodes on the path from the root to its key, and
+    /// the nodes at the top of the tree are on the path of almost every lookup. Caching
+    /// them saves reading and decoding them again, at the cost of keeping up to `capacity`
+    /// nodes in heap memory. When the cache is full, the least recently used node is
+    /// evicted.
+    ///
+    /// The cache is disabled by default, and isn't persisted: it has to be enabled again
+    /// after the map is loaded (e.g. after an upgrade).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    /// let mut map: BTreeMap<u64, u64, _> =
+    ///     BTreeMap::init(DefaultMemoryImpl::default()).with_node_cache(16);
+    ///
+    /// for i in 0..1_000 {
+    ///     map.insert(i, i);
+    /// }
+    ///
+    /// map.node_cache_reset_metrics();
+    /// for i in 0..1_000 {
+    ///     assert_eq!(map.get(&i), Some(i));
+    /// }
+    ///
+    /// // The nodes at the top of the tree are loaded from the cache.
+    /// assert!(map.node_cache_metrics().hits() > 1_000);
+    /// ```
+    pub fn with_node_cache(mut self, capacity: usize) -> Self {
+        self.node_cache_resize(capacity);
+        self
+    }
+
+    /// Changes the number of decoded nodes the cache can hold, evicting nodes if needed.
+    /// A capacity of zero disables the cache.
+    ///
+    /// See [`with_node_cache`](Self::with_node_cache).
+    pub fn node_cache_resize(&mut self, capacity: usize) {
+        self.node_cache.get_mut().resize(capacity);
+    }
+
+    /// Returns the number of node loads served from the cache, and the number of node
+    /// loads that missed it, since the metrics were last reset.
+    pub fn node_cache_metrics(&self) -> NodeCacheMetrics {
+        self.node_cache.borrow().metrics()
+    }
+
+    /// Resets the node cache metrics.
+    pub fn node_cache_reset_metrics(&mut self) {
+        self.node_cache.get_mut().reset_metrics();
+    }
+
+    /// Returns the entry at the given position in the map, where entries are ordered by key
+    /// and positions start at zero. Returns `None` if `index` is out of bounds.
+    ///
+    /// Runs in `O(log n)` for maps created with [`new_counted`](Self::new_counted),
+    /// and in `O(n)` otherwise.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::new_counted(DefaultMemoryImpl::default());
+    ///
+    /// map.insert(5, 50);
+    /// map.insert(1, 10);
+    /// map.insert(3, 30);
+    ///
+    /// assert_eq!(map.nth(0), Some((1, 10)));
+    /// assert_eq!(map.nth(2), Some((5, 50)));
+    /// assert_eq!(map.nth(3), None);
+    /// ```
+    pub fn nth(&self, index: u64) -> Option<(K, V)> {
+        if index >= self.length {
+            return None;
+        }
+
+        if !self.is_counted() {
+            return self
+                .iter()
+                .nth(usize::try_from(index).ok()?)
+                .map(|entry| entry.into_pair());
+        }
+
+        let mut index = index;
+        let mut node = self.load_node(self.root_addr);
+        loop {
+            if node.node_type() == NodeType::Leaf {
+                let (key, value) = node.entry(index as usize, self.memory());
+                return Some((key.clone(), V::from_bytes(Cow::Borrowed(value))));
+            }
+
+            // Skip over the children (and the entries between them) that come before `index`.
+            let mut idx = 0;
+            loop {
+                let count = node.child_count(idx);
+                if index < count {
+                    break;
+                }
+                index -= count;
+                if index == 0 {
+                    let (key, value) = node.entry(idx, self.memory());
+                    return Some((key.clone(), V::from_bytes(Cow::Borrowed(value))));
+                }
+                index -= 1;
+                idx += 1;
+            }
+            node = self.load_node(node.child(idx));
+        }
+    }
+
+    /// Returns the number of keys in the map that are strictly less than the given key.
+    ///
+    /// If the key exists, this is its position in the map, i.e. `map.nth(map.rank(&key))`
+    /// returns its entry.
+    ///
+    /// Runs in `O(log n)` for maps created with [`new_counted`](Self::new_counted),
+    /// and in `O(n)` otherwise.
+    pub fn rank(&self, key: &K) -> u64 {
+        if !self.is_counted() {
+            return self.range(..key).count() as u64;
+        }
+
+        self.rank_helper(self.root_addr, key).0
+    }
+
+    /// Returns the number of keys that are strictly less than the given key, and whether
+    /// the key exists, in the tree with the given root, which is the map's root unless a
+    /// snapshot of the map is counted.
+    ///
+    /// PRECONDITION: the map is counted.
+    fn rank_helper(&self, root: Address, key: &K) -> (u64, bool) {
+        debug_assert!(self.is_counted());
+        if root == NULL {
+            return (0, false);
+        }
+
+        let mut rank = 0;
+        let mut node = self.load_node(root);
+        loop {
+            let (idx, found) = match node.search(key, self.memory()) {
+                Ok(idx) => (idx, true),
+                Err(idx) => (idx, false),
+            };
+
+            // The entries before `idx` are smaller than the key.
+            rank += idx as u64;
+            if node.node_type() == NodeType::Leaf {
+                return (rank, found);
+            }
+
+            // So are the subtrees to their left.
+            rank += (0..idx).map(|i| node.child_count(i)).sum::<u64>();
+            if found {
+                // The left subtree of the key is smaller as well.
+                return (rank + node.child_count(idx), true);
+            }
+            node = self.load_node(node.child(idx));
+        }
+    }
+
     /// Returns the underlying memory.
     pub fn into_memory(self) -> M {
         self.allocator.into_memory()
@@ -706,10 +1604,181 @@ where
 
     /// Removes all elements from the map.
     pub fn clear_new(&mut self) {
+        if self.has_shared_nodes() {
+            // The memory is still used by the shared nodes, so only the nodes that aren't
+            // shared are deallocated.
+            if self.root_addr != NULL {
+                self.release_subtree(self.root_addr);
+            }
+            self.root_addr = NULL;
+            self.length = 0;
+            self.save_header();
+            self.next_generation();
+            return;
+        }
+
+        self.node_cache.get_mut().clear();
+        self.compaction = None;
         self.root_addr = NULL;
         self.length = 0;
         self.allocator.clear();
         self.save_header();
+        self.next_generation();
+    }
+
+    /// Removes entries from the map, making at most `max_nodes` leaf cuts, and reports how
+    /// many entries are left.
+    ///
+    /// Each leaf cut removes the right-most leaf, along with its separator, from the tree
+    /// and returns the leaf's node to the allocator. Rebalancing the right-most path after
+    /// a cut can also merge or free internal nodes, so a call may free more than
+    /// `max_nodes` nodes, but its work is bounded by `max_nodes` descents and the
+    /// rebalancing along each of them. Calling this method repeatedly, e.g. from a timer,
+    /// spreads the clearing of a large map over several messages. The map remains valid
+    /// between the calls, and the freed memory is reused by later insertions.
+    ///
+    /// Unlike [`clear_new`](Self::clear_new), which releases the whole tree at once when
+    /// the map shares nodes with snapshots, nodes are released gradually here as well.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    ///
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::new(DefaultMemoryImpl::default());
+    /// for i in 0..1_000 {
+    ///     map.insert(i, i);
+    /// }
+    ///
+    /// let progress = map.clear_incremental(10);
+    /// assert_eq!(progress.remaining, map.len());
+    /// assert!(progress.removed > 0);
+    ///
+    /// while !map.clear_incremental(10).is_done() {}
+    /// assert!(map.is_empty());
+    /// ```
+    pub fn clear_incremental(&mut self, max_nodes: u64) -> ClearProgress {
+        let len = self.length;
+        for _ in 0..max_nodes {
+            if self.root_addr == NULL {
+                break;
+            }
+
+            let mut node = self.load_node(self.root_addr);
+            if node.node_type() == NodeType::Leaf {
+                self.clear_new();
+                break;
+            }
+
+            // Find the parent of the right-most leaf, and cut the leaf along with its
+            // separator.
+            loop {
+                let child = self.load_node(node.child(node.children_len() - 1));
+                if child.node_type() == NodeType::Leaf {
+                    break;
+                }
+                node = child;
+            }
+            let separator = node.key(node.entries_len() - 1, self.memory()).clone();
+            self.truncate(&separator);
+        }
+
+        ClearProgress {
+            removed: len - self.length,
+            remaining: self.length,
+        }
+    }
+
+    /// Splits the map into two at the given key. Returns everything after the given key,
+    /// including the key, as a new map stored in the given `memory`.
+    ///
+    /// Nodes cannot be shared between memories, so the entries that are moved are written to
+    /// the new map's memory, which is built bottom-up as in
+    /// [`from_sorted_iter`](Self::from_sorted_iter). The moved entries are then cut from
+    /// this map at the node level: subtrees that lie entirely after `key` are deallocated
+    /// as a whole, and only the nodes along the path to `key` are rebalanced.
+    ///
+    /// As with [`new`](Self::new), the given `memory` is assumed to be exclusively reserved
+    /// for the new map, and any data it contains is overwritten. The new map has the same
+    /// node layout as this map, so it supports order statistics or compresses its keys if
+    /// this map does.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    ///
+    /// let mut a: BTreeMap<u64, u64, _> = BTreeMap::new(DefaultMemoryImpl::default());
+    /// for i in 0..100 {
+    ///     a.insert(i, i);
+    /// }
+    ///
+    /// let b = a.split_off(&40, DefaultMemoryImpl::default());
+    ///
+    /// assert_eq!(a.len(), 40);
+    /// assert_eq!(b.len(), 60);
+    /// assert_eq!(a.last_key_value(), Some((39, 39)));
+    /// assert_eq!(b.first_key_value(), Some((40, 40)));
+    /// ```
+    pub fn split_off(&mut self, key: &K, memory: M) -> Self {
+        let mut other = Self::new_with_version(memory, self.version);
+
+        let mut iter = self.range_internal((Bound::Included(key.clone()), Bound::Unbounded));
+        other.bulk_append(std::iter::from_fn(|| iter.next_encoded()));
+
+        self.truncate(key);
+        other
+    }
+
+    /// Moves all the entries from `other` into `self`, leaving `other` empty.
+    ///
+    /// If all the keys of `other` are greater than the keys of `self`, which is the case
+    /// when appending back a map produced by [`split_off`](Self::split_off), the entries
+    /// are appended along the right-most path of the tree, without searching for each of
+    /// them. Otherwise, the entries are inserted one at a time.
+    ///
+    /// If a key from `other` is already present in `self`, the value from `other` overwrites
+    /// the one in `self`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    ///
+    /// let mut a: BTreeMap<u64, u64, _> = BTreeMap::new(DefaultMemoryImpl::default());
+    /// let mut b: BTreeMap<u64, u64, _> = BTreeMap::new(DefaultMemoryImpl::default());
+    /// a.insert(1, 1);
+    /// b.insert(2, 2);
+    ///
+    /// a.append(&mut b);
+    ///
+    /// assert_eq!(a.len(), 2);
+    /// assert!(b.is_empty());
+    /// ```
+    pub fn append(&mut self, other: &mut Self) {
+        if other.is_empty() {
+            return;
+        }
+
+        let is_after = match (self.keys().next_back(), other.keys().next()) {
+            (Some(last), Some(first)) => last < first,
+            _ => true,
+        };
+
+        let mut iter = other.iter_internal();
+        let entries = std::iter::from_fn(|| iter.next_encoded());
+
+        // Appending along the right-most path modifies the nodes on it without copying them,
+        // so the entries are inserted one at a time if nodes may be shared.
+        if is_after && !self.has_shared_nodes() {
+            self.bulk_append(entries);
+        } else {
+            for (key, value) in entries {
+                self.insert_encoded(key, value);
+            }
+        }
+
+        other.clear_new();
     }
 
     /// Returns the first key-value pair in the map. The key in this
@@ -748,6 +1817,14 @@ where
             return None;
         }
 
+        // The subtree counts are decremented on the way down, and shared nodes are copied
+        // before the removal, so the key is looked up first to not write anything if it
+        // doesn't exist.
+        if (self.is_counted() || self.has_shared_nodes()) && !self.contains_key(key) {
+            return None;
+        }
+
+        self.unshare_path(key);
         let root_node = self.load_node(self.root_addr);
         self.remove_helper(root_node, key)
             .map(Cow::Owned)
@@ -760,8 +1837,9 @@ where
             return None;
         }
 
+        let (max_key, _) = self.load_node(self.root_addr).get_max(self.memory());
+        self.unshare_path(&max_key);
         let root = self.load_node(self.root_addr);
-        let (max_key, _) = root.get_max(self.memory());
         self.remove_helper(root, &max_key)
             .map(|v| (max_key, V::from_bytes(Cow::Owned(v))))
     }
@@ -772,12 +1850,142 @@ where
             return None;
         }
 
+        let (min_key, _) = self.load_node(self.root_addr).get_min(self.memory());
+        self.unshare_path(&min_key);
         let root = self.load_node(self.root_addr);
-        let (min_key, _) = root.get_min(self.memory());
         self.remove_helper(root, &min_key)
             .map(|v| (min_key, V::from_bytes(Cow::Owned(v))))
     }
 
+    /// Removes all the entries with keys in the given range, returning the number of
+    /// removed entries.
+    ///
+    /// Subtrees that lie entirely inside the range are deallocated as a whole rather than
+    /// having their entries removed one by one, so only the nodes along the boundaries of
+    /// the range are rebalanced. This makes removing a large range, such as all the entries
+    /// of an expired time bucket, much cheaper than calling [`remove`](Self::remove) for
+    /// each key.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::init(DefaultMemoryImpl::default());
+    ///
+    /// for i in 0..100 {
+    ///     map.insert(i, i * 10);
+    /// }
+    ///
+    /// assert_eq!(map.remove_range(10..90), 80);
+    /// assert_eq!(map.len(), 20);
+    /// assert!(map.keys().take(11).eq((0..10).chain([90])));
+    /// ```
+    pub fn remove_range(&mut self, key_range: impl RangeBounds<K>) -> u64 {
+        if self.root_addr == NULL {
+            return 0;
+        }
+
+        let range = (
+            key_range.start_bound().cloned(),
+            key_range.end_bound().cloned(),
+        );
+
+        match &range {
+            (Bound::Unbounded, Bound::Unbounded) => {
+                let removed = self.length;
+                self.clear_new();
+                return removed;
+            }
+            (Bound::Included(key), Bound::Unbounded) => {
+                // A suffix of the map can be cut in a single pass.
+                return self.truncate(key);
+            }
+            _ if self.has_shared_nodes() => return self.remove_each(range),
+            _ => {}
+        }
+
+        let mut removed = 0;
+        while self.root_addr != NULL {
+            let root = self.load_node(self.root_addr);
+            let step = self.remove_range_step(root, &range);
+            if step == 0 {
+                break;
+            }
+            removed += step;
+
+            if self.is_counted() {
+                self.recount_path(range.0.as_ref());
+            }
+        }
+        removed
+    }
+
+    /// Retains only the elements specified by the predicate.
+    ///
+    /// In other words, removes all pairs `(k, v)` for which `f(&k, &v)` returns `false`.
+    /// The elements are visited in ascending key order, and the removal happens in the
+    /// same pass, rebalancing the nodes along the way.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::init(DefaultMemoryImpl::default());
+    ///
+    /// for i in 0..8 {
+    ///     map.insert(i, i * 10);
+    /// }
+    ///
+    /// // Keep only the elements with even-numbered keys.
+    /// map.retain(|&k, _| k % 2 == 0);
+    /// assert!(map.keys().eq([0, 2, 4, 6]));
+    /// ```
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        self.extract_if(|k, v| !f(k, v)).for_each(drop);
+    }
+
+    /// Creates an iterator that visits all elements in ascending key order and
+    /// uses a closure to determine if an element should be removed.
+    ///
+    /// If the closure returns `true`, the element is removed from the map and yielded.
+    /// If the closure returns `false`, or panics, the element remains in the map and
+    /// will not be yielded.
+    ///
+    /// The iterator is lazy: elements are only removed as the iterator is advanced.
+    /// If the iterator is dropped before being fully consumed, the remaining elements
+    /// are retained. Use [`retain`](Self::retain) if you don't need the removed elements.
+    ///
+    /// The iterator keeps its position in the tree between elements. An element is removed
+    /// from its leaf in place when the leaf has entries to spare, and the tree is only
+    /// searched again from the root when the removal requires rebalancing, when the
+    /// element is in an internal node, or when the map shares nodes with a snapshot or a
+    /// transaction.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::init(DefaultMemoryImpl::default());
+    ///
+    /// for i in 0..8 {
+    ///     map.insert(i, i * 10);
+    /// }
+    ///
+    /// // Split the map into even and odd keys.
+    /// let evens: Vec<_> = map.extract_if(|k, _| k % 2 == 0).collect();
+    /// assert_eq!(evens, vec![(0, 0), (2, 20), (4, 40), (6, 60)]);
+    /// assert!(map.keys().eq([1, 3, 5, 7]));
+    /// ```
+    pub fn extract_if<F>(&mut self, pred: F) -> ExtractIf<'_, K, V, M, F>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        ExtractIf::new(self, pred)
+    }
+
     /// A helper method for recursively removing a key from the B-tree.
     fn remove_helper(&mut self, mut node: Node<K>, key: &K) -> Option<Vec<u8>> {
         if node.address() != self.root_addr {
@@ -846,6 +2054,9 @@ where
                             // Recursively delete the predecessor.
                             // TODO(EXC-1034): Do this in a single pass.
                             let predecessor = left_child.get_max(self.memory());
+                            if self.is_counted() {
+                                node.set_child_count(idx, node.child_count(idx) - 1);
+                            }
                             self.remove_helper(left_child, &predecessor.0)?;
 
                             // Replace the `key` with its predecessor.
@@ -881,6 +2092,9 @@ where
                             // Recursively delete the successor.
                             // TODO(EXC-1034): Do this in a single pass.
                             let successor = right_child.get_min(self.memory());
+                            if self.is_counted() {
+                                node.set_child_count(idx + 1, node.child_count(idx + 1) - 1);
+                            }
                             self.remove_helper(right_child, &successor.0)?;
 
                             // Replace the `key` with its successor.
@@ -922,6 +2136,7 @@ where
 
                         // Remove the right child from the parent node.
                         node.remove_child(idx + 1);
+                        node.set_child_count(idx, new_child.subtree_len() - 1);
 
                         if node.entries_len() == 0 {
                             // Can only happen if this node is root.
@@ -947,203 +2162,230 @@ where
                         // Case 3: The node is an internal node and the key does NOT exist in it.
 
                         // If the key does exist in the tree, it will exist in the subtree at index
-                        // `idx`.
-                        let mut child = self.load_node(node.child(idx));
+                        // `idx`. Make sure an entry can be removed from it, then recurse.
+                        let child = self.prepare_child_for_removal(node, idx, 1);
+                        self.remove_helper(child, key)
+                    }
+                }
+            }
+        }
+    }
 
-                        if child.can_remove_entry_without_merging() {
-                            // The child has enough nodes. Recurse to delete the `key` from the
-                            // `child`.
-                            return self.remove_helper(child, key);
-                        }
+    /// Makes sure that an entry can be removed from the child at index `idx` of `node`
+    /// without merging, either by moving an entry into it from one of its siblings or by
+    /// merging it with a sibling. Returns the child, which may be the result of a merge.
+    ///
+    /// `removed` is the number of entries that are about to be removed from the child's
+    /// subtree, and is accounted for in the subtree counts.
+    ///
+    /// If `node` is the root and runs out of entries, it is deallocated and the returned
+    /// child becomes the new root.
+    fn prepare_child_for_removal(
+        &mut self,
+        mut node: Node<K>,
+        idx: usize,
+        removed: u64,
+    ) -> Node<K> {
+        let mut child = self.load_node(node.child(idx));
+
+        if child.can_remove_entry_without_merging() {
+            // The child has enough nodes.
+            if removed > 0 && self.is_counted() {
+                node.set_child_count(idx, node.child_count(idx) - removed);
+                self.save_node(&mut node);
+            }
+            return child;
+        }
 
-                        // An entry can't be removed from the child without merging.
-                        // See if it has a sibling where an entry can be removed without merging.
-                        let mut left_sibling = if idx > 0 {
-                            Some(self.load_node(node.child(idx - 1)))
-                        } else {
-                            None
-                        };
+        // An entry can't be removed from the child without merging.
+        // See if it has a sibling where an entry can be removed without merging.
+        let mut left_sibling = if idx > 0 {
+            Some(self.load_node(node.child(idx - 1)))
+        } else {
+            None
+        };
 
-                        let mut right_sibling = if idx + 1 < node.children_len() {
-                            Some(self.load_node(node.child(idx + 1)))
-                        } else {
-                            None
-                        };
-
-                        if let Some(ref mut left_sibling) = left_sibling {
-                            if left_sibling.can_remove_entry_without_merging() {
-                                // Case 3.a (left):
-                                // A key can be removed from the left child without merging.
-                                //
-                                //                            [d] (parent)
-                                //                           /   \
-                                //  (left sibling) [a, b, c]     [e, f] (child)
-                                //                         \
-                                //                         [c']
-                                //
-                                // In this case, we move a key down from the parent into the child
-                                // and move a key from the left sibling up into the parent
-                                // resulting in the following tree:
-                                //
-                                //                            [c] (parent)
-                                //                           /   \
-                                //       (left sibling) [a, b]   [d, e, f] (child)
-                                //                              /
-                                //                            [c']
-                                //
-                                // We then recurse to delete the key from the child.
-
-                                // Remove the last entry from the left sibling.
-                                let (left_sibling_key, left_sibling_value) =
-                                    left_sibling.pop_entry(self.memory()).unwrap();
-
-                                // Replace the parent's entry with the one from the left sibling.
-                                let (parent_key, parent_value) = node.swap_entry(
-                                    idx - 1,
-                                    (left_sibling_key, left_sibling_value),
-                                    self.memory(),
-                                );
-
-                                // Move the entry from the parent into the child.
-                                child.insert_entry(0, (parent_key, parent_value));
-
-                                // Move the last child from left sibling into child.
-                                if let Some(last_child) = left_sibling.pop_child() {
-                                    assert_eq!(left_sibling.node_type(), NodeType::Internal);
-                                    assert_eq!(child.node_type(), NodeType::Internal);
-
-                                    child.insert_child(0, last_child);
-                                } else {
-                                    assert_eq!(left_sibling.node_type(), NodeType::Leaf);
-                                    assert_eq!(child.node_type(), NodeType::Leaf);
-                                }
-
-                                self.save_node(left_sibling);
-                                self.save_node(&mut child);
-                                self.save_node(&mut node);
-                                return self.remove_helper(child, key);
-                            }
-                        }
+        let mut right_sibling = if idx + 1 < node.children_len() {
+            Some(self.load_node(node.child(idx + 1)))
+        } else {
+            None
+        };
 
-                        if let Some(right_sibling) = &mut right_sibling {
-                            if right_sibling.can_remove_entry_without_merging() {
-                                // Case 3.a (right):
-                                // A key can be removed from the right child without merging.
-                                //
-                                //                            [c] (parent)
-                                //                           /   \
-                                //             (child) [a, b]     [d, e, f] (right sibling)
-                                //                               /
-                                //                            [d']
-                                //
-                                // In this case, we move a key down from the parent into the child
-                                // and move a key from the right sibling up into the parent
-                                // resulting in the following tree:
-                                //
-                                //                            [d] (parent)
-                                //                           /   \
-                                //          (child) [a, b, c]     [e, f] (right sibling)
-                                //                          \
-                                //                           [d']
-                                //
-                                // We then recurse to delete the key from the child.
-
-                                // Remove the first entry from the right sibling.
-                                let (right_sibling_key, right_sibling_value) =
-                                    right_sibling.remove_entry(0, self.memory());
-
-                                // Replace the parent's entry with the one from the right sibling.
-                                let parent_entry = node.swap_entry(
-                                    idx,
-                                    (right_sibling_key, right_sibling_value),
-                                    self.memory(),
-                                );
-
-                                // Move the entry from the parent into the child.
-                                child.push_entry(parent_entry);
-
-                                // Move the first child of right_sibling into `child`.
-                                match right_sibling.node_type() {
-                                    NodeType::Internal => {
-                                        assert_eq!(child.node_type(), NodeType::Internal);
-                                        child.push_child(right_sibling.remove_child(0));
-                                    }
-                                    NodeType::Leaf => {
-                                        assert_eq!(child.node_type(), NodeType::Leaf);
-                                    }
-                                }
-
-                                self.save_node(right_sibling);
-                                self.save_node(&mut child);
-                                self.save_node(&mut node);
-                                return self.remove_helper(child, key);
-                            }
-                        }
+        if let Some(ref mut left_sibling) = left_sibling {
+            if left_sibling.can_remove_entry_without_merging() {
+                // Case 3.a (left):
+                // A key can be removed from the left child without merging.
+                //
+                //                            [d] (parent)
+                //                           /   \
+                //  (left sibling) [a, b, c]     [e, f] (child)
+                //                         \
+                //                         [c']
+                //
+                // In this case, we move a key down from the parent into the child
+                // and move a key from the left sibling up into the parent
+                // resulting in the following tree:
+                //
+                //                            [c] (parent)
+                //                           /   \
+                //       (left sibling) [a, b]   [d, e, f] (child)
+                //                              /
+                //                            [c']
+                //
+
+                // Remove the last entry from the left sibling.
+                let (left_sibling_key, left_sibling_value) =
+                    left_sibling.pop_entry(self.memory()).unwrap();
+
+                // Replace the parent's entry with the one from the left sibling.
+                let (parent_key, parent_value) = node.swap_entry(
+                    idx - 1,
+                    (left_sibling_key, left_sibling_value),
+                    self.memory(),
+                );
 
-                        // Case 3.b: Both the left and right siblings are at their minimum sizes.
+                // Move the entry from the parent into the child.
+                child.insert_entry(0, (parent_key, parent_value));
 
-                        if let Some(left_sibling) = left_sibling {
-                            // Merge child into left sibling if it exists.
+                // Move the last child from left sibling into child.
+                if let Some((last_child, count)) = left_sibling.pop_child() {
+                    assert_eq!(left_sibling.node_type(), NodeType::Internal);
+                    assert_eq!(child.node_type(), NodeType::Internal);
 
-                            assert!(left_sibling.at_minimum());
-                            let left_sibling = self.merge(
-                                child,
-                                left_sibling,
-                                node.remove_entry(idx - 1, self.memory()),
-                            );
-                            // Removing child from parent.
-                            node.remove_child(idx);
-
-                            if node.entries_len() == 0 {
-                                let node_address = node.address();
-                                self.deallocate_node(node);
-
-                                if node_address == self.root_addr {
-                                    // Update the root.
-                                    self.root_addr = left_sibling.address();
-                                    self.save_header();
-                                }
-                            } else {
-                                self.save_node(&mut node);
-                            }
+                    child.insert_child(0, last_child, count);
+                } else {
+                    assert_eq!(left_sibling.node_type(), NodeType::Leaf);
+                    assert_eq!(child.node_type(), NodeType::Leaf);
+                }
 
-                            return self.remove_helper(left_sibling, key);
-                        }
+                // Update the subtree counts, accounting for the entries that are
+                // about to be removed from the child.
+                node.set_child_count(idx - 1, left_sibling.subtree_len());
+                node.set_child_count(idx, child.subtree_len() - removed);
+
+                self.save_node(left_sibling);
+                self.save_node(&mut child);
+                self.save_node(&mut node);
+                return child;
+            }
+        }
 
-                        if let Some(right_sibling) = right_sibling {
-                            // Merge child into right sibling.
+        if let Some(right_sibling) = &mut right_sibling {
+            if right_sibling.can_remove_entry_without_merging() {
+                // Case 3.a (right):
+                // A key can be removed from the right child without merging.
+                //
+                //                            [c] (parent)
+                //                           /   \
+                //             (child) [a, b]     [d, e, f] (right sibling)
+                //                               /
+                //                            [d']
+                //
+                // In this case, we move a key down from the parent into the child
+                // and move a key from the right sibling up into the parent
+                // resulting in the following tree:
+                //
+                //                            [d] (parent)
+                //                           /   \
+                //          (child) [a, b, c]     [e, f] (right sibling)
+                //                          \
+                //                           [d']
+                //
+
+                // Remove the first entry from the right sibling.
+                let (right_sibling_key, right_sibling_value) =
+                    right_sibling.remove_entry(0, self.memory());
+
+                // Replace the parent's entry with the one from the right sibling.
+                let parent_entry =
+                    node.swap_entry(idx, (right_sibling_key, right_sibling_value), self.memory());
+
+                // Move the entry from the parent into the child.
+                child.push_entry(parent_entry);
+
+                // Move the first child of right_sibling into `child`.
+                match right_sibling.node_type() {
+                    NodeType::Internal => {
+                        assert_eq!(child.node_type(), NodeType::Internal);
+                        let (first_child, count) = right_sibling.remove_child(0);
+                        child.push_child(first_child, count);
+                    }
+                    NodeType::Leaf => {
+                        assert_eq!(child.node_type(), NodeType::Leaf);
+                    }
+                }
 
-                            assert!(right_sibling.at_minimum());
-                            let right_sibling = self.merge(
-                                child,
-                                right_sibling,
-                                node.remove_entry(idx, self.memory()),
-                            );
+                // Update the subtree counts, accounting for the entries that are
+                // about to be removed from the child.
+                node.set_child_count(idx, child.subtree_len() - removed);
+                node.set_child_count(idx + 1, right_sibling.subtree_len());
 
-                            // Removing child from parent.
-                            node.remove_child(idx);
+                self.save_node(right_sibling);
+                self.save_node(&mut child);
+                self.save_node(&mut node);
+                return child;
+            }
+        }
 
-                            if node.entries_len() == 0 {
-                                let node_address = node.address();
-                                self.deallocate_node(node);
+        // Case 3.b: Both the left and right siblings are at their minimum sizes.
 
-                                if node_address == self.root_addr {
-                                    // Update the root.
-                                    self.root_addr = right_sibling.address();
-                                    self.save_header();
-                                }
-                            } else {
-                                self.save_node(&mut node);
-                            }
+        if let Some(left_sibling) = left_sibling {
+            // Merge child into left sibling if it exists.
 
-                            return self.remove_helper(right_sibling, key);
-                        }
+            assert!(left_sibling.at_minimum());
+            let left_sibling = self.merge(
+                child,
+                left_sibling,
+                node.remove_entry(idx - 1, self.memory()),
+            );
+            // Removing child from parent.
+            node.remove_child(idx);
+            node.set_child_count(idx - 1, left_sibling.subtree_len() - removed);
+
+            if node.entries_len() == 0 {
+                let node_address = node.address();
+                self.deallocate_node(node);
+
+                if node_address == self.root_addr {
+                    // Update the root.
+                    self.root_addr = left_sibling.address();
+                    self.save_header();
+                }
+            } else {
+                self.save_node(&mut node);
+            }
 
-                        unreachable!("At least one of the siblings must exist.");
-                    }
+            return left_sibling;
+        }
+
+        if let Some(right_sibling) = right_sibling {
+            // Merge child into right sibling.
+
+            assert!(right_sibling.at_minimum());
+            let right_sibling =
+                self.merge(child, right_sibling, node.remove_entry(idx, self.memory()));
+
+            // Removing child from parent.
+            node.remove_child(idx);
+            node.set_child_count(idx, right_sibling.subtree_len() - removed);
+
+            if node.entries_len() == 0 {
+                let node_address = node.address();
+                self.deallocate_node(node);
+
+                if node_address == self.root_addr {
+                    // Update the root.
+                    self.root_addr = right_sibling.address();
+                    self.save_header();
                 }
+            } else {
+                self.save_node(&mut node);
             }
+
+            return right_sibling;
         }
+
+        unreachable!("At least one of the siblings must exist.");
     }
 
     /// Returns an iterator over the entries of the map, sorted by key.
@@ -1188,6 +2430,110 @@ where
         self.range_internal(key_range).into()
     }
 
+    /// Returns an iterator over the entries in the map whose keys are tuples starting with
+    /// the given prefix, e.g. all the `(Principal, u64)` keys of a given principal.
+    ///
+    /// The first and the last keys with the prefix are found by comparing the prefix with
+    /// the keys of the nodes on the way down, so no key has to be built to bound the range.
+    /// The iteration starts from the nodes visited to find the first key.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    ///
+    /// let mut map: BTreeMap<(u64, u64), u64, _> = BTreeMap::init(DefaultMemoryImpl::default());
+    /// map.insert((1, 1), 10);
+    /// map.insert((2, 1), 20);
+    /// map.insert((2, 2), 30);
+    /// map.insert((3, 1), 40);
+    ///
+    /// let values: Vec<u64> = map.prefix_range(&2).map(|entry| entry.value()).collect();
+    /// assert_eq!(values, vec![20, 30]);
+    /// ```
+    pub fn prefix_range<P>(&self, prefix: &P) -> Iter<'_, K, V, M>
+    where
+        K: TuplePrefix<P>,
+    {
+        match self.last_key_by(|key| key.cmp_prefix(prefix) != Ordering::Greater) {
+            // If the last key up to the prefix has the prefix, then so does the first key
+            // that isn't less than the prefix.
+            Some(last) if last.cmp_prefix(prefix) == Ordering::Equal => {
+                IterInternal::new_from_partition_point(
+                    self,
+                    |key| key.cmp_prefix(prefix) == Ordering::Less,
+                    Bound::Included(last),
+                )
+                .into()
+            }
+            _ => IterInternal::null(self).into(),
+        }
+    }
+
+    /// Returns the last key that satisfies `pred`, assuming that the keys satisfying `pred`
+    /// come first.
+    fn last_key_by(&self, pred: impl Fn(&K) -> bool) -> Option<K> {
+        if self.root_addr == NULL {
+            return None;
+        }
+
+        let mut found = None;
+        let mut node = self.load_node(self.root_addr);
+        loop {
+            let idx = node.partition_point(self.memory(), &pred);
+            if idx > 0 {
+                // A closer key can only be in the child at `idx`.
+                found = Some(node.key(idx - 1, self.memory()).clone());
+            }
+            match node.node_type() {
+                NodeType::Leaf => return found,
+                NodeType::Internal => node = self.load_node(node.child(idx)),
+            }
+        }
+    }
+
+    /// Resumes an iteration from a token returned by [`Iter::token`], returning an iterator
+    /// over the entries that remained to be iterated on, including the entries inserted
+    /// since in that part of the map.
+    ///
+    /// Tokens are rejected if they're malformed, or if the map has been cleared since they
+    /// were created. A token holds the encodings of keys, which are rejected if their size
+    /// doesn't fit `K`'s [`Bound`](crate::storable::Bound), but are otherwise decoded with
+    /// [`Storable::from_bytes`]. A token that was altered can therefore still cause a panic if
+    /// `from_bytes` panics on invalid bytes, so tokens from untrusted sources should be
+    /// authenticated, e.g. with a MAC.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    ///
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::init(DefaultMemoryImpl::default());
+    /// for i in 0..10 {
+    ///     map.insert(i, i * 10);
+    /// }
+    ///
+    /// // Return a page of entries, along with a token to get the next page.
+    /// let mut iter = map.iter();
+    /// let page: Vec<u64> = iter.by_ref().take(4).map(|entry| entry.value()).collect();
+    /// assert_eq!(page, vec![0, 10, 20, 30]);
+    /// let token = iter.token();
+    ///
+    /// let next_page: Vec<u64> = map
+    ///     .iter_from_token(&token)
+    ///     .unwrap()
+    ///     .take(4)
+    ///     .map(|entry| entry.value())
+    ///     .collect();
+    /// assert_eq!(next_page, vec![40, 50, 60, 70]);
+    ///
+    /// map.clear_new();
+    /// assert!(map.iter_from_token(&token).is_err());
+    /// ```
+    pub fn iter_from_token(&self, token: &[u8]) -> Result<Iter<'_, K, V, M>, InvalidToken> {
+        Iter::from_token(self, token)
+    }
+
     /// Returns an iterator starting just before the given key.
     ///
     /// Finds the largest key strictly less than `bound` and starts from it.
@@ -1237,6 +2583,63 @@ where
         self.range_internal(key_range).into()
     }
 
+    /// Returns a cursor at the ghost position of the map, from which it can be moved to the
+    /// first entry with [`next`](Cursor::next), to the last one with [`prev`](Cursor::prev),
+    /// or to any key with [`seek`](Cursor::seek).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::init(DefaultMemoryImpl::default());
+    ///
+    /// for i in 0..10 {
+    ///     map.insert(i * 10, i);
+    /// }
+    ///
+    /// let mut cursor = map.cursor();
+    /// cursor.seek(&35);
+    /// assert_eq!(cursor.peek(), Some((40, 4)));
+    /// assert_eq!(cursor.next(), Some((50, 5)));
+    /// assert_eq!(cursor.prev(), Some((40, 4)));
+    /// ```
+    pub fn cursor(&self) -> Cursor<'_, K, V, M> {
+        Cursor::new(self)
+    }
+
+    /// Returns a cursor at the ghost position of the map, which can also remove entries and
+    /// replace values as it moves.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::init(DefaultMemoryImpl::default());
+    ///
+    /// for i in 0..10 {
+    ///     map.insert(i, i);
+    /// }
+    ///
+    /// // Remove the odd keys and double the values of the even keys from 4 on.
+    /// let mut cursor = map.cursor_mut();
+    /// cursor.seek(&4);
+    /// while let Some((key, value)) = cursor.peek() {
+    ///     if key % 2 == 1 {
+    ///         cursor.remove_current();
+    ///     } else {
+    ///         cursor.replace_value(value * 2);
+    ///         cursor.next();
+    ///     }
+    /// }
+    ///
+    /// assert!(map.iter().map(|e| e.into_pair()).eq([
+    ///     (0, 0), (1, 1), (2, 2), (3, 3), (4, 8), (6, 12), (8, 16)
+    /// ]));
+    /// ```
+    pub fn cursor_mut(&mut self) -> CursorMut<'_, K, V, M> {
+        CursorMut::new(self)
+    }
+
     fn iter_internal(&self) -> IterInternal<'_, K, V, M> {
         IterInternal::new(self)
     }
@@ -1255,6 +2658,206 @@ where
         IterInternal::new_in_range(self, range)
     }
 
+    /// Removes all the entries with keys greater than or equal to `key`.
+    ///
+    /// Descends along the path to `key`, cutting each node on the path at `key` and
+    /// deallocating the subtrees to the right of the cut as a whole. The cut nodes form the
+    /// new right-most path of the tree, which is then rebalanced.
+    /// Returns the number of removed entries.
+    fn truncate(&mut self, key: &K) -> u64 {
+        if self.root_addr == NULL {
+            return 0;
+        }
+
+        if self.has_shared_nodes() {
+            return self.remove_each((Bound::Included(key.clone()), Bound::Unbounded));
+        }
+
+        // The path from the root down to the last node that was cut.
+        let mut path = vec![];
+        let mut removed = 0;
+        let mut node = self.load_node(self.root_addr);
+        loop {
+            let (idx, found) = match node.search(key, self.memory()) {
+                Ok(idx) => (idx, true),
+                Err(idx) => (idx, false),
+            };
+
+            while node.entries_len() > idx {
+                node.pop_entry(self.memory());
+                removed += 1;
+            }
+            while node.children_len() > idx + 1 {
+                let (child, _) = node.pop_child().unwrap();
+                removed += self.deallocate_subtree(child);
+            }
+
+            // If the key was found, the child to its left only has smaller keys and is kept
+            // as is. Otherwise, the child at `idx` can hold keys on both sides of the cut.
+            let next = match node.node_type() {
+                NodeType::Internal if !found => Some(node.child(idx)),
+                _ => None,
+            };
+            path.push(node);
+
+            match next {
+                Some(address) => node = self.load_node(address),
+                None => break,
+            }
+        }
+
+        self.length -= removed;
+        path.reverse();
+        self.rebalance_right_path(path);
+        removed
+    }
+
+    /// Removes the entries in `range` one at a time, returning the number of removed entries.
+    ///
+    /// Used instead of cutting whole subtrees when nodes may be shared, as
+    /// each removal copies the shared nodes it modifies.
+    fn remove_each(&mut self, range: (Bound<K>, Bound<K>)) -> u64 {
+        let mut removed = 0;
+        while let Some(key) = self.keys_range(range.clone()).next() {
+            self.remove(&key);
+            removed += 1;
+        }
+        removed
+    }
+
+    /// Removes some of the entries in `range` from the subtree of `node`, which must be able
+    /// to lose an entry without merging unless it's the root.
+    /// Returns the number of removed entries, which is zero only if the range is empty.
+    ///
+    /// An entry is only removed from a node if the node stays above its minimum size, so a
+    /// single call may not remove the whole range.
+    fn remove_range_step(&mut self, mut node: Node<K>, range: &(Bound<K>, Bound<K>)) -> u64 {
+        // The entries at `start..end` of the node are in the range.
+        let start = self.entries_before(&node, range.0.as_ref(), false);
+        let end = self.entries_before(&node, range.1.as_ref(), true);
+        if end < start {
+            // The range is empty (its start is after its end).
+            return 0;
+        }
+
+        let is_root = node.address() == self.root_addr;
+        match node.node_type() {
+            NodeType::Leaf => {
+                let mut removed = end - start;
+                if !is_root {
+                    removed = removed.min(node.entries_len() + 1 - node::B);
+                }
+
+                for _ in 0..removed {
+                    node.remove_entry(start, self.memory());
+                }
+
+                if node.entries_len() == 0 {
+                    // Can only happen if this node is root.
+                    self.deallocate_node(node);
+                    self.root_addr = NULL;
+                } else if removed > 0 {
+                    self.save_node(&mut node);
+                }
+
+                self.length -= removed as u64;
+                self.save_header();
+                removed as u64
+            }
+            NodeType::Internal => match end - start {
+                0 => {
+                    // None of the node's entries are in the range, so the whole range is in
+                    // the child at `start`.
+                    let child = self.prepare_child_for_removal(node, start, 0);
+                    self.remove_range_step(child, range)
+                }
+                1 => {
+                    // A single entry is in the range. Remove it as `remove` would.
+                    let key = node.key(start, self.memory()).clone();
+                    self.remove_helper(node, &key);
+                    1
+                }
+                _ => {
+                    // The subtree between two consecutive entries in the range lies
+                    // entirely inside the range, so it's removed along with the entry to its
+                    // left without visiting it entry by entry.
+                    let mut removed = 0;
+                    let mut end = end;
+                    while end - start >= 2 && (is_root || node.can_remove_entry_without_merging()) {
+                        node.remove_entry(start, self.memory());
+                        let (child, _) = node.remove_child(start + 1);
+                        removed += 1 + self.deallocate_subtree(child);
+                        end -= 1;
+                    }
+
+                    self.save_node(&mut node);
+                    self.length -= removed;
+                    self.save_header();
+                    removed
+                }
+            },
+        }
+    }
+
+    /// Returns the number of entries of `node` that come before the given bound of a range,
+    /// where `is_end` tells whether it's the start or the end bound.
+    fn entries_before(&self, node: &Node<K>, bound: Bound<&K>, is_end: bool) -> usize {
+        let (key, count_equal) = match bound {
+            Bound::Included(key) => (key, is_end),
+            Bound::Excluded(key) => (key, !is_end),
+            Bound::Unbounded if is_end => return node.entries_len(),
+            Bound::Unbounded => return 0,
+        };
+
+        match node.search(key, self.memory()) {
+            Ok(idx) if count_equal => idx + 1,
+            Ok(idx) | Err(idx) => idx,
+        }
+    }
+
+    /// Recomputes the subtree counts along the path to the given range start.
+    ///
+    /// [`remove_range_step`](Self::remove_range_step) doesn't know how many entries it
+    /// removes before reaching the bottom of its path, so it leaves the counts of the
+    /// path to be fixed afterwards.
+    fn recount_path(&mut self, start: Bound<&K>) {
+        if self.root_addr == NULL {
+            return;
+        }
+
+        let mut path = vec![];
+        let mut node = self.load_node(self.root_addr);
+        while node.node_type() == NodeType::Internal {
+            let idx = self.entries_before(&node, start, false);
+            let child = self.load_node(node.child(idx));
+            path.push((node, idx));
+            node = child;
+        }
+
+        let mut count = node.subtree_len();
+        while let Some((mut parent, idx)) = path.pop() {
+            if parent.child_count(idx) != count {
+                parent.set_child_count(idx, count);
+                self.save_node(&mut parent);
+            }
+            count = parent.subtree_len();
+        }
+    }
+
+    /// Deallocates all the nodes of the subtree at the given address.
+    /// Returns the number of entries that the subtree contained.
+    fn deallocate_subtree(&mut self, address: Address) -> u64 {
+        let node = self.load_node(address);
+        let mut count = node.entries_len() as u64;
+        if node.node_type() == NodeType::Internal {
+            for i in 0..node.children_len() {
+                count += self.deallocate_subtree(node.child(i));
+            }
+        }
+        self.deallocate_node(node);
+        count
+    }
+
     /// Merges one node (`source`) into another (`into`), along with a median entry.
     ///
     /// Example (values are not included for brevity):
@@ -1267,7 +2870,11 @@ where
     /// Output:
     ///   [1, 2, 3, 4, 5, 6, 7] (stored in the `into` node)
     ///   `source` is deallocated.
-    fn merge(&mut self, source: Node<K>, mut into: Node<K>, median: Entry<K>) -> Node<K> {
+    fn merge(&mut self, source: Node<K>, mut into: Node<K>, median: node::Entry<K>) -> Node<K> {
+        self.migrate_node(&mut into);
+        let cache = self.node_cache.get_mut();
+        cache.remove(source.address());
+        cache.remove(into.address());
         into.merge(source, median, &mut self.allocator);
         into
     }
@@ -1277,27 +2884,54 @@ where
         match self.version {
             Version::V1(page_size) => Node::new_v1(self.allocator.allocate(), node_type, page_size),
             Version::V2(page_size) => Node::new_v2(self.allocator.allocate(), node_type, page_size),
+            Version::V3(page_size) => Node::new_v3(self.allocator.allocate(), node_type, page_size),
+            Version::V4(page_size) => Node::new_v4(self.allocator.allocate(), node_type, page_size),
         }
     }
 
     /// Deallocates a node.
     #[inline]
     fn deallocate_node(&mut self, node: Node<K>) {
+        self.node_cache.get_mut().remove(node.address());
         node.deallocate(self.allocator_mut());
     }
 
     /// Loads a node from memory.
     #[inline]
     fn load_node(&self, address: Address) -> Node<K> {
-        Node::load(address, self.version.page_size(), self.memory())
+        let mut cache = self.node_cache.borrow_mut();
+        if !cache.is_enabled() {
+            return Node::load(address, self.version.page_size(), self.memory());
+        }
+
+        if let Some(node) = cache.get(address) {
+            return node;
+        }
+
+        let node = Node::load(address, self.version.page_size(), self.memory());
+        cache.insert(&node);
+        node
     }
 
     /// Saves the node to memory.
     #[inline]
     fn save_node(&mut self, node: &mut Node<K>) {
+        self.migrate_node(node);
+        self.node_cache.get_mut().remove(node.address());
         node.save(self.allocator_mut());
     }
 
+    /// Switches a node written with an older layout to the layout of the map, which is how
+    /// maps are lazily migrated to prefix-compressed keys.
+    #[inline]
+    fn migrate_node(&self, node: &mut Node<K>) {
+        if let Version::V4(_) = self.version {
+            if node.version() != self.version {
+                node.migrate(self.version, self.memory());
+            }
+        }
+    }
+
     /// Replaces the value at `idx` in the node, saves the node, and returns the old value.
     fn update_value(&mut self, node: &mut Node<K>, idx: usize, new_value: Vec<u8>) -> Vec<u8> {
         let old_value = node.swap_value(idx, new_value, self.memory());
@@ -1305,15 +2939,92 @@ where
         old_value
     }
 
+    /// Returns true if the memory contains a map.
+    fn contains_map(memory: &M) -> bool {
+        if memory.size() == 0 {
+            return false;
+        }
+        let mut magic = [0; 3];
+        memory.read(0, &mut magic);
+        &magic == MAGIC
+    }
+
+    /// Returns the map's generation, which changes whenever the map is cleared or a new map
+    /// is created in its memory.
+    fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Starts a new generation of the map.
+    fn next_generation(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
+        self.save_header_extension();
+    }
+
+    /// Saves the address of the list of snapshots and the generation to memory.
+    ///
+    /// They're saved separately from the rest of the header since they change rarely.
+    fn save_header_extension(&self) {
+        Self::write_header_extension(self.memory(), self.saved_snapshots(), self.generation);
+    }
+
+    /// Reads the address of the list of snapshots and the generation from the header, or
+    /// returns `NULL` and zero if the header doesn't have the extension marker.
+    fn read_header_extension(memory: &M) -> (Address, u64) {
+        let mut marker = [0; 4];
+        memory.read(HEADER_EXTENSION_OFFSET as u64, &mut marker);
+        if &marker != HEADER_EXTENSION_MARKER {
+            return (NULL, 0);
+        }
+        (
+            Address::from(crate::read_u64(
+                memory,
+                Address::from(SNAPSHOTS_OFFSET as u64),
+            )),
+            crate::read_u64(memory, Address::from(GENERATION_OFFSET as u64)),
+        )
+    }
+
+    /// Writes the address of the list of snapshots and the generation to the header, along
+    /// with the extension marker.
+    ///
+    /// Nothing is written if neither field is set and the header doesn't have the marker yet.
+    fn write_header_extension(memory: &M, snapshots: Address, generation: u64) {
+        if snapshots == NULL && generation == 0 {
+            let mut marker = [0; 4];
+            memory.read(HEADER_EXTENSION_OFFSET as u64, &mut marker);
+            if &marker != HEADER_EXTENSION_MARKER {
+                return;
+            }
+        }
+        crate::write_u64(
+            memory,
+            Address::from(SNAPSHOTS_OFFSET as u64),
+            snapshots.get(),
+        );
+        crate::write_u64(memory, Address::from(GENERATION_OFFSET as u64), generation);
+        crate::write(
+            memory,
+            HEADER_EXTENSION_OFFSET as u64,
+            HEADER_EXTENSION_MARKER,
+        );
+    }
+
     /// Saves the map to memory.
     fn save_header(&self) {
+        // The tree a transaction modifies only replaces the saved one when it's committed.
+        let (root_addr, length) = match &self.committed {
+            Some(committed) => (committed.root_addr, committed.length),
+            None => (self.root_addr, self.length),
+        };
         let header = BTreeHeader {
             version: self.version,
-            root_addr: self.root_addr,
-            length: self.length,
+            root_addr,
+            length,
         };
 
         Self::write_header(&header, self.memory());
+        self.save_transaction_record();
     }
 
     /// Write the layout header to the memory.
@@ -1339,6 +3050,24 @@ where
                 buf[4..8].copy_from_slice(&page_size.to_le_bytes());
                 buf[8..12].copy_from_slice(&PAGE_SIZE_VALUE_MARKER.to_le_bytes());
             }
+            Version::V3(page_size) => {
+                buf[3] = LAYOUT_VERSION_3;
+                buf[4..8].copy_from_slice(&page_size.get().to_le_bytes());
+                buf[8..12].copy_from_slice(&PAGE_SIZE_VALUE_MARKER.to_le_bytes());
+            }
+            Version::V4(PageSize::Derived(DerivedPageSize {
+                max_key_size,
+                max_value_size,
+            })) => {
+                buf[3] = LAYOUT_VERSION_4;
+                buf[4..8].copy_from_slice(&max_key_size.to_le_bytes());
+                buf[8..12].copy_from_slice(&max_value_size.to_le_bytes());
+            }
+            Version::V4(PageSize::Value(page_size)) => {
+                buf[3] = LAYOUT_VERSION_4;
+                buf[4..8].copy_from_slice(&page_size.to_le_bytes());
+                buf[8..12].copy_from_slice(&PAGE_SIZE_VALUE_MARKER.to_le_bytes());
+            }
         };
         buf[12..20].copy_from_slice(&header.root_addr.get().to_le_bytes());
         buf[20..28].copy_from_slice(&header.length.to_le_bytes());
@@ -1476,7 +3205,8 @@ mod test {
         Rc::new(RefCell::new(Vec::new()))
     }
 
-    /// A test runner that runs the test using V1, migrated V2, and direct V2.
+    /// A test runner that runs the test using V1, migrated V2, direct V2, V3 (counted), and
+    /// V4 (compressed).
     pub fn run_btree_test<K, V, R, F>(f: F)
     where
         K: Storable + Ord + Clone,
@@ -1501,6 +3231,16 @@ mod test {
         let mem = make_memory();
         let tree_v2 = BTreeMap::new(mem);
         f(tree_v2);
+
+        // Test with V3.
+        let mem = make_memory();
+        let tree_v3 = BTreeMap::new_counted(mem);
+        f(tree_v3);
+
+        // Test with V4.
+        let mem = make_memory();
+        let tree_v4 = BTreeMap::new_compressed(mem);
+        f(tree_v4);
     }
 
     /// Checks that objects from boundary u32 values are strictly increasing.
@@ -2187,254 +3927,2060 @@ mod test {
                 vec![e(1), e(2), e(3), e(4), e(5)]
             );
 
-            let child_1 = btree.load_node(root.child(1));
-            assert_eq!(child_1.node_type(), NodeType::Leaf);
-            assert_eq!(
-                child_1.entries(btree.memory()),
-                vec![e(8), e(9), e(10), e(11), e(12)]
-            );
+            let child_1 = btree.load_node(root.child(1));
+            assert_eq!(child_1.node_type(), NodeType::Leaf);
+            assert_eq!(
+                child_1.entries(btree.memory()),
+                vec![e(8), e(9), e(10), e(11), e(12)]
+            );
+
+            // There are three allocated nodes.
+            assert_eq!(btree.allocator.num_allocated_chunks(), 3);
+
+            // Remove node 3. Triggers case 3.b
+            assert_eq!(btree.remove(&key(3)), Some(V::empty()));
+
+            // Reload the btree to verify that we saved it correctly.
+            let btree = BTreeMap::<K, V, _>::load(btree.into_memory());
+
+            // The result should look like this:
+            //
+            // [1, 2, 4, 5, 7, 8, 9, 10, 11, 12]
+            let root = btree.load_node(btree.root_addr);
+            assert_eq!(root.node_type(), NodeType::Leaf);
+            assert_eq!(
+                root.entries(btree.memory()),
+                collect([1, 2, 4, 5, 7, 8, 9, 10, 11, 12].into_iter().map(e))
+            );
+
+            // There is only one allocated node remaining.
+            assert_eq!(btree.allocator.num_allocated_chunks(), 1);
+        });
+    }
+    btree_test!(
+        test_remove_case_3b_merge_into_right,
+        remove_case_3b_merge_into_right
+    );
+
+    fn remove_case_3b_merge_into_left<K: TestKey, V: TestValue>() {
+        let key = K::build;
+        let e = |i: u32| (key(i), encode(V::empty()));
+        run_btree_test(|mut btree| {
+            for i in 1..=11 {
+                assert_eq!(btree.insert(key(i), V::empty()), None);
+            }
+
+            // Should now split a node.
+            assert_eq!(btree.insert(key(12), V::empty()), None);
+
+            // The result should look like this:
+            //                [6]
+            //               /   \
+            // [1, 2, 3, 4, 5]   [7, 8, 9, 10, 11, 12]
+
+            for i in 1..=12 {
+                assert_eq!(btree.get(&key(i)), Some(V::empty()));
+            }
+
+            // Remove node 6. Triggers case 2.b
+            assert_eq!(btree.remove(&key(6)), Some(V::empty()));
+
+            // The result should look like this:
+            //                [7]
+            //               /   \
+            // [1, 2, 3, 4, 5]   [8, 9, 10, 11, 12]
+            let root = btree.load_node(btree.root_addr);
+            assert_eq!(root.node_type(), NodeType::Internal);
+            assert_eq!(root.entries(btree.memory()), vec![e(7)]);
+            assert_eq!(root.children_len(), 2);
+
+            let child_0 = btree.load_node(root.child(0));
+            assert_eq!(child_0.node_type(), NodeType::Leaf);
+            assert_eq!(
+                child_0.entries(btree.memory()),
+                vec![e(1), e(2), e(3), e(4), e(5)]
+            );
+
+            let child_1 = btree.load_node(root.child(1));
+            assert_eq!(child_1.node_type(), NodeType::Leaf);
+            assert_eq!(
+                child_1.entries(btree.memory()),
+                vec![e(8), e(9), e(10), e(11), e(12)]
+            );
+
+            // There are three allocated nodes.
+            assert_eq!(btree.allocator.num_allocated_chunks(), 3);
+
+            // Remove node 10. Triggers case 3.b where we merge the right into the left.
+            assert_eq!(btree.remove(&key(10)), Some(V::empty()));
+
+            // Reload the btree to verify that we saved it correctly.
+            let btree = BTreeMap::<K, V, _>::load(btree.into_memory());
+
+            // The result should look like this:
+            //
+            // [1, 2, 3, 4, 5, 7, 8, 9, 11, 12], 10 is gone
+            let root = btree.load_node(btree.root_addr);
+            assert_eq!(root.node_type(), NodeType::Leaf);
+            assert_eq!(
+                root.entries(btree.memory()),
+                vec![e(1), e(2), e(3), e(4), e(5), e(7), e(8), e(9), e(11), e(12)]
+            );
+
+            // There is only one allocated node remaining.
+            assert_eq!(btree.allocator.num_allocated_chunks(), 1);
+        });
+    }
+    btree_test!(
+        test_remove_case_3b_merge_into_left,
+        remove_case_3b_merge_into_left
+    );
+
+    fn insert_remove_many<K: TestKey, V: TestValue>() {
+        let (key, value) = (K::build, V::build);
+        run_btree_test(|mut btree| {
+            let n = 10_000;
+            for i in 0..n {
+                assert_eq!(btree.insert(key(i), value(i)), None);
+                assert_eq!(btree.get(&key(i)), Some(value(i)));
+            }
+
+            let mut btree = BTreeMap::<K, V, _>::load(btree.into_memory());
+            for i in 0..n {
+                assert_eq!(btree.remove(&key(i)), Some(value(i)));
+                assert_eq!(btree.get(&key(i)), None);
+            }
+
+            // We've deallocated everything.
+            assert_eq!(btree.allocator.num_allocated_chunks(), 0);
+        });
+    }
+    btree_test!(test_insert_remove_many, insert_remove_many);
+
+    fn pop_first_many<K: TestKey, V: TestValue>() {
+        let (key, value) = (K::build, V::build);
+        run_btree_test(|mut btree| {
+            let n = 10_000;
+
+            assert!(btree.is_empty());
+            assert_eq!(btree.pop_first(), None);
+
+            for i in 0..n {
+                assert_eq!(btree.insert(key(i), value(i)), None);
+            }
+            assert_eq!(btree.len(), n as u64);
+
+            let mut btree = BTreeMap::<K, V, _>::load(btree.into_memory());
+            for i in 0..n {
+                assert_eq!(btree.pop_first(), Some((key(i), value(i))));
+                assert_eq!(btree.get(&key(i)), None);
+            }
+
+            // We've deallocated everything.
+            assert!(btree.is_empty());
+            assert_eq!(btree.allocator.num_allocated_chunks(), 0);
+        });
+    }
+    btree_test!(test_pop_first_many, pop_first_many);
+
+    fn pop_last_many<K: TestKey, V: TestValue>() {
+        let (key, value) = (K::build, V::build);
+        run_btree_test(|mut btree| {
+            let n = 10_000;
+
+            assert!(btree.is_empty());
+            assert_eq!(btree.pop_last(), None);
+
+            for i in 0..n {
+                assert_eq!(btree.insert(key(i), value(i)), None);
+            }
+            assert_eq!(btree.len(), n as u64);
+
+            let mut btree = BTreeMap::<K, V, _>::load(btree.into_memory());
+            for i in (0..n).rev() {
+                assert_eq!(btree.pop_last(), Some((key(i), value(i))));
+                assert_eq!(btree.get(&key(i)), None);
+            }
+
+            // We've deallocated everything.
+            assert!(btree.is_empty());
+            assert_eq!(btree.allocator.num_allocated_chunks(), 0);
+        });
+    }
+    btree_test!(test_pop_last_many, pop_last_many);
+
+    fn reloading<K: TestKey, V: TestValue>() {
+        let (key, value) = (K::build, V::build);
+        run_btree_test(|mut btree| {
+            let n = 1_000;
+            for i in 0..n {
+                assert_eq!(btree.insert(key(i), value(i)), None);
+                assert_eq!(btree.get(&key(i)), Some(value(i)));
+            }
+            assert_eq!(btree.len(), n as u64);
+
+            // Reload the BTreeMap and verify the entry.
+            let mut btree = BTreeMap::<K, V, VectorMemory>::load(btree.into_memory());
+            assert_eq!(btree.len(), n as u64);
+            for i in 0..n {
+                assert_eq!(btree.get(&key(i)), Some(value(i)));
+            }
+
+            // Remove all entries and verify the entry.
+            for i in 0..n {
+                assert_eq!(btree.remove(&key(i)), Some(value(i)));
+            }
+            assert_eq!(btree.len(), 0);
+
+            // Reload the BTreeMap and verify the entry.
+            let btree = BTreeMap::<K, V, VectorMemory>::load(btree.into_memory());
+            assert_eq!(btree.len(), 0);
+        });
+    }
+    btree_test!(test_reloading, reloading);
+
+    fn len<K: TestKey, V: TestValue>() {
+        let (key, value) = (K::build, V::build);
+        run_btree_test(|mut btree| {
+            let n = 1_000;
+            for i in 0..n {
+                assert_eq!(btree.insert(key(i), value(i)), None);
+            }
+
+            assert_eq!(btree.len(), n as u64);
+            assert!(!btree.is_empty());
+
+            for i in 0..n {
+                assert_eq!(btree.remove(&key(i)), Some(value(i)));
+            }
+
+            assert_eq!(btree.len(), 0);
+            assert!(btree.is_empty());
+        });
+    }
+    btree_test!(test_len, len);
+
+    fn contains_key<K: TestKey, V: TestValue>() {
+        let (key, value) = (K::build, V::build);
+        run_btree_test(|mut btree| {
+            let n = 1_000;
+            for i in (0..n).step_by(2) {
+                assert_eq!(btree.insert(key(i), value(i)), None);
+            }
+
+            // Only even keys should be present.
+            for i in 0..n {
+                assert_eq!(btree.contains_key(&key(i)), i % 2 == 0);
+            }
+        });
+    }
+    btree_test!(test_contains_key, contains_key);
+
+    /// Asserts that all the leaves are at the same depth, that every node but the root
+    /// has at least `B - 1` entries, and, for counted maps, that the subtree counts are
+    /// accurate. Returns the number of entries in the tree.
+    fn assert_balanced<K: Storable + Ord + Clone, V: Storable, M: Memory>(
+        btree: &BTreeMap<K, V, M>,
+    ) -> u64 {
+        fn helper<K: Storable + Ord + Clone, V: Storable, M: Memory>(
+            btree: &BTreeMap<K, V, M>,
+            address: Address,
+            depth: usize,
+            leaf_depth: &mut Option<usize>,
+        ) -> u64 {
+            let node = btree.load_node(address);
+            if address != btree.root_addr {
+                assert!(node.entries_len() >= node::B - 1, "node is underfull");
+            }
+            match node.node_type() {
+                NodeType::Leaf => {
+                    assert_eq!(*leaf_depth.get_or_insert(depth), depth);
+                    node.entries_len() as u64
+                }
+                NodeType::Internal => {
+                    assert_eq!(node.children_len(), node.entries_len() + 1);
+                    (0..node.children_len())
+                        .map(|i| {
+                            let count = helper(btree, node.child(i), depth + 1, leaf_depth);
+                            if btree.is_counted() {
+                                assert_eq!(node.child_count(i), count, "wrong subtree count");
+                            }
+                            count
+                        })
+                        .sum::<u64>()
+                        + node.entries_len() as u64
+                }
+            }
+        }
+
+        if btree.root_addr == NULL {
+            return 0;
+        }
+        helper(btree, btree.root_addr, 0, &mut None)
+    }
+
+    fn bulk_load<K: TestKey, V: TestValue>() {
+        let (key, value) = (K::build, V::build);
+        // Sizes around the boundaries of full nodes at different levels.
+        for n in [
+            0, 1, 5, 11, 12, 13, 17, 100, 131, 132, 143, 1_000, 1_583, 1_584, 2_000,
+        ] {
+            run_btree_test(|mut btree| {
+                btree.bulk_append((0..n).map(|i| (key(i), value(i).into_bytes_checked())));
+
+                assert_eq!(btree.len(), n as u64);
+                assert_eq!(assert_balanced(&btree), n as u64);
+                let expected: Vec<_> = (0..n).map(|i| (key(i), value(i))).collect();
+                assert_eq!(collect(btree.iter().map(|e| e.into_pair())), expected);
+                for i in 0..n {
+                    assert_eq!(btree.get(&key(i)), Some(value(i)));
+                }
+
+                // The loaded tree supports the regular operations.
+                for i in n..n + 100 {
+                    assert_eq!(btree.insert(key(i), value(i)), None);
+                }
+                for i in 0..n + 100 {
+                    assert_eq!(btree.remove(&key(i)), Some(value(i)));
+                }
+                assert!(btree.is_empty());
+                assert_eq!(btree.allocator.num_allocated_chunks(), 0);
+            });
+        }
+    }
+    btree_test!(test_bulk_load, bulk_load);
+
+    fn split_off_and_append<K: TestKey, V: TestValue>() {
+        let (key, value) = (K::build, V::build);
+        for n in [0u32, 1, 11, 12, 100, 500] {
+            for at in [
+                0,
+                1,
+                n / 3,
+                (n / 2).saturating_sub(1),
+                n / 2,
+                n.saturating_sub(1),
+                n,
+                n + 5,
+            ] {
+                run_btree_test(|mut btree| {
+                    for i in 0..n {
+                        btree.insert(key(i), value(i));
+                    }
+
+                    let mut other = btree.split_off(&key(at), make_memory());
+                    let at = at.min(n);
+
+                    assert_eq!(other.version, btree.version);
+
+                    assert_eq!(btree.len(), at as u64);
+                    assert_eq!(assert_balanced(&btree), at as u64);
+                    assert_eq!(
+                        collect(btree.iter().map(|e| e.into_pair())),
+                        (0..at).map(|i| (key(i), value(i))).collect::<Vec<_>>()
+                    );
+                    assert_eq!(other.len(), (n - at) as u64);
+                    assert_eq!(assert_balanced(&other), (n - at) as u64);
+                    assert_eq!(
+                        collect(other.iter().map(|e| e.into_pair())),
+                        (at..n).map(|i| (key(i), value(i))).collect::<Vec<_>>()
+                    );
+
+                    // Appending the split off entries restores the map.
+                    btree.append(&mut other);
+                    assert!(other.is_empty());
+                    assert_eq!(btree.len(), n as u64);
+                    assert_eq!(assert_balanced(&btree), n as u64);
+                    for i in 0..n {
+                        assert_eq!(btree.remove(&key(i)), Some(value(i)));
+                    }
+
+                    // No nodes were leaked.
+                    assert_eq!(btree.allocator.num_allocated_chunks(), 0);
+                });
+            }
+        }
+    }
+    btree_test!(test_split_off_and_append, split_off_and_append);
+
+    fn append_interleaved<K: TestKey, V: TestValue>() {
+        let (key, value) = (K::build, V::build);
+        run_btree_test(|mut btree| {
+            let n = 500;
+            let mut other = BTreeMap::new(make_memory());
+            for i in 0..n {
+                if i % 2 == 0 {
+                    btree.insert(key(i), value(i));
+                } else {
+                    other.insert(key(i), value(i));
+                }
+            }
+            // Overlapping keys take the value from `other`.
+            other.insert(key(0), value(n));
+
+            btree.append(&mut other);
+
+            assert!(other.is_empty());
+            assert_eq!(btree.len(), n as u64);
+            assert_eq!(btree.get(&key(0)), Some(value(n)));
+            for i in 1..n {
+                assert_eq!(btree.get(&key(i)), Some(value(i)));
+            }
+        });
+    }
+    btree_test!(test_append_interleaved, append_interleaved);
+
+    fn remove_range<K: TestKey, V: TestValue>() {
+        use Bound::*;
+        let (key, value) = (K::build, V::build);
+        for n in [0u32, 1, 11, 12, 100, 500] {
+            let ranges = [
+                (Unbounded, Unbounded),
+                (Included(0), Excluded(n / 2)),
+                (Included(n / 3), Unbounded),
+                (Excluded(n / 3), Unbounded),
+                (Unbounded, Included(n / 2)),
+                (Included(n / 4), Included(3 * n / 4)),
+                (Excluded(1), Excluded(n.saturating_sub(1))),
+                (Included(n / 2), Excluded(n / 2 + 1)),
+                (Included(n / 2), Excluded(n / 2)),
+                (Included(n + 1), Unbounded),
+            ];
+            for (start, end) in ranges {
+                run_btree_test(|mut btree| {
+                    for i in 0..n {
+                        btree.insert(key(i), value(i));
+                    }
+
+                    let in_range = |i: &u32| (start, end).contains(i);
+                    let expected: Vec<_> = (0..n)
+                        .filter(|i| !in_range(i))
+                        .map(|i| (key(i), value(i)))
+                        .collect();
+
+                    let removed = btree.remove_range((start.map(key), end.map(key)));
+
+                    assert_eq!(removed, (0..n).filter(in_range).count() as u64);
+                    assert_eq!(btree.len(), expected.len() as u64);
+                    assert_eq!(assert_balanced(&btree), expected.len() as u64);
+                    assert_eq!(collect(btree.iter().map(|e| e.into_pair())), expected);
+
+                    // No nodes were leaked.
+                    for (k, _) in expected {
+                        btree.remove(&k);
+                    }
+                    assert_eq!(btree.allocator.num_allocated_chunks(), 0);
+                });
+            }
+        }
+    }
+    btree_test!(test_remove_range, remove_range);
+
+    fn order_statistics<K: TestKey, V: TestValue>() {
+        let (key, value) = (K::build, V::build);
+        run_btree_test(|mut btree| {
+            let n = 500;
+            // Insert the keys in a scrambled order.
+            for i in 0..n {
+                let j = (i * 7_919) % n;
+                assert_eq!(btree.insert(key(j), value(j)), None);
+            }
+            assert_eq!(assert_balanced(&btree), n as u64);
+
+            for i in 0..n {
+                assert_eq!(btree.nth(i as u64), Some((key(i), value(i))));
+                assert_eq!(btree.rank(&key(i)), i as u64);
+            }
+            assert_eq!(btree.nth(n as u64), None);
+            assert_eq!(btree.iter().count(), n as usize);
+            assert_eq!(btree.range(key(100)..key(200)).count(), 100);
+            assert_eq!(btree.range(key(100)..=key(200)).count(), 101);
+            assert_eq!(
+                btree
+                    .range((Bound::Excluded(key(100)), Bound::Unbounded))
+                    .count(),
+                (n - 101) as usize
+            );
+
+            // Remove the even keys. Removing missing keys and overwriting existing ones
+            // doesn't change the counts.
+            for i in (0..n).step_by(2) {
+                assert_eq!(btree.remove(&key(i)), Some(value(i)));
+            }
+            for i in (0..n).step_by(2) {
+                // The removal of a missing key can still rebalance the nodes on its path.
+                assert_eq!(btree.remove(&key(i)), None);
+            }
+            assert_eq!(btree.remove(&key(n)), None);
+            for i in (1..n).step_by(2) {
+                assert_eq!(btree.insert(key(i), value(i)), Some(value(i)));
+            }
+            assert_eq!(assert_balanced(&btree), (n / 2) as u64);
+
+            for i in 0..n / 2 {
+                assert_eq!(
+                    btree.nth(i as u64),
+                    Some((key(2 * i + 1), value(2 * i + 1)))
+                );
+                assert_eq!(btree.rank(&key(2 * i)), i as u64);
+                assert_eq!(btree.rank(&key(2 * i + 1)), i as u64);
+            }
+            assert_eq!(btree.range(key(100)..key(200)).count(), 50);
+        });
+    }
+    btree_test!(test_order_statistics, order_statistics);
+
+    #[test]
+    fn init_counted_preserves_counts() {
+        let mut btree = BTreeMap::new_counted(make_memory());
+        for i in 0..1_000u64 {
+            btree.insert(i, i);
+        }
+
+        let btree: BTreeMap<u64, u64, _> = BTreeMap::init_counted(btree.into_memory());
+        assert!(btree.is_counted());
+        assert_eq!(btree.nth(500), Some((500, 500)));
+        assert_eq!(btree.rank(&700), 700);
+
+        // Regular initialization loads the map with its counts as well.
+        let btree: BTreeMap<u64, u64, _> = BTreeMap::init(btree.into_memory());
+        assert!(btree.is_counted());
+    }
+
+    #[test]
+    #[should_panic(expected = "does not support order statistics")]
+    fn init_counted_rejects_maps_without_counts() {
+        let btree: BTreeMap<u64, u64, _> = BTreeMap::new(make_memory());
+        let _: BTreeMap<u64, u64, _> = BTreeMap::init_counted(btree.into_memory());
+    }
+
+    /// Builds a key that shares a long prefix with the other keys.
+    fn long_prefixed_key(i: u64) -> String {
+        format!("{}/{i:06}", "shared-prefix".repeat(16))
+    }
+
+    #[test]
+    fn init_compressed_migrates_lazily() {
+        let n = 1_000;
+        let mut btree: BTreeMap<String, u64, _> = BTreeMap::new(make_memory());
+        for i in 0..n {
+            btree.insert(long_prefixed_key(i), i);
+        }
+
+        let mut btree: BTreeMap<String, u64, _> = BTreeMap::init_compressed(btree.into_memory());
+        assert!(matches!(btree.version, Version::V4(_)));
+
+        // Existing nodes are only rewritten when they're modified.
+        let root = btree.load_node(btree.root_addr);
+        assert!(matches!(root.version(), Version::V2(_)));
+
+        // Nodes of both layouts can be read and modified.
+        for i in (0..n).step_by(3) {
+            assert_eq!(btree.remove(&long_prefixed_key(i)), Some(i));
+        }
+        for i in n..2 * n {
+            btree.insert(long_prefixed_key(i), i);
+        }
+        assert_balanced(&btree);
+
+        // The migration persists after reloading the map.
+        let btree: BTreeMap<String, u64, _> = BTreeMap::load(btree.into_memory());
+        assert!(matches!(btree.version, Version::V4(_)));
+        let root = btree.load_node(btree.root_addr);
+        assert!(matches!(root.version(), Version::V4(_)));
+        assert!(btree.iter().map(|e| e.into_pair()).eq((0..2 * n)
+            .filter(|i| *i >= n || i % 3 != 0)
+            .map(|i| (long_prefixed_key(i), i))));
+    }
+
+    #[test]
+    fn init_compressed_migrates_v1_maps() {
+        let n = 500u64;
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new_v1(make_memory());
+        for i in 0..n {
+            btree.insert(i, i);
+        }
+
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::init_compressed(btree.into_memory());
+        for i in (0..n).step_by(2) {
+            assert_eq!(btree.remove(&i), Some(i));
+        }
+        for i in n..2 * n {
+            btree.insert(i, i);
+        }
+        assert_balanced(&btree);
+
+        let btree: BTreeMap<u64, u64, _> = BTreeMap::load(btree.into_memory());
+        assert!(matches!(btree.version, Version::V4(PageSize::Derived(_))));
+        assert!(btree
+            .iter()
+            .map(|e| e.into_pair())
+            .eq((0..2 * n).filter(|i| *i >= n || i % 2 == 1).map(|i| (i, i))));
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't support prefix-compressed keys")]
+    fn init_compressed_rejects_maps_with_counts() {
+        let btree: BTreeMap<u64, u64, _> = BTreeMap::new_counted(make_memory());
+        let _: BTreeMap<u64, u64, _> = BTreeMap::init_compressed(btree.into_memory());
+    }
+
+    #[test]
+    fn compressed_keys_use_less_memory() {
+        let mut btree: BTreeMap<String, u64, _> = BTreeMap::new(make_memory());
+        let mut compressed: BTreeMap<String, u64, _> = BTreeMap::new_compressed(make_memory());
+        for i in 0..1_000 {
+            btree.insert(long_prefixed_key(i), i);
+            compressed.insert(long_prefixed_key(i), i);
+        }
+
+        // The keys don't fit in a single page without compression, and do with it.
+        assert!(
+            compressed.allocator.num_allocated_chunks() * 2
+                < btree.allocator.num_allocated_chunks()
+        );
+    }
+
+    fn node_cache<K: TestKey, V: TestValue>() {
+        let (key, value) = (K::build, V::build);
+        run_btree_test(|btree| {
+            let n = 500;
+            let mut btree = btree.with_node_cache(8);
+            for i in 0..n {
+                btree.insert(key(i), value(i));
+            }
+
+            // Modified nodes are invalidated, so the map reads back what it wrote.
+            for i in (0..n).step_by(2) {
+                assert_eq!(btree.remove(&key(i)), Some(value(i)));
+            }
+            btree.remove_range(key(100)..key(200));
+            for i in 0..n {
+                let expected = (i % 2 == 1 && !(100..200).contains(&i)).then(|| value(i));
+                assert_eq!(btree.get(&key(i)), expected);
+            }
+            assert_balanced(&btree);
+
+            // Lookups of the same key hit the cache for all the nodes on its path.
+            btree.node_cache_reset_metrics();
+            btree.get(&key(1));
+            let misses = btree.node_cache_metrics().misses();
+            btree.get(&key(1));
+            assert_eq!(btree.node_cache_metrics().misses(), misses);
+            assert!(btree.node_cache_metrics().hits() > 0);
+
+            // Disabling the cache stops collecting metrics.
+            btree.node_cache_resize(0);
+            btree.node_cache_reset_metrics();
+            btree.get(&key(1));
+            assert_eq!(btree.node_cache_metrics(), NodeCacheMetrics::default());
+        });
+    }
+    btree_test!(test_node_cache, node_cache);
+
+    fn check_integrity<K: TestKey, V: TestValue>() {
+        let (key, value) = (K::build, V::build);
+        run_btree_test(|mut btree| {
+            let n = 500;
+            assert_eq!(btree.check_integrity(), Ok(()));
+            for i in 0..n {
+                btree.insert(key(i), value(i));
+            }
+            assert_eq!(btree.check_integrity(), Ok(()));
+
+            for i in (0..n).step_by(3) {
+                btree.remove(&key(i));
+            }
+            btree.remove_range(key(100)..key(300));
+            assert_eq!(btree.check_integrity(), Ok(()));
+
+            btree.clear_new();
+            assert_eq!(btree.check_integrity(), Ok(()));
+        });
+    }
+    btree_test!(test_check_integrity, check_integrity);
+
+    fn stats<K: TestKey, V: TestValue>() {
+        let (key, value) = (K::build, V::build);
+        run_btree_test(|mut btree| {
+            assert_eq!(btree.stats(), BTreeMapStats::default());
+
+            let n = 500;
+            for i in 0..n {
+                btree.insert(key(i), value(i));
+            }
+            let stats = btree.stats();
+            assert_eq!(
+                stats.internal_nodes + stats.leaf_nodes + stats.overflow_pages,
+                stats.allocated_chunks
+            );
+            assert!(stats.depth > 1);
+            let nodes = stats.internal_nodes + stats.leaf_nodes;
+            assert_eq!(
+                stats.fill_factor,
+                n as f64 / (nodes * node::CAPACITY as u64) as f64
+            );
+            assert_eq!(
+                stats.key_bytes,
+                (0..n).map(|i| key(i).to_bytes().len() as u64).sum()
+            );
+            assert_eq!(
+                stats.value_bytes,
+                (0..n).map(|i| value(i).to_bytes().len() as u64).sum()
+            );
+
+            // Removing entries frees chunks for reuse.
+            btree.remove_range(key(0)..key(400));
+            let (depth, free_chunks) = (stats.depth, stats.free_chunks);
+            let stats = btree.stats();
+            assert!(stats.depth <= depth);
+            assert!(stats.free_chunks > free_chunks);
+            assert_eq!(
+                stats.internal_nodes + stats.leaf_nodes + stats.overflow_pages,
+                stats.allocated_chunks
+            );
+        });
+    }
+    btree_test!(test_stats, stats);
+
+    #[test]
+    fn stats_count_overflow_pages() {
+        let mut btree: BTreeMap<u64, Vec<u8>, _> = BTreeMap::new(make_memory());
+        btree.insert(0, vec![0; 10_000]);
+
+        let stats = btree.stats();
+        assert_eq!(stats.leaf_nodes, 1);
+        assert!(stats.overflow_pages > 0);
+        assert_eq!(stats.allocated_chunks, 1 + stats.overflow_pages);
+        assert_eq!(stats.value_bytes, 10_000);
+    }
+
+    fn compact<K: TestKey, V: TestValue>() {
+        let (key, value) = (K::build, V::build);
+        run_btree_test(|mut btree| {
+            let n = 1_000;
+            for i in 0..n {
+                btree.insert(key(i), value(i));
+            }
+            btree.remove_range(key(0)..key(900));
+            for i in (900..n).step_by(2) {
+                btree.remove(&key(i));
+            }
+            let end = btree.allocator.end();
+
+            while !btree.compact(10) {}
+
+            assert!(btree.allocator.end() < end);
+            assert_eq!(btree.allocator.num_free_chunks(), 0);
+            assert_eq!(btree.check_integrity(), Ok(()));
+            assert!(btree
+                .iter()
+                .map(|e| e.into_pair())
+                .eq((901..n).step_by(2).map(|i| (key(i), value(i)))));
+        });
+    }
+    btree_test!(test_compact, compact);
+
+    #[test]
+    fn compact_moves_overflow_pages() {
+        let mut btree: BTreeMap<u64, Vec<u8>, _> = BTreeMap::new(make_memory());
+        for i in 0..100 {
+            btree.insert(i, vec![i as u8; 5_000]);
+        }
+        btree.remove_range(..90);
+        let end = btree.allocator.end();
+
+        while !btree.compact(10) {}
+
+        assert!(btree.stats().overflow_pages > 0);
+        assert!(btree.allocator.end() < end);
+        assert_eq!(btree.allocator.num_free_chunks(), 0);
+        assert_eq!(btree.check_integrity(), Ok(()));
+        assert!(btree
+            .iter()
+            .map(|e| e.into_pair())
+            .eq((90..100).map(|i| (i, vec![i as u8; 5_000]))));
+    }
+
+    #[test]
+    fn compact_with_modifications_between_calls() {
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new_counted(make_memory());
+        let mut expected = std::collections::BTreeMap::new();
+        for i in 0..2_000 {
+            btree.insert(i, i);
+            expected.insert(i, i);
+        }
+        btree.remove_range(500..1_500);
+        expected.retain(|k, _| !(500..1_500).contains(k));
+
+        let mut i = 0;
+        while !btree.compact(5) {
+            // Insert, overwrite and remove entries around the walk's position.
+            btree.insert(i * 7 % 3_000, i);
+            expected.insert(i * 7 % 3_000, i);
+            btree.remove(&(i * 13 % 3_000));
+            expected.remove(&(i * 13 % 3_000));
+            i += 1;
+        }
+
+        assert_eq!(btree.check_integrity(), Ok(()));
+        assert_eq!(
+            btree.allocator.num_allocated_chunks() + btree.allocator.num_free_chunks(),
+            btree.allocator.num_chunks()
+        );
+        assert!(btree.iter().map(|e| e.into_pair()).eq(expected.into_iter()));
+    }
+
+    #[test]
+    fn compact_with_allocations_past_the_limit() {
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new(make_memory());
+        for i in 0..10_000 {
+            btree.insert(i, i);
+        }
+        btree.remove_range(..9_000);
+        assert!(!btree.compact(5));
+
+        // The insertions run out of free memory before the compaction limit, so nodes are
+        // allocated past it, some of them behind the walk.
+        for i in 0..5_000 {
+            btree.insert(i, i);
+        }
+        while !btree.compact(100) {}
+
+        // Every chunk is either allocated or free.
+        assert_eq!(
+            btree.allocator.num_allocated_chunks() + btree.allocator.num_free_chunks(),
+            btree.allocator.num_chunks()
+        );
+        assert_eq!(btree.check_integrity(), Ok(()));
+        assert!(btree
+            .iter()
+            .map(|e| e.into_pair())
+            .eq((0..5_000).chain(9_000..10_000).map(|i| (i, i))));
+
+        // Nodes allocated past the limit behind the walk are moved by walking the tree again
+        // if they fit before the limit.
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new(make_memory());
+        for i in 0..10_000 {
+            btree.insert(i, i);
+        }
+        btree.remove_range(..9_000);
+        assert!(!btree.compact(5));
+        for i in 0..5_000 {
+            btree.insert(i, i);
+        }
+        btree.remove_range(..4_950);
+        btree.remove_range(9_500..);
+        let end = btree.allocator.end();
+        while !btree.compact(100) {}
+
+        assert!(btree.allocator.end() < end);
+        assert_eq!(
+            btree.allocator.num_allocated_chunks() + btree.allocator.num_free_chunks(),
+            btree.allocator.num_chunks()
+        );
+        assert_eq!(btree.check_integrity(), Ok(()));
+        assert!(btree
+            .iter()
+            .map(|e| e.into_pair())
+            .eq((4_950..5_000).chain(9_000..9_500).map(|i| (i, i))));
+    }
+
+    #[test]
+    fn compact_continues_after_reload() {
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new(make_memory());
+        for i in 0..1_000 {
+            btree.insert(i, i);
+        }
+        btree.remove_range(..900);
+        let end = btree.allocator.end();
+        assert!(!btree.compact(10));
+
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::load(btree.into_memory());
+        assert!(btree.allocator.is_compacting());
+        while !btree.compact(10) {}
+
+        assert!(btree.allocator.end() < end);
+        assert_eq!(btree.check_integrity(), Ok(()));
+        assert!(btree
+            .iter()
+            .map(|e| e.into_pair())
+            .eq((900..1_000).map(|i| (i, i))));
+    }
+
+    #[test]
+    fn compact_virtual_memory() {
+        use crate::memory_manager::{MemoryId, MemoryManager};
+
+        let memory_manager = MemoryManager::init(make_memory());
+        let mut btree: BTreeMap<u64, Vec<u8>, _> =
+            BTreeMap::init(memory_manager.get(MemoryId::new(0)));
+        let mut other: BTreeMap<u64, Vec<u8>, _> =
+            BTreeMap::init(memory_manager.get(MemoryId::new(1)));
+        for i in 0..200 {
+            // Interleave the maps' growth, so that their buckets are interleaved too.
+            btree.insert(i, vec![0; 1_000]);
+            other.insert(i, vec![1; 1_000]);
+        }
+        btree.remove_range(..150);
+        let end = btree.allocator.end();
+
+        while !btree.compact(100) {}
+
+        assert!(btree.allocator.end() < end);
+        assert_eq!(btree.allocator.num_free_chunks(), 0);
+        assert_eq!(btree.check_integrity(), Ok(()));
+        assert_eq!(other.check_integrity(), Ok(()));
+        assert!(btree.iter().all(|e| e.value() == vec![0; 1_000]));
+        assert!(other.iter().all(|e| e.value() == vec![1; 1_000]));
+        assert_eq!(btree.len(), 50);
+        assert_eq!(other.len(), 200);
+    }
+
+    fn snapshot<K: TestKey, V: TestValue>() {
+        let (key, value) = (K::build, V::build);
+        run_btree_test(|mut btree| {
+            let n = 500;
+            for i in 0..n {
+                btree.insert(key(i), value(i));
+            }
+            let snapshot = btree.snapshot();
+
+            // Modify the map with every kind of operation that modifies nodes.
+            for i in (0..n).step_by(3) {
+                btree.remove(&key(i));
+            }
+            for i in (1..n).step_by(3) {
+                btree.insert(key(i), value(i + 1));
+            }
+            for i in n..2 * n {
+                btree.insert(key(i), value(i));
+            }
+            btree.pop_first();
+            btree.pop_last();
+            btree.remove_range(key(100)..key(200));
+            btree.retain(|k, _| *k != key(300));
+            if let Entry::Occupied(mut entry) = btree.entry(key(400)) {
+                entry.insert(value(0));
+            }
+
+            let view = btree.view(&snapshot);
+            assert_eq!(view.len(), n as u64);
+            assert_eq!(view.get(&key(0)), Some(value(0)));
+            assert_eq!(view.get(&key(1)), Some(value(1)));
+            assert_eq!(view.get(&key(n)), None);
+            assert_eq!(view.first_key_value(), Some((key(0), value(0))));
+            assert_eq!(view.last_key_value(), Some((key(n - 1), value(n - 1))));
+            assert!(view
+                .iter()
+                .map(|e| e.into_pair())
+                .eq((0..n).map(|i| (key(i), value(i)))));
+            assert!(view
+                .range(key(100)..key(200))
+                .map(|e| e.into_pair())
+                .eq((100..200).map(|i| (key(i), value(i)))));
+            assert_eq!(btree.check_integrity(), Ok(()));
+
+            btree.release_snapshot(snapshot);
+            assert_eq!(btree.snapshots().count(), 0);
+            assert_eq!(btree.check_integrity(), Ok(()));
+        });
+    }
+    btree_test!(test_snapshot, snapshot);
+
+    #[test]
+    fn release_snapshot_deallocates_unshared_nodes() {
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new(make_memory());
+        let mut reference: BTreeMap<u64, u64, _> = BTreeMap::new(make_memory());
+        for i in 0..1_000 {
+            btree.insert(i, i);
+            reference.insert(i, i);
+        }
+
+        let snapshot = btree.snapshot();
+        for i in (0..1_000).step_by(2) {
+            btree.remove(&i);
+            reference.remove(&i);
+        }
+        assert!(
+            btree.allocator.num_allocated_chunks() > reference.allocator.num_allocated_chunks()
+        );
+
+        // Once the snapshot is released, the map uses as many nodes as one that never had a
+        // snapshot.
+        btree.release_snapshot(snapshot);
+        assert_eq!(
+            btree.allocator.num_allocated_chunks(),
+            reference.allocator.num_allocated_chunks()
+        );
+        assert_eq!(btree.check_integrity(), Ok(()));
+    }
+
+    #[test]
+    fn snapshots_of_several_versions() {
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new_counted(make_memory());
+        let mut snapshots = vec![];
+        for version in 0..5 {
+            for i in 0..300 {
+                btree.insert(i, version);
+            }
+            btree.remove_range(version * 50..version * 50 + 25);
+            snapshots.push((
+                btree.snapshot(),
+                btree.iter().map(|e| e.into_pair()).collect(),
+            ));
+        }
+        btree.clear_new();
+        assert_eq!(btree.check_integrity(), Ok(()));
+
+        // Release the snapshots out of order.
+        for idx in [2, 0, 2, 0, 0] {
+            let (snapshot, expected): (_, Vec<_>) = snapshots.remove(idx);
+            assert!(btree
+                .view(&snapshot)
+                .iter()
+                .map(|e| e.into_pair())
+                .eq(expected));
+            btree.release_snapshot(snapshot);
+            assert_eq!(btree.check_integrity(), Ok(()));
+        }
+        assert_eq!(btree.allocator.num_allocated_chunks(), 0);
+    }
+
+    #[test]
+    fn paginated_iteration_with_tokens() {
+        let mem = make_memory();
+        let mut btree: BTreeMap<String, u64, _> = BTreeMap::new(mem.clone());
+        for i in 0..1_000u64 {
+            btree.insert(format!("{i:04}"), i);
+        }
+
+        // Iterate forward in pages, removing entries between pages.
+        let mut token = btree.range(format!("{:04}", 100)..).token();
+        let mut seen = vec![];
+        loop {
+            let mut iter = btree.iter_from_token(&token).unwrap();
+            let page: Vec<_> = iter.by_ref().take(64).map(|e| e.value()).collect();
+            if page.is_empty() {
+                break;
+            }
+            seen.extend(page);
+            token = iter.token();
+            btree.remove(&format!("{:04}", seen.last().unwrap() + 1));
+        }
+        let expected: Vec<_> = btree
+            .range(format!("{:04}", 100)..)
+            .map(|e| e.value())
+            .collect();
+        assert_eq!(seen, expected);
+
+        // Iterate from both ends, then resume after a reload.
+        let mut iter = btree.range(..format!("{:04}", 10));
+        assert_eq!(iter.next().unwrap().value(), 0);
+        assert_eq!(iter.next_back().unwrap().value(), 9);
+        let token = iter.token();
+        let btree: BTreeMap<String, u64, _> = BTreeMap::load(mem);
+        let rest: Vec<_> = btree
+            .iter_from_token(&token)
+            .unwrap()
+            .rev()
+            .map(|e| e.value())
+            .collect();
+        assert_eq!(rest, (1..9).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn invalid_iteration_tokens() {
+        let mem = make_memory();
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new(mem.clone());
+        btree.insert(1, 1);
+        let token = btree.iter().token();
+
+        for malformed in [
+            &token[..10],
+            &token[1..],
+            &[token.clone(), vec![0]].concat(),
+        ] {
+            assert_eq!(
+                btree.iter_from_token(malformed).err(),
+                Some(InvalidToken::Malformed)
+            );
+        }
+
+        btree.clear_new();
+        btree.insert(1, 1);
+        assert_eq!(
+            btree.iter_from_token(&token).err(),
+            Some(InvalidToken::Stale)
+        );
+
+        // Creating a new map in the memory invalidates the tokens too.
+        let token = btree.iter().token();
+        let btree: BTreeMap<u64, u64, _> = BTreeMap::new(mem);
+        assert_eq!(
+            btree.iter_from_token(&token).err(),
+            Some(InvalidToken::Stale)
+        );
+    }
+
+    #[test]
+    fn iteration_tokens_of_snapshots() {
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new(make_memory());
+        for i in 0..10 {
+            btree.insert(i, i);
+        }
+        let snapshot = btree.snapshot();
+        let mut iter = btree.view(&snapshot).iter();
+        iter.by_ref().take(3).for_each(drop);
+        let token = iter.token();
+
+        // The iteration resumes over the snapshot, not over the map.
+        for i in 0..10 {
+            btree.remove(&i);
+        }
+        btree.insert(100, 100);
+        let rest: Vec<_> = btree
+            .iter_from_token(&token)
+            .unwrap()
+            .map(|e| e.value())
+            .collect();
+        assert_eq!(rest, (3..10).collect::<Vec<_>>());
+
+        // The token is stale once the snapshot is released, even if its record is reused.
+        let address = snapshot.address;
+        btree.release_snapshot(snapshot);
+        assert_eq!(
+            btree.iter_from_token(&token).err(),
+            Some(InvalidToken::Stale)
+        );
+        // The chunks of the record and of the snapshot's root are reused.
+        let snapshots: Vec<_> = (0..2).map(|_| btree.snapshot()).collect();
+        assert!(snapshots.iter().any(|s| s.address == address));
+        assert_eq!(
+            btree.iter_from_token(&token).err(),
+            Some(InvalidToken::Stale)
+        );
+        for snapshot in snapshots {
+            btree.release_snapshot(snapshot);
+        }
+        assert_eq!(btree.check_integrity(), Ok(()));
+    }
+
+    #[test]
+    fn iteration_tokens_with_corrupted_keys() {
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new(make_memory());
+        btree.insert(1, 1);
+        // The token holds the start bound's tag, the length of its key and the key, and
+        // then the unbounded end bound.
+        let token = btree.range(1..).token();
+        let (header, bound) = token.split_at(28);
+        let key = &bound[5..13];
+
+        for key in [&key[..4], &[key, &[0]].concat()] {
+            let mut corrupted = header.to_vec();
+            corrupted.push(bound[0]);
+            corrupted.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            corrupted.extend_from_slice(key);
+            corrupted.push(bound[13]);
+            assert_eq!(
+                btree.iter_from_token(&corrupted).err(),
+                Some(InvalidToken::Malformed)
+            );
+        }
+
+        // Keys of unbounded types can have any size.
+        let mut btree: BTreeMap<String, u64, _> = BTreeMap::new(make_memory());
+        btree.insert("b".to_string(), 1);
+        let token = btree.range("a".to_string()..).token();
+        let mut corrupted = token[..29].to_vec();
+        corrupted.extend_from_slice(&3u32.to_le_bytes());
+        corrupted.extend_from_slice(b"abc");
+        corrupted.push(token[token.len() - 1]);
+        assert_eq!(btree.iter_from_token(&corrupted).unwrap().count(), 1);
+
+        // The length of a key can't exceed the token.
+        let mut corrupted = token[..29].to_vec();
+        corrupted.extend_from_slice(&u32::MAX.to_le_bytes());
+        corrupted.extend_from_slice(b"abc");
+        assert_eq!(
+            btree.iter_from_token(&corrupted).err(),
+            Some(InvalidToken::Malformed)
+        );
+    }
+
+    #[test]
+    fn clear_incremental() {
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new_counted(make_memory());
+        for i in 0..2_000 {
+            btree.insert(i, i);
+        }
+
+        let mut calls = 0;
+        loop {
+            let len = btree.len();
+            let progress = btree.clear_incremental(5);
+            calls += 1;
+            assert_eq!(progress.removed, len - btree.len());
+            assert_eq!(progress.remaining, btree.len());
+            assert_eq!(btree.check_integrity(), Ok(()));
+            assert!(btree
+                .iter()
+                .map(|e| e.into_pair())
+                .eq((0..btree.len()).map(|i| (i, i))));
+            if progress.is_done() {
+                break;
+            }
+            assert!(progress.removed > 0);
+        }
+        assert!(calls > 1);
+        assert_eq!(btree.allocator.num_allocated_chunks(), 0);
+        assert!(btree.clear_incremental(5).is_done());
+        assert_eq!(btree.clear_incremental(0).removed, 0);
+
+        // The memory is reusable once cleared.
+        btree.insert(1, 1);
+        assert_eq!(btree.get(&1), Some(1));
+    }
+
+    #[test]
+    fn clear_incremental_with_snapshots() {
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new(make_memory());
+        for i in 0..500 {
+            btree.insert(i, i);
+        }
+        let snapshot = btree.snapshot();
+
+        while !btree.clear_incremental(3).is_done() {
+            assert_eq!(btree.check_integrity(), Ok(()));
+        }
+        assert!(btree.is_empty());
+        assert!(btree
+            .view(&snapshot)
+            .iter()
+            .map(|e| e.into_pair())
+            .eq((0..500).map(|i| (i, i))));
+
+        btree.release_snapshot(snapshot);
+        assert_eq!(btree.allocator.num_allocated_chunks(), 0);
+    }
+
+    /// Returns the events turning `old` into `new`, computed with a sorted merge.
+    fn expected_diff(
+        old: &std::collections::BTreeMap<u64, u64>,
+        new: &std::collections::BTreeMap<u64, u64>,
+    ) -> Vec<DiffEvent<u64, u64>> {
+        let keys: std::collections::BTreeSet<_> = old.keys().chain(new.keys()).collect();
+        keys.into_iter()
+            .filter_map(|key| match (old.get(key), new.get(key)) {
+                (Some(&old), None) => Some(DiffEvent::Removed(*key, old)),
+                (None, Some(&new)) => Some(DiffEvent::Added(*key, new)),
+                (Some(&old), Some(&new)) if old != new => Some(DiffEvent::Changed {
+                    key: *key,
+                    old,
+                    new,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn diff_of_two_maps() {
+        let mut a = BTreeMap::new(make_memory());
+        let mut b = BTreeMap::new(make_memory());
+        let mut model_a = std::collections::BTreeMap::new();
+        let mut model_b = std::collections::BTreeMap::new();
+        for i in 0..2_000u64 {
+            if i % 5 != 0 {
+                a.insert(i, i);
+                model_a.insert(i, i);
+            }
+            if i % 7 != 0 {
+                let value = if i % 11 == 0 { i + 1 } else { i };
+                b.insert(i, value);
+                model_b.insert(i, value);
+            }
+        }
+
+        assert_eq!(
+            a.diff(&b).collect::<Vec<_>>(),
+            expected_diff(&model_a, &model_b)
+        );
+        assert_eq!(
+            b.diff(&a).collect::<Vec<_>>(),
+            expected_diff(&model_b, &model_a)
+        );
+        assert_eq!(a.diff(&a).count(), 0);
+
+        let empty = BTreeMap::new(make_memory());
+        assert_eq!(empty.diff(&a).count(), model_a.len());
+        assert_eq!(a.diff(&empty).count(), model_a.len());
+    }
+
+    #[test]
+    fn diff_since_snapshot() {
+        let mut btree = BTreeMap::new(make_memory());
+        let mut model = std::collections::BTreeMap::new();
+        for i in 0..3_000u64 {
+            btree.insert(i, i);
+            model.insert(i, i);
+        }
+
+        let mut snapshots = vec![];
+        for round in 0..4u64 {
+            snapshots.push((btree.snapshot(), model.clone()));
+            for i in (round * 13..3_000 + round * 400).step_by(97 + round as usize) {
+                if i % 3 == 0 {
+                    btree.remove(&i);
+                    model.remove(&i);
+                } else {
+                    btree.insert(i, i * round);
+                    model.insert(i, i * round);
+                }
+            }
+        }
+
+        for (snapshot, old) in snapshots {
+            assert_eq!(
+                btree.diff_since(&snapshot).collect::<Vec<_>>(),
+                expected_diff(&old, &model)
+            );
+            btree.release_snapshot(snapshot);
+        }
+
+        let snapshot = btree.snapshot();
+        assert_eq!(btree.diff_since(&snapshot).count(), 0);
+        btree.clear_new();
+        assert_eq!(btree.diff_since(&snapshot).count(), model.len());
+        btree.release_snapshot(snapshot);
+    }
+
+    #[test]
+    fn merge_join_kinds() {
+        let mut left = BTreeMap::new(make_memory());
+        let mut right: BTreeMap<u64, String, _> = BTreeMap::new(make_memory());
+        for i in 0..500u64 {
+            if i % 2 == 0 {
+                left.insert(i, i);
+            }
+            if i % 3 == 0 {
+                right.insert(i, i.to_string());
+            }
+        }
+
+        let joined = |kind| left.merge_join(&right, kind).collect::<Vec<_>>();
+        let expected = |keep: fn(bool, bool) -> bool| {
+            (0..500u64)
+                .filter(|i| keep(i % 2 == 0, i % 3 == 0))
+                .map(|i| {
+                    let joined = match (i % 2 == 0, i % 3 == 0) {
+                        (true, true) => Joined::Both(i, i.to_string()),
+                        (true, false) => Joined::Left(i),
+                        _ => Joined::Right(i.to_string()),
+                    };
+                    (i, joined)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(joined(JoinKind::Inner), expected(|l, r| l && r));
+        assert_eq!(joined(JoinKind::Left), expected(|l, _| l));
+        assert_eq!(joined(JoinKind::Outer), expected(|l, r| l || r));
+
+        let empty: BTreeMap<u64, u64, _> = BTreeMap::new(make_memory());
+        assert_eq!(empty.merge_join(&right, JoinKind::Left).count(), 0);
+        assert_eq!(
+            empty.merge_join(&right, JoinKind::Outer).count() as u64,
+            right.len()
+        );
+    }
+
+    #[test]
+    fn snapshot_paginated_iteration() {
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new(make_memory());
+        for i in 0..1_000 {
+            btree.insert(i, i);
+        }
+        let snapshot = btree.snapshot();
+
+        // Read a page of the snapshot per call, modifying the map between the calls.
+        let mut read = vec![];
+        let mut i = 0;
+        loop {
+            let view = btree.view(&snapshot);
+            let page: Vec<_> = match read.last() {
+                Some(&(last, _)) => view.range((Bound::Excluded(last), Bound::Unbounded)),
+                None => view.iter(),
+            }
+            .take(50)
+            .map(|e| e.into_pair())
+            .collect();
+            if page.is_empty() {
+                break;
+            }
+            read.extend(page);
+
+            btree.remove_range(i * 50..i * 50 + 40);
+            btree.insert(1_000 + i, i);
+            i += 1;
+        }
+
+        assert!(read.into_iter().eq((0..1_000).map(|i| (i, i))));
+        btree.release_snapshot(snapshot);
+        assert_eq!(btree.check_integrity(), Ok(()));
+    }
+
+    #[test]
+    fn snapshot_survives_reload() {
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new(make_memory());
+        for i in 0..500 {
+            btree.insert(i, i);
+        }
+        btree.snapshot();
+        btree.clear_new();
+
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::load(btree.into_memory());
+        let snapshots: Vec<_> = btree.snapshots().collect();
+        assert_eq!(snapshots.len(), 1);
+        assert!(btree.is_empty());
+        assert!(btree
+            .view(&snapshots[0])
+            .iter()
+            .map(|e| e.into_pair())
+            .eq((0..500).map(|i| (i, i))));
+
+        for snapshot in snapshots {
+            btree.release_snapshot(snapshot);
+        }
+        assert_eq!(btree.allocator.num_allocated_chunks(), 0);
+    }
+
+    #[test]
+    fn removing_missing_key_does_not_write() {
+        let mem = make_memory();
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new_counted(mem.clone());
+        for i in 0..100 {
+            btree.insert(i * 2, i);
+        }
+        let snapshot = btree.snapshot();
+
+        let before = mem.borrow().clone();
+        for i in 0..100 {
+            assert_eq!(btree.remove(&(i * 2 + 1)), None);
+        }
+        assert!(*mem.borrow() == before);
+
+        btree.release_snapshot(snapshot);
+        let before = mem.borrow().clone();
+        assert_eq!(btree.remove(&1), None);
+        assert!(*mem.borrow() == before);
+        assert_eq!(btree.check_integrity(), Ok(()));
+    }
+
+    #[test]
+    fn count_snapshot_of_counted_map() {
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new_counted(make_memory());
+        for i in 0..100 {
+            btree.insert(i, i);
+        }
+        let snapshot = btree.snapshot();
+        btree.remove_range(..50);
+
+        let view = btree.view(&snapshot);
+        assert_eq!(view.len(), 100);
+        assert_eq!(view.iter().count(), 100);
+        assert_eq!(view.range(..10).count(), 10);
+        assert_eq!(view.range(40..=60).count(), 21);
+        assert_eq!(btree.range(40..=60).count(), 11);
+        btree.release_snapshot(snapshot);
+    }
+
+    #[test]
+    fn load_ignores_unmarked_header_extension() {
+        // Maps saved by earlier versions don't have the header extension, and the space
+        // reserved for it may contain anything.
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new(make_memory());
+        for i in 0..500 {
+            btree.insert(i, i);
+        }
+        let mem = btree.into_memory();
+        crate::write(&mem, SNAPSHOTS_OFFSET as u64, &[0xAB; 16]);
+
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::load(mem);
+        assert_eq!(btree.snapshots().count(), 0);
+        assert_eq!(btree.generation(), 0);
+        assert_eq!(btree.check_integrity(), Ok(()));
+
+        // Taking a snapshot writes the header extension.
+        btree.snapshot();
+        let btree: BTreeMap<u64, u64, _> = BTreeMap::load(btree.into_memory());
+        assert_eq!(btree.snapshots().count(), 1);
+        assert_eq!(btree.generation(), 0);
+        assert_eq!(btree.check_integrity(), Ok(()));
+    }
+
+    #[test]
+    fn compact_waits_for_snapshots() {
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new(make_memory());
+        for i in 0..1_000 {
+            btree.insert(i, i);
+        }
+        btree.remove_range(..900);
+        let end = btree.allocator.end();
+
+        let snapshot = btree.snapshot();
+        assert!(!btree.compact(1_000));
+        btree.release_snapshot(snapshot);
+        while !btree.compact(10) {}
+
+        assert!(btree.allocator.end() < end);
+        assert_eq!(btree.check_integrity(), Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown snapshot.")]
+    fn release_unknown_snapshot() {
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new(make_memory());
+        let mut other: BTreeMap<u64, u64, _> = BTreeMap::new(make_memory());
+        let snapshot = other.snapshot();
+        btree.release_snapshot(snapshot);
+    }
+
+    fn transaction<K: TestKey, V: TestValue>() {
+        let (key, value) = (K::build, V::build);
+        run_btree_test(|mut btree| {
+            for i in 0..500 {
+                btree.insert(key(i), value(i));
+            }
+
+            let result = btree.transaction(|tx| {
+                for i in 0..250 {
+                    tx.remove(&key(i * 2));
+                }
+                tx.remove_range(key(300)..key(400));
+                tx.insert(key(1), value(0));
+                tx.insert(key(500), value(500));
+                Ok::<_, ()>(tx.len())
+            });
+
+            let expected: Vec<_> = (0..501)
+                .filter(|i| i % 2 == 1 && !(300..400).contains(i) || *i == 500)
+                .map(|i| (key(i), value(if i == 1 { 0 } else { i })))
+                .collect();
+            assert_eq!(result, Ok(expected.len() as u64));
+            assert!(btree.iter().map(|e| e.into_pair()).eq(expected.clone()));
+            assert_eq!(btree.check_integrity(), Ok(()));
+
+            let btree: BTreeMap<K, V, _> = BTreeMap::load(btree.into_memory());
+            assert!(btree.iter().map(|e| e.into_pair()).eq(expected));
+        });
+    }
+    btree_test!(test_transaction, transaction);
+
+    fn transaction_rolls_back_on_error<K: TestKey, V: TestValue>() {
+        let (key, value) = (K::build, V::build);
+        run_btree_test(|mut btree| {
+            for i in 0..500 {
+                btree.insert(key(i), value(i));
+            }
+            let allocated = btree.allocator.num_allocated_chunks();
+
+            let result = btree.transaction(|tx| {
+                for i in 0..500 {
+                    tx.insert(key(i), value(i + 1));
+                }
+                tx.pop_first();
+                tx.remove_range(..);
+                Err::<(), _>("aborted")
+            });
+
+            assert_eq!(result, Err("aborted"));
+            assert!(btree
+                .iter()
+                .map(|e| e.into_pair())
+                .eq((0..500).map(|i| (key(i), value(i)))));
+            assert_eq!(btree.allocator.num_allocated_chunks(), allocated);
+            assert_eq!(btree.check_integrity(), Ok(()));
+        });
+    }
+    btree_test!(
+        test_transaction_rolls_back_on_error,
+        transaction_rolls_back_on_error
+    );
+
+    #[test]
+    fn transaction_rolls_back_on_panic() {
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new_counted(make_memory());
+        for i in 0..500 {
+            btree.insert(i, i);
+        }
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            btree.transaction(|tx| {
+                for i in 0..500 {
+                    tx.insert(i, i + 1);
+                    if i == 250 {
+                        panic!("trap");
+                    }
+                }
+                Ok::<_, ()>(())
+            })
+        }));
+
+        assert!(result.is_err());
+        assert!(btree
+            .iter()
+            .map(|e| e.into_pair())
+            .eq((0..500).map(|i| (i, i))));
+        assert_eq!(btree.check_integrity(), Ok(()));
+    }
+
+    #[test]
+    fn transaction_is_not_saved_until_committed() {
+        let mem = make_memory();
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new(mem.clone());
+        for i in 0..500 {
+            btree.insert(i, i);
+        }
 
-            // There are three allocated nodes.
-            assert_eq!(btree.allocator.num_allocated_chunks(), 3);
+        btree
+            .transaction(|tx| {
+                tx.remove_range(100..200);
+                for i in 500..1_000 {
+                    tx.insert(i, i);
+                }
 
-            // Remove node 3. Triggers case 3.b
-            assert_eq!(btree.remove(&key(3)), Some(V::empty()));
+                // A copy of the memory taken in the middle of the transaction, e.g. when the
+                // process crashes, holds the map as it was before the transaction.
+                let crashed = Rc::new(RefCell::new(mem.borrow().clone()));
+                let crashed: BTreeMap<u64, u64, _> = BTreeMap::load(crashed);
+                assert_eq!(crashed.len(), 500);
+                assert!(crashed
+                    .iter()
+                    .map(|e| e.into_pair())
+                    .eq((0..500).map(|i| (i, i))));
+                Ok::<_, ()>(())
+            })
+            .unwrap();
 
-            // Reload the btree to verify that we saved it correctly.
-            let btree = BTreeMap::<K, V, _>::load(btree.into_memory());
+        let btree: BTreeMap<u64, u64, _> = BTreeMap::load(mem);
+        assert_eq!(btree.len(), 900);
+        assert_eq!(btree.check_integrity(), Ok(()));
+    }
 
-            // The result should look like this:
-            //
-            // [1, 2, 4, 5, 7, 8, 9, 10, 11, 12]
-            let root = btree.load_node(btree.root_addr);
-            assert_eq!(root.node_type(), NodeType::Leaf);
-            assert_eq!(
-                root.entries(btree.memory()),
-                collect([1, 2, 4, 5, 7, 8, 9, 10, 11, 12].into_iter().map(e))
-            );
+    #[test]
+    fn transaction_interrupted_by_a_crash_is_undone_on_load() {
+        let mem = make_memory();
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new(mem.clone());
+        for i in 0..500 {
+            btree.insert(i, i);
+        }
+        let snapshot = btree.snapshot();
 
-            // There is only one allocated node remaining.
-            assert_eq!(btree.allocator.num_allocated_chunks(), 1);
+        let mut crashed = None;
+        let _ = btree.transaction(|tx| {
+            tx.remove_range(100..200);
+            for i in 500..1_000 {
+                tx.insert(i, i);
+            }
+            crashed = Some(Rc::new(RefCell::new(mem.borrow().clone())));
+            Err::<(), _>(())
+        });
+
+        // The nodes and the reference allocated by the transaction are released on load, so
+        // the map can keep being modified.
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::load(crashed.unwrap());
+        assert_eq!(btree.check_integrity(), Ok(()));
+        assert_eq!(btree.snapshots().collect::<Vec<_>>(), vec![snapshot]);
+        for i in 0..100 {
+            btree.remove(&i);
+            btree.insert(i + 1_000, i);
+        }
+        assert_eq!(btree.check_integrity(), Ok(()));
+        let snapshot = btree.snapshots().next().unwrap();
+        assert!(btree
+            .view(&snapshot)
+            .iter()
+            .map(|e| e.into_pair())
+            .eq((0..500).map(|i| (i, i))));
+        btree.release_snapshot(snapshot);
+        assert_eq!(btree.check_integrity(), Ok(()));
+
+        // A map without a snapshot is recovered too.
+        let mem = make_memory();
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::new(mem.clone());
+        for i in 0..500 {
+            btree.insert(i, i);
+        }
+        let mut crashed = None;
+        let _ = btree.transaction(|tx| {
+            tx.insert(500, 500);
+            crashed = Some(Rc::new(RefCell::new(mem.borrow().clone())));
+            Err::<(), _>(())
         });
+        let mut btree: BTreeMap<u64, u64, _> = BTreeMap::load(crashed.unwrap());
+        assert_eq!(btree.check_integrity(), Ok(()));
+        btree.insert(500, 500);
+        btree.remove(&0);
+        assert_eq!(btree.len(), 500);
+        assert_eq!(btree.check_integrity(), Ok(()));
     }
-    btree_test!(
-        test_remove_case_3b_merge_into_right,
-        remove_case_3b_merge_into_right
-    );
 
-    fn remove_case_3b_merge_into_left<K: TestKey, V: TestValue>() {
-        let key = K::build;
-        let e = |i: u32| (key(i), encode(V::empty()));
-        run_btree_test(|mut btree| {
-            for i in 1..=11 {
-                assert_eq!(btree.insert(key(i), V::empty()), None);
-            }
+    // Returns a map whose root is an internal node.
+    fn map_with_internal_root() -> BTreeMap<u64, u64, Rc<RefCell<Vec<u8>>>> {
+        let mut btree = BTreeMap::new_counted(make_memory());
+        for i in 0..100 {
+            btree.insert(i * 10, i);
+        }
+        assert_eq!(
+            btree.load_node(btree.root_addr).node_type(),
+            NodeType::Internal
+        );
+        btree
+    }
 
-            // Should now split a node.
-            assert_eq!(btree.insert(key(12), V::empty()), None);
+    #[test]
+    fn check_integrity_reports_wrong_length() {
+        let mut btree = map_with_internal_root();
+        btree.length += 1;
+        assert_eq!(
+            btree.check_integrity(),
+            Err(vec![IntegrityError::LengthMismatch {
+                stored: 101,
+                actual: 100
+            }])
+        );
+    }
 
-            // The result should look like this:
-            //                [6]
-            //               /   \
-            // [1, 2, 3, 4, 5]   [7, 8, 9, 10, 11, 12]
+    #[test]
+    fn check_integrity_reports_leaked_chunks() {
+        let mut btree = map_with_internal_root();
+        let allocated = btree.allocator.num_allocated_chunks();
+        btree.allocator.allocate();
+        assert_eq!(
+            btree.check_integrity(),
+            Err(vec![IntegrityError::AllocatedChunksMismatch {
+                allocated: allocated + 1,
+                reachable: allocated,
+            }])
+        );
+    }
 
-            for i in 1..=12 {
-                assert_eq!(btree.get(&key(i)), Some(V::empty()));
-            }
+    #[test]
+    fn check_integrity_reports_unaccounted_chunks() {
+        let mut btree = map_with_internal_root();
+        let allocated = btree.allocator.num_allocated_chunks();
+        let free = btree.allocator.num_free_chunks();
+        let chunks = allocated + free + 1;
+
+        // Clear the `allocated` flag of a new chunk, which follows the chunk header's
+        // magic and version, without adding the chunk to the free list.
+        let address = btree.allocator.allocate();
+        crate::write(btree.memory(), address.get() - 16 + 4, &[0]);
+
+        let errors = btree.check_integrity().unwrap_err();
+        assert!(errors.contains(&IntegrityError::UnaccountedChunks {
+            chunks,
+            allocated,
+            free,
+        }));
+    }
 
-            // Remove node 6. Triggers case 2.b
-            assert_eq!(btree.remove(&key(6)), Some(V::empty()));
+    #[test]
+    fn check_integrity_reports_invalid_nodes() {
+        let btree = map_with_internal_root();
+        let root = btree.load_node(btree.root_addr);
+        let child = root.child(0);
+        crate::write(btree.memory(), child.get(), b"XXX");
 
-            // The result should look like this:
-            //                [7]
-            //               /   \
-            // [1, 2, 3, 4, 5]   [8, 9, 10, 11, 12]
-            let root = btree.load_node(btree.root_addr);
-            assert_eq!(root.node_type(), NodeType::Internal);
-            assert_eq!(root.entries(btree.memory()), vec![e(7)]);
-            assert_eq!(root.children_len(), 2);
+        let errors = btree.check_integrity().unwrap_err();
+        assert!(errors.contains(&IntegrityError::InvalidNode {
+            address: child.get()
+        }));
+    }
 
-            let child_0 = btree.load_node(root.child(0));
-            assert_eq!(child_0.node_type(), NodeType::Leaf);
-            assert_eq!(
-                child_0.entries(btree.memory()),
-                vec![e(1), e(2), e(3), e(4), e(5)]
-            );
+    #[test]
+    fn check_integrity_reports_keys_out_of_order() {
+        let mut btree = map_with_internal_root();
+        let mut root = btree.load_node(btree.root_addr);
+
+        // Lower the first separator below the keys of the first child.
+        root.swap_entry(0, (1, vec![]), btree.memory());
+        root.save(&mut btree.allocator);
+
+        let errors = btree.check_integrity().unwrap_err();
+        let first_child = root.child(0).get();
+        assert!(errors.iter().any(|e| matches!(
+            e,
+            IntegrityError::KeysOutOfOrder { node, .. } if *node == first_child
+        )));
+    }
 
-            let child_1 = btree.load_node(root.child(1));
-            assert_eq!(child_1.node_type(), NodeType::Leaf);
-            assert_eq!(
-                child_1.entries(btree.memory()),
-                vec![e(8), e(9), e(10), e(11), e(12)]
-            );
+    #[test]
+    fn check_integrity_reports_wrong_subtree_counts() {
+        let mut btree = map_with_internal_root();
+        let mut root = btree.load_node(btree.root_addr);
+        let stored = root.child_count(1);
+        root.set_child_count(1, stored + 1);
+        root.save(&mut btree.allocator);
 
-            // There are three allocated nodes.
-            assert_eq!(btree.allocator.num_allocated_chunks(), 3);
+        assert_eq!(
+            btree.check_integrity(),
+            Err(vec![IntegrityError::SubtreeCountMismatch {
+                node: btree.root_addr.get(),
+                child: 1,
+                stored: stored + 1,
+                actual: stored,
+            }])
+        );
+    }
 
-            // Remove node 10. Triggers case 3.b where we merge the right into the left.
-            assert_eq!(btree.remove(&key(10)), Some(V::empty()));
+    #[test]
+    fn check_integrity_reports_unallocated_nodes() {
+        let mut btree = map_with_internal_root();
+        let root = btree.load_node(btree.root_addr);
+        let child = root.child(0);
+        btree.allocator.deallocate(child);
 
-            // Reload the btree to verify that we saved it correctly.
-            let btree = BTreeMap::<K, V, _>::load(btree.into_memory());
+        let errors = btree.check_integrity().unwrap_err();
+        assert!(errors.contains(&IntegrityError::UnallocatedNode {
+            address: child.get()
+        }));
+    }
 
-            // The result should look like this:
-            //
-            // [1, 2, 3, 4, 5, 7, 8, 9, 11, 12], 10 is gone
-            let root = btree.load_node(btree.root_addr);
-            assert_eq!(root.node_type(), NodeType::Leaf);
-            assert_eq!(
-                root.entries(btree.memory()),
-                vec![e(1), e(2), e(3), e(4), e(5), e(7), e(8), e(9), e(11), e(12)]
-            );
+    #[test]
+    fn bulk_load_is_compact() {
+        let n = 10_000u64;
+        let bulk_loaded: BTreeMap<u64, u64, _> =
+            BTreeMap::from_sorted_iter(make_memory(), (0..n).map(|i| (i, i)));
+
+        let mut inserted: BTreeMap<u64, u64, _> = BTreeMap::new(make_memory());
+        for i in 0..n {
+            inserted.insert(i, i);
+        }
 
-            // There is only one allocated node remaining.
-            assert_eq!(btree.allocator.num_allocated_chunks(), 1);
-        });
+        assert!(
+            bulk_loaded.allocator.num_allocated_chunks()
+                < inserted.allocator.num_allocated_chunks()
+        );
+        assert!(bulk_loaded
+            .iter()
+            .map(|e| e.into_pair())
+            .eq((0..n).map(|i| (i, i))));
     }
-    btree_test!(
-        test_remove_case_3b_merge_into_left,
-        remove_case_3b_merge_into_left
-    );
 
-    fn insert_remove_many<K: TestKey, V: TestValue>() {
+    #[test]
+    #[should_panic(expected = "Keys must be sorted in strictly ascending order.")]
+    fn bulk_load_rejects_unsorted_keys() {
+        BTreeMap::<u64, u64, _>::from_sorted_iter(make_memory(), [(1, 1), (3, 3), (2, 2)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Keys must be sorted in strictly ascending order.")]
+    fn bulk_load_rejects_duplicate_keys() {
+        BTreeMap::<u64, u64, _>::from_sorted_iter(make_memory(), (0..100).map(|i| (i / 2, i)));
+    }
+
+    fn retain<K: TestKey, V: TestValue>() {
         let (key, value) = (K::build, V::build);
         run_btree_test(|mut btree| {
-            let n = 10_000;
-            for i in 0..n {
-                assert_eq!(btree.insert(key(i), value(i)), None);
-                assert_eq!(btree.get(&key(i)), Some(value(i)));
-            }
-
-            let mut btree = BTreeMap::<K, V, _>::load(btree.into_memory());
+            let n = 1_000;
             for i in 0..n {
-                assert_eq!(btree.remove(&key(i)), Some(value(i)));
-                assert_eq!(btree.get(&key(i)), None);
+                btree.insert(key(i), value(i));
             }
 
-            // We've deallocated everything.
+            // Keep every third key.
+            let index: std::collections::BTreeMap<K, u32> = (0..n).map(|i| (key(i), i)).collect();
+            let mut visited = 0;
+            btree.retain(|k, v| {
+                visited += 1;
+                assert_eq!(*v, value(index[k]));
+                index[k] % 3 == 0
+            });
+            assert_eq!(visited, n);
+
+            let expected: Vec<_> = (0..n).step_by(3).map(|i| (key(i), value(i))).collect();
+            assert_eq!(btree.len(), expected.len() as u64);
+            assert_eq!(collect(btree.iter().map(|e| e.into_pair())), expected);
+
+            // Removing everything deallocates all the nodes.
+            btree.retain(|_, _| false);
+            assert!(btree.is_empty());
             assert_eq!(btree.allocator.num_allocated_chunks(), 0);
         });
     }
-    btree_test!(test_insert_remove_many, insert_remove_many);
+    btree_test!(test_retain, retain);
 
-    fn pop_first_many<K: TestKey, V: TestValue>() {
+    fn extract_if<K: TestKey, V: TestValue>() {
         let (key, value) = (K::build, V::build);
         run_btree_test(|mut btree| {
-            let n = 10_000;
-
-            assert!(btree.is_empty());
-            assert_eq!(btree.pop_first(), None);
-
-            for i in 0..n {
-                assert_eq!(btree.insert(key(i), value(i)), None);
-            }
-            assert_eq!(btree.len(), n as u64);
-
-            let mut btree = BTreeMap::<K, V, _>::load(btree.into_memory());
+            let n = 1_000;
             for i in 0..n {
-                assert_eq!(btree.pop_first(), Some((key(i), value(i))));
-                assert_eq!(btree.get(&key(i)), None);
+                btree.insert(key(i), value(i));
             }
 
-            // We've deallocated everything.
+            // Extract the first 10 elements of the second half. The iterator is lazy,
+            // so the remaining elements of the second half are left in the map.
+            let extracted: Vec<_> = btree.extract_if(|k, _| *k >= key(n / 2)).take(10).collect();
+            let expected: Vec<_> = (n / 2..n / 2 + 10).map(|i| (key(i), value(i))).collect();
+            assert_eq!(extracted, expected);
+            assert_eq!(btree.len(), (n - 10) as u64);
+
+            // Extract the rest of the second half.
+            let extracted: Vec<_> = btree.extract_if(|k, _| *k >= key(n / 2)).collect();
+            let expected: Vec<_> = (n / 2 + 10..n).map(|i| (key(i), value(i))).collect();
+            assert_eq!(extracted, expected);
+
+            let expected: Vec<_> = (0..n / 2).map(|i| (key(i), value(i))).collect();
+            assert_eq!(btree.len(), expected.len() as u64);
+            assert_eq!(collect(btree.iter().map(|e| e.into_pair())), expected);
+
+            // Extract the keys that aren't multiples of three. Most of them are removed from
+            // their leaves in place, and the others rebalance the tree.
+            let index: std::collections::BTreeMap<K, u32> = (0..n).map(|i| (key(i), i)).collect();
+            let extracted: Vec<_> = btree.extract_if(|k, _| index[k] % 3 != 0).collect();
+            let expected: Vec<_> = (0..n / 2)
+                .filter(|i| i % 3 != 0)
+                .map(|i| (key(i), value(i)))
+                .collect();
+            assert_eq!(extracted, expected);
+
+            let expected: Vec<_> = (0..n / 2).step_by(3).map(|i| (key(i), value(i))).collect();
+            assert_eq!(btree.len(), expected.len() as u64);
+            assert_eq!(assert_balanced(&btree), expected.len() as u64);
+            assert_eq!(btree.check_integrity(), Ok(()));
+            assert_eq!(collect(btree.iter().map(|e| e.into_pair())), expected);
+
+            // Extracting everything deallocates all the nodes.
+            assert_eq!(btree.extract_if(|_, _| true).count(), expected.len());
             assert!(btree.is_empty());
             assert_eq!(btree.allocator.num_allocated_chunks(), 0);
         });
     }
-    btree_test!(test_pop_first_many, pop_first_many);
+    btree_test!(test_extract_if, extract_if);
 
-    fn pop_last_many<K: TestKey, V: TestValue>() {
+    fn entry_or_insert<K: TestKey, V: TestValue>() {
         let (key, value) = (K::build, V::build);
         run_btree_test(|mut btree| {
-            let n = 10_000;
-
-            assert!(btree.is_empty());
-            assert_eq!(btree.pop_last(), None);
-
+            let n = 1_000;
             for i in 0..n {
-                assert_eq!(btree.insert(key(i), value(i)), None);
+                assert_eq!(btree.entry(key(i)).or_insert(value(i)), value(i));
             }
             assert_eq!(btree.len(), n as u64);
 
-            let mut btree = BTreeMap::<K, V, _>::load(btree.into_memory());
-            for i in (0..n).rev() {
-                assert_eq!(btree.pop_last(), Some((key(i), value(i))));
-                assert_eq!(btree.get(&key(i)), None);
+            // Existing entries are not overwritten.
+            for i in 0..n {
+                assert_eq!(btree.entry(key(i)).or_insert(value(i + 1)), value(i));
+                assert_eq!(btree.get(&key(i)), Some(value(i)));
             }
-
-            // We've deallocated everything.
-            assert!(btree.is_empty());
-            assert_eq!(btree.allocator.num_allocated_chunks(), 0);
+            assert_eq!(btree.len(), n as u64);
         });
     }
-    btree_test!(test_pop_last_many, pop_last_many);
+    btree_test!(test_entry_or_insert, entry_or_insert);
 
-    fn reloading<K: TestKey, V: TestValue>() {
+    fn entry_and_modify<K: TestKey, V: TestValue>() {
         let (key, value) = (K::build, V::build);
         run_btree_test(|mut btree| {
             let n = 1_000;
-            for i in 0..n {
-                assert_eq!(btree.insert(key(i), value(i)), None);
-                assert_eq!(btree.get(&key(i)), Some(value(i)));
+            for i in (0..n).step_by(2) {
+                btree.insert(key(i), value(i));
             }
-            assert_eq!(btree.len(), n as u64);
 
-            // Reload the BTreeMap and verify the entry.
-            let mut btree = BTreeMap::<K, V, VectorMemory>::load(btree.into_memory());
-            assert_eq!(btree.len(), n as u64);
+            // Odd keys are inserted, even keys are modified.
             for i in 0..n {
-                assert_eq!(btree.get(&key(i)), Some(value(i)));
+                btree
+                    .entry(key(i))
+                    .and_modify(|v| *v = value(i + 1))
+                    .or_insert(value(i));
             }
 
-            // Remove all entries and verify the entry.
+            assert_eq!(btree.len(), n as u64);
             for i in 0..n {
-                assert_eq!(btree.remove(&key(i)), Some(value(i)));
+                let expected = if i % 2 == 0 { value(i + 1) } else { value(i) };
+                assert_eq!(btree.get(&key(i)), Some(expected));
             }
-            assert_eq!(btree.len(), 0);
-
-            // Reload the BTreeMap and verify the entry.
-            let btree = BTreeMap::<K, V, VectorMemory>::load(btree.into_memory());
-            assert_eq!(btree.len(), 0);
         });
     }
-    btree_test!(test_reloading, reloading);
+    btree_test!(test_entry_and_modify, entry_and_modify);
 
-    fn len<K: TestKey, V: TestValue>() {
+    fn entry_occupied_insert_and_remove<K: TestKey, V: TestValue>() {
         let (key, value) = (K::build, V::build);
         run_btree_test(|mut btree| {
             let n = 1_000;
             for i in 0..n {
-                assert_eq!(btree.insert(key(i), value(i)), None);
+                btree.insert(key(i), value(i));
             }
 
-            assert_eq!(btree.len(), n as u64);
-            assert!(!btree.is_empty());
-
             for i in 0..n {
-                assert_eq!(btree.remove(&key(i)), Some(value(i)));
+                match btree.entry(key(i)) {
+                    Entry::Occupied(mut entry) => {
+                        assert_eq!(entry.key(), &key(i));
+                        assert_eq!(entry.get(), value(i));
+                        assert_eq!(entry.insert(value(i + 1)), value(i));
+                        assert_eq!(entry.get(), value(i + 1));
+                        assert_eq!(entry.remove_entry(), (key(i), value(i + 1)));
+                    }
+                    Entry::Vacant(_) => panic!("key {i} must be present"),
+                }
+                assert!(!btree.contains_key(&key(i)));
             }
 
-            assert_eq!(btree.len(), 0);
             assert!(btree.is_empty());
+            assert!(matches!(btree.entry(key(0)), Entry::Vacant(_)));
+            assert_eq!(btree.allocator.num_allocated_chunks(), 0);
         });
     }
-    btree_test!(test_len, len);
+    btree_test!(
+        test_entry_occupied_insert_and_remove,
+        entry_occupied_insert_and_remove
+    );
 
-    fn contains_key<K: TestKey, V: TestValue>() {
+    fn update<K: TestKey, V: TestValue>() {
         let (key, value) = (K::build, V::build);
         run_btree_test(|mut btree| {
             let n = 1_000;
-            for i in (0..n).step_by(2) {
-                assert_eq!(btree.insert(key(i), value(i)), None);
+            let mut expected = std::collections::BTreeMap::new();
+
+            // Missing keys are inserted, existing keys are modified or removed.
+            for round in 0..3 {
+                for i in (round..n).step_by(round as usize + 1) {
+                    let remove = round > 0 && i % 3 == 0;
+                    btree.update(&key(i), |old| {
+                        assert_eq!(old, expected.get(&i).cloned());
+                        (!remove).then(|| value(i + round))
+                    });
+                    if remove {
+                        expected.remove(&i);
+                    } else {
+                        expected.insert(i, value(i + round));
+                    }
+                }
+
+                assert_eq!(btree.len(), expected.len() as u64);
+                assert_eq!(
+                    collect(btree.iter().map(|e| e.into_pair())),
+                    expected
+                        .iter()
+                        .map(|(i, v)| (key(*i), v.clone()))
+                        .collect::<Vec<_>>()
+                );
             }
 
-            // Only even keys should be present.
+            // Removing a missing key is a no-op.
+            btree.update(&key(n), |old| {
+                assert_eq!(old, None);
+                None
+            });
+            assert_eq!(btree.len(), expected.len() as u64);
+
             for i in 0..n {
-                assert_eq!(btree.contains_key(&key(i)), i % 2 == 0);
+                btree.update(&key(i), |_| None);
             }
+            assert!(btree.is_empty());
+            assert_eq!(btree.allocator.num_allocated_chunks(), 0);
         });
     }
-    btree_test!(test_contains_key, contains_key);
+    btree_test!(test_update, update);
 
     fn range_empty<K: TestKey, V: TestValue>() {
         let key = K::build;
@@ -2680,6 +6226,67 @@ mod test {
     }
     btree_test!(test_range_various_prefixes_2, range_various_prefixes_2);
 
+    #[test]
+    fn prefix_range_of_tuple_keys() {
+        let principal = |i: u8| ic_principal::Principal::from_slice(&[i; 10]);
+        let mut btree = BTreeMap::new(make_memory());
+        for p in 0..20u8 {
+            for i in 0..(p as u64 * 7) {
+                btree.insert((principal(p), i), p as u64 * i);
+            }
+        }
+
+        for p in 0..21u8 {
+            let len = if p < 20 { p as u64 * 7 } else { 0 };
+            let expected: Vec<_> = (0..len)
+                .map(|i| ((principal(p), i), p as u64 * i))
+                .collect();
+            assert_eq!(collect_entry(btree.prefix_range(&principal(p))), expected);
+            assert_eq!(
+                btree.prefix_range(&principal(p)).rev().count(),
+                expected.len()
+            );
+
+            // Iterate from both ends at once.
+            let mut iter = btree.prefix_range(&principal(p));
+            let mut front = vec![];
+            let mut back = vec![];
+            while let Some(entry) = iter.next() {
+                front.push(entry.into_pair());
+                match iter.next_back() {
+                    Some(entry) => back.push(entry.into_pair()),
+                    None => break,
+                }
+            }
+            front.extend(back.into_iter().rev());
+            assert_eq!(front, expected);
+        }
+    }
+
+    #[test]
+    fn prefix_range_of_triple_keys() {
+        let mut btree = BTreeMap::new(make_memory());
+        for a in 0..10u64 {
+            for b in ["x", "y", "z"] {
+                for c in 0..10u32 {
+                    btree.insert((a, b.to_string(), c), ());
+                }
+            }
+        }
+
+        let keys: Vec<_> = btree.prefix_range(&3).map(|e| e.key().clone()).collect();
+        assert_eq!(keys.len(), 30);
+        assert!(keys.iter().all(|(a, _, _)| *a == 3));
+
+        let keys: Vec<_> = btree
+            .prefix_range(&(5, "y".to_string()))
+            .map(|e| e.key().2)
+            .collect();
+        assert_eq!(keys, (0..10).collect::<Vec<_>>());
+        assert_eq!(btree.prefix_range(&(5, "w".to_string())).count(), 0);
+        assert_eq!(btree.prefix_range(&10).count(), 0);
+    }
+
     fn range_large<K: TestKey, V: TestValue>() {
         let (key, value) = (K::build, V::build);
         run_btree_test(|mut btree| {
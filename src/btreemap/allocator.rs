@@ -42,9 +42,50 @@ pub struct Allocator<M: Memory> {
     // A linked list of unallocated chunks.
     free_list_head: Address,
 
+    // The address past which chunks are being reclaimed by a compaction, or `NULL` if no
+    // compaction is in progress. See `start_compaction`.
+    compaction_limit: Address,
+
+    // A linked list of the unallocated chunks past the compaction limit, which are kept out
+    // of the free list during a compaction, so that every unallocated chunk is in one of
+    // the two lists.
+    reclaimed_list_head: Address,
+
+    // The progress of `compact_tail`. Only kept in the heap, as it can be recomputed.
+    tail_compaction: Option<TailCompaction>,
+
     memory: M,
 }
 
+/// The result of a call to `Allocator::compact_tail`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TailCompactionStatus {
+    /// The compaction is finished.
+    Done,
+    /// The budget ran out before the compaction finished.
+    Pending,
+    /// Chunks past the compaction limit are allocated, and there's enough free space before
+    /// the limit to move them there. They must be moved before the compaction continues.
+    Rewalk,
+}
+
+/// The phases of `Allocator::compact_tail`.
+#[derive(Clone, Copy, Debug)]
+enum TailCompaction {
+    // Walking the free list to drop the chunks past the compaction limit. `prev` is the
+    // last chunk kept in the list.
+    Purge {
+        prev: Option<Address>,
+    },
+    // Scanning the chunks before `end`, the chunk the free list ends with, backwards to
+    // find the last allocated chunk. The chunks from `new_end` to `end` are free.
+    Scan {
+        prev: Option<Address>,
+        end: Address,
+        new_end: Address,
+    },
+}
+
 #[repr(C, packed)]
 #[derive(PartialEq, Debug)]
 struct AllocatorHeader {
@@ -55,8 +96,8 @@ struct AllocatorHeader {
     allocation_size: Bytes,
     num_allocated_chunks: u64,
     free_list_head: Address,
-    // Additional space reserved to add new fields without breaking backward-compatibility.
-    _buffer: [u8; 16],
+    compaction_limit: Address,
+    reclaimed_list_head: Address,
 }
 
 impl AllocatorHeader {
@@ -82,6 +123,9 @@ impl<M: Memory> Allocator<M> {
             allocation_size,
             num_allocated_chunks: 0,
             free_list_head: NULL, // Will be set in `allocator.clear()` below.
+            compaction_limit: NULL,
+            reclaimed_list_head: NULL,
+            tail_compaction: None,
             memory,
         };
 
@@ -97,6 +141,9 @@ impl<M: Memory> Allocator<M> {
         chunk.save(self.free_list_head, &self.memory);
 
         self.num_allocated_chunks = 0;
+        self.compaction_limit = NULL;
+        self.reclaimed_list_head = NULL;
+        self.tail_compaction = None;
 
         self.save()
     }
@@ -115,6 +162,9 @@ impl<M: Memory> Allocator<M> {
             allocation_size: header.allocation_size,
             num_allocated_chunks: header.num_allocated_chunks,
             free_list_head: header.free_list_head,
+            compaction_limit: header.compaction_limit,
+            reclaimed_list_head: header.reclaimed_list_head,
+            tail_compaction: None,
             memory,
         }
     }
@@ -158,6 +208,8 @@ impl<M: Memory> Allocator<M> {
     ///                   |_______________________________↑         |____ NULL
     ///
     pub fn allocate(&mut self) -> Address {
+        self.reclaim_chunks_past_compaction_limit();
+
         // Get the next available chunk.
         let chunk_addr = self.free_list_head;
         let mut chunk = ChunkHeader::load(chunk_addr, &self.memory);
@@ -198,16 +250,70 @@ impl<M: Memory> Allocator<M> {
         let mut chunk = ChunkHeader::load(chunk_addr, &self.memory);
 
         assert!(chunk.allocated);
+        assert!(
+            { chunk.extra_references } == 0,
+            "Attempting to deallocate a shared chunk."
+        );
 
         chunk.allocated = false;
-        chunk.next = self.free_list_head;
+        if self.is_past_compaction_limit(chunk_addr) {
+            // The chunk is being reclaimed, so it isn't added to the free list.
+            chunk.next = self.reclaimed_list_head;
+            chunk.save(chunk_addr, &self.memory);
+            self.reclaimed_list_head = chunk_addr;
+        } else {
+            chunk.next = self.free_list_head;
+            chunk.save(chunk_addr, &self.memory);
+            self.free_list_head = chunk_addr;
+        }
+
+        self.num_allocated_chunks -= 1;
+        self.save();
+    }
+
+    /// Adds a reference to an allocated chunk, so that it's only deallocated once all its
+    /// references are released with `release`.
+    ///
+    /// A chunk starts with a single reference when it's allocated. References allow a chunk
+    /// to be shared, e.g. by the nodes of several versions of a tree.
+    pub fn add_reference(&mut self, address: Address) {
+        let chunk_addr = address - ChunkHeader::size();
+        let mut chunk = ChunkHeader::load(chunk_addr, &self.memory);
+        assert!(chunk.allocated);
 
+        chunk.extra_references = chunk
+            .extra_references
+            .checked_add(1)
+            .expect("Too many references to a chunk.");
         chunk.save(chunk_addr, &self.memory);
+    }
 
-        self.free_list_head = chunk_addr;
+    /// Returns the number of references to the allocated chunk.
+    pub fn references(&self, address: Address) -> u64 {
+        let chunk = ChunkHeader::load(address - ChunkHeader::size(), &self.memory);
+        chunk.extra_references as u64 + 1
+    }
 
-        self.num_allocated_chunks -= 1;
-        self.save();
+    /// Returns true if the allocated chunk has more than one reference.
+    pub fn is_shared(&self, address: Address) -> bool {
+        self.references(address) > 1
+    }
+
+    /// Releases a reference to an allocated chunk, deallocating the chunk if it was the last
+    /// one. Returns true if the chunk was deallocated.
+    pub fn release(&mut self, address: Address) -> bool {
+        let chunk_addr = address - ChunkHeader::size();
+        let mut chunk = ChunkHeader::load(chunk_addr, &self.memory);
+        assert!(chunk.allocated);
+
+        if chunk.extra_references == 0 {
+            self.deallocate(address);
+            return true;
+        }
+
+        chunk.extra_references -= 1;
+        chunk.save(chunk_addr, &self.memory);
+        false
     }
 
     /// Saves the allocator to memory.
@@ -219,17 +325,374 @@ impl<M: Memory> Allocator<M> {
             num_allocated_chunks: self.num_allocated_chunks,
             allocation_size: self.allocation_size,
             free_list_head: self.free_list_head,
-            _buffer: [0; 16],
+            compaction_limit: self.compaction_limit,
+            reclaimed_list_head: self.reclaimed_list_head,
         };
 
         write_struct(&header, self.header_addr, &self.memory);
     }
 
-    #[cfg(test)]
     pub fn num_allocated_chunks(&self) -> u64 {
         self.num_allocated_chunks
     }
 
+    /// Returns the number of deallocated chunks that are available for reuse.
+    ///
+    /// The chunk past the end of the allocated chunks, which is always at the end of the free
+    /// list, isn't counted. Returns zero if the free list is invalid.
+    pub fn num_free_chunks(&self) -> u64 {
+        self.check_free_list()
+            .map_or(0, |chunks| chunks.saturating_sub(1))
+    }
+
+    /// Returns true if the given address was returned by `allocate` and hasn't been
+    /// deallocated since, i.e. if it's the start of an allocated chunk's blob.
+    ///
+    /// Unlike `deallocate`, this doesn't panic if the address isn't a valid chunk.
+    pub fn is_allocated(&self, address: Address) -> bool {
+        match address.get().checked_sub(ChunkHeader::size().get()) {
+            Some(chunk_addr) => self
+                .chunk_at(Address::from(chunk_addr))
+                .is_some_and(|chunk| chunk.allocated),
+            None => false,
+        }
+    }
+
+    /// Walks the list of free chunks, checking that every chunk in it is a valid chunk that
+    /// isn't allocated, and that the list has no cycles.
+    ///
+    /// Returns the number of free chunks, or the address of the first invalid chunk.
+    pub fn check_free_list(&self) -> Result<u64, Address> {
+        self.check_list(self.free_list_head).map(|(count, _)| count)
+    }
+
+    // Walks the list of unallocated chunks starting at `head`. See `check_free_list`.
+    // Returns the number of chunks in the list and the address of its last chunk.
+    fn check_list(&self, head: Address) -> Result<(u64, Address), Address> {
+        // A list with more chunks than fit in the memory has a cycle.
+        let max_chunks = (self.memory.size() * crate::WASM_PAGE_SIZE) / self.chunk_size().get();
+
+        let mut count = 0;
+        let mut last = NULL;
+        let mut chunk_addr = head;
+        while chunk_addr != NULL {
+            match self.chunk_at(chunk_addr) {
+                Some(chunk) if !chunk.allocated && count < max_chunks => {
+                    count += 1;
+                    last = chunk_addr;
+                    chunk_addr = chunk.next;
+                }
+                _ => return Err(chunk_addr),
+            }
+        }
+        Ok((count, last))
+    }
+
+    /// Walks the list of reclaimed chunks, with the same checks as `check_free_list`.
+    ///
+    /// Returns the number of reclaimed chunks, or the address of the first invalid chunk.
+    pub fn check_reclaimed_list(&self) -> Result<u64, Address> {
+        self.check_list(self.reclaimed_list_head)
+            .map(|(count, _)| count)
+    }
+
+    /// Walks every chunk before the chunk past the last allocated chunk.
+    ///
+    /// Returns the number of chunks and how many of them are allocated, or `None` if the
+    /// free list is invalid. Chunks with an invalid header are counted as not allocated.
+    pub fn count_chunks(&self) -> Option<(u64, u64)> {
+        let (_, end) = self.check_list(self.free_list_head).ok()?;
+        let mut chunks = 0;
+        let mut allocated = 0;
+        let mut chunk_addr = self.first_chunk();
+        while chunk_addr < end {
+            chunks += 1;
+            if self
+                .chunk_at(chunk_addr)
+                .is_some_and(|chunk| chunk.allocated)
+            {
+                allocated += 1;
+            }
+            chunk_addr += self.chunk_size();
+        }
+        Some((chunks, allocated))
+    }
+
+    /// Starts a compaction, unless one is already in progress.
+    ///
+    /// The compaction limit is set to the end of the first `num_allocated_chunks` chunks,
+    /// which is where the allocated chunks end once they're packed at the start of the
+    /// memory. Until the compaction finishes, free chunks past the limit are moved from the
+    /// free list to the list of reclaimed chunks rather than reused, and chunks past the
+    /// limit are added to the reclaimed chunks when they're deallocated. Chunks allocated
+    /// past the limit can be moved before it with `allocate_before_compaction_limit`, and
+    /// `compact_tail` then reclaims the chunks at the end of the memory.
+    ///
+    /// The limit is saved in the allocator's header, so that a compaction can continue after
+    /// the allocator is reloaded.
+    pub fn start_compaction(&mut self) {
+        if !self.is_compacting() {
+            self.compaction_limit =
+                self.first_chunk() + Bytes::from(self.num_allocated_chunks) * self.chunk_size();
+            self.tail_compaction = None;
+            self.save();
+        }
+    }
+
+    /// Returns true if a compaction is in progress.
+    pub fn is_compacting(&self) -> bool {
+        self.compaction_limit != NULL || self.is_restoring()
+    }
+
+    /// Returns true if a compaction that couldn't reclaim the end of the memory is
+    /// returning its reclaimed chunks to the free list.
+    pub fn is_restoring(&self) -> bool {
+        self.compaction_limit == NULL && self.reclaimed_list_head != NULL
+    }
+
+    /// Returns true if a compaction is in progress and the given address is past its limit.
+    pub fn is_past_compaction_limit(&self, address: Address) -> bool {
+        self.compaction_limit != NULL && address >= self.compaction_limit
+    }
+
+    /// Allocates a chunk before the compaction limit, or returns `None` if no free chunk
+    /// before the limit is available.
+    pub fn allocate_before_compaction_limit(&mut self) -> Option<Address> {
+        self.reclaim_chunks_past_compaction_limit();
+        if self.is_past_compaction_limit(self.free_list_head) {
+            return None;
+        }
+        Some(self.allocate())
+    }
+
+    /// Reclaims the chunks at the end of the memory once the allocated chunks were moved
+    /// before the compaction limit, examining at most about `budget` chunks. The work done
+    /// is subtracted from `budget`.
+    ///
+    /// Once the compaction is finished, the chunk that the free list ends with is moved to
+    /// the compaction limit, and the memory past it is no longer used. If chunks past the
+    /// limit are still allocated, e.g. because they were allocated after the chunks were
+    /// moved, `Rewalk` is returned if they can be moved before the limit. Otherwise, the
+    /// memory can't be reclaimed, and the reclaimed chunks are returned to the free list
+    /// before the compaction finishes.
+    pub fn compact_tail(&mut self, budget: &mut u64) -> TailCompactionStatus {
+        while self.is_restoring() {
+            if *budget == 0 {
+                return TailCompactionStatus::Pending;
+            }
+            *budget -= 1;
+            self.restore_step();
+        }
+
+        if self.compaction_limit == NULL {
+            return TailCompactionStatus::Done;
+        }
+
+        while *budget > 0 {
+            match self
+                .tail_compaction
+                .unwrap_or(TailCompaction::Purge { prev: None })
+            {
+                TailCompaction::Purge { prev } => {
+                    *budget -= 1;
+                    self.tail_compaction = Some(self.purge_step(prev));
+                }
+                TailCompaction::Scan { prev, end, new_end } => {
+                    if new_end > self.compaction_limit {
+                        let last = new_end - self.chunk_size();
+                        *budget -= 1;
+                        if !ChunkHeader::load(last, &self.memory).allocated {
+                            self.tail_compaction = Some(TailCompaction::Scan {
+                                prev,
+                                end,
+                                new_end: last,
+                            });
+                            continue;
+                        }
+                    }
+
+                    // The chunk before `new_end` is allocated, or `new_end` is the limit.
+                    if new_end > self.compaction_limit {
+                        self.tail_compaction = None;
+                        if self.num_allocated_chunks * self.chunk_size().get()
+                            <= self.compaction_limit.get() - self.first_chunk().get()
+                        {
+                            return TailCompactionStatus::Rewalk;
+                        }
+
+                        // The allocated chunks don't fit before the limit anymore. The
+                        // compaction ends once the reclaimed chunks are reused again.
+                        self.compaction_limit = NULL;
+                        self.save();
+                        return self.compact_tail(budget);
+                    }
+
+                    if self.trim(prev, end, new_end) {
+                        return TailCompactionStatus::Done;
+                    }
+                }
+            }
+        }
+
+        TailCompactionStatus::Pending
+    }
+
+    // Moves the first reclaimed chunk back to the free list.
+    fn restore_step(&mut self) {
+        let chunk_addr = self.reclaimed_list_head;
+        let mut chunk = ChunkHeader::load(chunk_addr, &self.memory);
+        self.reclaimed_list_head = chunk.next;
+        chunk.next = self.free_list_head;
+        chunk.save(chunk_addr, &self.memory);
+        self.free_list_head = chunk_addr;
+        self.save();
+    }
+
+    // Moves an unallocated chunk that was removed from the free list to the reclaimed
+    // chunks.
+    fn reclaim(&mut self, chunk_addr: Address, mut chunk: ChunkHeader) {
+        chunk.next = self.reclaimed_list_head;
+        chunk.save(chunk_addr, &self.memory);
+        self.reclaimed_list_head = chunk_addr;
+        self.save();
+    }
+
+    // Examines the chunk after `prev` in the free list, moving it to the reclaimed chunks
+    // if it's past the compaction limit, and returns the next phase of the tail compaction.
+    fn purge_step(&mut self, prev: Option<Address>) -> TailCompaction {
+        let prev = match prev {
+            // The previous chunk was allocated since it was examined: start over.
+            Some(prev) if ChunkHeader::load(prev, &self.memory).allocated => None,
+            prev => prev,
+        };
+
+        let chunk_addr = match prev {
+            Some(prev) => ChunkHeader::load(prev, &self.memory).next,
+            None => self.free_list_head,
+        };
+        let chunk = ChunkHeader::load(chunk_addr, &self.memory);
+
+        if chunk.next == NULL {
+            // This is the chunk past the last allocated chunk, where the free list ends.
+            return TailCompaction::Scan {
+                prev,
+                end: chunk_addr,
+                new_end: chunk_addr,
+            };
+        }
+
+        if chunk_addr < self.compaction_limit {
+            return TailCompaction::Purge {
+                prev: Some(chunk_addr),
+            };
+        }
+
+        // Move the chunk from the free list to the reclaimed chunks.
+        match prev {
+            Some(prev) => {
+                let mut prev_chunk = ChunkHeader::load(prev, &self.memory);
+                prev_chunk.next = chunk.next;
+                prev_chunk.save(prev, &self.memory);
+            }
+            None => self.free_list_head = chunk.next,
+        }
+        self.reclaim(chunk_addr, chunk);
+        TailCompaction::Purge { prev }
+    }
+
+    // Moves the chunk that ends the free list from `end` to `new_end`, and finishes the
+    // compaction. If the free list changed since `end` was found, the tail compaction starts
+    // over and false is returned.
+    fn trim(&mut self, prev: Option<Address>, end: Address, new_end: Address) -> bool {
+        let prev_next = match prev {
+            Some(prev) => {
+                let chunk = ChunkHeader::load(prev, &self.memory);
+                (!chunk.allocated).then_some(chunk.next)
+            }
+            None => Some(self.free_list_head),
+        };
+        let end_chunk = ChunkHeader::load(end, &self.memory);
+        if prev_next != Some(end) || end_chunk.allocated || end_chunk.next != NULL {
+            self.tail_compaction = None;
+            return false;
+        }
+
+        if new_end != end {
+            ChunkHeader::null().save(new_end, &self.memory);
+            match prev {
+                Some(prev) => {
+                    let mut chunk = ChunkHeader::load(prev, &self.memory);
+                    chunk.next = new_end;
+                    chunk.save(prev, &self.memory);
+                }
+                None => self.free_list_head = new_end,
+            }
+        }
+
+        // The reclaimed chunks are all past the limit, where the memory is no longer used.
+        self.compaction_limit = NULL;
+        self.reclaimed_list_head = NULL;
+        self.tail_compaction = None;
+        self.save();
+        true
+    }
+
+    /// Returns the address of the chunk past the last allocated chunk, where the free list
+    /// ends. The memory past this chunk's header isn't used.
+    #[cfg(test)]
+    pub fn end(&self) -> Address {
+        let mut chunk_addr = self.free_list_head;
+        loop {
+            let chunk = ChunkHeader::load(chunk_addr, &self.memory);
+            if chunk.next == NULL {
+                return chunk_addr;
+            }
+            chunk_addr = chunk.next;
+        }
+    }
+
+    /// Returns the number of chunks before `end`, whether they're allocated or not.
+    #[cfg(test)]
+    pub fn num_chunks(&self) -> u64 {
+        (self.end().get() - self.first_chunk().get()) / self.chunk_size().get()
+    }
+
+    // During a compaction, moves the free chunks past the compaction limit from the head of
+    // the free list to the reclaimed chunks, so that they aren't reused.
+    fn reclaim_chunks_past_compaction_limit(&mut self) {
+        while self.is_past_compaction_limit(self.free_list_head) {
+            let chunk_addr = self.free_list_head;
+            let chunk = ChunkHeader::load(chunk_addr, &self.memory);
+            if chunk.next == NULL {
+                // The free list always ends with the chunk past the last allocated chunk.
+                break;
+            }
+            self.free_list_head = chunk.next;
+            self.reclaim(chunk_addr, chunk);
+        }
+    }
+
+    // The address of the first chunk.
+    fn first_chunk(&self) -> Address {
+        self.header_addr + AllocatorHeader::size()
+    }
+
+    /// Returns the header of the chunk at the given address, or `None` if the address
+    /// doesn't hold a valid chunk header.
+    fn chunk_at(&self, chunk_addr: Address) -> Option<ChunkHeader> {
+        let first_chunk = self.header_addr + AllocatorHeader::size();
+        let memory_size = self.memory.size() * crate::WASM_PAGE_SIZE;
+        if chunk_addr.get() < first_chunk.get()
+            || (chunk_addr.get() - first_chunk.get()) % self.chunk_size().get() != 0
+            || chunk_addr.get().saturating_add(ChunkHeader::size().get()) > memory_size
+        {
+            return None;
+        }
+
+        let header: ChunkHeader = read_struct(chunk_addr, &self.memory);
+        (&header.magic == CHUNK_MAGIC && header.version == CHUNK_LAYOUT_VERSION).then_some(header)
+    }
+
     // The full size of a chunk, which is the size of the header + the `allocation_size` that's
     // available to the user.
     fn chunk_size(&self) -> Bytes {
@@ -255,8 +718,10 @@ struct ChunkHeader {
     magic: [u8; 3],
     version: u8,
     allocated: bool,
+    // The number of references to the chunk besides the first one. See `add_reference`.
+    extra_references: u16,
     // Empty space to memory-align the following fields.
-    _alignment: [u8; 3],
+    _alignment: [u8; 1],
     next: Address,
 }
 
@@ -267,7 +732,8 @@ impl ChunkHeader {
             magic: *CHUNK_MAGIC,
             version: CHUNK_LAYOUT_VERSION,
             allocated: false,
-            _alignment: [0; 3],
+            extra_references: 0,
+            _alignment: [0; 1],
             next: NULL,
         }
     }
@@ -470,4 +936,106 @@ mod test {
         // Try deallocating the free chunk - should panic.
         allocator.deallocate(chunk_addr);
     }
+
+    #[test]
+    fn compaction_reclaims_chunks_past_the_limit() {
+        let mut allocator = Allocator::new(make_memory(), Address::from(0), Bytes::from(16u64));
+        let chunks: Vec<_> = (0..10).map(|_| allocator.allocate()).collect();
+        let end = allocator.end();
+
+        // Free the even chunks, leaving holes before the last allocated chunks.
+        for chunk in chunks.iter().step_by(2) {
+            allocator.deallocate(*chunk);
+        }
+        allocator.start_compaction();
+
+        // Chunks past the limit are neither reused nor added back to the free list.
+        assert!(allocator.is_past_compaction_limit(chunks[5]));
+        assert!(!allocator.is_past_compaction_limit(chunks[4]));
+        allocator.deallocate(chunks[9]);
+        assert_eq!(allocator.check_free_list(), Ok(6));
+        assert_eq!(allocator.check_reclaimed_list(), Ok(1));
+
+        // Move the allocated chunks past the limit into the holes.
+        for chunk in [chunks[5], chunks[7]] {
+            let moved = allocator.allocate_before_compaction_limit().unwrap();
+            assert!(!allocator.is_past_compaction_limit(moved));
+            allocator.deallocate(chunk);
+        }
+
+        let mut budget = 1;
+        assert_eq!(
+            allocator.compact_tail(&mut budget),
+            TailCompactionStatus::Pending
+        );
+        let mut budget = 100;
+        assert_eq!(
+            allocator.compact_tail(&mut budget),
+            TailCompactionStatus::Done
+        );
+        assert!(!allocator.is_compacting());
+        assert_eq!(allocator.check_reclaimed_list(), Ok(0));
+
+        // The memory ends at the limit, with a hole left by the chunk deallocated during the
+        // compaction.
+        assert_eq!(allocator.num_allocated_chunks(), 4);
+        assert_eq!(allocator.check_free_list(), Ok(2));
+        assert_eq!(allocator.end(), chunks[5] - ChunkHeader::size());
+        assert!(allocator.end() < end);
+    }
+
+    #[test]
+    fn compaction_is_saved() {
+        let mem = make_memory();
+        let mut allocator = Allocator::new(mem.clone(), Address::from(0), Bytes::from(16u64));
+        let chunks: Vec<_> = (0..4).map(|_| allocator.allocate()).collect();
+        allocator.deallocate(chunks[0]);
+        allocator.start_compaction();
+
+        let mut allocator = Allocator::load(mem, Address::from(0));
+        assert!(allocator.is_compacting());
+        assert!(allocator.is_past_compaction_limit(chunks[3]));
+
+        // The last chunk must be moved before the limit first.
+        assert_eq!(
+            allocator.compact_tail(&mut 100),
+            TailCompactionStatus::Rewalk
+        );
+        let moved = allocator.allocate_before_compaction_limit().unwrap();
+        assert_eq!(moved, chunks[0]);
+        allocator.deallocate(chunks[3]);
+
+        let end = allocator.end();
+        assert_eq!(allocator.compact_tail(&mut 100), TailCompactionStatus::Done);
+        assert_eq!(allocator.end(), chunks[3] - ChunkHeader::size());
+        assert!(allocator.end() < end);
+    }
+
+    #[test]
+    fn compaction_restores_reclaimed_chunks() {
+        let mut allocator = Allocator::new(make_memory(), Address::from(0), Bytes::from(16u64));
+        let chunks: Vec<_> = (0..4).map(|_| allocator.allocate()).collect();
+        allocator.deallocate(chunks[1]);
+        allocator.deallocate(chunks[0]);
+        allocator.start_compaction();
+
+        // The chunks allocated during the compaction don't fit before the limit anymore.
+        let more: Vec<_> = (0..3).map(|_| allocator.allocate()).collect();
+        allocator.deallocate(chunks[3]);
+        allocator.deallocate(more[2]);
+        assert_eq!(allocator.check_reclaimed_list(), Ok(2));
+
+        let end = allocator.end();
+        let mut budget = 1;
+        while allocator.compact_tail(&mut budget) == TailCompactionStatus::Pending {
+            budget = 1;
+        }
+
+        // No memory was reclaimed, but the reclaimed chunks can be reused again.
+        assert!(!allocator.is_compacting());
+        assert_eq!(allocator.end(), end);
+        assert_eq!(allocator.check_reclaimed_list(), Ok(0));
+        assert_eq!(allocator.num_allocated_chunks(), 3);
+        assert_eq!(allocator.check_free_list(), Ok(3));
+    }
 }
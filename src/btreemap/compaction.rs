@@ -0,0 +1,199 @@
+use super::{
+    allocator::TailCompactionStatus,
+    node::{Node, NodeType},
+    BTreeMap,
+};
+use crate::{
+    types::{Address, NULL},
+    Memory, Storable,
+};
+
+/// The progress of a compaction, kept in the heap between calls to [`BTreeMap::compact`].
+pub(super) enum Compaction<K> {
+    /// Walking the tree to move the nodes past the compaction limit. The leaves up to the
+    /// one whose largest key is `cursor` were visited.
+    Walk { cursor: Option<K> },
+    /// Reclaiming the chunks at the end of the memory.
+    Tail,
+}
+
+impl<K, V, M> BTreeMap<K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    /// Incrementally compacts the map's memory, doing about `budget` units of work, and
+    /// returns true once the compaction is finished.
+    ///
+    /// Removing entries frees the nodes that held them, but the freed memory can only be
+    /// reused by the map: the memory the map uses never shrinks. A compaction moves the
+    /// nodes at the end of the memory into the free space before them, so that the memory
+    /// past the last node is no longer used. This memory is left untouched, and is reused
+    /// first when the map grows again.
+    ///
+    /// A compaction visits every node of the tree. Each node visited, and each freed chunk
+    /// of memory examined, counts as one unit of work, so a compaction can be spread across
+    /// calls, e.g. across messages of a canister to stay within the instruction limit. The
+    /// map can be modified between calls. While a compaction is in progress, the map doesn't
+    /// reuse the free memory at the end, and grows past it if it runs out of free memory.
+    /// Nodes created past the end of the compacted memory are moved as well, walking the
+    /// tree again if needed. If the map grew too much to fit before the end of the
+    /// compacted memory, the compaction finishes by making the free memory at the end
+    /// reusable again, without reclaiming it.
+    ///
+    /// The compaction is saved in the map's memory, and continues where it left off if the
+    /// map is reloaded, e.g. after an upgrade, though the tree is then walked from the start
+    /// again. This works for any memory, including the virtual memories of a
+    /// [`MemoryManager`](crate::memory_manager::MemoryManager).
+    ///
+    /// The nodes shared with [snapshots](Self::snapshot) can't be moved, so a compaction
+    /// makes no progress while the map has snapshots, and continues once they're released.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::init(DefaultMemoryImpl::default());
+    ///
+    /// for i in 0..1_000 {
+    ///     map.insert(i, i);
+    /// }
+    /// map.remove_range(..900);
+    ///
+    /// while !map.compact(100) {}
+    ///
+    /// assert!(map.iter().map(|e| e.into_pair()).eq((900..1_000).map(|i| (i, i))));
+    /// ```
+    pub fn compact(&mut self, budget: u64) -> bool {
+        if self.has_shared_nodes() {
+            return false;
+        }
+
+        if !self.allocator.is_compacting() {
+            self.allocator.start_compaction();
+            self.compaction = None;
+        }
+
+        let mut budget = budget;
+        // The progress is lost if the map was reloaded, so the walk then starts over, unless
+        // the allocator is already finishing the compaction.
+        let mut compaction = self.compaction.take().unwrap_or_else(|| {
+            if self.allocator.is_restoring() {
+                Compaction::Tail
+            } else {
+                Compaction::Walk { cursor: None }
+            }
+        });
+        while budget > 0 {
+            match compaction {
+                Compaction::Walk { cursor } => {
+                    compaction = match self.compact_step(cursor.as_ref(), &mut budget) {
+                        Some(cursor) => Compaction::Walk {
+                            cursor: Some(cursor),
+                        },
+                        None => Compaction::Tail,
+                    };
+                }
+                Compaction::Tail => match self.allocator.compact_tail(&mut budget) {
+                    TailCompactionStatus::Done => return true,
+                    TailCompactionStatus::Pending => {}
+                    TailCompactionStatus::Rewalk => {
+                        // Nodes were allocated past the compaction limit after the walk
+                        // passed them.
+                        compaction = Compaction::Walk { cursor: None };
+                    }
+                },
+            }
+        }
+
+        self.compaction = Some(compaction);
+        false
+    }
+
+    /// Visits the next leaf after the one whose largest key is `cursor`, moving the nodes on
+    /// its path that are past the compaction limit. Returns the largest key of the leaf, or
+    /// `None` if all the leaves were visited.
+    fn compact_step(&mut self, cursor: Option<&K>, budget: &mut u64) -> Option<K> {
+        if self.root_addr == NULL {
+            return None;
+        }
+
+        let mut node = self.load_node(self.root_addr);
+        *budget = budget.saturating_sub(1);
+        if let Some(address) = self.relocate_node(&mut node) {
+            self.root_addr = address;
+            self.save_header();
+        }
+
+        let mut path: Vec<(Node<K>, usize)> = vec![];
+        // Once the walk moves past the cursor, it follows the leftmost path of the subtree.
+        let mut leftmost = cursor.is_none();
+        loop {
+            match node.node_type() {
+                NodeType::Internal => {
+                    let idx = match cursor {
+                        Some(cursor) if !leftmost => match node.search(cursor, self.memory()) {
+                            Ok(idx) => idx + 1,
+                            Err(idx) => idx,
+                        },
+                        _ => 0,
+                    };
+                    let child = self.compact_child(&mut node, idx, budget);
+                    path.push((node, idx));
+                    node = child;
+                }
+                NodeType::Leaf => {
+                    let max = node.entries_len().checked_sub(1)?;
+                    let max = node.key(max, self.memory());
+                    if leftmost || cursor.is_none_or(|cursor| max > cursor) {
+                        return Some(max.clone());
+                    }
+
+                    // The leaf was visited by the previous step: visit the next subtree.
+                    loop {
+                        let (mut parent, idx) = path.pop()?;
+                        if idx < parent.entries_len() {
+                            node = self.compact_child(&mut parent, idx + 1, budget);
+                            path.push((parent, idx + 1));
+                            leftmost = true;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Loads the child of the node at the given index, moving it if it's past the
+    /// compaction limit.
+    fn compact_child(&mut self, parent: &mut Node<K>, idx: usize, budget: &mut u64) -> Node<K> {
+        let mut child = self.load_node(parent.child(idx));
+        *budget = budget.saturating_sub(1);
+        if let Some(address) = self.relocate_node(&mut child) {
+            let (_, count) = parent.remove_child(idx);
+            parent.insert_child(idx, address, count);
+            self.save_node(parent);
+        }
+        child
+    }
+
+    /// Moves the node before the compaction limit if it or its overflow pages are past it,
+    /// returning the node's new address.
+    fn relocate_node(&mut self, node: &mut Node<K>) -> Option<Address> {
+        let allocator = &self.allocator;
+        if !allocator.is_past_compaction_limit(node.address())
+            && !node
+                .overflows()
+                .iter()
+                .any(|page| allocator.is_past_compaction_limit(*page))
+        {
+            return None;
+        }
+
+        let address = self.allocator.allocate_before_compaction_limit()?;
+        self.node_cache.get_mut().remove(node.address());
+        node.relocate(address, self.allocator_mut());
+        Some(address)
+    }
+}
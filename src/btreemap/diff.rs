@@ -0,0 +1,333 @@
+use super::{
+    node::{Node, NodeType},
+    BTreeMap, Snapshot,
+};
+use crate::{
+    types::{Address, NULL},
+    Memory, Storable,
+};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+/// A difference between two maps, yielded by [`BTreeMap::diff`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiffEvent<K, V> {
+    /// The key is only in the new map.
+    Added(K, V),
+    /// The key is only in the old map.
+    Removed(K, V),
+    /// The key is in both maps, with different values.
+    Changed { key: K, old: V, new: V },
+}
+
+/// An iterator over the differences between two maps, sorted by key.
+///
+/// This `struct` is created by [`BTreeMap::diff`] and [`BTreeMap::diff_since`].
+pub struct Diff<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    old: TreeCursor<'a, K, V, M>,
+    new: TreeCursor<'a, K, V, M>,
+    // True if both trees are stored in the same memory, so that a node address in both of
+    // them refers to the same node.
+    shared: bool,
+}
+
+impl<'a, K, V, M> Diff<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    fn new(
+        old: (&'a BTreeMap<K, V, M>, Address),
+        new: (&'a BTreeMap<K, V, M>, Address),
+        shared: bool,
+    ) -> Self {
+        Self {
+            old: TreeCursor::new(old.0, old.1),
+            new: TreeCursor::new(new.0, new.1),
+            shared,
+        }
+    }
+}
+
+impl<K, V, M> Iterator for Diff<'_, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    type Item = DiffEvent<K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match (self.old.peek(), self.new.peek()) {
+                (Position::Subtree(old, _), Position::Subtree(new, _))
+                    if self.shared && old == new =>
+                {
+                    // Both trees continue with the same subtree, so with the same entries.
+                    self.old.advance();
+                    self.new.advance();
+                }
+                (Position::Subtree(_, old_height), Position::Subtree(_, new_height)) => {
+                    // Descend into the taller subtree first, as it may contain the other.
+                    if old_height >= new_height {
+                        self.old.descend();
+                    } else {
+                        self.new.descend();
+                    }
+                }
+                (Position::Subtree(..), _) => self.old.descend(),
+                (_, Position::Subtree(..)) => self.new.descend(),
+                (Position::End, Position::End) => return None,
+                (Position::Entry, Position::End) => {
+                    let (key, value) = self.old.take_entry();
+                    return Some(DiffEvent::Removed(key, value));
+                }
+                (Position::End, Position::Entry) => {
+                    let (key, value) = self.new.take_entry();
+                    return Some(DiffEvent::Added(key, value));
+                }
+                (Position::Entry, Position::Entry) => match self.old.key().cmp(self.new.key()) {
+                    Ordering::Less => {
+                        let (key, value) = self.old.take_entry();
+                        return Some(DiffEvent::Removed(key, value));
+                    }
+                    Ordering::Greater => {
+                        let (key, value) = self.new.take_entry();
+                        return Some(DiffEvent::Added(key, value));
+                    }
+                    Ordering::Equal if self.old.value() == self.new.value() => {
+                        self.old.advance();
+                        self.new.advance();
+                    }
+                    Ordering::Equal => {
+                        let (key, old) = self.old.take_entry();
+                        let (_, new) = self.new.take_entry();
+                        return Some(DiffEvent::Changed { key, old, new });
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// The position of a [`TreeCursor`].
+#[derive(Copy, Clone)]
+enum Position {
+    /// Before the subtree with the given root address and height.
+    Subtree(Address, usize),
+    /// At an entry.
+    Entry,
+    /// After the last entry.
+    End,
+}
+
+/// A cursor that walks a tree in key order, visiting each subtree before loading its nodes,
+/// so that subtrees can be skipped as a whole.
+struct TreeCursor<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    map: &'a BTreeMap<K, V, M>,
+    // The root of the tree and its height, until the cursor descends into it.
+    root: Option<(Address, usize)>,
+    // The nodes from the root down to the current one, with the position in each node and
+    // the node's height. The positions of an internal node alternate between its children
+    // (even positions) and its entries (odd positions).
+    stack: Vec<(Node<K>, usize, usize)>,
+}
+
+impl<'a, K, V, M> TreeCursor<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    fn new(map: &'a BTreeMap<K, V, M>, root_addr: Address) -> Self {
+        let root = (root_addr != NULL).then(|| {
+            // All the leaves are at the same depth, so the height is the length of any path.
+            let mut height = 0;
+            let mut node = map.load_node(root_addr);
+            while node.node_type() == NodeType::Internal {
+                node = map.load_node(node.child(0));
+                height += 1;
+            }
+            (root_addr, height)
+        });
+        Self {
+            map,
+            root,
+            stack: vec![],
+        }
+    }
+
+    fn peek(&self) -> Position {
+        match self.stack.last() {
+            None => match self.root {
+                Some((address, height)) => Position::Subtree(address, height),
+                None => Position::End,
+            },
+            Some((node, pos, height)) => {
+                if node.node_type() == NodeType::Internal && pos % 2 == 0 {
+                    Position::Subtree(node.child(pos / 2), height - 1)
+                } else {
+                    Position::Entry
+                }
+            }
+        }
+    }
+
+    /// Loads the subtree at the current position, moving to its first position.
+    fn descend(&mut self) {
+        let (address, height) = match self.peek() {
+            Position::Subtree(address, height) => (address, height),
+            _ => unreachable!("the cursor must be before a subtree"),
+        };
+        self.advance();
+        self.stack.push((self.map.load_node(address), 0, height));
+    }
+
+    /// Moves past the subtree or entry at the current position.
+    fn advance(&mut self) {
+        if self.stack.is_empty() {
+            self.root = None;
+            return;
+        }
+
+        self.stack.last_mut().unwrap().1 += 1;
+        while let Some((node, pos, _)) = self.stack.last() {
+            let len = match node.node_type() {
+                NodeType::Leaf => node.entries_len(),
+                NodeType::Internal => 2 * node.entries_len() + 1,
+            };
+            if *pos < len {
+                break;
+            }
+            self.stack.pop();
+        }
+    }
+
+    /// Returns the current node and the index of the current entry in it.
+    ///
+    /// PRECONDITION: the cursor is at an entry.
+    fn entry_idx(&self) -> (&Node<K>, usize) {
+        let (node, pos, _) = self.stack.last().unwrap();
+        match node.node_type() {
+            NodeType::Leaf => (node, *pos),
+            NodeType::Internal => (node, pos / 2),
+        }
+    }
+
+    fn key(&self) -> &K {
+        let (node, idx) = self.entry_idx();
+        node.key(idx, self.map.memory())
+    }
+
+    fn value(&self) -> &[u8] {
+        let (node, idx) = self.entry_idx();
+        node.value(idx, self.map.memory())
+    }
+
+    /// Returns the current entry and moves past it.
+    fn take_entry(&mut self) -> (K, V) {
+        let entry = (
+            self.key().clone(),
+            V::from_bytes(Cow::Borrowed(self.value())),
+        );
+        self.advance();
+        entry
+    }
+}
+
+impl<K, V, M> BTreeMap<K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    /// Returns an iterator over the differences between this map and `other`, sorted by
+    /// key. The events describe how to turn this map into `other`: a key that is only in
+    /// `other` is [`Added`](DiffEvent::Added), a key that is only in this map is
+    /// [`Removed`](DiffEvent::Removed), and a key whose value differs is
+    /// [`Changed`](DiffEvent::Changed).
+    ///
+    /// Both trees are walked in a single pass. Values are compared in their encoded form,
+    /// and only decoded for the keys that differ.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::btreemap::DiffEvent;
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    ///
+    /// let mut a: BTreeMap<u64, u64, _> = BTreeMap::new(DefaultMemoryImpl::default());
+    /// let mut b: BTreeMap<u64, u64, _> = BTreeMap::new(DefaultMemoryImpl::default());
+    /// a.insert(1, 1);
+    /// a.insert(2, 2);
+    /// b.insert(2, 20);
+    /// b.insert(3, 3);
+    ///
+    /// assert_eq!(
+    ///     a.diff(&b).collect::<Vec<_>>(),
+    ///     vec![
+    ///         DiffEvent::Removed(1, 1),
+    ///         DiffEvent::Changed { key: 2, old: 2, new: 20 },
+    ///         DiffEvent::Added(3, 3),
+    ///     ]
+    /// );
+    /// ```
+    pub fn diff<'a>(&'a self, other: &'a Self) -> Diff<'a, K, V, M> {
+        Diff::new(
+            (self, self.root_addr),
+            (other, other.root_addr),
+            std::ptr::eq(self, other),
+        )
+    }
+
+    /// Returns an iterator over the changes made to the map since the given snapshot was
+    /// taken, sorted by key, as [`diff`](Self::diff) from the snapshot to the map.
+    ///
+    /// The map shares the nodes it didn't modify with the snapshot, so the subtrees that
+    /// both trees share are skipped without being read, and the cost of the diff depends
+    /// on the number of modified nodes rather than on the size of the map.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the snapshot isn't a snapshot of this map.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::btreemap::DiffEvent;
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    ///
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::new(DefaultMemoryImpl::default());
+    /// for i in 0..1_000 {
+    ///     map.insert(i, i);
+    /// }
+    ///
+    /// let snapshot = map.snapshot();
+    /// map.insert(500, 0);
+    /// map.remove(&7);
+    ///
+    /// assert_eq!(
+    ///     map.diff_since(&snapshot).collect::<Vec<_>>(),
+    ///     vec![
+    ///         DiffEvent::Removed(7, 7),
+    ///         DiffEvent::Changed { key: 500, old: 500, new: 0 },
+    ///     ]
+    /// );
+    /// map.release_snapshot(snapshot);
+    /// ```
+    pub fn diff_since<'a>(&'a self, snapshot: &Snapshot) -> Diff<'a, K, V, M> {
+        let old_root = self.view(snapshot).root_addr;
+        Diff::new((self, old_root), (self, self.root_addr), true)
+    }
+}
@@ -0,0 +1,275 @@
+use super::{
+    node::{Node, NodeType},
+    BTreeMap,
+};
+use crate::{types::NULL, Memory, Storable};
+use std::borrow::Cow;
+
+/// A view into a single entry in a [`BTreeMap`], which may either be vacant or occupied.
+///
+/// This `enum` is constructed from the [`entry`](BTreeMap::entry) method on [`BTreeMap`].
+pub enum Entry<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    /// A vacant entry.
+    Vacant(VacantEntry<'a, K, V, M>),
+    /// An occupied entry.
+    Occupied(OccupiedEntry<'a, K, V, M>),
+}
+
+/// A view into a vacant entry in a [`BTreeMap`]. It is part of the [`Entry`] enum.
+pub struct VacantEntry<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    pub(super) map: &'a mut BTreeMap<K, V, M>,
+    pub(super) key: K,
+    // The leaf where the key should be inserted, along with the index of the insertion point.
+    // `None` if the map is empty.
+    pub(super) leaf: Option<(Node<K>, usize)>,
+}
+
+/// A view into an occupied entry in a [`BTreeMap`]. It is part of the [`Entry`] enum.
+pub struct OccupiedEntry<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    pub(super) map: &'a mut BTreeMap<K, V, M>,
+    pub(super) key: K,
+    // The node containing the key, along with the index of the key in that node.
+    pub(super) node: Node<K>,
+    pub(super) idx: usize,
+}
+
+impl<K, V, M> Entry<'_, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    /// Returns a reference to this entry's key.
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Vacant(entry) => entry.key(),
+            Entry::Occupied(entry) => entry.key(),
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the default if empty,
+    /// and returns the value in the entry.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::init(DefaultMemoryImpl::default());
+    ///
+    /// assert_eq!(map.entry(1).or_insert(10), 10);
+    /// assert_eq!(map.entry(1).or_insert(20), 10);
+    /// ```
+    pub fn or_insert(self, default: V) -> V {
+        match self {
+            Entry::Vacant(entry) => entry.insert(default),
+            Entry::Occupied(entry) => entry.get(),
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the result of the default function
+    /// if empty, and returns the value in the entry.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> V {
+        match self {
+            Entry::Vacant(entry) => entry.insert(default()),
+            Entry::Occupied(entry) => entry.get(),
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the result of the default function
+    /// if empty, and returns the value in the entry.
+    ///
+    /// The default function is given a reference to the key that is being inserted.
+    pub fn or_insert_with_key<F: FnOnce(&K) -> V>(self, default: F) -> V {
+        match self {
+            Entry::Vacant(entry) => {
+                let value = default(entry.key());
+                entry.insert(value)
+            }
+            Entry::Occupied(entry) => entry.get(),
+        }
+    }
+
+    /// Provides in-place mutable access to an occupied entry before any
+    /// potential inserts into the map.
+    ///
+    /// The value is decoded, modified by `f`, and written back to the node it was found in.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::init(DefaultMemoryImpl::default());
+    ///
+    /// for _ in 0..3 {
+    ///     map.entry(1).and_modify(|v| *v += 1).or_insert(1);
+    /// }
+    /// assert_eq!(map.get(&1), Some(3));
+    /// ```
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(mut entry) => {
+                let mut value = entry.get();
+                f(&mut value);
+                entry.insert(value);
+                Entry::Occupied(entry)
+            }
+            Entry::Vacant(entry) => Entry::Vacant(entry),
+        }
+    }
+}
+
+impl<K, V, M> Entry<'_, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable + Default,
+    M: Memory,
+{
+    /// Ensures a value is in the entry by inserting the default value if empty,
+    /// and returns the value in the entry.
+    pub fn or_default(self) -> V {
+        self.or_insert_with(V::default)
+    }
+}
+
+impl<K, V, M> VacantEntry<'_, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    /// Returns a reference to the key that would be used when inserting a value
+    /// through this entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Takes ownership of the key.
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Sets the value of the entry with the `VacantEntry`'s key, and returns the value.
+    ///
+    /// If the leaf found during the lookup has room for another entry, the entry is
+    /// inserted directly into it without descending the tree again. Otherwise, the
+    /// insertion falls back to a regular insert, which splits full nodes on its way down.
+    /// Maps with order statistics always use a regular insert, as the subtree counts
+    /// along the path need to be updated.
+    pub fn insert(self, value: V) -> V {
+        let encoded_value = value.to_bytes_checked().into_owned();
+
+        match self.leaf {
+            Some((mut leaf, idx)) if !leaf.is_full() && !self.map.is_counted() => {
+                leaf.insert_entry(idx, (self.key, encoded_value));
+                self.map.save_node(&mut leaf);
+
+                // Update the length.
+                self.map.length += 1;
+                self.map.save_header();
+            }
+            _ => {
+                let prev = self.map.insert_encoded(self.key, encoded_value);
+                debug_assert!(prev.is_none(), "a vacant entry must not have a value");
+            }
+        }
+
+        value
+    }
+}
+
+impl<K, V, M> OccupiedEntry<'_, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    /// Returns a reference to the key in the entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Returns the value in the entry.
+    pub fn get(&self) -> V {
+        let encoded_value = self.node.value(self.idx, self.map.memory());
+        V::from_bytes(Cow::Borrowed(encoded_value))
+    }
+
+    /// Sets the value of the entry, and returns the entry's old value.
+    ///
+    /// The value is written to the node the key was found in, without descending the tree again.
+    pub fn insert(&mut self, value: V) -> V {
+        let encoded_value = value.into_bytes_checked();
+        let old_value = self
+            .map
+            .update_value(&mut self.node, self.idx, encoded_value);
+        V::from_bytes(Cow::Owned(old_value))
+    }
+
+    /// Takes the value out of the entry, and returns it.
+    ///
+    /// NOTE: removal may require rebalancing the tree, in which case this descends the tree
+    /// again from the root.
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    /// Takes the key and value out of the entry, and returns them.
+    ///
+    /// NOTE: removal may require rebalancing the tree, in which case this descends the tree
+    /// again from the root.
+    pub fn remove_entry(self) -> (K, V) {
+        let key = self.key.clone();
+        let value = V::from_bytes(Cow::Owned(self.remove_encoded()));
+        (key, value)
+    }
+
+    /// Takes the encoded value out of the entry, and returns it.
+    ///
+    /// If the key is in a leaf that stays at or above the minimum number of entries without
+    /// it, the key is removed directly from the leaf. Otherwise, the removal falls back to a
+    /// regular remove, which rebalances the nodes on its way down. Maps with order
+    /// statistics always use a regular remove, as the subtree counts along the path need to
+    /// be updated.
+    pub(super) fn remove_encoded(mut self) -> Vec<u8> {
+        let in_removable_leaf = self.node.node_type() == NodeType::Leaf
+            && (self.node.address() == self.map.root_addr
+                || self.node.can_remove_entry_without_merging());
+
+        if !in_removable_leaf || self.map.is_counted() {
+            let root = self.map.load_node(self.map.root_addr);
+            return self
+                .map
+                .remove_helper(root, &self.key)
+                .expect("an occupied entry must have a value");
+        }
+
+        let value = self.node.remove_entry(self.idx, self.map.memory()).1;
+        if self.node.entries_len() == 0 {
+            // Only the root can become empty.
+            self.map.deallocate_node(self.node);
+            self.map.root_addr = NULL;
+        } else {
+            self.map.save_node(&mut self.node);
+        }
+
+        // Update the length.
+        self.map.length -= 1;
+        self.map.save_header();
+        value
+    }
+}
@@ -0,0 +1,447 @@
+use super::{
+    node::{LayoutError, Node, NodeType, B, CAPACITY},
+    BTreeMap,
+};
+use crate::{
+    types::{Address, NULL},
+    Memory, Storable,
+};
+use std::collections::{BTreeMap as StdBTreeMap, BTreeSet};
+use std::fmt;
+
+/// An inconsistency found by [`BTreeMap::check_integrity`].
+///
+/// Addresses are offsets in the map's memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IntegrityError {
+    /// The address doesn't hold a valid node header.
+    InvalidNode { address: u64 },
+    /// The node's address isn't an allocated chunk of the allocator.
+    UnallocatedNode { address: u64 },
+    /// The node is referenced more than once in the tree.
+    DuplicateNode { address: u64 },
+    /// An overflow page of the node is invalid, or its overflow pages form a cycle.
+    InvalidOverflowPage { node: u64, page: u64 },
+    /// An overflow page of the node isn't an allocated chunk of the allocator.
+    UnallocatedOverflowPage { node: u64, page: u64 },
+    /// The key at `index` is not greater than the key before it, or is outside of the
+    /// range its parent assigns to the node.
+    KeysOutOfOrder { node: u64, index: usize },
+    /// The node has fewer entries than the minimum (one for the root).
+    Underfull { node: u64, entries: usize },
+    /// The node has more entries than the maximum.
+    Overfull { node: u64, entries: usize },
+    /// The number of children of the node doesn't match its number of entries.
+    ChildrenMismatch {
+        node: u64,
+        entries: usize,
+        children: usize,
+    },
+    /// The leaf isn't at the same depth as the other leaves.
+    UnevenDepth {
+        node: u64,
+        depth: usize,
+        expected: usize,
+    },
+    /// The subtree count stored for a child doesn't match its number of entries.
+    SubtreeCountMismatch {
+        node: u64,
+        child: usize,
+        stored: u64,
+        actual: u64,
+    },
+    /// The length stored in the map's header doesn't match the number of entries.
+    LengthMismatch { stored: u64, actual: u64 },
+    /// The number of allocated chunks doesn't match the number of nodes and overflow
+    /// pages in the tree, e.g. because nodes were leaked.
+    AllocatedChunksMismatch { allocated: u64, reachable: u64 },
+    /// The list of free chunks, or of chunks reclaimed by a compaction, has an invalid or
+    /// allocated chunk, or a cycle.
+    InvalidFreeList { chunk: u64 },
+    /// Some of the allocator's chunks are neither allocated nor free, e.g. because a
+    /// deallocated chunk was dropped from the free list.
+    UnaccountedChunks {
+        chunks: u64,
+        allocated: u64,
+        free: u64,
+    },
+    /// The number of references the allocator stores for the node doesn't match the
+    /// number of nodes and snapshots that reference it.
+    ReferenceCountMismatch { node: u64, stored: u64, actual: u64 },
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidNode { address } => write!(f, "Invalid node at address {address}"),
+            Self::UnallocatedNode { address } => {
+                write!(f, "Node at address {address} is not allocated")
+            }
+            Self::DuplicateNode { address } => {
+                write!(f, "Node at address {address} is referenced more than once")
+            }
+            Self::InvalidOverflowPage { node, page } => {
+                write!(f, "Invalid overflow page {page} of node {node}")
+            }
+            Self::UnallocatedOverflowPage { node, page } => {
+                write!(f, "Overflow page {page} of node {node} is not allocated")
+            }
+            Self::KeysOutOfOrder { node, index } => {
+                write!(f, "Key {index} of node {node} is out of order")
+            }
+            Self::Underfull { node, entries } => {
+                write!(f, "Node {node} is underfull with {entries} entries")
+            }
+            Self::Overfull { node, entries } => {
+                write!(f, "Node {node} is overfull with {entries} entries")
+            }
+            Self::ChildrenMismatch {
+                node,
+                entries,
+                children,
+            } => write!(
+                f,
+                "Node {node} has {children} children for {entries} entries"
+            ),
+            Self::UnevenDepth {
+                node,
+                depth,
+                expected,
+            } => write!(
+                f,
+                "Leaf {node} is at depth {depth}, but other leaves are at depth {expected}"
+            ),
+            Self::SubtreeCountMismatch {
+                node,
+                child,
+                stored,
+                actual,
+            } => write!(
+                f,
+                "Child {child} of node {node} has a stored count of {stored}, but {actual} entries"
+            ),
+            Self::LengthMismatch { stored, actual } => write!(
+                f,
+                "The map's length is {stored}, but it has {actual} entries"
+            ),
+            Self::AllocatedChunksMismatch {
+                allocated,
+                reachable,
+            } => write!(
+                f,
+                "{allocated} chunks are allocated, but {reachable} are used by the tree"
+            ),
+            Self::InvalidFreeList { chunk } => {
+                write!(f, "Invalid chunk {chunk} in the list of free chunks")
+            }
+            Self::UnaccountedChunks {
+                chunks,
+                allocated,
+                free,
+            } => write!(
+                f,
+                "The allocator has {chunks} chunks, but {allocated} are allocated and {free} are free"
+            ),
+            Self::ReferenceCountMismatch {
+                node,
+                stored,
+                actual,
+            } => write!(
+                f,
+                "Node {node} has a reference count of {stored}, but {actual} references"
+            ),
+        }
+    }
+}
+
+/// The state of a walk over the trees of the map and of its snapshots.
+struct Walk<K> {
+    errors: Vec<IntegrityError>,
+    // The root of the tree being walked.
+    root: Address,
+    // The addresses of the nodes and overflow pages of the tree being walked.
+    visited: BTreeSet<u64>,
+    // The depth of the first leaf seen in the tree being walked.
+    leaf_depth: Option<usize>,
+    // The addresses of the nodes and overflow pages of all the trees walked.
+    reachable: BTreeSet<u64>,
+    // For each node, the addresses of the nodes referencing it, or the address of the
+    // snapshot record referencing it if it's the root of a snapshot, or NULL if it's the
+    // root of the map.
+    references: StdBTreeMap<u64, BTreeSet<u64>>,
+    _key: std::marker::PhantomData<K>,
+}
+
+impl<K, V, M> BTreeMap<K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    /// Walks the whole tree and checks that its structure is consistent, returning all the
+    /// inconsistencies found.
+    ///
+    /// The checks cover the ordering of the keys across nodes, the occupancy of the
+    /// nodes, the number of children of the nodes, the depth of the leaves, the subtree
+    /// counts of maps that support order statistics, the length stored in the header, the
+    /// overflow pages of the nodes, and the allocator's bookkeeping: every node and
+    /// overflow page must be allocated, the free list must be valid, and no chunk may be
+    /// leaked.
+    ///
+    /// The trees of the map's [snapshots](Self::snapshot) are checked as well, along with
+    /// the reference counts of the nodes they share with the map.
+    ///
+    /// The layout of every node is validated before the node is decoded, so that corrupted
+    /// nodes are reported rather than causing a panic. Keys that are corrupted in a way
+    /// that `K::from_bytes` can't decode can still panic.
+    ///
+    /// This reads every node of the tree, so its cost is linear in the size of the map, and
+    /// of each of its snapshots.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::init(DefaultMemoryImpl::default());
+    ///
+    /// for i in 0..100 {
+    ///     map.insert(i, i);
+    /// }
+    ///
+    /// assert_eq!(map.check_integrity(), Ok(()));
+    /// ```
+    pub fn check_integrity(&self) -> Result<(), Vec<IntegrityError>> {
+        let mut walk = Walk {
+            errors: vec![],
+            root: NULL,
+            visited: BTreeSet::new(),
+            leaf_depth: None,
+            reachable: BTreeSet::new(),
+            references: StdBTreeMap::new(),
+            _key: std::marker::PhantomData,
+        };
+
+        self.check_tree(&mut walk, self.root_addr, NULL, self.length);
+        for (record, root_addr, length) in self.snapshot_records() {
+            walk.reachable.insert(record.get());
+            self.check_tree(&mut walk, root_addr, record, length);
+        }
+
+        for (node, referrers) in &walk.references {
+            let address = Address::from(*node);
+            if !self.allocator.is_allocated(address) {
+                // Already reported by the walk.
+                continue;
+            }
+            let stored = self.allocator.references(address);
+            if stored != referrers.len() as u64 {
+                walk.errors.push(IntegrityError::ReferenceCountMismatch {
+                    node: *node,
+                    stored,
+                    actual: referrers.len() as u64,
+                });
+            }
+        }
+
+        let reachable = walk.reachable.len() as u64;
+        if reachable != self.allocator.num_allocated_chunks() {
+            walk.errors.push(IntegrityError::AllocatedChunksMismatch {
+                allocated: self.allocator.num_allocated_chunks(),
+                reachable,
+            });
+        }
+
+        let free = self.allocator.check_free_list().map_err(|chunk| {
+            walk.errors
+                .push(IntegrityError::InvalidFreeList { chunk: chunk.get() });
+        });
+        let reclaimed = self.allocator.check_reclaimed_list().map_err(|chunk| {
+            walk.errors
+                .push(IntegrityError::InvalidFreeList { chunk: chunk.get() });
+        });
+        if let (Ok(free), Ok(reclaimed), Some((chunks, allocated))) =
+            (free, reclaimed, self.allocator.count_chunks())
+        {
+            // The free list ends with the chunk past the last chunk, which isn't counted.
+            let free = free - 1 + reclaimed;
+            if allocated + free != chunks {
+                walk.errors.push(IntegrityError::UnaccountedChunks {
+                    chunks,
+                    allocated,
+                    free,
+                });
+            }
+        }
+
+        if walk.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(walk.errors)
+        }
+    }
+
+    /// Checks the tree with the given root, which is referenced by `referrer` and must
+    /// have `length` entries.
+    fn check_tree(&self, walk: &mut Walk<K>, root: Address, referrer: Address, length: u64) {
+        walk.root = root;
+        walk.visited.clear();
+        walk.leaf_depth = None;
+
+        let actual = if root == NULL {
+            0
+        } else {
+            self.check_subtree(walk, root, referrer, 0, None, None)
+                .unwrap_or(0)
+        };
+
+        if actual != length {
+            walk.errors.push(IntegrityError::LengthMismatch {
+                stored: length,
+                actual,
+            });
+        }
+
+        walk.reachable.extend(walk.visited.iter());
+    }
+
+    /// Checks the subtree at the given address, whose keys must be strictly between
+    /// `lower` and `upper`. Returns the number of entries in the subtree, or `None` if it
+    /// couldn't be counted.
+    fn check_subtree(
+        &self,
+        walk: &mut Walk<K>,
+        address: Address,
+        referrer: Address,
+        depth: usize,
+        lower: Option<&K>,
+        upper: Option<&K>,
+    ) -> Option<u64> {
+        let node_addr = address.get();
+        if !walk.visited.insert(node_addr) {
+            walk.errors
+                .push(IntegrityError::DuplicateNode { address: node_addr });
+            return None;
+        }
+        walk.references
+            .entry(node_addr)
+            .or_default()
+            .insert(referrer.get());
+
+        if !self.allocator.is_allocated(address) {
+            walk.errors
+                .push(IntegrityError::UnallocatedNode { address: node_addr });
+        }
+
+        match Node::<K>::check_layout(address, self.memory()) {
+            Ok(overflows) => {
+                for page in overflows {
+                    if !walk.visited.insert(page.get()) || !self.allocator.is_allocated(page) {
+                        walk.errors.push(IntegrityError::UnallocatedOverflowPage {
+                            node: node_addr,
+                            page: page.get(),
+                        });
+                    }
+                }
+            }
+            Err(LayoutError::InvalidHeader) => {
+                walk.errors
+                    .push(IntegrityError::InvalidNode { address: node_addr });
+                return None;
+            }
+            Err(LayoutError::InvalidOverflow(page)) => {
+                walk.errors.push(IntegrityError::InvalidOverflowPage {
+                    node: node_addr,
+                    page: page.get(),
+                });
+                return None;
+            }
+        }
+
+        let node = Node::<K>::load(address, self.version.page_size(), self.memory());
+        let entries = node.entries_len();
+
+        // Check the ordering of the keys.
+        for i in 0..entries {
+            let key = node.key(i, self.memory());
+            let after_previous = match i {
+                0 => lower.is_none_or(|lower| lower < key),
+                _ => node.key(i - 1, self.memory()) < key,
+            };
+            let before_upper = i + 1 < entries || upper.is_none_or(|upper| key < upper);
+            if !after_previous || !before_upper {
+                walk.errors.push(IntegrityError::KeysOutOfOrder {
+                    node: node_addr,
+                    index: i,
+                });
+            }
+        }
+
+        // Check the occupancy of the node.
+        let min_entries = if address == walk.root { 1 } else { B - 1 };
+        if entries < min_entries {
+            walk.errors.push(IntegrityError::Underfull {
+                node: node_addr,
+                entries,
+            });
+        }
+        if entries > CAPACITY {
+            walk.errors.push(IntegrityError::Overfull {
+                node: node_addr,
+                entries,
+            });
+        }
+
+        match node.node_type() {
+            NodeType::Leaf => {
+                let expected = *walk.leaf_depth.get_or_insert(depth);
+                if depth != expected {
+                    walk.errors.push(IntegrityError::UnevenDepth {
+                        node: node_addr,
+                        depth,
+                        expected,
+                    });
+                }
+                Some(entries as u64)
+            }
+            NodeType::Internal => {
+                if node.children_len() != entries + 1 {
+                    walk.errors.push(IntegrityError::ChildrenMismatch {
+                        node: node_addr,
+                        entries,
+                        children: node.children_len(),
+                    });
+                    return None;
+                }
+
+                let mut total = Some(entries as u64);
+                for i in 0..node.children_len() {
+                    let lower = if i == 0 {
+                        lower
+                    } else {
+                        Some(node.key(i - 1, self.memory()))
+                    };
+                    let upper = if i == entries {
+                        upper
+                    } else {
+                        Some(node.key(i, self.memory()))
+                    };
+
+                    let count =
+                        self.check_subtree(walk, node.child(i), address, depth + 1, lower, upper);
+                    if let Some(count) = count {
+                        if self.is_counted() && node.child_count(i) != count {
+                            walk.errors.push(IntegrityError::SubtreeCountMismatch {
+                                node: node_addr,
+                                child: i,
+                                stored: node.child_count(i),
+                                actual: count,
+                            });
+                        }
+                    }
+                    total = total.zip(count).map(|(total, count)| total + count);
+                }
+                total
+            }
+        }
+    }
+}
@@ -1,14 +1,16 @@
 use super::{
+    entry::OccupiedEntry,
     node::{Node, NodeType},
     BTreeMap,
 };
-use crate::{types::NULL, Address, Memory, Storable};
+use crate::{storable::Bound as StorableBound, types::NULL, Address, Memory, Storable};
 use std::borrow::Cow;
+use std::fmt;
 use std::ops::{Bound, RangeBounds};
 use std::rc::Rc;
 
 /// An indicator of the current position in the map.
-pub(crate) enum Cursor<K: Storable + Ord + Clone> {
+pub(crate) enum Position<K: Storable + Ord + Clone> {
     Address(Address),
     Node { node: Rc<Node<K>>, next: Index },
 }
@@ -19,6 +21,18 @@ pub(crate) enum Index {
     Entry(usize),
 }
 
+/// The state of an [`IterInternal`] without its reference to the map, so that an iteration
+/// can be kept while the map is borrowed mutably.
+pub(crate) struct IterState<K: Storable + Ord + Clone> {
+    snapshot: Address,
+    root_addr: Address,
+    forward_cursors_initialized: bool,
+    backward_cursors_initialized: bool,
+    forward_cursors: Vec<Position<K>>,
+    backward_cursors: Vec<Position<K>>,
+    range: (Bound<K>, Bound<K>),
+}
+
 /// An iterator over the entries of a [`BTreeMap`].
 #[must_use = "iterators are lazy and do nothing unless consumed"]
 pub(crate) struct IterInternal<'a, K, V, M>
@@ -30,6 +44,14 @@ where
     // A reference to the map being iterated on.
     map: &'a BTreeMap<K, V, M>,
 
+    // The address of the record of the snapshot being iterated on, or `NULL` if the map
+    // itself is iterated on.
+    snapshot: Address,
+
+    // The root of the tree being iterated on, which is the map's root unless a snapshot of
+    // the map is iterated on.
+    root_addr: Address,
+
     // Flags indicating if the cursors have been initialized yet. These are needed to distinguish
     // between the case where the iteration hasn't started yet and the case where the iteration has
     // finished (in both cases the cursors will be empty).
@@ -37,8 +59,8 @@ where
     backward_cursors_initialized: bool,
 
     // Stacks of cursors indicating the current iteration positions in the tree.
-    forward_cursors: Vec<Cursor<K>>,
-    backward_cursors: Vec<Cursor<K>>,
+    forward_cursors: Vec<Position<K>>,
+    backward_cursors: Vec<Position<K>>,
 
     // The range of keys we want to traverse.
     range: (Bound<K>, Bound<K>),
@@ -53,6 +75,8 @@ where
     pub(crate) fn new(map: &'a BTreeMap<K, V, M>) -> Self {
         Self {
             map,
+            snapshot: NULL,
+            root_addr: map.root_addr,
             forward_cursors_initialized: false,
             backward_cursors_initialized: false,
             forward_cursors: vec![],
@@ -65,6 +89,8 @@ where
     pub(crate) fn null(map: &'a BTreeMap<K, V, M>) -> Self {
         Self {
             map,
+            snapshot: NULL,
+            root_addr: map.root_addr,
             forward_cursors_initialized: true,
             backward_cursors_initialized: true,
             forward_cursors: vec![],
@@ -76,6 +102,8 @@ where
     pub(crate) fn new_in_range(map: &'a BTreeMap<K, V, M>, range: (Bound<K>, Bound<K>)) -> Self {
         Self {
             map,
+            snapshot: NULL,
+            root_addr: map.root_addr,
             forward_cursors_initialized: false,
             backward_cursors_initialized: false,
             forward_cursors: vec![],
@@ -84,23 +112,125 @@ where
         }
     }
 
+    /// Returns an iterator from the first key that doesn't satisfy `pred` up to `end`,
+    /// assuming that the keys satisfying `pred` come first.
+    ///
+    /// The first key is found in the same descent that initializes the forward cursors, so
+    /// the iteration doesn't search the tree for it again.
+    pub(crate) fn new_from_partition_point(
+        map: &'a BTreeMap<K, V, M>,
+        pred: impl Fn(&K) -> bool,
+        end: Bound<K>,
+    ) -> Self {
+        if map.root_addr == NULL {
+            return Self::null(map);
+        }
+
+        let mut forward_cursors = vec![];
+        let mut node = map.load_node(map.root_addr);
+        loop {
+            // As when initializing the cursors from a key, the entry at `idx` is visited
+            // after the subtree of the child at `idx`.
+            let idx = node.partition_point(map.memory(), &pred);
+            let child = match node.node_type() {
+                NodeType::Internal => Some(map.load_node(node.child(idx))),
+                NodeType::Leaf => None,
+            };
+            if idx < node.entries_len() {
+                forward_cursors.push(Position::Node {
+                    node: Rc::new(node),
+                    next: Index::Entry(idx),
+                });
+            }
+            match child {
+                Some(child) => node = child,
+                None => break,
+            }
+        }
+
+        // The first key is the one the cursor on top of the stack is at.
+        let first = match forward_cursors.last() {
+            Some(Position::Node {
+                node,
+                next: Index::Entry(idx),
+            }) => node.key(*idx, map.memory()).clone(),
+            _ => return Self::null(map),
+        };
+        Self {
+            map,
+            snapshot: NULL,
+            root_addr: map.root_addr,
+            forward_cursors_initialized: true,
+            backward_cursors_initialized: false,
+            forward_cursors,
+            backward_cursors: vec![],
+            range: (Bound::Included(first), end),
+        }
+    }
+
+    /// Returns an iterator over the given range of the tree with the given root, which is
+    /// the root of the map's snapshot whose record is at the given address.
+    pub(crate) fn new_in_snapshot(
+        map: &'a BTreeMap<K, V, M>,
+        snapshot: Address,
+        root_addr: Address,
+        range: (Bound<K>, Bound<K>),
+    ) -> Self {
+        let mut iter = if root_addr == NULL {
+            Self::null(map)
+        } else {
+            Self::new_in_range(map, range)
+        };
+        iter.snapshot = snapshot;
+        iter.root_addr = root_addr;
+        iter
+    }
+
+    /// Returns the state of the iteration, which can be resumed with `from_state`.
+    pub(crate) fn into_state(self) -> IterState<K> {
+        IterState {
+            snapshot: self.snapshot,
+            root_addr: self.root_addr,
+            forward_cursors_initialized: self.forward_cursors_initialized,
+            backward_cursors_initialized: self.backward_cursors_initialized,
+            forward_cursors: self.forward_cursors,
+            backward_cursors: self.backward_cursors,
+            range: self.range,
+        }
+    }
+
+    /// Resumes an iteration over the map from the given state.
+    ///
+    /// PRECONDITION: the map wasn't modified since the state was saved.
+    pub(crate) fn from_state(map: &'a BTreeMap<K, V, M>, state: IterState<K>) -> Self {
+        Self {
+            map,
+            snapshot: state.snapshot,
+            root_addr: state.root_addr,
+            forward_cursors_initialized: state.forward_cursors_initialized,
+            backward_cursors_initialized: state.backward_cursors_initialized,
+            forward_cursors: state.forward_cursors,
+            backward_cursors: state.backward_cursors,
+            range: state.range,
+        }
+    }
+
     fn initialize_forward_cursors(&mut self) {
         debug_assert!(!self.forward_cursors_initialized);
 
         match self.range.start_bound() {
             Bound::Unbounded => {
-                self.forward_cursors
-                    .push(Cursor::Address(self.map.root_addr));
+                self.forward_cursors.push(Position::Address(self.root_addr));
             }
             Bound::Included(key) | Bound::Excluded(key) => {
-                let mut node = self.map.load_node(self.map.root_addr);
+                let mut node = self.map.load_node(self.root_addr);
                 loop {
                     match node.search(key, self.map.memory()) {
                         Ok(idx) => {
                             if let Bound::Included(_) = self.range.start_bound() {
                                 // We found the key exactly matching the left bound.
                                 // Here is where we'll start the iteration.
-                                self.forward_cursors.push(Cursor::Node {
+                                self.forward_cursors.push(Position::Node {
                                     node: Rc::new(node),
                                     next: Index::Entry(idx),
                                 });
@@ -118,13 +248,13 @@ where
                                 if idx + 1 < node.entries_len()
                                     && self.range.contains(node.key(idx + 1, self.map.memory()))
                                 {
-                                    self.forward_cursors.push(Cursor::Node {
+                                    self.forward_cursors.push(Position::Node {
                                         node: Rc::new(node),
                                         next: Index::Entry(idx + 1),
                                     });
                                 }
                                 if let Some(right_child) = right_child {
-                                    self.forward_cursors.push(Cursor::Address(right_child));
+                                    self.forward_cursors.push(Position::Address(right_child));
                                 }
                                 break;
                             }
@@ -156,7 +286,7 @@ where
                             if idx < node.entries_len()
                                 && self.range.contains(node.key(idx, self.map.memory()))
                             {
-                                self.forward_cursors.push(Cursor::Node {
+                                self.forward_cursors.push(Position::Node {
                                     node: Rc::new(node),
                                     next: Index::Entry(idx),
                                 });
@@ -186,17 +316,17 @@ where
         match self.range.end_bound() {
             Bound::Unbounded => {
                 self.backward_cursors
-                    .push(Cursor::Address(self.map.root_addr));
+                    .push(Position::Address(self.root_addr));
             }
             Bound::Included(key) | Bound::Excluded(key) => {
-                let mut node = self.map.load_node(self.map.root_addr);
+                let mut node = self.map.load_node(self.root_addr);
                 loop {
                     match node.search(key, self.map.memory()) {
                         Ok(idx) => {
                             if let Bound::Included(_) = self.range.end_bound() {
                                 // We found the key exactly matching the right bound.
                                 // Here is where we'll start the iteration.
-                                self.backward_cursors.push(Cursor::Node {
+                                self.backward_cursors.push(Position::Node {
                                     node: Rc::new(node),
                                     next: Index::Entry(idx),
                                 });
@@ -214,13 +344,13 @@ where
                                 if idx > 0
                                     && self.range.contains(node.key(idx - 1, self.map.memory()))
                                 {
-                                    self.backward_cursors.push(Cursor::Node {
+                                    self.backward_cursors.push(Position::Node {
                                         node: Rc::new(node),
                                         next: Index::Entry(idx - 1),
                                     });
                                 }
                                 if let Some(left_child) = left_child {
-                                    self.backward_cursors.push(Cursor::Address(left_child));
+                                    self.backward_cursors.push(Position::Address(left_child));
                                 }
                                 break;
                             }
@@ -250,7 +380,7 @@ where
 
                             if idx > 0 && self.range.contains(node.key(idx - 1, self.map.memory()))
                             {
-                                self.backward_cursors.push(Cursor::Node {
+                                self.backward_cursors.push(Position::Node {
                                     node: Rc::new(node),
                                     next: Index::Entry(idx - 1),
                                 });
@@ -283,11 +413,11 @@ where
 
         // If the cursors are empty. Iteration is complete.
         match self.forward_cursors.pop()? {
-            Cursor::Address(address) => {
+            Position::Address(address) => {
                 if address != NULL {
                     // Load the node at the given address, and add it to the cursors.
                     let node = self.map.load_node(address);
-                    self.forward_cursors.push(Cursor::Node {
+                    self.forward_cursors.push(Position::Node {
                         next: match node.node_type() {
                             // Iterate on internal nodes starting from the first child.
                             NodeType::Internal => Index::Child(0),
@@ -300,7 +430,7 @@ where
                 self.next_map(map)
             }
 
-            Cursor::Node {
+            Position::Node {
                 node,
                 next: Index::Child(child_idx),
             } => {
@@ -309,19 +439,19 @@ where
                 if child_idx < node.entries_len() {
                     // After iterating on the child, iterate on the next _entry_ in this node.
                     // The entry immediately after the child has the same index as the child's.
-                    self.forward_cursors.push(Cursor::Node {
+                    self.forward_cursors.push(Position::Node {
                         node,
                         next: Index::Entry(child_idx),
                     });
                 }
 
                 // Add the child to the top of the cursors to be iterated on first.
-                self.forward_cursors.push(Cursor::Address(child_address));
+                self.forward_cursors.push(Position::Address(child_address));
 
                 self.next_map(map)
             }
 
-            Cursor::Node {
+            Position::Node {
                 node,
                 next: Index::Entry(entry_idx),
             } => {
@@ -348,7 +478,7 @@ where
                 };
 
                 // Add to the cursors the next element to be traversed.
-                self.forward_cursors.push(Cursor::Node { next, node });
+                self.forward_cursors.push(Position::Node { next, node });
                 Some(res)
             }
         }
@@ -363,7 +493,7 @@ where
 
         // If the cursors are empty. Iteration is complete.
         match self.backward_cursors.pop()? {
-            Cursor::Address(address) => {
+            Position::Address(address) => {
                 if address != NULL {
                     // Load the node at the given address, and add it to the cursors.
                     let node = self.map.load_node(address);
@@ -378,7 +508,7 @@ where
                         }
                         _ => None,
                     } {
-                        self.backward_cursors.push(Cursor::Node {
+                        self.backward_cursors.push(Position::Node {
                             next,
                             node: Rc::new(node),
                         });
@@ -387,7 +517,7 @@ where
                 self.next_back_map(map)
             }
 
-            Cursor::Node {
+            Position::Node {
                 node,
                 next: Index::Child(child_idx),
             } => {
@@ -395,19 +525,19 @@ where
 
                 if 0 < child_idx {
                     // After iterating on the child, iterate on the previous _entry_ in this node.
-                    self.backward_cursors.push(Cursor::Node {
+                    self.backward_cursors.push(Position::Node {
                         node,
                         next: Index::Entry(child_idx - 1),
                     });
                 }
 
                 // Add the child to the top of the cursors to be iterated on first.
-                self.backward_cursors.push(Cursor::Address(child_address));
+                self.backward_cursors.push(Position::Address(child_address));
 
                 self.next_back_map(map)
             }
 
-            Cursor::Node {
+            Position::Node {
                 node,
                 next: Index::Entry(entry_idx),
             } => {
@@ -430,7 +560,7 @@ where
                     _ => None,
                 } {
                     // Add to the cursors the next element to be traversed.
-                    self.backward_cursors.push(Cursor::Node { next, node });
+                    self.backward_cursors.push(Position::Node { next, node });
                 }
 
                 Some(res)
@@ -439,12 +569,58 @@ where
     }
 
     fn count(&mut self) -> usize {
+        if self.map.is_counted()
+            && !self.forward_cursors_initialized
+            && !self.backward_cursors_initialized
+        {
+            // The iteration hasn't started, so the entries in the range can be counted
+            // using the subtree counts, without visiting them.
+            return self.count_range() as usize;
+        }
+
         let mut cnt = 0;
         while self.next_map(|_, _| ()).is_some() {
             cnt += 1;
         }
         cnt
     }
+
+    /// Returns the number of entries in the range in `O(log n)`.
+    ///
+    /// PRECONDITION: the map is counted.
+    fn count_range(&self) -> u64 {
+        let start = match self.range.start_bound() {
+            Bound::Unbounded => 0,
+            Bound::Included(key) => self.map.rank_helper(self.root_addr, key).0,
+            Bound::Excluded(key) => {
+                let (rank, found) = self.map.rank_helper(self.root_addr, key);
+                rank + found as u64
+            }
+        };
+
+        let end = match self.range.end_bound() {
+            // The tree may be a snapshot's, whose length differs from the map's.
+            Bound::Unbounded if self.root_addr == NULL => 0,
+            Bound::Unbounded => self.map.load_node(self.root_addr).subtree_len(),
+            Bound::Included(key) => {
+                let (rank, found) = self.map.rank_helper(self.root_addr, key);
+                rank + found as u64
+            }
+            Bound::Excluded(key) => self.map.rank_helper(self.root_addr, key).0,
+        };
+
+        end.saturating_sub(start)
+    }
+
+    /// Returns the next entry with its value still encoded.
+    pub(crate) fn next_encoded(&mut self) -> Option<(K, Vec<u8>)> {
+        self.next_map(|node, idx| {
+            (
+                node.key(idx, self.map.memory()).clone(),
+                node.value(idx, self.map.memory()).to_vec(),
+            )
+        })
+    }
 }
 
 /// A lazily evaluated key-value entry from a `BTreeMap` iterator.
@@ -530,6 +706,146 @@ where
     }
 }
 
+const TOKEN_MAGIC: &[u8; 3] = b"BTI";
+const TOKEN_LAYOUT_VERSION: u8 = 1;
+
+const UNBOUNDED: u8 = 0;
+const INCLUDED: u8 = 1;
+const EXCLUDED: u8 = 2;
+
+/// The reason why a token passed to [`BTreeMap::iter_from_token`] was rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InvalidToken {
+    /// The token isn't a token of an iterator.
+    Malformed,
+    /// The token is from a map that has since been cleared or recreated in its memory, or
+    /// from a snapshot that has since been released.
+    Stale,
+}
+
+impl fmt::Display for InvalidToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "Malformed iteration token"),
+            Self::Stale => write!(
+                f,
+                "Iteration token of a map that has been cleared or of a released snapshot"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InvalidToken {}
+
+impl<'a, K, V, M> Iter<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    /// Returns an opaque token of the entries that remain to be iterated on, in both
+    /// directions, which can be stored or sent to a client and given to
+    /// [`BTreeMap::iter_from_token`] to resume the iteration.
+    ///
+    /// The token holds the encodings of the keys the iteration has reached at each end,
+    /// along with the map's generation, which changes when the map is cleared. The token of
+    /// an iterator over a snapshot's [view](BTreeMap::view) also identifies the snapshot, and
+    /// resumes the iteration over it for as long as the snapshot isn't released.
+    pub fn token(&self) -> Vec<u8> {
+        let mut token = TOKEN_MAGIC.to_vec();
+        token.push(TOKEN_LAYOUT_VERSION);
+        token.extend_from_slice(&self.0.map.generation().to_le_bytes());
+        token.extend_from_slice(&self.0.snapshot.get().to_le_bytes());
+        token.extend_from_slice(&self.0.root_addr.get().to_le_bytes());
+        for bound in [&self.0.range.0, &self.0.range.1] {
+            match bound {
+                Bound::Unbounded => token.push(UNBOUNDED),
+                Bound::Included(key) | Bound::Excluded(key) => {
+                    let tag = match bound {
+                        Bound::Included(_) => INCLUDED,
+                        _ => EXCLUDED,
+                    };
+                    let key = key.to_bytes();
+                    token.push(tag);
+                    token.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                    token.extend_from_slice(&key);
+                }
+            }
+        }
+        token
+    }
+
+    /// Returns an iterator over the entries described by a token of [`Iter::token`].
+    pub(crate) fn from_token(
+        map: &'a BTreeMap<K, V, M>,
+        token: &[u8],
+    ) -> Result<Self, InvalidToken> {
+        if token.len() < 28 || &token[0..3] != TOKEN_MAGIC || token[3] != TOKEN_LAYOUT_VERSION {
+            return Err(InvalidToken::Malformed);
+        }
+        if u64::from_le_bytes(token[4..12].try_into().unwrap()) != map.generation() {
+            return Err(InvalidToken::Stale);
+        }
+        let snapshot = Address::from(u64::from_le_bytes(token[12..20].try_into().unwrap()));
+        let root_addr = Address::from(u64::from_le_bytes(token[20..28].try_into().unwrap()));
+        let view = if snapshot == NULL {
+            None
+        } else {
+            // The snapshot's record may have been reused by a snapshot of another tree.
+            let view = map
+                .snapshots()
+                .find(|s| s.address == snapshot)
+                .map(|s| map.view(&s))
+                .filter(|view| view.root_addr == root_addr)
+                .ok_or(InvalidToken::Stale)?;
+            Some(view)
+        };
+
+        let mut rest = &token[28..];
+        let mut next_bound = || -> Result<Bound<K>, InvalidToken> {
+            let (&tag, bytes) = rest.split_first().ok_or(InvalidToken::Malformed)?;
+            if tag == UNBOUNDED {
+                rest = bytes;
+                return Ok(Bound::Unbounded);
+            }
+            if bytes.len() < 4 {
+                return Err(InvalidToken::Malformed);
+            }
+            if tag != INCLUDED && tag != EXCLUDED {
+                return Err(InvalidToken::Malformed);
+            }
+            let len = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+            // Keys that couldn't have been encoded by `K` are rejected before decoding them.
+            if let StorableBound::Bounded {
+                max_size,
+                is_fixed_size,
+            } = K::BOUND
+            {
+                if len > max_size as usize || (is_fixed_size && len != max_size as usize) {
+                    return Err(InvalidToken::Malformed);
+                }
+            }
+            let end = 4usize.checked_add(len).ok_or(InvalidToken::Malformed)?;
+            let key = bytes.get(4..end).ok_or(InvalidToken::Malformed)?;
+            rest = &bytes[end..];
+            let key = K::from_bytes(Cow::Borrowed(key));
+            Ok(if tag == INCLUDED {
+                Bound::Included(key)
+            } else {
+                Bound::Excluded(key)
+            })
+        };
+        let range = (next_bound()?, next_bound()?);
+        if !rest.is_empty() {
+            return Err(InvalidToken::Malformed);
+        }
+        Ok(match view {
+            Some(view) => view.range(range),
+            None => map.range(range),
+        })
+    }
+}
+
 pub struct KeysIter<'a, K, V, M>(IterInternal<'a, K, V, M>)
 where
     K: Storable + Ord + Clone,
@@ -612,6 +928,186 @@ where
     }
 }
 
+/// An iterator that removes and yields the entries of a [`BTreeMap`] matching a predicate.
+///
+/// This `struct` is created by the [`extract_if`](BTreeMap::extract_if) method on [`BTreeMap`].
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+pub struct ExtractIf<'a, K, V, M, F>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+    F: FnMut(&K, &V) -> bool,
+{
+    map: &'a mut BTreeMap<K, V, M>,
+
+    // The nodes from the root down to the next entry to visit, each with an index. In the
+    // last node, the index is the one of the next entry. In the nodes above it, it's the
+    // index of the child the iteration is in. `None` if the iteration hasn't started yet,
+    // and empty once it's done.
+    path: Option<Vec<(Node<K>, usize)>>,
+
+    pred: F,
+}
+
+impl<'a, K, V, M, F> ExtractIf<'a, K, V, M, F>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+    F: FnMut(&K, &V) -> bool,
+{
+    pub(crate) fn new(map: &'a mut BTreeMap<K, V, M>, pred: F) -> Self {
+        Self {
+            map,
+            path: None,
+            pred,
+        }
+    }
+
+    /// Returns the path to the first entry after the given key, or to the first entry of
+    /// the map if `after` is `None`.
+    fn seek(&self, after: Option<&K>) -> Vec<(Node<K>, usize)> {
+        let mut path = vec![];
+        if self.map.root_addr == NULL {
+            return path;
+        }
+
+        let mut node = self.map.load_node(self.map.root_addr);
+        loop {
+            let idx = match after.map(|key| node.search(key, self.map.memory())) {
+                None => 0,
+                Some(Ok(idx)) => idx + 1,
+                Some(Err(idx)) => idx,
+            };
+            match node.node_type() {
+                NodeType::Leaf => {
+                    path.push((node, idx));
+                    break;
+                }
+                NodeType::Internal => {
+                    let child = self.map.load_node(node.child(idx));
+                    path.push((node, idx));
+                    node = child;
+                }
+            }
+        }
+        Self::skip_visited(&mut path);
+        path
+    }
+
+    /// Moves up the path from the nodes whose entries were all visited.
+    fn skip_visited(path: &mut Vec<(Node<K>, usize)>) {
+        while let Some((node, idx)) = path.last() {
+            if *idx < node.entries_len() {
+                break;
+            }
+            path.pop();
+        }
+    }
+
+    /// Moves the path past its current entry, down to the first entry of the next subtree
+    /// if the entry is in an internal node.
+    fn advance(&self, path: &mut Vec<(Node<K>, usize)>) {
+        let (node, idx) = path.last_mut().expect("the path must be at an entry");
+        *idx += 1;
+        if node.node_type() == NodeType::Internal {
+            let mut child = self.map.load_node(node.child(*idx));
+            while child.node_type() == NodeType::Internal {
+                let grandchild = self.map.load_node(child.child(0));
+                path.push((child, 0));
+                child = grandchild;
+            }
+            path.push((child, 0));
+        }
+        Self::skip_visited(path);
+    }
+
+    /// Returns true if the current entry can be removed from its leaf without rebalancing
+    /// the tree or copying nodes shared with snapshots.
+    fn can_remove_in_place(&self, path: &[(Node<K>, usize)]) -> bool {
+        let (node, _) = path.last().expect("the path must be at an entry");
+        node.node_type() == NodeType::Leaf
+            && !self.map.has_shared_nodes()
+            && if path.len() == 1 {
+                // The root can go below the minimum size, as long as it isn't emptied.
+                node.entries_len() > 1
+            } else {
+                node.can_remove_entry_without_merging()
+            }
+    }
+
+    /// Removes the current entry from its leaf, leaving the path at the next entry.
+    ///
+    /// PRECONDITION: `can_remove_in_place` holds.
+    fn remove_in_place(&mut self, path: &mut Vec<(Node<K>, usize)>) {
+        let leaf_idx = path.len() - 1;
+        let (ancestors, leaf) = path.split_at_mut(leaf_idx);
+        let (leaf, idx) = &mut leaf[0];
+        leaf.remove_entry(*idx, self.map.memory());
+        self.map.save_node(leaf);
+
+        if self.map.is_counted() {
+            for (node, child_idx) in ancestors {
+                node.set_child_count(*child_idx, node.child_count(*child_idx) - 1);
+                self.map.save_node(node);
+            }
+        }
+
+        self.map.length -= 1;
+        self.map.save_header();
+        Self::skip_visited(path);
+    }
+}
+
+impl<K, V, M, F> Iterator for ExtractIf<'_, K, V, M, F>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+    F: FnMut(&K, &V) -> bool,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut path = match self.path.take() {
+            Some(path) => path,
+            None => self.seek(None),
+        };
+
+        // Scan forward from where the previous call stopped until an entry matches.
+        let entry = loop {
+            let Some((node, idx)) = path.last() else {
+                break None;
+            };
+            let key = node.key(*idx, self.map.memory());
+            let value = V::from_bytes(Cow::Borrowed(node.value(*idx, self.map.memory())));
+            if (self.pred)(key, &value) {
+                break Some((key.clone(), value));
+            }
+            self.advance(&mut path);
+        };
+
+        if let Some((key, _)) = &entry {
+            if self.can_remove_in_place(&path) {
+                self.remove_in_place(&mut path);
+            } else {
+                // Removing the entry rebalances the nodes on its way down, so the path is
+                // searched again from the root.
+                self.map.unshare_path(key);
+                let root = self.map.load_node(self.map.root_addr);
+                self.map
+                    .remove_helper(root, key)
+                    .expect("the extracted key must exist in the map");
+                path = self.seek(Some(key));
+            }
+        }
+
+        self.path = Some(path);
+        entry
+    }
+}
+
 impl<'a, K, V, M> From<IterInternal<'a, K, V, M>> for Iter<'a, K, V, M>
 where
     K: Storable + Ord + Clone,
@@ -645,6 +1141,297 @@ where
     }
 }
 
+/// The position of a [`Cursor`] or a [`CursorMut`].
+struct CursorState<K: Storable + Ord + Clone> {
+    // The entry the cursor is at, with its value still encoded, or `None` if the cursor is
+    // at the ghost position, which is both before the first entry and after the last one.
+    current: Option<(K, Vec<u8>)>,
+
+    // The node the current entry is in, along with the entry's index, so that the entry
+    // can be modified without searching the tree again. `None` if the node may be outdated.
+    location: Option<(Rc<Node<K>>, usize)>,
+
+    // The iterations over the entries after and before the current one, which are kept
+    // so that moving the cursor in the same direction doesn't search the tree again.
+    // `None` if the iteration must start over from the current entry, e.g. because the
+    // map was modified.
+    forward: Option<IterState<K>>,
+    backward: Option<IterState<K>>,
+}
+
+impl<K: Storable + Ord + Clone> CursorState<K> {
+    fn new() -> Self {
+        Self {
+            current: None,
+            location: None,
+            forward: None,
+            backward: None,
+        }
+    }
+
+    fn key(&self) -> Option<&K> {
+        self.current.as_ref().map(|(key, _)| key)
+    }
+
+    fn peek<V: Storable>(&self) -> Option<(K, V)> {
+        self.current
+            .as_ref()
+            .map(|(key, value)| (key.clone(), V::from_bytes(Cow::Borrowed(value))))
+    }
+
+    // The bound of the iterations from the current entry.
+    fn bound(&self) -> Bound<K> {
+        match self.key() {
+            Some(key) => Bound::Excluded(key.clone()),
+            None => Bound::Unbounded,
+        }
+    }
+
+    fn seek<V: Storable, M: Memory>(&mut self, map: &BTreeMap<K, V, M>, key: &K) {
+        let iter =
+            IterInternal::new_in_range(map, (Bound::Included(key.clone()), Bound::Unbounded));
+        self.move_forward(iter);
+    }
+
+    fn next<V: Storable, M: Memory>(&mut self, map: &BTreeMap<K, V, M>) {
+        let iter = match self.forward.take() {
+            Some(state) => IterInternal::from_state(map, state),
+            None => IterInternal::new_in_range(map, (self.bound(), Bound::Unbounded)),
+        };
+        self.move_forward(iter);
+    }
+
+    fn prev<V: Storable, M: Memory>(&mut self, map: &BTreeMap<K, V, M>) {
+        let mut iter = match self.backward.take() {
+            Some(state) => IterInternal::from_state(map, state),
+            None => IterInternal::new_in_range(map, (Bound::Unbounded, self.bound())),
+        };
+        let map = iter.map;
+        (self.current, self.location) = iter
+            .next_back_map(|node, idx| Self::located_entry(map.memory(), node, idx))
+            .unzip();
+        self.backward = self.current.is_some().then(|| iter.into_state());
+        self.forward = None;
+    }
+
+    // Moves the cursor to the next entry of the iteration, or to the ghost position if the
+    // iteration is finished.
+    fn move_forward<V: Storable, M: Memory>(&mut self, mut iter: IterInternal<'_, K, V, M>) {
+        let map = iter.map;
+        (self.current, self.location) = iter
+            .next_map(|node, idx| Self::located_entry(map.memory(), node, idx))
+            .unzip();
+        self.forward = self.current.is_some().then(|| iter.into_state());
+        self.backward = None;
+    }
+
+    // Returns the entry at the given index of the node, with its value still encoded, along
+    // with its location.
+    #[allow(clippy::type_complexity)]
+    fn located_entry<M: Memory>(
+        memory: &M,
+        node: &Rc<Node<K>>,
+        idx: usize,
+    ) -> ((K, Vec<u8>), (Rc<Node<K>>, usize)) {
+        (
+            (
+                node.key(idx, memory).clone(),
+                node.value(idx, memory).to_vec(),
+            ),
+            (node.clone(), idx),
+        )
+    }
+
+    // Drops the iterations, which must be done when the map is modified.
+    fn invalidate(&mut self) {
+        self.forward = None;
+        self.backward = None;
+    }
+}
+
+/// A cursor over the entries of a [`BTreeMap`], created with [`BTreeMap::cursor`].
+///
+/// A cursor is at an entry of the map, or at the ghost position, which is both before the
+/// first entry and after the last one. Unlike an iterator, a cursor can move in both
+/// directions and be moved to any key. Moving a cursor to the next entry doesn't search the
+/// tree again, so scanning the map with a cursor is as cheap as with an iterator.
+pub struct Cursor<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    map: &'a BTreeMap<K, V, M>,
+    state: CursorState<K>,
+}
+
+impl<'a, K, V, M> Cursor<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    pub(crate) fn new(map: &'a BTreeMap<K, V, M>) -> Self {
+        Self {
+            map,
+            state: CursorState::new(),
+        }
+    }
+
+    /// Moves the cursor to the first entry whose key is greater than or equal to the given
+    /// key, or to the ghost position if there's no such entry.
+    pub fn seek(&mut self, key: &K) {
+        self.state.seek(self.map, key);
+    }
+
+    /// Moves the cursor to the next entry and returns it. If the cursor is at the last
+    /// entry, it moves to the ghost position and `None` is returned. If the cursor is at
+    /// the ghost position, it moves to the first entry.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<(K, V)> {
+        self.state.next(self.map);
+        self.peek()
+    }
+
+    /// Moves the cursor to the previous entry and returns it. If the cursor is at the first
+    /// entry, it moves to the ghost position and `None` is returned. If the cursor is at
+    /// the ghost position, it moves to the last entry.
+    pub fn prev(&mut self) -> Option<(K, V)> {
+        self.state.prev(self.map);
+        self.peek()
+    }
+
+    /// Returns the entry the cursor is at, or `None` if it's at the ghost position.
+    pub fn peek(&self) -> Option<(K, V)> {
+        self.state.peek()
+    }
+
+    /// Returns the key of the entry the cursor is at, or `None` if it's at the ghost
+    /// position.
+    pub fn key(&self) -> Option<&K> {
+        self.state.key()
+    }
+}
+
+/// A cursor over the entries of a [`BTreeMap`] that can modify the map, created with
+/// [`BTreeMap::cursor_mut`].
+///
+/// Like a [`Cursor`], it's at an entry of the map or at the ghost position. In addition, it
+/// can remove the entry it's at, or replace its value. After the map is modified, the next
+/// move of the cursor searches the tree again from the cursor's key.
+pub struct CursorMut<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    map: &'a mut BTreeMap<K, V, M>,
+    state: CursorState<K>,
+}
+
+impl<'a, K, V, M> CursorMut<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    pub(crate) fn new(map: &'a mut BTreeMap<K, V, M>) -> Self {
+        Self {
+            map,
+            state: CursorState::new(),
+        }
+    }
+
+    /// Moves the cursor to the first entry whose key is greater than or equal to the given
+    /// key, or to the ghost position if there's no such entry.
+    pub fn seek(&mut self, key: &K) {
+        self.state.seek(self.map, key);
+    }
+
+    /// Moves the cursor to the next entry and returns it. If the cursor is at the last
+    /// entry, it moves to the ghost position and `None` is returned. If the cursor is at
+    /// the ghost position, it moves to the first entry.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<(K, V)> {
+        self.state.next(self.map);
+        self.peek()
+    }
+
+    /// Moves the cursor to the previous entry and returns it. If the cursor is at the first
+    /// entry, it moves to the ghost position and `None` is returned. If the cursor is at
+    /// the ghost position, it moves to the last entry.
+    pub fn prev(&mut self) -> Option<(K, V)> {
+        self.state.prev(self.map);
+        self.peek()
+    }
+
+    /// Returns the entry the cursor is at, or `None` if it's at the ghost position.
+    pub fn peek(&self) -> Option<(K, V)> {
+        self.state.peek()
+    }
+
+    /// Returns the key of the entry the cursor is at, or `None` if it's at the ghost
+    /// position.
+    pub fn key(&self) -> Option<&K> {
+        self.state.key()
+    }
+
+    /// Removes the entry the cursor is at and returns it, moving the cursor to the next
+    /// entry. Returns `None` if the cursor is at the ghost position.
+    ///
+    /// As with [`OccupiedEntry::remove`], the entry is removed directly from its leaf if the
+    /// leaf doesn't need to be rebalanced.
+    pub fn remove_current(&mut self) -> Option<(K, V)> {
+        let (key, _) = self.state.current.take()?;
+        self.state.invalidate();
+        let value = match self.state.location.take() {
+            Some((node, idx)) if !self.map.has_shared_nodes() => OccupiedEntry {
+                map: &mut *self.map,
+                key: key.clone(),
+                node: Rc::unwrap_or_clone(node),
+                idx,
+            }
+            .remove(),
+            // The nodes shared with snapshots must be copied before they're modified.
+            _ => self
+                .map
+                .remove(&key)
+                .expect("the cursor's entry must exist in the map"),
+        };
+
+        let iter =
+            IterInternal::new_in_range(self.map, (Bound::Excluded(key.clone()), Bound::Unbounded));
+        self.state.move_forward(iter);
+        Some((key, value))
+    }
+
+    /// Replaces the value of the entry the cursor is at, returning the old value. Returns
+    /// `None`, leaving the map unchanged, if the cursor is at the ghost position.
+    ///
+    /// As with [`OccupiedEntry::insert`], the value is written to the node the entry is in,
+    /// without descending the tree again.
+    pub fn replace_value(&mut self, value: V) -> Option<V> {
+        let (key, encoded_value) = self.state.current.as_mut()?;
+        *encoded_value = value.into_bytes_checked();
+        let (key, encoded_value) = (key.clone(), encoded_value.clone());
+        self.state.invalidate();
+        let old_value = match self.state.location.take() {
+            Some((node, idx)) if !self.map.has_shared_nodes() => {
+                let mut node = Rc::unwrap_or_clone(node);
+                let old_value = self.map.update_value(&mut node, idx, encoded_value);
+                self.state.location = Some((Rc::new(node), idx));
+                old_value
+            }
+            // The nodes shared with snapshots must be copied before they're modified.
+            _ => self
+                .map
+                .insert_encoded(key, encoded_value)
+                .expect("the cursor's entry must exist in the map"),
+        };
+        Some(V::from_bytes(Cow::Owned(old_value)))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -905,4 +1692,137 @@ mod test {
         assert!(iter.next().is_none());
         assert!(iter.next_back().is_none());
     }
+
+    #[test]
+    fn cursor_moves_in_both_directions() {
+        let mut btree = BTreeMap::new(make_memory());
+        for i in 0..100u64 {
+            btree.insert(i * 2, i);
+        }
+
+        let mut cursor = btree.cursor();
+        assert_eq!(cursor.peek(), None);
+        assert_eq!(cursor.next(), Some((0, 0)));
+        assert_eq!(cursor.prev(), None);
+        assert_eq!(cursor.prev(), Some((198, 99)));
+        assert_eq!(cursor.next(), None);
+
+        cursor.seek(&51);
+        assert_eq!(cursor.key(), Some(&52));
+        for i in 27..100 {
+            assert_eq!(cursor.next(), Some((i * 2, i)));
+        }
+        assert_eq!(cursor.next(), None);
+
+        cursor.seek(&52);
+        for i in (0..26).rev() {
+            assert_eq!(cursor.prev(), Some((i * 2, i)));
+        }
+        assert_eq!(cursor.prev(), None);
+
+        cursor.seek(&199);
+        assert_eq!(cursor.peek(), None);
+    }
+
+    #[test]
+    fn cursor_mut_matches_std_btreemap() {
+        let mut btree = BTreeMap::new(make_memory());
+        let mut std_btree = std::collections::BTreeMap::new();
+        for i in 0..500u64 {
+            btree.insert(i, i);
+            std_btree.insert(i, i);
+        }
+
+        let mut cursor = btree.cursor_mut();
+        let mut current: Option<u64> = None;
+        let after = |key: Option<u64>| match key {
+            Some(key) => (Bound::Excluded(key), Bound::Unbounded),
+            None => (Bound::Unbounded, Bound::Unbounded),
+        };
+        for i in 0..5_000u64 {
+            match i * 7_919 % 11 {
+                0 => {
+                    cursor.seek(&(i % 520));
+                    current = std_btree.range(i % 520..).next().map(|(k, _)| *k);
+                }
+                1..=4 => {
+                    cursor.next();
+                    current = std_btree.range(after(current)).next().map(|(k, _)| *k);
+                }
+                5..=7 => {
+                    cursor.prev();
+                    let before = match current {
+                        Some(key) => (Bound::Unbounded, Bound::Excluded(key)),
+                        None => (Bound::Unbounded, Bound::Unbounded),
+                    };
+                    current = std_btree.range(before).next_back().map(|(k, _)| *k);
+                }
+                8 => {
+                    let removed = cursor.remove_current();
+                    assert_eq!(removed, current.map(|k| (k, std_btree.remove(&k).unwrap())));
+                    if removed.is_some() {
+                        current = std_btree.range(after(current)).next().map(|(k, _)| *k);
+                    }
+                }
+                _ => {
+                    let old = cursor.replace_value(i);
+                    assert_eq!(old, current.map(|k| std_btree.insert(k, i).unwrap()));
+                }
+            }
+            assert_eq!(cursor.peek(), current.map(|k| (k, std_btree[&k])));
+        }
+
+        assert!(btree.iter().map(|e| e.into_pair()).eq(std_btree));
+    }
+
+    #[test]
+    fn cursor_mut_keeps_counts_and_snapshots() {
+        for counted in [false, true] {
+            let mut btree = if counted {
+                BTreeMap::new_counted(make_memory())
+            } else {
+                BTreeMap::new(make_memory())
+            };
+            for i in 0..500u64 {
+                btree.insert(i, i);
+            }
+
+            let mut expected = std::collections::BTreeMap::new();
+            let mut cursor = btree.cursor_mut();
+            cursor.next();
+            while let Some(key) = cursor.key().copied() {
+                if key % 3 == 0 {
+                    assert_eq!(cursor.remove_current(), Some((key, key)));
+                } else {
+                    assert_eq!(cursor.replace_value(key + 1), Some(key));
+                    assert_eq!(cursor.replace_value(key + 2), Some(key + 1));
+                    expected.insert(key, key + 2);
+                    cursor.next();
+                }
+            }
+            assert!(btree.iter().map(|e| e.into_pair()).eq(expected.clone()));
+            assert_eq!(btree.check_integrity(), Ok(()));
+
+            // The entries of a snapshot are left unchanged.
+            let snapshot = btree.snapshot();
+            let mut cursor = btree.cursor_mut();
+            cursor.next();
+            while let Some(key) = cursor.key().copied() {
+                if key % 3 == 1 {
+                    cursor.remove_current();
+                } else {
+                    cursor.replace_value(0);
+                    cursor.next();
+                }
+            }
+            assert!(btree
+                .view(&snapshot)
+                .iter()
+                .map(|e| e.into_pair())
+                .eq(expected));
+            assert!(btree.iter().all(|e| *e.key() % 3 == 2 && e.value() == 0));
+            btree.release_snapshot(snapshot);
+            assert_eq!(btree.check_integrity(), Ok(()));
+        }
+    }
 }
@@ -0,0 +1,146 @@
+use super::{BTreeMap, Iter};
+use crate::{Memory, Storable};
+use std::cmp::Ordering;
+use std::iter::Peekable;
+
+/// The kind of join performed by [`BTreeMap::merge_join`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum JoinKind {
+    /// Only the keys that are in both maps.
+    Inner,
+    /// All the keys of the left map.
+    Left,
+    /// All the keys of either map.
+    Outer,
+}
+
+/// The values of a key in the maps of a [`BTreeMap::merge_join`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Joined<L, R> {
+    /// The key is only in the left map.
+    Left(L),
+    /// The key is only in the right map.
+    Right(R),
+    /// The key is in both maps.
+    Both(L, R),
+}
+
+/// An iterator over the join of two maps, sorted by key.
+///
+/// This `struct` is created by [`BTreeMap::merge_join`].
+pub struct MergeJoin<'a, K, V, M, V2, M2>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+    V2: Storable,
+    M2: Memory,
+{
+    left: Peekable<Iter<'a, K, V, M>>,
+    right: Peekable<Iter<'a, K, V2, M2>>,
+    kind: JoinKind,
+}
+
+impl<K, V, M, V2, M2> Iterator for MergeJoin<'_, K, V, M, V2, M2>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+    V2: Storable,
+    M2: Memory,
+{
+    type Item = (K, Joined<V, V2>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let ordering = match (self.left.peek(), self.right.peek()) {
+                (None, None) => return None,
+                // Only an outer join has keys after the end of the left map.
+                (None, Some(_)) if self.kind != JoinKind::Outer => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(left), Some(right)) => left.key().cmp(right.key()),
+            };
+
+            // Values are only decoded for the keys that are part of the join.
+            match ordering {
+                Ordering::Less => {
+                    let left = self.left.next().unwrap();
+                    if self.kind != JoinKind::Inner {
+                        let (key, value) = left.into_pair();
+                        return Some((key, Joined::Left(value)));
+                    }
+                }
+                Ordering::Greater => {
+                    let right = self.right.next().unwrap();
+                    if self.kind == JoinKind::Outer {
+                        let (key, value) = right.into_pair();
+                        return Some((key, Joined::Right(value)));
+                    }
+                }
+                Ordering::Equal => {
+                    let (key, left) = self.left.next().unwrap().into_pair();
+                    let right = self.right.next().unwrap().value();
+                    return Some((key, Joined::Both(left, right)));
+                }
+            }
+        }
+    }
+}
+
+impl<K, V, M> BTreeMap<K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    /// Returns an iterator joining the entries of this map, on the left, with the entries
+    /// of `other`, on the right, by key. The maps can have different value types and
+    /// memories.
+    ///
+    /// Both maps are iterated in a single pass, in key order. The `kind` of the join selects
+    /// the keys that are yielded: the keys in both maps for [`JoinKind::Inner`], the keys of
+    /// this map for [`JoinKind::Left`], and the keys of either map for [`JoinKind::Outer`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::btreemap::{JoinKind, Joined};
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    ///
+    /// let mut balances: BTreeMap<u64, u64, _> = BTreeMap::new(DefaultMemoryImpl::default());
+    /// let mut names: BTreeMap<u64, String, _> = BTreeMap::new(DefaultMemoryImpl::default());
+    /// balances.insert(1, 100);
+    /// balances.insert(2, 200);
+    /// names.insert(2, "bob".to_string());
+    /// names.insert(3, "carol".to_string());
+    ///
+    /// assert_eq!(
+    ///     balances.merge_join(&names, JoinKind::Inner).collect::<Vec<_>>(),
+    ///     vec![(2, Joined::Both(200, "bob".to_string()))]
+    /// );
+    /// assert_eq!(
+    ///     balances.merge_join(&names, JoinKind::Left).collect::<Vec<_>>(),
+    ///     vec![
+    ///         (1, Joined::Left(100)),
+    ///         (2, Joined::Both(200, "bob".to_string())),
+    ///     ]
+    /// );
+    /// assert_eq!(balances.merge_join(&names, JoinKind::Outer).count(), 3);
+    /// ```
+    pub fn merge_join<'a, V2, M2>(
+        &'a self,
+        other: &'a BTreeMap<K, V2, M2>,
+        kind: JoinKind,
+    ) -> MergeJoin<'a, K, V, M, V2, M2>
+    where
+        V2: Storable,
+        M2: Memory,
+    {
+        MergeJoin {
+            left: self.iter().peekable(),
+            right: other.iter().peekable(),
+            kind,
+        }
+    }
+}
@@ -0,0 +1,418 @@
+use super::{
+    node::{PageSize, Version},
+    BTreeMap, MAGIC,
+};
+use crate::{
+    read_u64,
+    storable::Bound,
+    types::{Address, NULL},
+    write, write_u64, Memory, Storable,
+};
+use std::borrow::Cow;
+
+// The size of the chunks the values are stored in, which is also the page size of the
+// nodes of the map.
+const CHUNK_SIZE: u32 = 4096;
+
+// The number of addresses in an index chunk.
+const FANOUT: u64 = CHUNK_SIZE as u64 / 8;
+
+/// The descriptor of a value, stored in the map in place of the value.
+///
+/// The value is stored in chunks of `CHUNK_SIZE` bytes. If `depth` is 0, `root` is the only
+/// chunk of the value. Otherwise, `root` is an index chunk that holds the addresses of up to
+/// `FANOUT` chunks of depth `depth - 1`, so a value's chunk at any offset is found by
+/// reading `depth` index chunks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct LargeValue {
+    len: u64,
+    root: Address,
+    depth: u8,
+}
+
+impl LargeValue {
+    const EMPTY: Self = Self {
+        len: 0,
+        root: NULL,
+        depth: 0,
+    };
+}
+
+impl Storable for LargeValue {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.into_bytes())
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(17);
+        bytes.extend_from_slice(&self.len.to_le_bytes());
+        bytes.extend_from_slice(&self.root.get().to_le_bytes());
+        bytes.push(self.depth);
+        bytes
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            len: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            root: Address::from(u64::from_le_bytes(bytes[8..16].try_into().unwrap())),
+            depth: bytes[16],
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 17,
+        is_fixed_size: true,
+    };
+}
+
+/// A [`BTreeMap`] for large values, such as assets or wasm chunks, that are read and
+/// written in parts.
+///
+/// The values are stored out of the map's nodes, in chunks allocated from the map's memory,
+/// and only a small descriptor of each value is stored in the map. Parts of a value can be
+/// read with [`read_value_at`](Self::read_value_at), e.g. to serve an HTTP range request,
+/// and written with [`write_value_chunk`](Self::write_value_chunk), without loading or
+/// copying the rest of the value.
+///
+/// # Example
+///
+/// ```rust
+/// use ic_stable_structures::btreemap::LargeValueMap;
+/// use ic_stable_structures::DefaultMemoryImpl;
+///
+/// let mut map: LargeValueMap<String, _> = LargeValueMap::init(DefaultMemoryImpl::default());
+///
+/// // Upload a value in chunks.
+/// let asset = "index.html".to_string();
+/// map.write_value_chunk(asset.clone(), 0, b"<html>");
+/// map.write_value_chunk(asset.clone(), 6, b"</html>");
+/// assert_eq!(map.value_len(&asset), Some(13));
+///
+/// // Read a range of the value.
+/// let mut buf = [0; 8];
+/// assert_eq!(map.read_value_at(&asset, 6, &mut buf), Some(7));
+/// assert_eq!(&buf[..7], b"</html>");
+/// ```
+pub struct LargeValueMap<K, M>
+where
+    K: Storable + Ord + Clone,
+    M: Memory,
+{
+    map: BTreeMap<K, LargeValue, M>,
+}
+
+impl<K, M> LargeValueMap<K, M>
+where
+    K: Storable + Ord + Clone,
+    M: Memory,
+{
+    /// Initializes a `LargeValueMap`.
+    ///
+    /// If the memory provided already contains a `LargeValueMap`, then that map is loaded.
+    /// Otherwise, a new `LargeValueMap` instance is created.
+    pub fn init(memory: M) -> Self {
+        if memory.size() == 0 {
+            return Self::new(memory);
+        }
+
+        let mut dst = vec![0; 3];
+        memory.read(0, &mut dst);
+        if dst != MAGIC {
+            Self::new(memory)
+        } else {
+            Self::load(memory)
+        }
+    }
+
+    /// Creates a new instance of a `LargeValueMap`.
+    pub fn new(memory: M) -> Self {
+        Self {
+            map: BTreeMap::new_with_version(memory, Version::V2(PageSize::Value(CHUNK_SIZE))),
+        }
+    }
+
+    /// Loads the `LargeValueMap` from memory.
+    pub fn load(memory: M) -> Self {
+        Self {
+            map: BTreeMap::load(memory),
+        }
+    }
+
+    /// Sets the value of the key, returning `true` if the key had a value.
+    pub fn insert(&mut self, key: K, value: &[u8]) -> bool {
+        let existed = self.remove(&key);
+        self.write_value_chunk(key, 0, value);
+        existed
+    }
+
+    /// Writes `data` at the given offset of the value of the key, extending the value if
+    /// `data` goes past its end. A key without a value is given an empty value first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `offset` is past the end of the value.
+    pub fn write_value_chunk(&mut self, key: K, offset: u64, data: &[u8]) {
+        let mut value = self.map.get(&key).unwrap_or(LargeValue::EMPTY);
+        assert!(
+            offset <= value.len,
+            "offset {offset} is past the end of the value ({} bytes)",
+            value.len
+        );
+
+        let mut written = 0;
+        while written < data.len() {
+            let position = offset + written as u64;
+            let chunk_offset = position % CHUNK_SIZE as u64;
+            let n = (data.len() - written).min((CHUNK_SIZE as u64 - chunk_offset) as usize);
+            let chunk = self.chunk_for_write(&mut value, position / CHUNK_SIZE as u64);
+            write(
+                self.map.memory(),
+                (chunk + chunk_offset.into()).get(),
+                &data[written..written + n],
+            );
+            written += n;
+        }
+
+        value.len = value.len.max(offset + data.len() as u64);
+        self.map.insert(key, value);
+    }
+
+    /// Reads the value of the key from the given offset into `buf`, returning the number of
+    /// bytes read, which is less than the length of `buf` if the value ends before, or
+    /// `None` if the key has no value.
+    pub fn read_value_at(&self, key: &K, offset: u64, buf: &mut [u8]) -> Option<usize> {
+        let value = self.map.get(key)?;
+        let len = (value.len.saturating_sub(offset)).min(buf.len() as u64) as usize;
+
+        let mut read = 0;
+        while read < len {
+            let position = offset + read as u64;
+            let chunk_offset = position % CHUNK_SIZE as u64;
+            let n = (len - read).min((CHUNK_SIZE as u64 - chunk_offset) as usize);
+            let chunk = self.chunk(&value, position / CHUNK_SIZE as u64);
+            self.map.memory().read(
+                (chunk + chunk_offset.into()).get(),
+                &mut buf[read..read + n],
+            );
+            read += n;
+        }
+        Some(len)
+    }
+
+    /// Returns the whole value of the key.
+    pub fn get(&self, key: &K) -> Option<Vec<u8>> {
+        let len = self.value_len(key)?;
+        let mut value = vec![0; len as usize];
+        self.read_value_at(key, 0, &mut value);
+        Some(value)
+    }
+
+    /// Returns the length of the value of the key.
+    pub fn value_len(&self, key: &K) -> Option<u64> {
+        self.map.get(key).map(|value| value.len)
+    }
+
+    /// Returns `true` if the key has a value.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// Removes the key and its value, returning `true` if the key had a value.
+    pub fn remove(&mut self, key: &K) -> bool {
+        match self.map.remove(key) {
+            Some(value) => {
+                if value.root != NULL {
+                    self.deallocate(value.root, value.depth);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the number of keys in the map.
+    pub fn len(&self) -> u64 {
+        self.map.len()
+    }
+
+    /// Returns `true` if the map contains no keys.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Returns an iterator over the keys of the map, sorted.
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = K> + '_ {
+        self.map.keys()
+    }
+
+    /// Removes all the keys and values from the map.
+    pub fn clear(&mut self) {
+        self.map.clear_new();
+    }
+
+    /// Returns the underlying memory.
+    pub fn into_memory(self) -> M {
+        self.map.into_memory()
+    }
+
+    /// Returns the number of chunks allocated in the map's memory, for nodes and values.
+    #[cfg(test)]
+    fn num_allocated_chunks(&self) -> u64 {
+        self.map.allocator.num_allocated_chunks()
+    }
+
+    // Returns the address of the value's chunk with the given index, which must exist.
+    fn chunk(&self, value: &LargeValue, index: u64) -> Address {
+        let mut address = value.root;
+        for level in (0..value.depth as u32).rev() {
+            let slot = index / FANOUT.pow(level) % FANOUT;
+            address = Address::from(read_u64(self.map.memory(), address + (slot * 8).into()));
+        }
+        address
+    }
+
+    // Returns the address of the value's chunk with the given index, allocating the chunk
+    // and the index chunks on its path if they don't exist.
+    fn chunk_for_write(&mut self, value: &mut LargeValue, index: u64) -> Address {
+        // Add levels to the value until it can hold the chunk.
+        while index >= FANOUT.pow(value.depth as u32) {
+            if value.root != NULL {
+                let root = self.allocate(true);
+                write_u64(self.map.memory(), root, value.root.get());
+                value.root = root;
+            }
+            value.depth += 1;
+        }
+
+        if value.root == NULL {
+            value.root = self.allocate(value.depth > 0);
+        }
+
+        let mut address = value.root;
+        for level in (0..value.depth as u32).rev() {
+            let slot = address + (index / FANOUT.pow(level) % FANOUT * 8).into();
+            let mut child = Address::from(read_u64(self.map.memory(), slot));
+            if child == NULL {
+                child = self.allocate(level > 0);
+                write_u64(self.map.memory(), slot, child.get());
+            }
+            address = child;
+        }
+        address
+    }
+
+    // Allocates a chunk, zeroing it if it's an index chunk.
+    fn allocate(&mut self, index: bool) -> Address {
+        let address = self.map.allocator.allocate();
+        if index {
+            write(self.map.memory(), address.get(), &[0; CHUNK_SIZE as usize]);
+        }
+        address
+    }
+
+    // Deallocates the chunk at the given address and, if it's an index chunk, the chunks
+    // it points to.
+    fn deallocate(&mut self, address: Address, depth: u8) {
+        if depth > 0 {
+            for slot in 0..FANOUT {
+                let child = Address::from(read_u64(self.map.memory(), address + (slot * 8).into()));
+                if child != NULL {
+                    self.deallocate(child, depth - 1);
+                }
+            }
+        }
+        self.map.allocator.deallocate(address);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn make_memory() -> Rc<RefCell<Vec<u8>>> {
+        Rc::new(RefCell::new(Vec::new()))
+    }
+
+    // A value of the given length whose bytes depend on their offset.
+    fn make_value(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8 ^ seed).collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn write_and_read_ranges_of_large_values() {
+        let mem = make_memory();
+        let mut map: LargeValueMap<u64, _> = LargeValueMap::new(mem.clone());
+
+        // Values spanning one chunk, a few chunks, and more chunks than an index holds.
+        let lens = [0, 100, CHUNK_SIZE as usize * 3 + 5, 3_000_000];
+        for (key, len) in lens.iter().enumerate() {
+            let value = make_value(*len, key as u8);
+            // Upload the value in chunks that aren't aligned with the map's chunks.
+            map.write_value_chunk(key as u64, 0, &[]);
+            for (i, part) in value.chunks(100_003).enumerate() {
+                map.write_value_chunk(key as u64, i as u64 * 100_003, part);
+            }
+            assert_eq!(map.value_len(&(key as u64)), Some(*len as u64));
+        }
+
+        let map: LargeValueMap<u64, _> = LargeValueMap::load(mem);
+        for (key, len) in lens.iter().enumerate() {
+            let value = make_value(*len, key as u8);
+            assert_eq!(map.get(&(key as u64)), Some(value.clone()));
+
+            for (offset, size) in [(0, 10), (4090, 20), (*len as u64 / 2, 70_000), (0, 0)] {
+                let mut buf = vec![0; size];
+                let read = map.read_value_at(&(key as u64), offset, &mut buf).unwrap();
+                let start = (offset as usize).min(*len);
+                let end = (start + size).min(*len);
+                assert_eq!(read, end - start);
+                assert_eq!(&buf[..read], &value[start..end]);
+            }
+        }
+        assert_eq!(map.read_value_at(&10, 0, &mut [0; 10]), None);
+    }
+
+    #[test]
+    fn overwrite_parts_of_a_value() {
+        let mut map: LargeValueMap<String, _> = LargeValueMap::new(make_memory());
+        let key = "wasm".to_string();
+        let mut expected = make_value(50_000, 1);
+        map.insert(key.clone(), &expected);
+
+        let patch = make_value(10_000, 2);
+        map.write_value_chunk(key.clone(), 8_000, &patch);
+        expected[8_000..18_000].copy_from_slice(&patch);
+        map.write_value_chunk(key.clone(), 50_000, &patch);
+        expected.extend_from_slice(&patch);
+
+        assert_eq!(map.get(&key), Some(expected));
+        assert!(map.insert(key.clone(), b"small"));
+        assert_eq!(map.get(&key), Some(b"small".to_vec()));
+    }
+
+    #[test]
+    #[should_panic(expected = "offset 11 is past the end of the value (10 bytes)")]
+    fn write_past_the_end_of_a_value() {
+        let mut map: LargeValueMap<u64, _> = LargeValueMap::new(make_memory());
+        map.insert(0, &[0; 10]);
+        map.write_value_chunk(0, 11, &[0]);
+    }
+
+    #[test]
+    fn remove_deallocates_chunks() {
+        let mut map: LargeValueMap<u64, _> = LargeValueMap::new(make_memory());
+        map.insert(1, b"small");
+        let chunks = map.num_allocated_chunks();
+
+        map.insert(2, &make_value(CHUNK_SIZE as usize * FANOUT as usize + 1, 0));
+        map.insert(3, &make_value(100_000, 0));
+        assert!(map.remove(&2));
+        assert!(map.remove(&3));
+        assert!(!map.remove(&3));
+        assert_eq!(map.num_allocated_chunks(), chunks);
+        assert_eq!(map.keys().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(map.get(&1), Some(b"small".to_vec()));
+    }
+}
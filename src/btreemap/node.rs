@@ -18,11 +18,13 @@ use io::NodeReader;
 
 // The minimum degree to use in the btree.
 // This constant is taken from Rust's std implementation of BTreeMap.
-const B: usize = 6;
+pub(super) const B: usize = 6;
 // The maximum number of entries per node.
-const CAPACITY: usize = 2 * B - 1;
+pub(super) const CAPACITY: usize = 2 * B - 1;
 const LAYOUT_VERSION_1: u8 = 1;
 const LAYOUT_VERSION_2: u8 = 2;
+const LAYOUT_VERSION_3: u8 = 3;
+const LAYOUT_VERSION_4: u8 = 4;
 const MAGIC: &[u8; 3] = b"BTN";
 const LEAF_NODE_TYPE: u8 = 0;
 const INTERNAL_NODE_TYPE: u8 = 1;
@@ -43,13 +45,15 @@ type LazyEntry<K> = (LazyKey<K>, LazyValue);
 
 /// A node of a B-Tree.
 ///
-/// There are two versions of a `Node`:
+/// There are four versions of a `Node`:
 ///
 /// 1. `V1`, which supports only bounded types.
 /// 2. `V2`, which supports both bounded and unbounded types.
+/// 3. `V3`, which extends `V2` with the number of entries in the subtree of each child.
+/// 4. `V4`, which extends `V2` by storing the prefix shared by all the keys only once.
 ///
 /// See `v1.rs` and `v2.rs` for more details.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Node<K: Storable + Ord + Clone> {
     address: Address,
     // List of tuples consisting of a key and the encoded value.
@@ -58,6 +62,13 @@ pub struct Node<K: Storable + Ord + Clone> {
     // For the key at position I, children[I] points to the left
     // child of this key and children[I + 1] points to the right child.
     children: Vec<Address>,
+    // For the child at position I, counts[I] is the number of entries in its subtree.
+    // INVARIANT: `counts` has the same length as `children`.
+    // The counts are only persisted (and kept accurate by the map) in V3 nodes.
+    counts: Vec<u64>,
+    // The prefix shared by the encoded keys of the node, as stored in memory.
+    // Only used by V4 nodes, whose keys are stored in memory without this prefix.
+    key_prefix: Vec<u8>,
     node_type: NodeType,
     version: Version,
 
@@ -82,17 +93,119 @@ impl<K: Storable + Ord + Clone> Node<K> {
                     unreachable!("Tried to load a V1 node without a derived PageSize.")
                 }
             },
-            LAYOUT_VERSION_2 => Self::load_v2(address, page_size, header, memory),
+            LAYOUT_VERSION_2 | LAYOUT_VERSION_3 | LAYOUT_VERSION_4 => {
+                Self::load_v2(address, page_size, header, memory)
+            }
             unknown_version => unreachable!("Unsupported version {unknown_version}."),
         }
     }
 
+    /// Checks that the given address holds a valid node header, and returns the addresses
+    /// of the node's overflow pages.
+    ///
+    /// Unlike [`load`](Self::load), which panics on invalid data, this returns an error,
+    /// so that a corrupted node can be reported before it's decoded.
+    pub fn check_layout<M: Memory>(
+        address: Address,
+        memory: &M,
+    ) -> Result<Vec<Address>, LayoutError> {
+        let memory_size = memory.size() * crate::WASM_PAGE_SIZE;
+        let in_bounds = |address: Address, size: Bytes| {
+            address
+                .get()
+                .checked_add(size.get())
+                .is_some_and(|end| end <= memory_size)
+        };
+
+        if !in_bounds(address, v2::OVERFLOW_ADDRESS_OFFSET + Address::size()) {
+            return Err(LayoutError::InvalidHeader);
+        }
+        let header: NodeHeader = read_struct(address, memory);
+        if &header.magic != MAGIC || header.node_type > INTERNAL_NODE_TYPE {
+            return Err(LayoutError::InvalidHeader);
+        }
+
+        match header.version {
+            // V1 nodes never overflow.
+            LAYOUT_VERSION_1 => Ok(vec![]),
+            LAYOUT_VERSION_2 | LAYOUT_VERSION_3 | LAYOUT_VERSION_4 => {
+                let mut overflows: Vec<Address> = vec![];
+                let mut next =
+                    Address::from(read_u64(memory, address + v2::OVERFLOW_ADDRESS_OFFSET));
+                while next != crate::types::NULL {
+                    if overflows.contains(&next) || !in_bounds(next, v2::PAGE_OVERFLOW_DATA_OFFSET)
+                    {
+                        return Err(LayoutError::InvalidOverflow(next));
+                    }
+
+                    let mut magic = [0; 3];
+                    memory.read(next.get(), &mut magic);
+                    if &magic != v2::OVERFLOW_MAGIC {
+                        return Err(LayoutError::InvalidOverflow(next));
+                    }
+
+                    overflows.push(next);
+                    next = Address::from(read_u64(memory, next + v2::PAGE_OVERFLOW_NEXT_OFFSET));
+                }
+                Ok(overflows)
+            }
+            _ => Err(LayoutError::InvalidHeader),
+        }
+    }
+
     /// Saves the node to memory.
     pub fn save<M: Memory>(&mut self, allocator: &mut Allocator<M>) {
         match self.version {
             Version::V1(_) => self.save_v1(allocator.memory()),
-            Version::V2(_) => self.save_v2(allocator),
+            Version::V2(_) | Version::V3(_) | Version::V4(_) => self.save_v2(allocator),
+        }
+    }
+
+    /// Changes the version of the node, so that it's written with the layout of the given
+    /// version the next time it's saved.
+    ///
+    /// All the entries are loaded first, as they can only be read using the layout of the
+    /// version they were written with.
+    pub fn migrate<M: Memory>(&mut self, version: Version, memory: &M) {
+        for i in 0..self.entries.len() {
+            self.entry(i, memory);
+        }
+        self.version = version;
+    }
+
+    /// Makes the node a copy that's written to the given address, which must be allocated,
+    /// the next time it's saved. The node's current chunk and overflow pages are left as
+    /// they are.
+    pub fn detach<M: Memory>(&mut self, address: Address, memory: &M) {
+        // Load all the entries, as they're read relative to the node's current address.
+        for i in 0..self.entries.len() {
+            self.entry(i, memory);
+        }
+
+        self.address = address;
+        self.overflows = Vec::with_capacity(0);
+    }
+
+    /// Moves the node to the given address, which must be allocated, deallocating its old
+    /// chunk and overflow pages and saving it there with new overflow pages.
+    pub fn relocate<M: Memory>(&mut self, address: Address, allocator: &mut Allocator<M>) {
+        // Load all the entries, as they're read relative to the node's current address.
+        for i in 0..self.entries.len() {
+            self.entry(i, allocator.memory());
+        }
+
+        // Deallocate the old chunks first, so that the new overflow pages can reuse them.
+        for overflow in core::mem::take(&mut self.overflows) {
+            allocator.deallocate(overflow);
         }
+        allocator.deallocate(core::mem::replace(&mut self.address, address));
+
+        self.save(allocator);
+    }
+
+    /// Returns the version of the node.
+    pub fn version(&self) -> Version {
+        self.version
     }
 
     /// Returns the address of the node.
@@ -235,7 +348,7 @@ impl<K: Storable + Ord + Clone> Node<K> {
                 offset += U32_SIZE;
                 loaded_size
             }
-            Version::V2(_) => {
+            Version::V2(_) | Version::V3(_) => {
                 // V2: use fixed size if available, otherwise the one loaded from memory.
                 if K::BOUND.is_fixed_size() {
                     K::BOUND.max_size()
@@ -244,10 +357,22 @@ impl<K: Storable + Ord + Clone> Node<K> {
                     loaded_size
                 }
             }
+            Version::V4(_) => {
+                // V4: only the part of the key after the shared prefix is stored.
+                if K::BOUND.is_fixed_size() {
+                    K::BOUND.max_size() - self.key_prefix.len() as u32
+                } else {
+                    offset += U32_SIZE;
+                    loaded_size
+                }
+            }
         } as usize;
 
         let mut bytes = Vec::with_capacity(size);
         read_to_vec(&reader, Address::from(offset.get()), &mut bytes, size);
+        if !self.key_prefix.is_empty() {
+            bytes.splice(0..0, self.key_prefix.iter().copied());
+        }
 
         K::from_bytes(Cow::Borrowed(&bytes))
     }
@@ -287,19 +412,37 @@ impl<K: Storable + Ord + Clone> Node<K> {
         self.children[idx]
     }
 
-    /// Inserts the given child at the given index.
-    pub fn insert_child(&mut self, idx: usize, address: Address) {
-        self.children.insert(idx, address)
+    /// Returns the number of entries in the subtree of the child at the given index.
+    pub fn child_count(&self, idx: usize) -> u64 {
+        self.counts[idx]
+    }
+
+    /// Sets the number of entries in the subtree of the child at the given index.
+    pub fn set_child_count(&mut self, idx: usize, count: u64) {
+        self.counts[idx] = count;
+    }
+
+    /// Returns the number of entries in the subtree rooted at this node.
+    pub fn subtree_len(&self) -> u64 {
+        self.entries.len() as u64 + self.counts.iter().sum::<u64>()
     }
 
-    /// Pushes the child to the far right of the node.
-    pub fn push_child(&mut self, address: Address) {
-        self.children.push(address)
+    /// Inserts the given child, with the number of entries in its subtree, at the given index.
+    pub fn insert_child(&mut self, idx: usize, address: Address, count: u64) {
+        self.children.insert(idx, address);
+        self.counts.insert(idx, count);
+    }
+
+    /// Pushes the child, with the number of entries in its subtree, to the far right of the node.
+    pub fn push_child(&mut self, address: Address, count: u64) {
+        self.children.push(address);
+        self.counts.push(count);
     }
 
     /// Removes the child at the given index.
-    pub fn remove_child(&mut self, idx: usize) -> Address {
-        self.children.remove(idx)
+    /// Returns its address and the number of entries in its subtree.
+    pub fn remove_child(&mut self, idx: usize) -> (Address, u64) {
+        (self.children.remove(idx), self.counts.remove(idx))
     }
 
     /// Returns the number of children in the node.
@@ -308,8 +451,9 @@ impl<K: Storable + Ord + Clone> Node<K> {
     }
 
     /// Pops the right-most child of the node.
-    pub fn pop_child(&mut self) -> Option<Address> {
-        self.children.pop()
+    /// Returns its address and the number of entries in its subtree.
+    pub fn pop_child(&mut self) -> Option<(Address, u64)> {
+        Some((self.children.pop()?, self.counts.pop()?))
     }
 
     /// Inserts a new entry at the specified index.
@@ -391,6 +535,7 @@ impl<K: Storable + Ord + Clone> Node<K> {
             // Move the entries and children into self.
             self.entries = core::mem::take(&mut source.entries);
             self.children = core::mem::take(&mut source.children);
+            self.counts = core::mem::take(&mut source.counts);
         }
 
         self.save(allocator);
@@ -422,6 +567,7 @@ impl<K: Storable + Ord + Clone> Node<K> {
 
         // Move the children (if any exist).
         a.children.append(&mut b.children);
+        a.counts.append(&mut b.counts);
 
         // Assert postconditions.
         assert_eq!(b.entries.len(), 0);
@@ -442,11 +588,34 @@ impl<K: Storable + Ord + Clone> Node<K> {
             .collect()
     }
 
-    #[cfg(test)]
+    /// Returns the addresses of the node's overflow pages.
     pub fn overflows(&self) -> &[Address] {
         &self.overflows
     }
 
+    /// Returns the total size of the node's keys in bytes, without loading them.
+    pub fn keys_size(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|(key, _)| match &key.0 {
+                LazyObject::ByVal(key) => key.to_bytes().len() as u64,
+                // Keys that are stored by reference exclude the node's key prefix.
+                LazyObject::ByRef { size, .. } => (*size as usize + self.key_prefix.len()) as u64,
+            })
+            .sum()
+    }
+
+    /// Returns the total size of the node's values in bytes, without loading them.
+    pub fn values_size(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|(_, value)| match &value.0 {
+                LazyObject::ByVal(value) => value.len() as u64,
+                LazyObject::ByRef { size, .. } => *size as u64,
+            })
+            .sum()
+    }
+
     /// Returns the number of entries in the node.
     pub fn entries_len(&self) -> usize {
         self.entries.len()
@@ -463,6 +632,13 @@ impl<K: Storable + Ord + Clone> Node<K> {
             .binary_search_by_key(&key, |entry| self.get_key(entry, memory))
     }
 
+    /// Returns the index of the first entry whose key doesn't satisfy `pred`, assuming that
+    /// the keys satisfying it come first.
+    pub fn partition_point<M: Memory>(&self, memory: &M, pred: impl Fn(&K) -> bool) -> usize {
+        self.entries
+            .partition_point(|entry| pred(self.get_key(entry, memory)))
+    }
+
     /// Returns the maximum size a node can be if it has bounded keys and values.
     ///
     /// See the documentation of [`Node`] for the memory layout.
@@ -494,6 +670,7 @@ impl<K: Storable + Ord + Clone> Node<K> {
         sibling.entries = self.entries.split_off(B);
         if self.node_type == NodeType::Internal {
             sibling.children = self.children.split_off(B);
+            sibling.counts = self.counts.split_off(B);
         }
 
         // Return the median entry.
@@ -511,6 +688,15 @@ impl<K: Storable + Ord + Clone> Node<K> {
     }
 }
 
+/// An error found by [`Node::check_layout`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum LayoutError {
+    /// The address doesn't hold a valid node header.
+    InvalidHeader,
+    /// The overflow page at the given address is invalid, or is part of a cycle.
+    InvalidOverflow(Address),
+}
+
 // A transient data structure for reading/writing metadata into/from stable memory.
 #[repr(C, packed)]
 struct NodeHeader {
@@ -527,7 +713,7 @@ impl NodeHeader {
 }
 
 /// A lazily-loaded object, which can be either an immediate value or a deferred reference.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 enum LazyObject<T> {
     ByVal(T),
     ByRef {
@@ -579,7 +765,7 @@ impl<T> LazyObject<T> {
 
 type Blob = Vec<u8>;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct LazyValue(LazyObject<Blob>);
 
 impl LazyValue {
@@ -604,7 +790,7 @@ impl LazyValue {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct LazyKey<K>(LazyObject<K>);
 
 impl<K> LazyKey<K> {
@@ -636,13 +822,18 @@ pub enum Version {
     V1(DerivedPageSize),
     /// V2 nodes have a fixed page size.
     V2(PageSize),
+    /// V3 nodes have a fixed page size and store the number of entries in the
+    /// subtree of each child.
+    V3(PageSize),
+    /// V4 nodes have a fixed page size and store their keys with prefix compression.
+    V4(PageSize),
 }
 
 impl Version {
     pub fn page_size(&self) -> PageSize {
         match self {
             Self::V1(page_size) => PageSize::Derived(*page_size),
-            Self::V2(page_size) => *page_size,
+            Self::V2(page_size) | Self::V3(page_size) | Self::V4(page_size) => *page_size,
         }
     }
 }
@@ -56,7 +56,7 @@ impl NodeV1Data {
 
         // Push the children
         for child in self.children() {
-            node.push_child(child);
+            node.push_child(child, 0);
         }
 
         node
@@ -97,7 +97,7 @@ impl NodeV2Data {
             node.push_entry(entry);
         }
         for child in self.children() {
-            node.push_child(child);
+            node.push_child(child, 0);
         }
 
         node
@@ -167,6 +167,95 @@ fn saving_and_loading_v2_preserves_data(node_data: NodeV2Data) {
     );
 }
 
+#[proptest]
+fn saving_and_loading_v3_preserves_data(node_data: NodeV2Data) {
+    let mem = make_memory();
+    let allocator_addr = Address::from(0);
+    let mut allocator = Allocator::new(
+        mem.clone(),
+        allocator_addr,
+        Bytes::from(node_data.page_size as u64),
+    );
+
+    // Create a new node with subtree counts and save it into memory.
+    let node_addr = allocator.allocate();
+    let mut node = Node::new_v3(
+        node_addr,
+        node_data.node_type,
+        PageSize::Value(node_data.page_size),
+    );
+    for entry in node_data.entries.clone().into_iter() {
+        node.push_entry(entry);
+    }
+    let counts: Vec<u64> = (0..node_data.children().len() as u64)
+        .map(|i| i * 1_000 + 5)
+        .collect();
+    for (child, count) in node_data.children().into_iter().zip(counts.iter()) {
+        node.push_child(child, *count);
+    }
+    node.save(&mut allocator);
+
+    // Reload the node and double check all the entries, children and counts are correct.
+    let node = Node::load(node_addr, PageSize::Value(node_data.page_size), &mem);
+
+    assert_eq!(
+        node.version,
+        Version::V3(PageSize::Value(node_data.page_size))
+    );
+    assert_eq!(node.children, node_data.children());
+    assert_eq!(node.counts, counts);
+    assert_eq!(
+        node.entries(&mem),
+        node_data.entries.into_iter().collect::<Vec<_>>()
+    );
+}
+
+#[proptest]
+fn saving_and_loading_v4_preserves_data(
+    node_data: NodeV2Data,
+    #[strategy(pvec(0..u8::MAX, 0..300))] prefix: Vec<u8>,
+) {
+    let mem = make_memory();
+    let allocator_addr = Address::from(0);
+    let mut allocator = Allocator::new(
+        mem.clone(),
+        allocator_addr,
+        Bytes::from(node_data.page_size as u64),
+    );
+
+    // Create a new node with keys sharing a prefix and save it into memory.
+    let entries: Vec<_> = node_data
+        .entries
+        .clone()
+        .into_iter()
+        .map(|(key, value)| ([prefix.clone(), key].concat(), value))
+        .collect();
+    let node_addr = allocator.allocate();
+    let mut node = Node::new_v4(
+        node_addr,
+        node_data.node_type,
+        PageSize::Value(node_data.page_size),
+    );
+    for entry in entries.clone().into_iter() {
+        node.push_entry(entry);
+    }
+    for child in node_data.children() {
+        node.push_child(child, 0);
+    }
+    node.save(&mut allocator);
+
+    // Reload the node and double check all the entries and children are correct.
+    let node = Node::load(node_addr, PageSize::Value(node_data.page_size), &mem);
+
+    assert_eq!(
+        node.version,
+        Version::V4(PageSize::Value(node_data.page_size))
+    );
+    assert!(node.key_prefix.starts_with(&prefix));
+    assert_eq!(node.children, node_data.children());
+    assert_eq!(node.entries(&mem), entries);
+}
+
 #[proptest]
 fn migrating_v1_nodes_to_v2(node_data: NodeV1Data) {
     let v1_size = v1::size_v1(node_data.max_key_size, node_data.max_value_size);
@@ -50,6 +50,8 @@ impl<K: Storable + Ord + Clone> Node<K> {
             node_type,
             entries: vec![],
             children: vec![],
+            counts: vec![],
+            key_prefix: vec![],
             version: Version::V1(page_size),
             overflows: Vec::with_capacity(0),
         }
@@ -98,6 +100,8 @@ impl<K: Storable + Ord + Clone> Node<K> {
         Self {
             address,
             entries,
+            counts: vec![0; children.len()],
+            key_prefix: vec![],
             children,
             node_type: match header.node_type {
                 LEAF_NODE_TYPE => NodeType::Leaf,
@@ -139,7 +143,9 @@ impl<K: Storable + Ord + Clone> Node<K> {
                 max_key_size,
                 max_value_size,
             }) => (max_key_size, max_value_size),
-            Version::V2 { .. } => unreachable!("cannot save v2 node as v1."),
+            Version::V2 { .. } | Version::V3 { .. } | Version::V4 { .. } => {
+                unreachable!("cannot save v2 node as v1.")
+            }
         };
 
         let header = NodeHeader {
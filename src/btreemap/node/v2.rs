@@ -60,12 +60,57 @@
 //! ----------------------------------------
 //! ```
 //!
+//! ## Subtree Counts (V3)
+//!
+//! A v3 node has the same layout as a v2 node, except that its layout version is 3, and
+//! that an internal node stores the number of entries in the subtree of each child right
+//! after the children addresses. These counts allow computing the rank of a key, or
+//! looking up the entry at a given position, in logarithmic time.
+//!
+//! ```text
+//! ---------------------------------------- <-- Children
+//! Child(0) address        ↕ 8 bytes
+//! ----------------------------------------
+//! ...
+//! ----------------------------------------
+//! Child(k + 1) address    ↕ 8 bytes
+//! ---------------------------------------- <-- Subtree counts
+//! Child(0) count          ↕ 8 bytes
+//! ----------------------------------------
+//! ...
+//! ----------------------------------------
+//! Child(k + 1) count      ↕ 8 bytes
+//! ---------------------------------------- <-- Keys
+//! ```
+//!
+//! ## Prefix-Compressed Keys (V4)
+//!
+//! A v4 node has the same layout as a v2 node, except that its layout version is 4, and
+//! that the longest prefix shared by the encoded keys of the node is stored once, right
+//! after the children addresses. Each key is then stored without that prefix.
+//!
+//! ```text
+//! ---------------------------------------- <-- Children
+//! ...
+//! ---------------------------------------- <-- Shared key prefix
+//! Prefix size             ↕ 4 bytes
+//! ----------------------------------------
+//! Prefix
+//! ---------------------------------------- <-- Keys (without the prefix)
+//! ```
+//!
+//! Keys that are composite values, such as tuples starting with the same principal,
+//! tend to share long prefixes within a node, since neighboring keys are stored
+//! together. Storing the prefix once makes nodes smaller, and reduces the number of
+//! overflow pages they need.
+//!
 //! ## Keys and Values
 //! Keys and values are both encoded in memory as blobs.
 //!
 //! If they are variable in size (i.e. their `IS_FIXED` attribute is set to false),
 //! then the size of the blob is encoded before the blob itself. Otherwise, no size
-//! information is stored.
+//! information is stored. In a v4 node, the size of a fixed-size key is the size of
+//! the key minus the size of the prefix.
 //!
 //! [^note]: The page here refers to a fixed-size chunk of memory that is provided to the
 //! node by the BTreeMap Allocator, and has no connection with OS memory pages or
@@ -100,10 +145,28 @@ impl<K: Storable + Ord + Clone> Node<K> {
             version: Version::V2(page_size),
             entries: vec![],
             children: vec![],
+            counts: vec![],
+            key_prefix: vec![],
             overflows: Vec::with_capacity(0),
         }
     }
 
+    /// Creates a new v3 node at the given address.
+    pub fn new_v3(address: Address, node_type: NodeType, page_size: PageSize) -> Node<K> {
+        Node {
+            version: Version::V3(page_size),
+            ..Self::new_v2(address, node_type, page_size)
+        }
+    }
+
+    /// Creates a new v4 node at the given address.
+    pub fn new_v4(address: Address, node_type: NodeType, page_size: PageSize) -> Node<K> {
+        Node {
+            version: Version::V4(page_size),
+            ..Self::new_v2(address, node_type, page_size)
+        }
+    }
+
     /// Loads a v2 node from memory at the given address.
     pub(super) fn load_v2<M: Memory>(
         address: Address,
@@ -161,6 +224,32 @@ impl<K: Storable + Ord + Clone> Node<K> {
             vec![]
         };
 
+        // Load the subtree counts if this is a v3 node.
+        let counts = if header.version == LAYOUT_VERSION_3 {
+            let counts: Vec<u64> = read_address_vec(&reader, offset, children.len())
+                .into_iter()
+                .map(|count| count.get())
+                .collect();
+            offset += Bytes::from(children.len()) * Address::size();
+            counts
+        } else {
+            vec![0; children.len()]
+        };
+
+        // Load the shared key prefix if this is a v4 node.
+        let mut key_prefix = vec![];
+        if header.version == LAYOUT_VERSION_4 {
+            let prefix_size = read_u32(&reader, offset);
+            offset += U32_SIZE;
+            read_to_vec(
+                &reader,
+                Address::from(offset.get()),
+                &mut key_prefix,
+                prefix_size as usize,
+            );
+            offset += Bytes::from(prefix_size);
+        }
+
         // Load the keys (eagerly if small).
         const EAGER_LOAD_KEY_SIZE_THRESHOLD: u32 = 16;
         let mut entries = Vec::with_capacity(num_entries);
@@ -171,7 +260,7 @@ impl<K: Storable + Ord + Clone> Node<K> {
 
             // Get key size.
             let key_size = if K::BOUND.is_fixed_size() {
-                K::BOUND.max_size()
+                K::BOUND.max_size() - key_prefix.len() as u32
             } else {
                 let size = read_u32(&reader, offset);
                 offset += U32_SIZE;
@@ -186,6 +275,9 @@ impl<K: Storable + Ord + Clone> Node<K> {
                     &mut buf,
                     key_size as usize,
                 );
+                if !key_prefix.is_empty() {
+                    buf.splice(0..0, key_prefix.iter().copied());
+                }
                 LazyKey::by_value(K::from_bytes(Cow::Borrowed(&buf)))
             } else {
                 LazyKey::by_ref(key_offset, key_size)
@@ -207,8 +299,14 @@ impl<K: Storable + Ord + Clone> Node<K> {
             address,
             entries,
             children,
+            counts,
+            key_prefix,
             node_type,
-            version: Version::V2(page_size),
+            version: match header.version {
+                LAYOUT_VERSION_3 => Version::V3(page_size),
+                LAYOUT_VERSION_4 => Version::V4(page_size),
+                _ => Version::V2(page_size),
+            },
             overflows,
         }
     }
@@ -240,7 +338,11 @@ impl<K: Storable + Ord + Clone> Node<K> {
         let mut offset = Address::from(0);
         let header = NodeHeader {
             magic: *MAGIC,
-            version: LAYOUT_VERSION_2,
+            version: match self.version {
+                Version::V3(_) => LAYOUT_VERSION_3,
+                Version::V4(_) => LAYOUT_VERSION_4,
+                _ => LAYOUT_VERSION_2,
+            },
             node_type: match self.node_type {
                 NodeType::Leaf => LEAF_NODE_TYPE,
                 NodeType::Internal => INTERNAL_NODE_TYPE,
@@ -266,10 +368,35 @@ impl<K: Storable + Ord + Clone> Node<K> {
         writer.write(offset, &bytes);
         offset += Bytes::from(byte_len);
 
+        // Write the subtree counts.
+        if let Version::V3(_) = self.version {
+            let mut bytes = Vec::with_capacity(byte_len);
+            for count in &self.counts {
+                bytes.extend_from_slice(&count.to_le_bytes());
+            }
+            writer.write(offset, &bytes);
+            offset += Bytes::from(byte_len);
+        }
+
+        // Write the prefix shared by the keys if this is a v4 node, which takes a first pass
+        // over the keys.
+        let prefix = match self.version {
+            Version::V4(_) => {
+                let prefix = self.shared_key_prefix(writer.memory());
+                writer.write_u32(offset, prefix.len() as u32);
+                offset += U32_SIZE;
+                writer.write(offset, &prefix);
+                offset += Bytes::from(prefix.len());
+                prefix
+            }
+            _ => vec![],
+        };
+
         // Write the keys.
         for i in 0..self.entries.len() {
             let key = self.key(i, writer.memory());
             let key_bytes = key.to_bytes_checked();
+            let key_bytes = &key_bytes[prefix.len()..];
 
             // Write the size of the key if it isn't fixed in size.
             if !K::BOUND.is_fixed_size() {
@@ -278,9 +405,10 @@ impl<K: Storable + Ord + Clone> Node<K> {
             }
 
             // Write the key.
-            writer.write(offset, key_bytes.borrow());
+            writer.write(offset, key_bytes);
             offset += Bytes::from(key_bytes.len());
         }
+        self.key_prefix = prefix;
 
         // Write the values.
         for i in 0..self.entries.len() {
@@ -296,6 +424,26 @@ impl<K: Storable + Ord + Clone> Node<K> {
 
         self.overflows = writer.finish();
     }
+
+    /// Returns the longest prefix shared by the encodings of all the keys of the node.
+    fn shared_key_prefix<M: Memory>(&self, memory: &M) -> Vec<u8> {
+        let mut prefix: Option<Vec<u8>> = None;
+        for i in 0..self.entries.len() {
+            let key_bytes = self.key(i, memory).to_bytes_checked();
+            match &mut prefix {
+                None => prefix = Some(key_bytes.to_vec()),
+                Some(prefix) => {
+                    let len = prefix
+                        .iter()
+                        .zip(key_bytes.iter())
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    prefix.truncate(len);
+                }
+            }
+        }
+        prefix.unwrap_or_default()
+    }
 }
 
 fn read_overflows<M: Memory>(address: Address, memory: &M) -> Vec<Address> {
@@ -0,0 +1,189 @@
+use super::node::Node;
+use crate::{types::Address, Storable};
+use std::collections::{BTreeMap, HashMap};
+
+/// The hit and miss counters of a [`BTreeMap`](super::BTreeMap)'s node cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NodeCacheMetrics {
+    hits: u64,
+    misses: u64,
+}
+
+impl NodeCacheMetrics {
+    /// Returns the number of node loads that were served from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Returns the number of node loads that had to read and decode the node from memory.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Returns the ratio of node loads that were served from the cache, or zero if no
+    /// nodes were loaded.
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / total as f64
+    }
+}
+
+/// A cache of decoded nodes, keyed by their address.
+///
+/// The cache holds at most `capacity` nodes. When it's full, the least recently used node
+/// is evicted. A capacity of zero disables the cache.
+///
+/// The cache doesn't observe the memory: the map is responsible for removing a node from
+/// the cache whenever the node is saved or deallocated.
+pub(super) struct NodeCache<K: Storable + Ord + Clone> {
+    capacity: usize,
+    // The cached nodes, along with the tick of their last use.
+    nodes: HashMap<u64, (Node<K>, u64)>,
+    // The addresses of the cached nodes, ordered by the tick of their last use.
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+    metrics: NodeCacheMetrics,
+}
+
+impl<K: Storable + Ord + Clone> NodeCache<K> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            nodes: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            metrics: NodeCacheMetrics::default(),
+        }
+    }
+
+    /// Returns true if the cache can hold any nodes.
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Changes the number of nodes the cache can hold, evicting nodes if needed.
+    pub fn resize(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.nodes.len() > self.capacity {
+            self.evict();
+        }
+    }
+
+    /// Returns a copy of the node at the given address if it's cached.
+    pub fn get(&mut self, address: Address) -> Option<Node<K>> {
+        let tick = self.next_tick();
+        match self.nodes.get_mut(&address.get()) {
+            Some((node, last_used)) => {
+                self.lru.remove(last_used);
+                self.lru.insert(tick, address.get());
+                *last_used = tick;
+                self.metrics.hits += 1;
+                Some(node.clone())
+            }
+            None => {
+                self.metrics.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Adds a copy of the given node to the cache.
+    pub fn insert(&mut self, node: &Node<K>) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        self.remove(node.address());
+        if self.nodes.len() >= self.capacity {
+            self.evict();
+        }
+
+        let tick = self.next_tick();
+        self.lru.insert(tick, node.address().get());
+        self.nodes
+            .insert(node.address().get(), (node.clone(), tick));
+    }
+
+    /// Removes the node at the given address from the cache, if present.
+    pub fn remove(&mut self, address: Address) {
+        if let Some((_, last_used)) = self.nodes.remove(&address.get()) {
+            self.lru.remove(&last_used);
+        }
+    }
+
+    /// Removes all the nodes from the cache.
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.lru.clear();
+    }
+
+    pub fn metrics(&self) -> NodeCacheMetrics {
+        self.metrics
+    }
+
+    pub fn reset_metrics(&mut self) {
+        self.metrics = NodeCacheMetrics::default();
+    }
+
+    /// Evicts the least recently used node.
+    fn evict(&mut self) {
+        if let Some((_, address)) = self.lru.pop_first() {
+            self.nodes.remove(&address);
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::btreemap::node::{NodeType, PageSize};
+
+    fn node(address: u64) -> Node<u64> {
+        Node::new_v2(
+            Address::from(address),
+            NodeType::Leaf,
+            PageSize::Value(1024),
+        )
+    }
+
+    #[test]
+    fn evicts_least_recently_used_node() {
+        let mut cache = NodeCache::new(2);
+        cache.insert(&node(1));
+        cache.insert(&node(2));
+
+        // Using node 1 makes node 2 the least recently used.
+        assert!(cache.get(Address::from(1)).is_some());
+        cache.insert(&node(3));
+
+        assert!(cache.get(Address::from(2)).is_none());
+        assert!(cache.get(Address::from(1)).is_some());
+        assert!(cache.get(Address::from(3)).is_some());
+        assert_eq!(cache.metrics().hits(), 3);
+        assert_eq!(cache.metrics().misses(), 1);
+
+        cache.resize(1);
+        assert!(cache.get(Address::from(1)).is_none());
+        assert!(cache.get(Address::from(3)).is_some());
+    }
+
+    #[test]
+    fn removed_nodes_are_not_returned() {
+        let mut cache = NodeCache::new(2);
+        cache.insert(&node(1));
+        cache.remove(Address::from(1));
+        assert!(cache.get(Address::from(1)).is_none());
+
+        cache.insert(&node(2));
+        cache.clear();
+        assert!(cache.get(Address::from(2)).is_none());
+    }
+}
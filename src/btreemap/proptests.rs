@@ -69,6 +69,103 @@ fn comprehensive(#[strategy(pvec(operation_strategy(), 100..5_000))] ops: Vec<Op
     }
 }
 
+// Runs the comprehensive test on a map with order statistics.
+// Ranks and positions are validated against a standard BTreeMap at the end.
+#[proptest(cases = 10)]
+fn comprehensive_counted(#[strategy(pvec(operation_strategy(), 100..5_000))] ops: Vec<Operation>) {
+    let mem = make_memory();
+    let mut btree = BTreeMap::new_counted(mem);
+    let mut std_btree = StdBTreeMap::new();
+
+    for op in ops.into_iter() {
+        execute_operation(&mut std_btree, &mut btree, op);
+    }
+
+    for (i, (key, value)) in std_btree.iter().enumerate() {
+        prop_assert_eq!(btree.nth(i as u64), Some((key.clone(), value.clone())));
+        prop_assert_eq!(btree.rank(key), i as u64);
+    }
+    prop_assert_eq!(btree.nth(std_btree.len() as u64), None);
+}
+
+// Runs the comprehensive test on a map with a small node cache, so that nodes are
+// frequently evicted, and checks the map against one without a cache at the end.
+#[proptest(cases = 10)]
+fn comprehensive_cached(#[strategy(pvec(operation_strategy(), 100..5_000))] ops: Vec<Operation>) {
+    let mem = make_memory();
+    let mut btree = BTreeMap::new(mem.clone()).with_node_cache(4);
+    let mut std_btree = StdBTreeMap::new();
+
+    for op in ops.into_iter() {
+        execute_operation(&mut std_btree, &mut btree, op);
+    }
+
+    let uncached: BTreeMap<Vec<u8>, Vec<u8>, _> = BTreeMap::load(mem);
+    prop_assert!(btree.iter().map(|e| e.into_pair()).eq(std_btree.clone()));
+    prop_assert!(uncached.iter().map(|e| e.into_pair()).eq(std_btree));
+    prop_assert!(btree.node_cache_metrics().hits() > 0);
+}
+
+// Runs the comprehensive test with a compaction making progress between the operations.
+#[proptest(cases = 10)]
+fn comprehensive_compacted(
+    #[strategy(pvec(operation_strategy(), 100..5_000))] ops: Vec<Operation>,
+) {
+    let mem = make_memory();
+    let mut btree = BTreeMap::new(mem);
+    let mut std_btree = StdBTreeMap::new();
+
+    for op in ops.into_iter() {
+        execute_operation(&mut std_btree, &mut btree, op);
+        btree.compact(3);
+    }
+
+    while !btree.compact(100) {}
+    prop_assert_eq!(btree.check_integrity(), Ok(()));
+    prop_assert!(btree.iter().map(|e| e.into_pair()).eq(std_btree));
+}
+
+// Runs the comprehensive test on a map with order statistics, taking snapshots between the
+// operations. The snapshots are checked against copies of the standard BTreeMap taken at the
+// same time, and released in a different order than they were taken.
+#[proptest(cases = 10)]
+fn comprehensive_snapshots(
+    #[strategy(pvec(operation_strategy(), 100..5_000))] ops: Vec<Operation>,
+) {
+    let mem = make_memory();
+    let mut btree = BTreeMap::new_counted(mem);
+    let mut std_btree = StdBTreeMap::new();
+    let mut snapshots = vec![];
+
+    for (i, op) in ops.into_iter().enumerate() {
+        execute_operation(&mut std_btree, &mut btree, op);
+        if i % 200 == 0 {
+            snapshots.push((btree.snapshot(), std_btree.clone()));
+        }
+        if snapshots.len() > 3 {
+            let (snapshot, expected) = snapshots.remove(1);
+            prop_assert!(btree
+                .view(&snapshot)
+                .iter()
+                .map(|e| e.into_pair())
+                .eq(expected));
+            btree.release_snapshot(snapshot);
+        }
+    }
+
+    prop_assert_eq!(btree.check_integrity(), Ok(()));
+    for (snapshot, expected) in snapshots {
+        prop_assert!(btree
+            .view(&snapshot)
+            .iter()
+            .map(|e| e.into_pair())
+            .eq(expected));
+        btree.release_snapshot(snapshot);
+    }
+    prop_assert_eq!(btree.check_integrity(), Ok(()));
+    prop_assert!(btree.iter().map(|e| e.into_pair()).eq(std_btree));
+}
+
 // A comprehensive fuzz test that runs until it's explicitly terminated. To run:
 //
 // ```
@@ -173,6 +270,78 @@ fn iter_count_test(#[strategy(0..250u8)] start: u8, #[strategy(#start..255u8)] e
     });
 }
 
+#[proptest(cases = 10)]
+fn retain(
+    #[strategy(pvec(any::<u64>(), 0..2_000))] keys: Vec<u64>,
+    #[strategy(1..10u64)] modulus: u64,
+) {
+    run_btree_test(|mut map| {
+        let mut std_map = StdBTreeMap::new();
+        for k in keys.iter() {
+            map.insert(*k, *k);
+            std_map.insert(*k, *k);
+        }
+
+        map.retain(|k, v| {
+            assert_eq!(k, v);
+            k % modulus == 0
+        });
+        std_map.retain(|k, _| k % modulus == 0);
+
+        assert_eq!(map.len(), std_map.len() as u64);
+        assert!(map.iter().map(|e| e.into_pair()).eq(std_map.into_iter()));
+    });
+}
+
+#[proptest(cases = 10)]
+fn split_off_and_append(
+    #[strategy(pvec(any::<u64>(), 0..2_000))] keys: Vec<u64>,
+    #[strategy(any::<u64>())] split_key: u64,
+) {
+    run_btree_test(|mut map| {
+        let mut std_map = StdBTreeMap::new();
+        for k in keys.iter() {
+            map.insert(*k, *k);
+            std_map.insert(*k, *k);
+        }
+
+        let mut tail = map.split_off(&split_key, make_memory());
+        let mut std_tail = std_map.split_off(&split_key);
+        assert_eq!(map.len(), std_map.len() as u64);
+        assert_eq!(tail.len(), std_tail.len() as u64);
+        assert!(map.iter().map(|e| e.into_pair()).eq(std_map.clone()));
+        assert!(tail.iter().map(|e| e.into_pair()).eq(std_tail.clone()));
+
+        // Append in the opposite direction to exercise the general case.
+        tail.append(&mut map);
+        std_tail.append(&mut std_map);
+        assert!(map.is_empty());
+        assert!(tail.iter().map(|e| e.into_pair()).eq(std_tail));
+    });
+}
+
+#[proptest(cases = 10)]
+fn remove_range(
+    #[strategy(pvec(0..5_000u64, 0..2_000))] keys: Vec<u64>,
+    #[strategy(0..5_000u64)] start: u64,
+    #[strategy(0..5_000u64)] len: u64,
+) {
+    run_btree_test(|mut map| {
+        let mut std_map = StdBTreeMap::new();
+        for k in keys.iter() {
+            map.insert(*k, *k);
+            std_map.insert(*k, *k);
+        }
+
+        let before = std_map.len();
+        let removed = map.remove_range(start..start + len);
+        std_map.retain(|k, _| !(start..start + len).contains(k));
+        assert_eq!(removed, (before - std_map.len()) as u64);
+        assert_eq!(map.len(), std_map.len() as u64);
+        assert!(map.iter().map(|e| e.into_pair()).eq(std_map));
+    });
+}
+
 #[proptest]
 fn no_memory_leaks(#[strategy(pvec(pvec(0..u8::MAX, 100..10_000), 100))] keys: Vec<Vec<u8>>) {
     let mem = make_memory();
@@ -0,0 +1,336 @@
+use super::BTreeMap;
+use crate::{storable::Bound, Memory, Storable};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Bound as RangeBound, RangeBounds};
+
+/// The order of the keys of a [`RawBTreeMap`], given as a comparison of their encodings.
+///
+/// It must be the order of the key type the map was created with.
+pub trait RawKeyOrder {
+    /// Compares the encodings of two keys.
+    fn cmp(a: &[u8], b: &[u8]) -> Ordering;
+}
+
+/// Keys are ordered as their encodings, byte by byte.
+///
+/// This is the order of the keys of types whose ordering matches their encoding, such as
+/// unsigned integers, `String`, `Vec<u8>`, `Blob` and `Principal`.
+pub enum Lexicographic {}
+
+impl RawKeyOrder for Lexicographic {
+    fn cmp(a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// The encoding of a key of a [`BTreeMap`], ordered by `O`.
+///
+/// Fixed-size keys are stored without their size, so the key type of a map created with
+/// fixed-size keys (e.g. `u64`) must be read with `KEY_SIZE` set to the size of its keys.
+/// A `KEY_SIZE` of 0 reads maps with keys of any other type.
+pub struct RawKey<O = Lexicographic, const KEY_SIZE: u32 = 0> {
+    bytes: Vec<u8>,
+    _phantom: PhantomData<O>,
+}
+
+impl<O, const KEY_SIZE: u32> RawKey<O, KEY_SIZE> {
+    /// Creates a key from its encoding.
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self {
+            bytes,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Returns the encoding of the key.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Returns the encoding of the key.
+    pub fn into_vec(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl<O, const KEY_SIZE: u32> Clone for RawKey<O, KEY_SIZE> {
+    fn clone(&self) -> Self {
+        Self::new(self.bytes.clone())
+    }
+}
+
+impl<O, const KEY_SIZE: u32> fmt::Debug for RawKey<O, KEY_SIZE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.bytes.fmt(f)
+    }
+}
+
+impl<O: RawKeyOrder, const KEY_SIZE: u32> PartialEq for RawKey<O, KEY_SIZE> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<O: RawKeyOrder, const KEY_SIZE: u32> Eq for RawKey<O, KEY_SIZE> {}
+
+impl<O: RawKeyOrder, const KEY_SIZE: u32> PartialOrd for RawKey<O, KEY_SIZE> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<O: RawKeyOrder, const KEY_SIZE: u32> Ord for RawKey<O, KEY_SIZE> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        O::cmp(&self.bytes, &other.bytes)
+    }
+}
+
+impl<O, const KEY_SIZE: u32> Storable for RawKey<O, KEY_SIZE> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.bytes)
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self::new(bytes.into_owned())
+    }
+
+    const BOUND: Bound = if KEY_SIZE == 0 {
+        Bound::Unbounded
+    } else {
+        Bound::Bounded {
+            max_size: KEY_SIZE,
+            is_fixed_size: true,
+        }
+    };
+}
+
+/// A [`BTreeMap`] read and written as bytes, without knowing the types of its keys and
+/// values.
+///
+/// Any map created by this crate can be loaded as a `RawBTreeMap`, given the order of its
+/// keys and, if its keys are fixed-size, their size (see [`RawKey`]). Values are always
+/// stored with their size, so they can be read as `Vec<u8>` regardless of their type.
+///
+/// As with [`BTreeMap::load`], a map in the V1 layout, which stores its keys and values with
+/// their size, is migrated to the V2 layout when it's loaded. Its nodes are rewritten in the
+/// V2 layout as they're modified, with the keys stored according to `KEY_SIZE`, so the
+/// `KEY_SIZE` of a V1 map must also match its key type.
+///
+/// # Example
+///
+/// ```rust
+/// use ic_stable_structures::btreemap::RawBTreeMap;
+/// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+/// use std::ops::Bound;
+///
+/// let mut map: BTreeMap<String, u64, _> = BTreeMap::init(DefaultMemoryImpl::default());
+/// map.insert("a".to_string(), 1);
+/// map.insert("b".to_string(), 2);
+///
+/// // Read and write the map as bytes.
+/// let mut raw: RawBTreeMap<_> = RawBTreeMap::load(map.into_memory());
+/// assert_eq!(raw.get_raw(b"a"), Some(1u64.to_be_bytes().to_vec()));
+/// raw.insert_raw(b"c", &3u64.to_be_bytes());
+/// assert_eq!(
+///     raw.range_raw::<(Bound<&[u8]>, _)>((Bound::Excluded(b"a"), Bound::Unbounded))
+///         .map(|(key, _)| key)
+///         .collect::<Vec<_>>(),
+///     vec![b"b".to_vec(), b"c".to_vec()]
+/// );
+///
+/// let map: BTreeMap<String, u64, _> = BTreeMap::load(raw.into_memory());
+/// assert_eq!(map.get(&"c".to_string()), Some(3));
+/// ```
+pub type RawBTreeMap<M, O = Lexicographic, const KEY_SIZE: u32 = 0> =
+    BTreeMap<RawKey<O, KEY_SIZE>, Vec<u8>, M>;
+
+impl<M, O, const KEY_SIZE: u32> BTreeMap<RawKey<O, KEY_SIZE>, Vec<u8>, M>
+where
+    M: Memory,
+    O: RawKeyOrder,
+{
+    /// Inserts the encodings of a key and a value into the map, returning the encoding of
+    /// the previous value of the key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the map has fixed-size keys and the key doesn't have that size.
+    pub fn insert_raw(&mut self, key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+        RawKey::<O, KEY_SIZE>::check_bounds(key);
+        self.insert_encoded(RawKey::new(key.to_vec()), value.to_vec())
+    }
+
+    /// Returns the encoding of the value of the key with the given encoding.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the map has fixed-size keys and the key doesn't have that size.
+    pub fn get_raw(&self, key: &[u8]) -> Option<Vec<u8>> {
+        RawKey::<O, KEY_SIZE>::check_bounds(key);
+        self.get(&RawKey::new(key.to_vec()))
+    }
+
+    /// Removes the key with the given encoding from the map, returning the encoding of its
+    /// value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the map has fixed-size keys and the key doesn't have that size.
+    pub fn remove_raw(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        RawKey::<O, KEY_SIZE>::check_bounds(key);
+        self.remove(&RawKey::new(key.to_vec()))
+    }
+
+    /// Returns an iterator over the encodings of the entries of the map, sorted by key.
+    pub fn iter_raw(&self) -> impl DoubleEndedIterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        self.range_raw::<(RangeBound<&[u8]>, _)>((RangeBound::Unbounded, RangeBound::Unbounded))
+    }
+
+    /// Returns an iterator over the encodings of the entries of the map whose keys' encodings
+    /// belong to the specified range.
+    pub fn range_raw<R: RangeBounds<[u8]>>(
+        &self,
+        key_range: R,
+    ) -> impl DoubleEndedIterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        let to_key = |bound: RangeBound<&[u8]>| bound.map(|key| RawKey::new(key.to_vec()));
+        self.range((
+            to_key(key_range.start_bound()),
+            to_key(key_range.end_bound()),
+        ))
+        .map(|entry| {
+            let (key, value) = entry.into_pair();
+            (key.into_vec(), value)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storable::Blob;
+    use std::cell::RefCell;
+    use std::cmp::Reverse;
+    use std::rc::Rc;
+
+    fn make_memory() -> Rc<RefCell<Vec<u8>>> {
+        Rc::new(RefCell::new(Vec::new()))
+    }
+
+    #[test]
+    fn raw_map_with_fixed_size_keys() {
+        let mem = make_memory();
+        let mut map: BTreeMap<u64, Blob<10>, _> = BTreeMap::new(mem.clone());
+        for i in 0..500u64 {
+            map.insert(i * 2, Blob::try_from(&i.to_le_bytes()[..]).unwrap());
+        }
+
+        let mut raw: RawBTreeMap<_, Lexicographic, 8> = RawBTreeMap::load(mem.clone());
+        assert_eq!(
+            raw.get_raw(&10u64.to_be_bytes()),
+            Some(5u64.to_le_bytes().to_vec())
+        );
+        assert_eq!(raw.get_raw(&11u64.to_be_bytes()), None);
+        assert!(raw
+            .iter_raw()
+            .map(|(key, _)| u64::from_be_bytes(key.try_into().unwrap()))
+            .eq((0..500).map(|i| i * 2)));
+
+        assert_eq!(raw.insert_raw(&11u64.to_be_bytes(), b"x"), None);
+        assert_eq!(
+            raw.remove_raw(&10u64.to_be_bytes()),
+            Some(5u64.to_le_bytes().to_vec())
+        );
+
+        let map: BTreeMap<u64, Blob<10>, _> = BTreeMap::load(mem);
+        assert_eq!(map.get(&11).unwrap().as_slice(), b"x");
+        assert_eq!(map.get(&10), None);
+        assert_eq!(map.len(), 500);
+    }
+
+    #[test]
+    fn raw_map_of_v1_map() {
+        // A V1 map with fixed-size keys.
+        let mem = make_memory();
+        let mut map: BTreeMap<u64, Blob<10>, _> = BTreeMap::new_v1(mem.clone());
+        for i in 0..500u64 {
+            map.insert(i, Blob::try_from(&i.to_le_bytes()[..]).unwrap());
+        }
+        let mut raw: RawBTreeMap<_, Lexicographic, 8> = RawBTreeMap::load(mem.clone());
+        assert_eq!(
+            raw.get_raw(&10u64.to_be_bytes()),
+            Some(10u64.to_le_bytes().to_vec())
+        );
+        assert_eq!(raw.insert_raw(&500u64.to_be_bytes(), b"x"), None);
+        let map: BTreeMap<u64, Blob<10>, _> = BTreeMap::load(mem);
+        assert_eq!(map.get(&500).unwrap().as_slice(), b"x");
+        assert_eq!(map.len(), 501);
+
+        // A V1 map with keys of varying sizes.
+        let mem = make_memory();
+        let mut map: BTreeMap<Blob<10>, u64, _> = BTreeMap::new_v1(mem.clone());
+        for i in 0..500u64 {
+            map.insert(Blob::try_from(i.to_string().as_bytes()).unwrap(), i);
+        }
+        let mut raw: RawBTreeMap<_> = RawBTreeMap::load(mem.clone());
+        assert_eq!(raw.len(), 500);
+        assert_eq!(raw.insert_raw(b"key", &1u64.to_be_bytes()), None);
+        let map: BTreeMap<Blob<10>, u64, _> = BTreeMap::load(mem);
+        assert_eq!(map.get(&Blob::try_from(&b"key"[..]).unwrap()), Some(1));
+        assert_eq!(map.len(), 501);
+    }
+
+    #[test]
+    fn raw_key_of_wrong_size() {
+        let mut raw: RawBTreeMap<_, Lexicographic, 8> = RawBTreeMap::new(make_memory());
+        raw.insert_raw(&[0; 8], b"value");
+
+        fn assert_panics(f: impl FnOnce()) {
+            let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_err();
+            let message = err.downcast_ref::<String>().unwrap();
+            assert!(message.contains("expected a fixed-size element with length 8 bytes"));
+        }
+        assert_panics(|| {
+            raw.insert_raw(b"key", b"value");
+        });
+        assert_panics(|| {
+            raw.get_raw(b"key");
+        });
+        assert_panics(|| {
+            raw.remove_raw(b"key");
+        });
+        assert_eq!(raw.len(), 1);
+    }
+
+    #[test]
+    fn raw_map_with_custom_order() {
+        enum Descending {}
+        impl RawKeyOrder for Descending {
+            fn cmp(a: &[u8], b: &[u8]) -> Ordering {
+                b.cmp(a)
+            }
+        }
+
+        let mem = make_memory();
+        let mut map: BTreeMap<Reverse<String>, u32, _> = BTreeMap::new(mem.clone());
+        for i in 0..300u32 {
+            map.insert(Reverse(format!("{i:03}")), i);
+        }
+
+        let raw: RawBTreeMap<_, Descending> = RawBTreeMap::load(mem);
+        assert_eq!(raw.get_raw(b"042"), Some(42u32.to_be_bytes().to_vec()));
+        assert!(raw
+            .range_raw::<(RangeBound<&[u8]>, _)>((
+                RangeBound::Included(b"010"),
+                RangeBound::Excluded(b"005"),
+            ))
+            .map(|(key, _)| key)
+            .eq((6..=10).rev().map(|i: u32| format!("{i:03}").into_bytes())));
+    }
+}
@@ -0,0 +1,396 @@
+use super::{
+    iter::{IterInternal, KeysIter, ValuesIter},
+    node::{Node, NodeType},
+    BTreeMap, Iter,
+};
+use crate::{
+    read_struct,
+    types::{Address, NULL},
+    write_struct, Memory, Storable,
+};
+use std::borrow::Cow;
+use std::ops::RangeBounds;
+
+const SNAPSHOT_MAGIC: &[u8; 3] = b"BTS";
+const SNAPSHOT_LAYOUT_VERSION: u8 = 1;
+
+/// A handle to a read-only snapshot of a [`BTreeMap`], taken with [`BTreeMap::snapshot`].
+///
+/// The snapshot is stored in the map's memory, and keeps the nodes it reads from until it's
+/// released with [`BTreeMap::release_snapshot`]. The handles of the snapshots of a map can
+/// be recovered with [`BTreeMap::snapshots`], e.g. after an upgrade.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    // The address of the snapshot's record.
+    pub(super) address: Address,
+}
+
+/// The record of a snapshot, stored in a chunk of the map's allocator.
+#[repr(C, packed)]
+struct SnapshotRecord {
+    magic: [u8; 3],
+    version: u8,
+    // Empty space to memory-align the following fields.
+    _alignment: [u8; 4],
+    root_addr: Address,
+    length: u64,
+    // The address of the next record in the list of snapshots.
+    next: Address,
+}
+
+impl SnapshotRecord {
+    fn load<M: Memory>(address: Address, memory: &M) -> Self {
+        let record: SnapshotRecord = read_struct(address, memory);
+        assert_eq!(&record.magic, SNAPSHOT_MAGIC, "Bad snapshot magic.");
+        assert_eq!(
+            record.version, SNAPSHOT_LAYOUT_VERSION,
+            "Unsupported snapshot version."
+        );
+        record
+    }
+}
+
+/// A read-only view of a snapshot of a [`BTreeMap`], returned by [`BTreeMap::view`].
+///
+/// The view reads the map as it was when the snapshot was taken, regardless of the changes
+/// made to the map since.
+pub struct SnapshotView<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    map: &'a BTreeMap<K, V, M>,
+    // The address of the snapshot's record.
+    address: Address,
+    pub(super) root_addr: Address,
+    length: u64,
+}
+
+impl<'a, K, V, M> SnapshotView<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    /// Returns the value for the given key, if it exists.
+    pub fn get(&self, key: &K) -> Option<V> {
+        if self.root_addr == NULL {
+            return None;
+        }
+        self.map
+            .traverse(self.root_addr, key, |node, idx| {
+                node.extract_entry_at(idx, self.map.memory()).1
+            })
+            .map(Cow::Owned)
+            .map(V::from_bytes)
+    }
+
+    /// Returns true if the key exists.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.root_addr != NULL && self.map.traverse(self.root_addr, key, |_, _| ()).is_some()
+    }
+
+    /// Returns `true` if the snapshot contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Returns the number of elements in the snapshot.
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    /// Returns the first key-value pair in the snapshot.
+    pub fn first_key_value(&self) -> Option<(K, V)> {
+        if self.root_addr == NULL {
+            return None;
+        }
+        let root = self.map.load_node(self.root_addr);
+        let (k, encoded_v) = root.get_min(self.map.memory());
+        Some((k, V::from_bytes(Cow::Owned(encoded_v))))
+    }
+
+    /// Returns the last key-value pair in the snapshot.
+    pub fn last_key_value(&self) -> Option<(K, V)> {
+        if self.root_addr == NULL {
+            return None;
+        }
+        let root = self.map.load_node(self.root_addr);
+        let (k, encoded_v) = root.get_max(self.map.memory());
+        Some((k, V::from_bytes(Cow::Owned(encoded_v))))
+    }
+
+    /// Returns an iterator over the entries of the snapshot, sorted by key.
+    pub fn iter(&self) -> Iter<'a, K, V, M> {
+        self.range_internal(..).into()
+    }
+
+    /// Returns an iterator over the entries of the snapshot which belong to the specified
+    /// range.
+    pub fn range(&self, key_range: impl RangeBounds<K>) -> Iter<'a, K, V, M> {
+        self.range_internal(key_range).into()
+    }
+
+    /// Returns an iterator over the keys of the snapshot.
+    pub fn keys(&self) -> KeysIter<'a, K, V, M> {
+        self.range_internal(..).into()
+    }
+
+    /// Returns an iterator over the values of the snapshot, sorted by key.
+    pub fn values(&self) -> ValuesIter<'a, K, V, M> {
+        self.range_internal(..).into()
+    }
+
+    fn range_internal(&self, key_range: impl RangeBounds<K>) -> IterInternal<'a, K, V, M> {
+        let range = (
+            key_range.start_bound().cloned(),
+            key_range.end_bound().cloned(),
+        );
+        IterInternal::new_in_snapshot(self.map, self.address, self.root_addr, range)
+    }
+}
+
+impl<K, V, M> BTreeMap<K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    /// Takes a read-only snapshot of the map, which can be read with [`view`](Self::view)
+    /// while the map keeps being modified.
+    ///
+    /// Taking a snapshot is cheap: the snapshot shares all its nodes with the map. Once a
+    /// snapshot is taken, modifying the map writes copies of the nodes it changes rather
+    /// than overwriting them, so a modification also copies the nodes on its path that are
+    /// still shared with a snapshot. The nodes that are only used by a snapshot are
+    /// deallocated when it's released with [`release_snapshot`](Self::release_snapshot),
+    /// so every snapshot must eventually be released to avoid leaking memory.
+    ///
+    /// While the map has snapshots, [`remove_range`](Self::remove_range),
+    /// [`split_off`](Self::split_off) and [`append`](Self::append) modify the map one
+    /// entry at a time, and [`compact`](Self::compact) makes no progress.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::init(DefaultMemoryImpl::default());
+    ///
+    /// for i in 0..100 {
+    ///     map.insert(i, i);
+    /// }
+    ///
+    /// let snapshot = map.snapshot();
+    /// map.remove_range(..50);
+    /// map.insert(0, 1);
+    ///
+    /// let view = map.view(&snapshot);
+    /// assert_eq!(view.len(), 100);
+    /// assert_eq!(view.get(&0), Some(0));
+    /// assert!(view.iter().map(|e| e.into_pair()).eq((0..100).map(|i| (i, i))));
+    ///
+    /// map.release_snapshot(snapshot);
+    /// ```
+    pub fn snapshot(&mut self) -> Snapshot {
+        let address = self.allocator.allocate();
+        write_struct(
+            &SnapshotRecord {
+                magic: *SNAPSHOT_MAGIC,
+                version: SNAPSHOT_LAYOUT_VERSION,
+                _alignment: [0; 4],
+                root_addr: self.root_addr,
+                length: self.length,
+                next: self.snapshots,
+            },
+            address,
+            self.memory(),
+        );
+
+        if self.root_addr != NULL {
+            self.allocator.add_reference(self.root_addr);
+        }
+
+        self.snapshots = address;
+        self.save_header_extension();
+        Snapshot { address }
+    }
+
+    /// Returns a read-only view of the map as it was when the given snapshot was taken.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the snapshot isn't a snapshot of this map.
+    pub fn view(&self, snapshot: &Snapshot) -> SnapshotView<'_, K, V, M> {
+        assert!(
+            self.snapshots().any(|s| s == *snapshot),
+            "Unknown snapshot."
+        );
+        let record = SnapshotRecord::load(snapshot.address, self.memory());
+        SnapshotView {
+            map: self,
+            address: snapshot.address,
+            root_addr: record.root_addr,
+            length: record.length,
+        }
+    }
+
+    /// Releases the given snapshot, deallocating the nodes only it uses.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the snapshot isn't a snapshot of this map.
+    pub fn release_snapshot(&mut self, snapshot: Snapshot) {
+        // Unlink the snapshot's record from the list of snapshots.
+        let mut prev = None;
+        let mut current = self.snapshots;
+        while current != snapshot.address {
+            assert!(current != NULL, "Unknown snapshot.");
+            prev = Some(current);
+            current = SnapshotRecord::load(current, self.memory()).next;
+        }
+        let record = SnapshotRecord::load(snapshot.address, self.memory());
+        match prev {
+            Some(prev) => {
+                let mut prev_record = SnapshotRecord::load(prev, self.memory());
+                prev_record.next = record.next;
+                write_struct(&prev_record, prev, self.memory());
+            }
+            None => {
+                self.snapshots = record.next;
+                self.save_header_extension();
+            }
+        }
+        self.allocator.deallocate(snapshot.address);
+
+        if record.root_addr != NULL {
+            self.release_subtree(record.root_addr);
+        }
+    }
+
+    /// Returns the handles of the map's snapshots, from the most recent to the oldest.
+    pub fn snapshots(&self) -> impl Iterator<Item = Snapshot> + '_ {
+        let mut address = self.snapshots;
+        std::iter::from_fn(move || {
+            if address == NULL {
+                return None;
+            }
+            let snapshot = Snapshot { address };
+            address = SnapshotRecord::load(address, self.memory()).next;
+            Some(snapshot)
+        })
+    }
+
+    /// Returns the address of the record of each snapshot of the map, along with the root
+    /// and the length of the snapshot's tree.
+    pub(super) fn snapshot_records(&self) -> Vec<(Address, Address, u64)> {
+        self.snapshots()
+            .map(|s| {
+                let record = SnapshotRecord::load(s.address, self.memory());
+                (s.address, record.root_addr, record.length)
+            })
+            .collect()
+    }
+
+    /// Returns true if the map may share nodes with snapshots, or with the tree that the
+    /// ongoing transaction started from.
+    pub(super) fn has_shared_nodes(&self) -> bool {
+        self.snapshots != NULL || self.committed.is_some()
+    }
+
+    /// Copies the nodes that can be modified by inserting or removing the given key if
+    /// they're shared, so that they can be modified in place.
+    ///
+    /// An insertion only modifies the nodes on the path to the key. A removal can also
+    /// modify the nodes next to them on each level: rotations and merges modify a node's
+    /// siblings, and after a merge, the children of a sibling become neighbors of the path.
+    /// If the key is in an internal node, the paths to its predecessor and successor are
+    /// modified as well. So the nodes that are copied on each level are the ones containing
+    /// the key or its predecessor or successor, along with their neighbors on that level.
+    pub(super) fn unshare_path(&mut self, key: &K) {
+        if !self.has_shared_nodes() || self.root_addr == NULL {
+            return;
+        }
+
+        let mut root = self.load_node(self.root_addr);
+        if self.unshare_node(&mut root) {
+            self.root_addr = root.address();
+            self.save_header();
+        }
+
+        // The nodes of the current level that are unshared, in key order, and the indices
+        // of the ones on the path to the key.
+        let mut level = vec![root];
+        let mut path = vec![0];
+        while level[0].node_type() == NodeType::Internal {
+            // The children of the level's nodes in key order, as the index of their parent in
+            // the level along with their index in the parent.
+            let mut children = vec![];
+            let mut next_path = vec![];
+            for (i, node) in level.iter().enumerate() {
+                if path.contains(&i) {
+                    match node.search(key, self.memory()) {
+                        Err(idx) => next_path.push(children.len() + idx),
+                        Ok(idx) => {
+                            next_path.push(children.len() + idx);
+                            next_path.push(children.len() + idx + 1);
+                        }
+                    }
+                }
+                children.extend((0..node.children_len()).map(|idx| (i, idx)));
+            }
+
+            let first = next_path[0].saturating_sub(1);
+            let last = (next_path[next_path.len() - 1] + 1).min(children.len() - 1);
+            let mut next_level = Vec::with_capacity(last - first + 1);
+            for &(parent, idx) in &children[first..=last] {
+                let mut child = self.load_node(level[parent].child(idx));
+                if self.unshare_node(&mut child) {
+                    let (_, count) = level[parent].remove_child(idx);
+                    level[parent].insert_child(idx, child.address(), count);
+                    self.save_node(&mut level[parent]);
+                }
+                next_level.push(child);
+            }
+
+            level = next_level;
+            path = next_path.into_iter().map(|i| i - first).collect();
+        }
+    }
+
+    /// Replaces the node with a copy if it's shared, returning true if it was copied. The
+    /// parent of the node must then be updated to point to the copy.
+    fn unshare_node(&mut self, node: &mut Node<K>) -> bool {
+        if !self.allocator.is_shared(node.address()) {
+            return false;
+        }
+
+        let address = self.allocator.allocate();
+        let old_address = node.address();
+        node.detach(address, self.memory());
+        self.save_node(node);
+
+        // The copy takes over one of the references to the node, and adds a reference to
+        // each of its children.
+        self.allocator.release(old_address);
+        for i in 0..node.children_len() {
+            self.allocator.add_reference(node.child(i));
+        }
+        true
+    }
+
+    /// Releases a reference to the subtree with the given root, deallocating the nodes
+    /// that are no longer referenced.
+    pub(super) fn release_subtree(&mut self, address: Address) {
+        if self.allocator.is_shared(address) {
+            self.allocator.release(address);
+            return;
+        }
+
+        let node = self.load_node(address);
+        for i in 0..node.children_len() {
+            self.release_subtree(node.child(i));
+        }
+        self.deallocate_node(node);
+    }
+}
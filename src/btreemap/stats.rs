@@ -0,0 +1,95 @@
+use super::{
+    node::{Node, NodeType, CAPACITY},
+    BTreeMap,
+};
+use crate::{types::NULL, Memory, Storable};
+
+/// Statistics about the structure and memory usage of a [`BTreeMap`], returned by
+/// [`BTreeMap::stats`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BTreeMapStats {
+    /// The number of levels in the tree, which is zero for an empty map.
+    pub depth: u64,
+    /// The number of internal nodes.
+    pub internal_nodes: u64,
+    /// The number of leaf nodes.
+    pub leaf_nodes: u64,
+    /// The average number of entries in a node, as a fraction of the node's capacity.
+    /// Zero for an empty map.
+    pub fill_factor: f64,
+    /// The number of overflow pages used by nodes that don't fit in a single page.
+    pub overflow_pages: u64,
+    /// The number of chunks allocated by the map's allocator, i.e. the nodes and their
+    /// overflow pages.
+    pub allocated_chunks: u64,
+    /// The number of chunks that were deallocated and are available for reuse.
+    pub free_chunks: u64,
+    /// The total size of the keys in bytes.
+    pub key_bytes: u64,
+    /// The total size of the values in bytes.
+    pub value_bytes: u64,
+}
+
+impl<K, V, M> BTreeMap<K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    /// Returns statistics about the structure and memory usage of the map.
+    ///
+    /// This reads every node of the tree, so its cost is linear in the number of nodes.
+    /// Keys and values aren't decoded, except for small keys that nodes load eagerly.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::init(DefaultMemoryImpl::default());
+    ///
+    /// for i in 0..100 {
+    ///     map.insert(i, i);
+    /// }
+    ///
+    /// let stats = map.stats();
+    /// assert_eq!(stats.depth, 3);
+    /// assert_eq!(stats.key_bytes, 800);
+    /// assert_eq!(stats.value_bytes, 800);
+    /// ```
+    pub fn stats(&self) -> BTreeMapStats {
+        let mut stats = BTreeMapStats {
+            allocated_chunks: self.allocator.num_allocated_chunks(),
+            free_chunks: self.allocator.num_free_chunks(),
+            ..Default::default()
+        };
+
+        if self.root_addr == NULL {
+            return stats;
+        }
+
+        let mut entries = 0;
+        let mut stack = vec![(self.root_addr, 1)];
+        while let Some((address, depth)) = stack.pop() {
+            // Bypass the node cache so that the walk doesn't evict the nodes in use.
+            let node = Node::<K>::load(address, self.version.page_size(), self.memory());
+
+            stats.depth = stats.depth.max(depth);
+            stats.overflow_pages += node.overflows().len() as u64;
+            stats.key_bytes += node.keys_size();
+            stats.value_bytes += node.values_size();
+            entries += node.entries_len() as u64;
+
+            match node.node_type() {
+                NodeType::Leaf => stats.leaf_nodes += 1,
+                NodeType::Internal => {
+                    stats.internal_nodes += 1;
+                    stack.extend((0..node.children_len()).map(|i| (node.child(i), depth + 1)));
+                }
+            }
+        }
+
+        let nodes = stats.internal_nodes + stats.leaf_nodes;
+        stats.fill_factor = entries as f64 / (nodes * CAPACITY as u64) as f64;
+        stats
+    }
+}
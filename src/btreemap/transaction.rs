@@ -0,0 +1,309 @@
+use super::{BTreeMap, Entry, Iter};
+use crate::{
+    read_struct,
+    types::{Address, NULL},
+    write_struct, Memory, Storable,
+};
+use std::ops::RangeBounds;
+
+const TRANSACTION_MAGIC: &[u8; 3] = b"BTT";
+const TRANSACTION_LAYOUT_VERSION: u8 = 1;
+
+/// A transaction on a [`BTreeMap`], started with [`BTreeMap::transaction`].
+///
+/// The changes made through a transaction are only applied to the map when the transaction
+/// is committed. A transaction that is dropped without being committed, e.g. because of a
+/// panic, is rolled back.
+pub struct Transaction<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    map: &'a mut BTreeMap<K, V, M>,
+}
+
+/// The tree that an ongoing transaction started from, which stays saved in the header until
+/// the transaction is committed.
+pub(super) struct Committed {
+    pub(super) root_addr: Address,
+    pub(super) length: u64,
+    // The address of the transaction's record.
+    record: Address,
+}
+
+/// The record of an ongoing transaction, stored in a chunk of the map's allocator.
+///
+/// While the transaction is in progress, the record is saved in the header in place of the
+/// list of snapshots, which follows it, so that a transaction interrupted by a crash can be
+/// undone when the map is loaded.
+#[repr(C, packed)]
+struct TransactionRecord {
+    magic: [u8; 3],
+    version: u8,
+    // Empty space to memory-align the following fields.
+    _alignment: [u8; 4],
+    // The root of the tree the transaction started from.
+    committed_root: Address,
+    // The root of the tree the transaction modifies.
+    root_addr: Address,
+    // The address of the first record in the list of snapshots.
+    next: Address,
+}
+
+impl<'a, K, V, M> Transaction<'a, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    fn new(map: &'a mut BTreeMap<K, V, M>) -> Self {
+        // The map keeps a reference to the tree it starts from, so that the nodes the
+        // transaction modifies are copied rather than overwritten.
+        if map.root_addr != NULL {
+            map.allocator.add_reference(map.root_addr);
+        }
+        map.committed = Some(Committed {
+            root_addr: map.root_addr,
+            length: map.length,
+            record: map.allocator.allocate(),
+        });
+        map.save_transaction_record();
+        map.save_header_extension();
+        Self { map }
+    }
+
+    fn commit(self) {
+        let committed = self
+            .map
+            .committed
+            .take()
+            .expect("a transaction in progress");
+
+        // Saving the header is what applies the transaction to the map. The record is only
+        // removed from the header afterwards, and the tree the transaction started from is
+        // only released once it is, so that a crash doesn't release it twice.
+        self.map.save_header();
+        self.map.save_header_extension();
+        self.map.allocator.deallocate(committed.record);
+        if committed.root_addr != NULL {
+            self.map.release_subtree(committed.root_addr);
+        }
+    }
+
+    /// Inserts a key-value pair into the map, returning the previous value of the key.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.map.insert(key, value)
+    }
+
+    /// Removes a key from the map, returning the previous value at the key if it exists.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.map.remove(key)
+    }
+
+    /// Removes all the entries with keys in the given range, returning the number of
+    /// removed entries.
+    pub fn remove_range(&mut self, key_range: impl RangeBounds<K>) -> u64 {
+        self.map.remove_range(key_range)
+    }
+
+    /// Removes and returns the first element in the map.
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        self.map.pop_first()
+    }
+
+    /// Removes and returns the last element in the map.
+    pub fn pop_last(&mut self) -> Option<(K, V)> {
+        self.map.pop_last()
+    }
+
+    /// Gets the given key's corresponding entry in the map for in-place manipulation.
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, M> {
+        self.map.entry(key)
+    }
+
+    /// Returns the value for the given key, including the changes made by the transaction.
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.map.get(key)
+    }
+
+    /// Returns true if the key exists.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// Returns `true` if the map contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Returns the number of elements in the map.
+    pub fn len(&self) -> u64 {
+        self.map.len()
+    }
+
+    /// Returns the first key-value pair in the map.
+    pub fn first_key_value(&self) -> Option<(K, V)> {
+        self.map.first_key_value()
+    }
+
+    /// Returns the last key-value pair in the map.
+    pub fn last_key_value(&self) -> Option<(K, V)> {
+        self.map.last_key_value()
+    }
+
+    /// Returns an iterator over the entries of the map, sorted by key.
+    pub fn iter(&self) -> Iter<'_, K, V, M> {
+        self.map.iter()
+    }
+
+    /// Returns an iterator over the entries of the map which belong to the specified range.
+    pub fn range(&self, key_range: impl RangeBounds<K>) -> Iter<'_, K, V, M> {
+        self.map.range(key_range)
+    }
+}
+
+impl<K, V, M> Drop for Transaction<'_, K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    fn drop(&mut self) {
+        // Roll back the transaction if it wasn't committed.
+        if let Some(committed) = self.map.committed.take() {
+            self.map.save_header_extension();
+            self.map.allocator.deallocate(committed.record);
+            if self.map.root_addr != NULL {
+                self.map.release_subtree(self.map.root_addr);
+            }
+            self.map.root_addr = committed.root_addr;
+            self.map.length = committed.length;
+        }
+    }
+}
+
+impl<K, V, M> BTreeMap<K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    /// Runs `f` as a transaction on the map: the changes it makes are applied if it returns
+    /// `Ok`, and rolled back if it returns `Err` or panics.
+    ///
+    /// The nodes modified by the transaction are copied rather than overwritten, as with
+    /// [snapshots](Self::snapshot), and the map's header keeps pointing to the tree the
+    /// transaction started from until the transaction is committed. So the map saved in
+    /// memory is either the one before the transaction or the one after it, even if the
+    /// process crashes in the middle of the transaction, e.g. when using a
+    /// [`FileMemory`](crate::FileMemory). The transaction is also recorded in the header,
+    /// and loading a map whose transaction was interrupted by a crash between two of its
+    /// operations releases the memory the transaction allocated.
+    ///
+    /// As with snapshots, [`remove_range`](Transaction::remove_range) modifies the map one
+    /// entry at a time within a transaction.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeMap, DefaultMemoryImpl};
+    /// let mut map: BTreeMap<u64, u64, _> = BTreeMap::init(DefaultMemoryImpl::default());
+    /// map.insert(1, 100);
+    ///
+    /// // Move 30 from the entry of key 1 to the entry of key 2.
+    /// let transfer = map.transaction(|tx| {
+    ///     let from = tx.remove(&1).unwrap_or(0);
+    ///     tx.insert(2, 30);
+    ///     if from < 30 {
+    ///         return Err("insufficient funds");
+    ///     }
+    ///     tx.insert(1, from - 30);
+    ///     Ok(())
+    /// });
+    /// assert_eq!(transfer, Ok(()));
+    /// assert!(map.iter().map(|e| e.into_pair()).eq([(1, 70), (2, 30)]));
+    ///
+    /// // A failed transaction leaves the map unchanged.
+    /// let transfer = map.transaction(|tx| {
+    ///     tx.insert(3, 100);
+    ///     tx.remove(&1);
+    ///     Err::<(), _>("aborted")
+    /// });
+    /// assert_eq!(transfer, Err("aborted"));
+    /// assert!(map.iter().map(|e| e.into_pair()).eq([(1, 70), (2, 30)]));
+    /// ```
+    pub fn transaction<T, E, F>(&mut self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut Transaction<'_, K, V, M>) -> Result<T, E>,
+    {
+        let mut tx = Transaction::new(self);
+        let result = f(&mut tx);
+        if result.is_ok() {
+            tx.commit();
+        }
+        result
+    }
+
+    /// Returns the address saved in the header in place of the list of snapshots, which is
+    /// the address of the record of the ongoing transaction, if any.
+    pub(super) fn saved_snapshots(&self) -> Address {
+        self.committed
+            .as_ref()
+            .map_or(self.snapshots, |committed| committed.record)
+    }
+
+    /// Saves the record of the ongoing transaction, which holds the root of the tree the
+    /// transaction modifies.
+    pub(super) fn save_transaction_record(&self) {
+        if let Some(committed) = &self.committed {
+            write_struct(
+                &TransactionRecord {
+                    magic: *TRANSACTION_MAGIC,
+                    version: TRANSACTION_LAYOUT_VERSION,
+                    _alignment: [0; 4],
+                    committed_root: committed.root_addr,
+                    root_addr: self.root_addr,
+                    next: self.snapshots,
+                },
+                committed.record,
+                self.memory(),
+            );
+        }
+    }
+
+    /// Undoes the transaction that was in progress when the map was saved, which happens
+    /// if the process crashed in the middle of a transaction.
+    pub(super) fn recover_transaction(&mut self) {
+        if self.snapshots == NULL {
+            return;
+        }
+        let record: TransactionRecord = read_struct(self.snapshots, self.memory());
+        if &record.magic != TRANSACTION_MAGIC {
+            return;
+        }
+        assert_eq!(
+            record.version, TRANSACTION_LAYOUT_VERSION,
+            "Unsupported transaction version."
+        );
+
+        // As when committing, the record is removed from the header before a tree is
+        // released.
+        let address = self.snapshots;
+        self.snapshots = record.next;
+        self.save_header_extension();
+        self.allocator.deallocate(address);
+
+        // The header holds the tree the transaction modifies if the crash happened while
+        // committing it, and the tree the transaction started from otherwise. The other
+        // tree is released.
+        let released = if self.root_addr == record.root_addr {
+            record.committed_root
+        } else {
+            record.root_addr
+        };
+        if released != NULL {
+            self.release_subtree(released);
+        }
+    }
+}
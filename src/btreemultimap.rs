@@ -0,0 +1,590 @@
+//! This module implements a multimap based on a B-Tree in stable memory.
+
+use crate::{btreemap::Iter as IterMap, storable::Bound, BTreeMap, Memory, Storable};
+use std::borrow::Cow;
+use std::ops::Bound as RangeBound;
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// The order of the values of each key in a [`BTreeMultimap`], which is either
+/// [`InsertionOrder`] or [`SortedValues`].
+pub trait ValueOrder<V: Storable>: private::Sealed {
+    /// The part of the keys of the underlying [`BTreeMap`] that comes after the multimap's
+    /// key. It orders the values of a key, and tells equal values apart.
+    type Suffix: Storable + Ord + Clone;
+
+    /// The type of the values of the underlying [`BTreeMap`].
+    type Stored: Storable;
+
+    /// Splits a value into the suffix and the stored value of its entry, given the value's
+    /// sequence number among the equal values of its key.
+    fn split(value: V, seq: u64) -> (Self::Suffix, Self::Stored);
+
+    /// Returns the value of an entry.
+    fn join(suffix: Self::Suffix, stored: Self::Stored) -> V;
+
+    /// Returns the sequence number of an entry.
+    fn seq(suffix: &Self::Suffix) -> u64;
+
+    /// Returns the smallest and the largest suffixes of the entries that can hold the given
+    /// value, or any value if `value` is `None`.
+    fn bounds(value: Option<&V>) -> (Self::Suffix, Self::Suffix);
+}
+
+/// The values of each key of a [`BTreeMultimap`] are kept in the order they were inserted.
+pub enum InsertionOrder {}
+
+impl private::Sealed for InsertionOrder {}
+
+impl<V: Storable> ValueOrder<V> for InsertionOrder {
+    type Suffix = u64;
+    type Stored = V;
+
+    fn split(value: V, seq: u64) -> (u64, V) {
+        (seq, value)
+    }
+
+    fn join(_seq: u64, value: V) -> V {
+        value
+    }
+
+    fn seq(seq: &u64) -> u64 {
+        *seq
+    }
+
+    fn bounds(_value: Option<&V>) -> (u64, u64) {
+        (0, u64::MAX)
+    }
+}
+
+/// The values of each key of a [`BTreeMultimap`] are kept sorted, and equal values are kept
+/// in the order they were inserted.
+pub enum SortedValues {}
+
+impl private::Sealed for SortedValues {}
+
+impl<V: Storable + Ord + Clone> ValueOrder<V> for SortedValues {
+    type Suffix = SortedSuffix<V>;
+    type Stored = ();
+
+    fn split(value: V, seq: u64) -> (SortedSuffix<V>, ()) {
+        (SortedSuffix::Value(value, seq), ())
+    }
+
+    fn join(suffix: SortedSuffix<V>, _: ()) -> V {
+        match suffix {
+            SortedSuffix::Value(value, _) => value,
+            _ => unreachable!("the bounds of a range are never stored"),
+        }
+    }
+
+    fn seq(suffix: &SortedSuffix<V>) -> u64 {
+        match suffix {
+            SortedSuffix::Value(_, seq) => *seq,
+            _ => unreachable!("the bounds of a range are never stored"),
+        }
+    }
+
+    fn bounds(value: Option<&V>) -> (SortedSuffix<V>, SortedSuffix<V>) {
+        match value {
+            Some(value) => (
+                SortedSuffix::Value(value.clone(), 0),
+                SortedSuffix::Value(value.clone(), u64::MAX),
+            ),
+            None => (SortedSuffix::First, SortedSuffix::Last),
+        }
+    }
+}
+
+/// The suffix of the keys of a [`BTreeMultimap`] whose values are [sorted](SortedValues).
+///
+/// Only values are stored: `First` and `Last` are the bounds of the range of the values of
+/// a key.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SortedSuffix<V> {
+    First,
+    Value(V, u64),
+    Last,
+}
+
+const FIRST_TAG: u8 = 0;
+const VALUE_TAG: u8 = 1;
+const LAST_TAG: u8 = 2;
+
+impl<V: Storable> Storable for SortedSuffix<V> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(match self {
+            Self::First => vec![FIRST_TAG],
+            Self::Value(value, seq) => {
+                let mut bytes = vec![VALUE_TAG];
+                bytes.extend_from_slice(&seq.to_be_bytes());
+                bytes.extend_from_slice(&value.to_bytes());
+                bytes
+            }
+            Self::Last => vec![LAST_TAG],
+        })
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match bytes[0] {
+            FIRST_TAG => Self::First,
+            VALUE_TAG => Self::Value(
+                V::from_bytes(Cow::Borrowed(&bytes[9..])),
+                u64::from_be_bytes(bytes[1..9].try_into().unwrap()),
+            ),
+            LAST_TAG => Self::Last,
+            tag => panic!("Invalid sorted suffix tag {tag}."),
+        }
+    }
+
+    const BOUND: Bound = match V::BOUND {
+        Bound::Bounded { max_size, .. } => Bound::Bounded {
+            max_size: max_size + 9,
+            is_fixed_size: false,
+        },
+        Bound::Unbounded => Bound::Unbounded,
+    };
+}
+
+/// A key of the map underlying a [`BTreeMultimap`]: a key of the multimap followed by the
+/// suffix of one of its values.
+///
+/// The key is encoded first, along with its length, so that the entries are ordered by key
+/// and then by suffix, and keys of unbounded types are supported.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Slot<K, S> {
+    key: K,
+    suffix: S,
+}
+
+impl<K: Storable, S: Storable> Storable for Slot<K, S> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let key = self.key.to_bytes();
+        let mut bytes = Vec::with_capacity(4 + key.len());
+        bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&key);
+        bytes.extend_from_slice(&self.suffix.to_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let key_len = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+        Self {
+            key: K::from_bytes(Cow::Borrowed(&bytes[4..4 + key_len])),
+            suffix: S::from_bytes(Cow::Borrowed(&bytes[4 + key_len..])),
+        }
+    }
+
+    const BOUND: Bound = match (K::BOUND, S::BOUND) {
+        (
+            Bound::Bounded {
+                max_size: key_size, ..
+            },
+            Bound::Bounded {
+                max_size: suffix_size,
+                ..
+            },
+        ) => Bound::Bounded {
+            max_size: 4 + key_size + suffix_size,
+            is_fixed_size: false,
+        },
+        _ => Bound::Unbounded,
+    };
+}
+
+/// An iterator over the values of a key of a [`BTreeMultimap`], created with
+/// [`BTreeMultimap::get_all`].
+pub struct Values<'a, K, V, M, O>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+    O: ValueOrder<V>,
+{
+    iter: IterMap<'a, Slot<K, O::Suffix>, O::Stored, M>,
+}
+
+impl<K, V, M, O> Iterator for Values<'_, K, V, M, O>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+    O: ValueOrder<V>,
+{
+    type Item = V;
+
+    fn next(&mut self) -> Option<V> {
+        self.iter
+            .next()
+            .map(|entry| O::join(entry.key().suffix.clone(), entry.value()))
+    }
+
+    fn count(self) -> usize {
+        self.iter.count()
+    }
+}
+
+impl<K, V, M, O> DoubleEndedIterator for Values<'_, K, V, M, O>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+    O: ValueOrder<V>,
+{
+    fn next_back(&mut self) -> Option<V> {
+        self.iter
+            .next_back()
+            .map(|entry| O::join(entry.key().suffix.clone(), entry.value()))
+    }
+}
+
+/// An iterator over the entries of a [`BTreeMultimap`], created with
+/// [`BTreeMultimap::iter`].
+pub struct Iter<'a, K, V, M, O>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+    O: ValueOrder<V>,
+{
+    iter: IterMap<'a, Slot<K, O::Suffix>, O::Stored, M>,
+}
+
+impl<K, V, M, O> Iterator for Iter<'_, K, V, M, O>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+    O: ValueOrder<V>,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        self.iter.next().map(|entry| {
+            let Slot { key, suffix } = entry.key().clone();
+            (key, O::join(suffix, entry.value()))
+        })
+    }
+}
+
+/// A B-Tree multimap that stores its data into a designated memory.
+///
+/// # Overview
+///
+/// A `BTreeMultimap` maps each key to any number of values, which may be equal. It's built
+/// on a [`BTreeMap`] whose keys are the multimap's keys followed by a suffix that orders the
+/// values of each key, so the values of a key are stored next to each other, and the
+/// operations on a key cost `O(log n)`, plus the number of values visited.
+///
+/// The values of each key are kept in the order they were inserted, or, if the multimap is
+/// created with [`SortedValues`] as its value order, sorted. The order is part of the
+/// multimap's type, and a memory must always be loaded with the order it was created with.
+///
+/// The underlying map supports order statistics, so that [`count`](Self::count) doesn't
+/// visit the values of the key.
+///
+/// # Examples
+///
+/// ```rust
+/// use ic_stable_structures::{BTreeMultimap, DefaultMemoryImpl};
+/// use ic_stable_structures::btreemultimap::SortedValues;
+///
+/// let mut multimap: BTreeMultimap<u64, String, _> =
+///     BTreeMultimap::new(DefaultMemoryImpl::default());
+/// multimap.insert(1, "b".to_string());
+/// multimap.insert(1, "a".to_string());
+/// multimap.insert(2, "c".to_string());
+/// assert_eq!(multimap.get_all(&1).collect::<Vec<_>>(), vec!["b", "a"]);
+///
+/// let mut sorted: BTreeMultimap<u64, String, _, SortedValues> =
+///     BTreeMultimap::new(DefaultMemoryImpl::default());
+/// sorted.insert(1, "b".to_string());
+/// sorted.insert(1, "a".to_string());
+/// assert_eq!(sorted.get_all(&1).collect::<Vec<_>>(), vec!["a", "b"]);
+/// ```
+pub struct BTreeMultimap<K, V, M, O = InsertionOrder>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+    O: ValueOrder<V>,
+{
+    map: BTreeMap<Slot<K, O::Suffix>, O::Stored, M>,
+}
+
+impl<K, V, M, O> BTreeMultimap<K, V, M, O>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+    O: ValueOrder<V>,
+{
+    /// Initializes a `BTreeMultimap`.
+    ///
+    /// If the memory provided already contains a `BTreeMultimap`, then that multimap is
+    /// loaded. Otherwise, a new `BTreeMultimap` instance is created.
+    pub fn init(memory: M) -> Self {
+        Self {
+            map: BTreeMap::init_counted(memory),
+        }
+    }
+
+    /// Creates a new instance of a `BTreeMultimap`.
+    pub fn new(memory: M) -> Self {
+        Self {
+            map: BTreeMap::new_counted(memory),
+        }
+    }
+
+    /// Loads the `BTreeMultimap` from memory.
+    pub fn load(memory: M) -> Self {
+        Self {
+            map: BTreeMap::load(memory),
+        }
+    }
+
+    /// Adds a value to the values of the key. Equal values can be added to a key more than
+    /// once.
+    ///
+    /// # Complexity
+    /// O(log n), where n is the number of values in the multimap.
+    pub fn insert(&mut self, key: K, value: V) {
+        // The value comes after the equal values of the key.
+        let seq = self
+            .range(&key, Some(&value))
+            .next_back()
+            .map_or(0, |entry| {
+                O::seq(&entry.key().suffix)
+                    .checked_add(1)
+                    .expect("Too many values inserted for a key.")
+            });
+        let (suffix, stored) = O::split(value, seq);
+        self.map.insert(Slot { key, suffix }, stored);
+    }
+
+    /// Returns an iterator over the values of the key.
+    ///
+    /// # Complexity
+    /// O(log n + m), where n is the number of values in the multimap, and m the number of
+    /// values of the key.
+    pub fn get_all(&self, key: &K) -> Values<'_, K, V, M, O> {
+        Values {
+            iter: self.range(key, None),
+        }
+    }
+
+    /// Returns the first value of the key, if any.
+    pub fn get_first(&self, key: &K) -> Option<V> {
+        self.get_all(key).next()
+    }
+
+    /// Returns `true` if the key has at least one value.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.range(key, None).next().is_some()
+    }
+
+    /// Returns the number of values of the key.
+    ///
+    /// # Complexity
+    /// O(log n), where n is the number of values in the multimap.
+    pub fn count(&self, key: &K) -> u64 {
+        self.range(key, None).count() as u64
+    }
+
+    /// Removes the first of the values of the key that are equal to the given value.
+    /// Returns `true` if a value was removed.
+    ///
+    /// Values are compared by their encoding. With [`InsertionOrder`], the values of the key
+    /// are visited until one is equal to the given value. With [`SortedValues`], the value
+    /// is found in O(log n).
+    pub fn remove_one(&mut self, key: &K, value: &V) -> bool {
+        let encoded_value = value.to_bytes();
+        let slot = self.range(key, Some(value)).find_map(|entry| {
+            let Slot { key, suffix } = entry.key().clone();
+            let value = O::join(suffix.clone(), entry.value());
+            (value.to_bytes() == encoded_value).then_some(Slot { key, suffix })
+        });
+
+        match slot {
+            Some(slot) => self.map.remove(&slot).is_some(),
+            None => false,
+        }
+    }
+
+    /// Removes all the values of the key, returning the number of removed values.
+    ///
+    /// # Complexity
+    /// O(log n + m), where n is the number of values in the multimap, and m the number of
+    /// values of the key.
+    pub fn remove_all(&mut self, key: &K) -> u64 {
+        let (first, last) = O::bounds(None);
+        self.map.remove_range(
+            Slot {
+                key: key.clone(),
+                suffix: first,
+            }..=Slot {
+                key: key.clone(),
+                suffix: last,
+            },
+        )
+    }
+
+    /// Returns the total number of values in the multimap.
+    pub fn len(&self) -> u64 {
+        self.map.len()
+    }
+
+    /// Returns `true` if the multimap contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Removes all the keys and values from the multimap.
+    pub fn clear(&mut self) {
+        self.map.clear_new();
+    }
+
+    /// Returns the underlying memory.
+    pub fn into_memory(self) -> M {
+        self.map.into_memory()
+    }
+
+    /// Returns an iterator over the entries of the multimap, sorted by key, and then in the
+    /// order of the values of each key.
+    pub fn iter(&self) -> Iter<'_, K, V, M, O> {
+        Iter {
+            iter: self.map.iter(),
+        }
+    }
+
+    // Returns an iterator over the entries of the key that can hold the given value, or
+    // any value if `value` is `None`.
+    fn range(&self, key: &K, value: Option<&V>) -> IterMap<'_, Slot<K, O::Suffix>, O::Stored, M> {
+        let (first, last) = O::bounds(value);
+        self.map.range((
+            RangeBound::Included(Slot {
+                key: key.clone(),
+                suffix: first,
+            }),
+            RangeBound::Included(Slot {
+                key: key.clone(),
+                suffix: last,
+            }),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn make_memory() -> Rc<RefCell<Vec<u8>>> {
+        Rc::new(RefCell::new(Vec::new()))
+    }
+
+    #[test]
+    fn values_in_insertion_order() {
+        let mut multimap: BTreeMultimap<u64, u64, _> = BTreeMultimap::new(make_memory());
+        for i in 0..100 {
+            multimap.insert(i % 3, 100 - i);
+        }
+
+        assert_eq!(multimap.len(), 100);
+        assert_eq!(multimap.count(&0), 34);
+        assert_eq!(multimap.count(&3), 0);
+        assert!(multimap
+            .get_all(&1)
+            .eq((0..100).filter(|i| i % 3 == 1).map(|i| 100 - i)));
+        assert_eq!(multimap.get_first(&2), Some(98));
+        assert!(!multimap.contains_key(&3));
+
+        // Only the first of the equal values is removed.
+        multimap.insert(1, 99);
+        assert!(multimap.remove_one(&1, &99));
+        assert_eq!(multimap.get_all(&1).last(), Some(99));
+        assert!(!multimap.remove_one(&1, &100));
+
+        assert_eq!(multimap.remove_all(&1), 33);
+        assert_eq!(multimap.count(&1), 0);
+        assert_eq!(multimap.count(&0), 34);
+        assert_eq!(multimap.len(), 67);
+    }
+
+    #[test]
+    fn sorted_values() {
+        let mut multimap: BTreeMultimap<String, String, _, SortedValues> =
+            BTreeMultimap::new(make_memory());
+        for value in ["c", "a", "b", "a", "d"] {
+            multimap.insert("key".to_string(), value.to_string());
+        }
+        multimap.insert("other".to_string(), "a".to_string());
+
+        assert!(multimap
+            .get_all(&"key".to_string())
+            .eq(["a", "a", "b", "c", "d"]));
+        assert!(multimap.remove_one(&"key".to_string(), &"a".to_string()));
+        assert!(!multimap.remove_one(&"key".to_string(), &"e".to_string()));
+        assert!(multimap
+            .get_all(&"key".to_string())
+            .eq(["a", "b", "c", "d"]));
+        assert!(multimap
+            .iter()
+            .map(|(k, v)| format!("{k}:{v}"))
+            .eq(["key:a", "key:b", "key:c", "key:d", "other:a"]));
+    }
+
+    #[test]
+    fn matches_std_btreemap_of_vecs() {
+        let mem = make_memory();
+        let mut multimap: BTreeMultimap<u32, Vec<u8>, _> = BTreeMultimap::new(mem.clone());
+        let mut expected = std::collections::BTreeMap::<u32, Vec<Vec<u8>>>::new();
+
+        for i in 0..2_000u32 {
+            let key = i * 7 % 13;
+            let value = vec![(i % 5) as u8; (i % 3) as usize];
+            match i % 7 {
+                0 => {
+                    let removed = multimap.remove_one(&key, &value);
+                    let values = expected.entry(key).or_default();
+                    let position = values.iter().position(|v| *v == value);
+                    assert_eq!(removed, position.is_some());
+                    if let Some(position) = position {
+                        values.remove(position);
+                    }
+                }
+                1 if i % 11 == 0 => {
+                    let values = expected.remove(&key).unwrap_or_default();
+                    assert_eq!(multimap.remove_all(&key), values.len() as u64);
+                }
+                _ => {
+                    multimap.insert(key, value.clone());
+                    expected.entry(key).or_default().push(value);
+                }
+            }
+        }
+
+        let multimap: BTreeMultimap<u32, Vec<u8>, _> = BTreeMultimap::load(mem);
+        for key in 0..13 {
+            let values = expected.get(&key).cloned().unwrap_or_default();
+            assert_eq!(multimap.count(&key), values.len() as u64);
+            assert!(multimap.get_all(&key).eq(values.iter().cloned()));
+            assert!(multimap.get_all(&key).rev().eq(values.into_iter().rev()));
+        }
+        assert_eq!(
+            multimap.len(),
+            expected.values().map(|v| v.len() as u64).sum::<u64>()
+        );
+    }
+}
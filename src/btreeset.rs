@@ -1,7 +1,9 @@
 //! This module implements a set based on a B-Tree in stable memory.
 
+use crate::export::{ImportError, StructureKind};
 use crate::{btreemap::Iter as IterMap, BTreeMap, Memory, Storable};
 use core::ops::RangeBounds;
+use std::io;
 
 #[cfg(test)]
 mod proptests;
@@ -37,6 +39,10 @@ where
     fn next(&mut self) -> Option<Self::Item> {
         self.iter_internal.next().map(|entry| entry.key().clone())
     }
+
+    fn count(self) -> usize {
+        self.iter_internal.count()
+    }
 }
 
 /// A B-Tree set implementation that stores its data into a designated memory.
@@ -210,6 +216,40 @@ where
         }
     }
 
+    /// Initializes a `BTreeSet` that supports order statistics.
+    ///
+    /// If the memory provided already contains a `BTreeSet`, then that set is loaded.
+    /// Otherwise, a new `BTreeSet` is created with [`new_counted`](Self::new_counted).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the memory contains a set that was not created with
+    /// [`new_counted`](Self::new_counted).
+    pub fn init_counted(memory: M) -> Self {
+        BTreeSet {
+            map: BTreeMap::<K, (), M>::init_counted(memory),
+        }
+    }
+
+    /// Creates a new `BTreeSet` that supports order statistics.
+    ///
+    /// Such a set answers [`nth`](Self::nth), [`rank`](Self::rank) and the number of
+    /// elements in a [`range`](Self::range) in O(log n). See [`BTreeMap::new_counted`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeSet, DefaultMemoryImpl};
+    ///
+    /// let set: BTreeSet<u64, _> =
+    ///     BTreeSet::new_counted(DefaultMemoryImpl::default());
+    /// ```
+    pub fn new_counted(memory: M) -> Self {
+        BTreeSet {
+            map: BTreeMap::<K, (), M>::new_counted(memory),
+        }
+    }
+
     /// Loads the `BTreeSet` from memory.
     ///
     /// # Example
@@ -235,6 +275,72 @@ where
         }
     }
 
+    /// Creates a new `BTreeSet` from an iterator of keys sorted in ascending order.
+    ///
+    /// The underlying tree is built bottom-up, which is significantly cheaper than
+    /// inserting the keys one at a time. As with [`new`](Self::new), the set doesn't support
+    /// order statistics. See [`BTreeMap::from_sorted_iter`].
+    ///
+    /// # Complexity
+    /// O(n), where n is the number of keys.
+    ///
+    /// # Panics
+    /// Panics if the keys are not in strictly ascending order.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeSet, DefaultMemoryImpl};
+    /// use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+    ///
+    /// let mem_mgr = MemoryManager::init(DefaultMemoryImpl::default());
+    /// let set: BTreeSet<u64, _> = BTreeSet::from_sorted_iter(mem_mgr.get(MemoryId::new(0)), 0..100);
+    /// assert_eq!(set.len(), 100);
+    /// assert!(set.contains(&42));
+    /// ```
+    pub fn from_sorted_iter<I>(memory: M, iter: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+    {
+        BTreeSet {
+            map: BTreeMap::from_sorted_iter(memory, iter.into_iter().map(|k| (k, ()))),
+        }
+    }
+
+    /// Creates a new `BTreeSet` in the given memory from a stream written by
+    /// [`export`](Self::export), overwriting any data the memory contains.
+    ///
+    /// # Complexity
+    /// O(n), where n is the number of keys in the stream.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeSet, DefaultMemoryImpl};
+    ///
+    /// let set: BTreeSet<u64, _> = BTreeSet::from_sorted_iter(DefaultMemoryImpl::default(), 0..100);
+    ///
+    /// let mut stream = vec![];
+    /// set.export(&mut stream).unwrap();
+    ///
+    /// let copy: BTreeSet<u64, _> =
+    ///     BTreeSet::import(DefaultMemoryImpl::default(), stream.as_slice()).unwrap();
+    /// assert_eq!(copy.len(), 100);
+    /// ```
+    pub fn import<R: io::Read>(memory: M, reader: R) -> Result<Self, ImportError> {
+        Ok(BTreeSet {
+            map: BTreeMap::import_as(memory, reader, StructureKind::BTreeSet, None)?,
+        })
+    }
+
+    /// Writes the keys of the set to `writer`, in the [portable format](crate::export).
+    ///
+    /// # Complexity
+    /// O(n), where n is the number of elements in the set.
+    pub fn export<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        self.map.export_as(writer, StructureKind::BTreeSet, None)
+    }
+
     /// Inserts a key into the set. Returns `true` if the key
     /// did not exist in the set before.
     ///
@@ -400,6 +506,49 @@ where
         self.map.last_key_value().map(|(a, _)| a)
     }
 
+    /// Returns the element at the given position in the set, where elements are sorted and
+    /// positions start at zero. Returns `None` if `index` is out of bounds.
+    ///
+    /// # Complexity
+    /// O(log n) for sets created with [`new_counted`](Self::new_counted), and O(n) otherwise.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeSet, DefaultMemoryImpl};
+    ///
+    /// let mut set: BTreeSet<u64, _> = BTreeSet::new_counted(DefaultMemoryImpl::default());
+    /// set.insert(30);
+    /// set.insert(10);
+    /// set.insert(20);
+    /// assert_eq!(set.nth(1), Some(20));
+    /// assert_eq!(set.nth(3), None);
+    /// ```
+    pub fn nth(&self, index: u64) -> Option<K> {
+        self.map.nth(index).map(|(k, _)| k)
+    }
+
+    /// Returns the number of elements in the set that are strictly less than the given key.
+    ///
+    /// # Complexity
+    /// O(log n) for sets created with [`new_counted`](Self::new_counted), and O(n) otherwise.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeSet, DefaultMemoryImpl};
+    ///
+    /// let mut set: BTreeSet<u64, _> = BTreeSet::new_counted(DefaultMemoryImpl::default());
+    /// set.insert(30);
+    /// set.insert(10);
+    /// set.insert(20);
+    /// assert_eq!(set.rank(&20), 1);
+    /// assert_eq!(set.rank(&25), 2);
+    /// ```
+    pub fn rank(&self, key: &K) -> u64 {
+        self.map.rank(key)
+    }
+
     /// Removes a key from the set, returning `true` if it exists.
     ///
     /// # Complexity
@@ -463,6 +612,118 @@ where
         self.map.pop_first().map(|(a, _)| a)
     }
 
+    /// Moves all the elements of `other` into `self`, leaving `other` empty.
+    ///
+    /// # Complexity
+    /// O(m) if all the elements of `other` are greater than the elements of `self`, such
+    /// as when appending a set produced by [`split_off`](Self::split_off), and
+    /// O(m log(n + m)) otherwise, where n and m are the sizes of `self` and `other`.
+    /// See [`BTreeMap::append`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeSet, DefaultMemoryImpl};
+    ///
+    /// let mut a: BTreeSet<u64, _> = BTreeSet::from_sorted_iter(DefaultMemoryImpl::default(), 0..3);
+    /// let mut b: BTreeSet<u64, _> = BTreeSet::from_sorted_iter(DefaultMemoryImpl::default(), 2..5);
+    ///
+    /// a.append(&mut b);
+    /// assert!(a.iter().eq(0..5));
+    /// assert!(b.is_empty());
+    /// ```
+    pub fn append(&mut self, other: &mut Self) {
+        self.map.append(&mut other.map);
+    }
+
+    /// Splits the set into two at the given key. Returns everything after the given key,
+    /// including the key, as a new set stored in the given `memory`.
+    ///
+    /// The elements that are moved are cut from this set at the node level. See
+    /// [`BTreeMap::split_off`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeSet, DefaultMemoryImpl};
+    ///
+    /// let mut a: BTreeSet<u64, _> = BTreeSet::from_sorted_iter(DefaultMemoryImpl::default(), 0..10);
+    /// let b = a.split_off(&4, DefaultMemoryImpl::default());
+    ///
+    /// assert!(a.iter().eq(0..4));
+    /// assert!(b.iter().eq(4..10));
+    /// ```
+    pub fn split_off(&mut self, key: &K, memory: M) -> Self {
+        BTreeSet {
+            map: self.map.split_off(key, memory),
+        }
+    }
+
+    /// Retains only the elements specified by the predicate.
+    ///
+    /// In other words, removes all elements `k` for which `f(&k)` returns `false`.
+    /// The elements are visited in ascending order and removed in the same pass.
+    ///
+    /// # Complexity
+    /// O(n + m log n), where n is the number of elements in the set and m is the number of
+    /// removed elements.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeSet, DefaultMemoryImpl};
+    /// use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+    ///
+    /// let mem_mgr = MemoryManager::init(DefaultMemoryImpl::default());
+    /// let mut set: BTreeSet<u64, _> = BTreeSet::new(mem_mgr.get(MemoryId::new(0)));
+    /// for i in 0..8 {
+    ///     set.insert(i);
+    /// }
+    /// set.retain(|k| k % 2 == 0);
+    /// assert!(set.iter().eq([0, 2, 4, 6]));
+    /// ```
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K) -> bool,
+    {
+        self.map.retain(|k, _| f(k));
+    }
+
+    /// Creates an iterator that visits all elements in ascending order and uses a
+    /// closure to determine if an element should be removed.
+    ///
+    /// If the closure returns `true`, the element is removed from the set and yielded.
+    /// If the closure returns `false`, or panics, the element remains in the set and
+    /// will not be yielded.
+    ///
+    /// The iterator is lazy: elements are only removed as the iterator is advanced.
+    ///
+    /// # Complexity
+    /// O(n + m log n) to consume the iterator, where n is the number of elements in the
+    /// set and m is the number of removed elements.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeSet, DefaultMemoryImpl};
+    /// use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+    ///
+    /// let mem_mgr = MemoryManager::init(DefaultMemoryImpl::default());
+    /// let mut set: BTreeSet<u64, _> = BTreeSet::new(mem_mgr.get(MemoryId::new(0)));
+    /// for i in 0..8 {
+    ///     set.insert(i);
+    /// }
+    /// let evens: Vec<_> = set.extract_if(|k| k % 2 == 0).collect();
+    /// assert_eq!(evens, vec![0, 2, 4, 6]);
+    /// assert!(set.iter().eq([1, 3, 5, 7]));
+    /// ```
+    pub fn extract_if<'a, F>(&'a mut self, mut pred: F) -> impl Iterator<Item = K> + 'a
+    where
+        F: FnMut(&K) -> bool + 'a,
+    {
+        self.map.extract_if(move |k, _| pred(k)).map(|(k, _)| k)
+    }
+
     /// Returns an iterator over the entries of the set, sorted by key.
     ///
     /// # Complexity
@@ -491,6 +752,8 @@ where
     ///
     /// # Complexity
     /// O(log n) for creating the iterator. Iterating over the range is O(k), where k is the number of elements in the range.
+    /// Counting the elements of the range with [`Iterator::count`] is O(log n) for sets
+    /// created with [`new_counted`](Self::new_counted).
     ///
     /// # Example
     ///
@@ -643,6 +906,68 @@ where
         })
     }
 
+    /// Returns an iterator over the difference of this set and another.
+    ///
+    /// The difference of two sets is the set of elements that are in this set but not in
+    /// the other one.
+    ///
+    /// # Complexity
+    /// O(n + m), where:
+    /// - n is the size of the first set.
+    /// - m is the size of the second set.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{BTreeSet, DefaultMemoryImpl};
+    /// use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+    ///
+    /// let mem_mgr = MemoryManager::init(DefaultMemoryImpl::default());
+    /// let mut set1: BTreeSet<u64, _> = BTreeSet::new(mem_mgr.get(MemoryId::new(0)));
+    /// let mut set2: BTreeSet<u64, _> = BTreeSet::new(mem_mgr.get(MemoryId::new(1)));
+    ///
+    /// set1.insert(1);
+    /// set1.insert(2);
+    /// set1.insert(3);
+    ///
+    /// set2.insert(2);
+    /// set2.insert(4);
+    ///
+    /// let difference: Vec<_> = set1.difference(&set2).collect();
+    /// assert_eq!(difference, vec![1, 3]);
+    /// ```
+    pub fn difference<'a>(&'a self, other: &'a BTreeSet<K, M>) -> impl Iterator<Item = K> + 'a {
+        let mut iter_self = self.iter();
+        let mut iter_other = other.iter();
+        let mut next_self = iter_self.next();
+        let mut next_other = iter_other.next();
+
+        // Use a closure to find the elements of `self` that are missing from `other` by
+        // traversing both iterators simultaneously.
+        std::iter::from_fn(move || {
+            while let Some(ref a) = next_self.clone() {
+                match next_other.as_ref().map(|b| a.cmp(b)) {
+                    // If `other` is exhausted or its element is larger, `a` is not in `other`.
+                    None | Some(std::cmp::Ordering::Less) => {
+                        next_self = iter_self.next();
+                        return Some(a.clone());
+                    }
+                    Some(std::cmp::Ordering::Greater) => {
+                        // If the element from `other` is smaller, advance `other`.
+                        next_other = iter_other.next();
+                    }
+                    Some(std::cmp::Ordering::Equal) => {
+                        // Skip elements that are in both sets and advance both iterators.
+                        next_self = iter_self.next();
+                        next_other = iter_other.next();
+                    }
+                }
+            }
+            // Stop the iteration when `self` is exhausted.
+            None
+        })
+    }
+
     /// Returns `true` if this set has no elements in common with another set.
     ///
     /// # Complexity
@@ -862,6 +1187,19 @@ where
     }
 }
 
+impl<K, M> Extend<K> for BTreeSet<K, M>
+where
+    K: Storable + Ord + Clone,
+    M: Memory,
+{
+    /// Inserts the keys of the iterator into the set, one at a time.
+    fn extend<I: IntoIterator<Item = K>>(&mut self, iter: I) {
+        for key in iter {
+            self.insert(key);
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -890,6 +1228,39 @@ mod test {
         f(btree);
     }
 
+    #[test]
+    fn test_from_sorted_iter() {
+        let set: BTreeSet<u32, _> = BTreeSet::from_sorted_iter(make_memory(), 0..1_000);
+        assert_eq!(set.len(), 1_000);
+        assert!(set.iter().eq(0..1_000));
+
+        let mut set = BTreeSet::<u32, _>::init(set.into_memory());
+        assert!(!set.insert(500));
+        assert!(set.insert(1_000));
+        assert_eq!(set.len(), 1_001);
+    }
+
+    #[test]
+    fn test_retain_and_extract_if() {
+        let mem = make_memory();
+        let mut set: BTreeSet<u32, _> = BTreeSet::new(mem);
+
+        for i in 0..1_000 {
+            set.insert(i);
+        }
+
+        set.retain(|k| k % 2 == 0);
+        assert_eq!(set.len(), 500);
+        assert!(set.iter().eq((0..1_000).step_by(2)));
+
+        let extracted: Vec<_> = set.extract_if(|k| k % 4 == 0).collect();
+        assert_eq!(extracted, (0..1_000).step_by(4).collect::<Vec<_>>());
+        assert!(set.iter().eq((2..1_000).step_by(4)));
+
+        set.retain(|_| false);
+        assert!(set.is_empty());
+    }
+
     #[test]
     fn test_union_with_duplicates() {
         let mem1 = make_memory();
@@ -1679,4 +2050,80 @@ mod test {
         set2.insert(2001);
         assert!(!set1.is_superset(&set2));
     }
+
+    #[test]
+    fn test_difference() {
+        let mut set1: BTreeSet<u32, _> = BTreeSet::new(make_memory());
+        let mut set2: BTreeSet<u32, _> = BTreeSet::new(make_memory());
+
+        for i in 0..1000 {
+            set1.insert(i);
+            if i % 3 == 0 {
+                set2.insert(i);
+            }
+        }
+        set2.insert(5000);
+
+        let difference: Vec<_> = set1.difference(&set2).collect();
+        assert_eq!(
+            difference,
+            (0..1000).filter(|i| i % 3 != 0).collect::<Vec<_>>()
+        );
+        assert_eq!(set2.difference(&set1).collect::<Vec<_>>(), vec![5000]);
+        assert_eq!(set1.difference(&set1).count(), 0);
+
+        let empty: BTreeSet<u32, _> = BTreeSet::new(make_memory());
+        assert!(set1.difference(&empty).eq(0..1000));
+        assert_eq!(empty.difference(&set1).count(), 0);
+    }
+
+    #[test]
+    fn test_extend_append_and_split_off() {
+        let mut set: BTreeSet<u32, _> = BTreeSet::new(make_memory());
+        set.extend((0..500).rev());
+        set.extend(250..750);
+        assert_eq!(set.len(), 750);
+        assert!(set.iter().eq(0..750));
+
+        let mut other = set.split_off(&300, make_memory());
+        assert!(set.iter().eq(0..300));
+        assert!(other.iter().eq(300..750));
+
+        set.append(&mut other);
+        assert!(other.is_empty());
+        assert!(set.iter().eq(0..750));
+
+        // Appending a set that overlaps with this one.
+        let mut other: BTreeSet<u32, _> = BTreeSet::from_sorted_iter(make_memory(), 700..800);
+        set.append(&mut other);
+        assert!(other.is_empty());
+        assert!(set.iter().eq(0..800));
+    }
+
+    #[test]
+    fn test_nth_rank_and_range_count() {
+        let counted: BTreeSet<u32, _> = BTreeSet::new_counted(make_memory());
+        let not_counted: BTreeSet<u32, _> = BTreeSet::new(make_memory());
+
+        for mut set in [counted, not_counted] {
+            set.extend((0..2000).map(|i| i * 2));
+
+            assert_eq!(set.nth(0), Some(0));
+            assert_eq!(set.nth(1234), Some(2468));
+            assert_eq!(set.nth(2000), None);
+
+            assert_eq!(set.rank(&0), 0);
+            assert_eq!(set.rank(&1001), 501);
+            assert_eq!(set.rank(&10_000), 2000);
+
+            assert_eq!(set.range(100..200).count(), 50);
+            assert_eq!(set.range(101..=3999).count(), 1949);
+            assert_eq!(set.range(..).count(), 2000);
+            assert_eq!(set.iter().count(), 2000);
+        }
+
+        let set: BTreeSet<u32, _> = BTreeSet::init_counted(make_memory());
+        assert_eq!(set.nth(0), None);
+        assert_eq!(set.rank(&0), 0);
+    }
 }
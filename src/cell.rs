@@ -1,8 +1,10 @@
 //! A serializable value stored in the stable memory.
+use crate::export::{Exporter, ImportError, Importer, StructureKind};
 use crate::storable::Storable;
 use crate::{read_to_vec, Memory, WASM_PAGE_SIZE};
 use std::borrow::{Borrow, Cow};
 use std::fmt;
+use std::io;
 
 #[cfg(test)]
 mod tests;
@@ -244,6 +246,40 @@ impl<T: Storable, M: Memory> Cell<T, M> {
         self.memory
     }
 
+    /// Creates a new cell in the specified memory from a stream written by
+    /// [`export`](Self::export), overwriting the previous contents of the memory.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{Cell, DefaultMemoryImpl};
+    ///
+    /// let cell = Cell::new(DefaultMemoryImpl::default(), 42u64);
+    ///
+    /// let mut stream = vec![];
+    /// cell.export(&mut stream).unwrap();
+    ///
+    /// let copy: Cell<u64, _> = Cell::import(DefaultMemoryImpl::default(), stream.as_slice()).unwrap();
+    /// assert_eq!(*copy.get(), 42);
+    /// ```
+    pub fn import<R: io::Read>(memory: M, reader: R) -> Result<Self, ImportError> {
+        let mut importer = Importer::new(reader, StructureKind::Cell, None, Some(&T::BOUND))?;
+        if importer.len() != 1 {
+            return Err(ImportError::InvalidEntry(importer.len().min(1)));
+        }
+        let (_, value) = importer.read_entry()?;
+        let value = T::from_bytes(Cow::Owned(value));
+        importer.finish()?;
+        Ok(Self::new(memory, value))
+    }
+
+    /// Writes the value of the cell to `writer`, in the [portable format](crate::export).
+    pub fn export<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        let mut exporter = Exporter::new(writer, StructureKind::Cell, None, Some(&T::BOUND), 1)?;
+        exporter.write_entry(&[], &self.value.to_bytes())?;
+        exporter.finish()
+    }
+
     /// Updates the current value in the cell.
     /// If the new value is too large to fit into the memory, the value in the cell does not
     /// change.
@@ -0,0 +1,699 @@
+//! A portable stream format for moving stable structures between memories, e.g. from one
+//! canister to another, or to an off-chain backup.
+//!
+//! Structures are written to any [`std::io::Write`] with their `export` method, such as
+//! [`BTreeMap::export`](crate::BTreeMap::export), and read back from any
+//! [`std::io::Read`] with their `import` method. The stream describes itself, so that an
+//! import fails with an [`ImportError`] instead of misreading a stream written by a
+//! different kind of structure or by a type with incompatible bounds.
+//!
+//! # V1 format
+//!
+//! ```text
+//! ---------------------------------------- <- Offset 0
+//! Magic "SSX"             ↕ 3 bytes
+//! ----------------------------------------
+//! Format version          ↕ 1 byte
+//! ----------------------------------------
+//! Structure kind          ↕ 1 byte
+//! ----------------------------------------
+//! Key bound               ↕ 6 bytes
+//! ----------------------------------------
+//! Value bound             ↕ 6 bytes
+//! ----------------------------------------
+//! Number of entries = N   ↕ 8 bytes
+//! ---------------------------------------- <- Offset 25
+//! Entry 0 with its checksum
+//! ----------------------------------------
+//! ...
+//! ----------------------------------------
+//! Entry (N-1) with its checksum
+//! ----------------------------------------
+//! Checksum                ↕ 4 bytes
+//! ----------------------------------------
+//! ```
+//!
+//! A bound is a tag (0 if the structure has no such field, 1 if the type is unbounded and
+//! 2 if it is bounded), followed by the maximum size (4 bytes) and the fixed size flag
+//! (1 byte), which are zero unless the type is bounded.
+//!
+//! An entry is its key, if the structure has keys, followed by its value, if the structure
+//! has values, and by a checksum. Each of the key and the value is stored as its length
+//! (4 bytes) followed by its bytes.
+//!
+//! A checksum is the CRC-32 (IEEE) of all the preceding bytes of the stream, except for the
+//! checksums of the previous entries. As each entry has a checksum, an entry is checked
+//! before it's decoded, so that a corrupted stream is rejected rather than decoded into
+//! invalid keys or values. All the integers are little-endian.
+use crate::storable::Bound;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// The magic number: Stable Structure eXport.
+const MAGIC: &[u8; 3] = b"SSX";
+
+/// The current version of the format.
+const FORMAT_VERSION: u8 = 1;
+
+/// The size of the stream header.
+const HEADER_SIZE: usize = 25;
+
+/// The kind of structure a stream was exported from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StructureKind {
+    BTreeMap = 1,
+    BTreeSet = 2,
+    Vec = 3,
+    Log = 4,
+    MinHeap = 5,
+    Cell = 6,
+}
+
+impl StructureKind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        Some(match kind {
+            1 => Self::BTreeMap,
+            2 => Self::BTreeSet,
+            3 => Self::Vec,
+            4 => Self::Log,
+            5 => Self::MinHeap,
+            6 => Self::Cell,
+            _ => return None,
+        })
+    }
+}
+
+/// An error that occurred while importing a structure.
+#[derive(Debug)]
+pub enum ImportError {
+    /// Reading the stream failed, e.g. because it ended early.
+    Io(io::Error),
+    /// The stream doesn't start with the magic number of the format.
+    BadMagic([u8; 3]),
+    /// The current version of the format doesn't support the stream's version.
+    UnsupportedVersion(u8),
+    /// The stream was exported from a different kind of structure.
+    KindMismatch {
+        expected: StructureKind,
+        /// The raw kind, which may be unknown to this version of the library.
+        actual: u8,
+    },
+    /// The keys of the stream may not fit the bounds of the key type.
+    IncompatibleKeyBound,
+    /// The values of the stream may not fit the bounds of the value type.
+    IncompatibleValueBound,
+    /// The entry at the given index doesn't match the bounds declared by the stream, or
+    /// its key is not greater than the key of the previous entry.
+    InvalidEntry(u64),
+    /// A checksum of the stream doesn't match the contents it covers.
+    ChecksumMismatch { stored: u32, actual: u32 },
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "failed to read the stream: {err}"),
+            Self::BadMagic(magic) => {
+                write!(f, "bad magic number {magic:?}, expected {MAGIC:?}")
+            }
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported format version {version}; supported version numbers are 1..={FORMAT_VERSION}"
+            ),
+            Self::KindMismatch { expected, actual } => write!(
+                f,
+                "expected a stream of kind {expected:?}, but found kind {actual}"
+            ),
+            Self::IncompatibleKeyBound => {
+                write!(f, "the key bound of the stream is incompatible with the key type")
+            }
+            Self::IncompatibleValueBound => write!(
+                f,
+                "the value bound of the stream is incompatible with the value type"
+            ),
+            Self::InvalidEntry(index) => write!(f, "invalid entry at index {index}"),
+            Self::ChecksumMismatch { stored, actual } => write!(
+                f,
+                "checksum mismatch: the stream stores {stored:#010x}, but its contents hash to {actual:#010x}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl From<io::Error> for ImportError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// Writes a structure to a stream.
+pub(crate) struct Exporter<W: Write> {
+    writer: W,
+    checksum: Crc32,
+    has_keys: bool,
+    has_values: bool,
+}
+
+impl<W: Write> Exporter<W> {
+    /// Writes the header of a stream of `len` entries.
+    ///
+    /// A bound is `None` if the structure has no such field.
+    pub fn new(
+        writer: W,
+        kind: StructureKind,
+        key_bound: Option<&Bound>,
+        value_bound: Option<&Bound>,
+        len: u64,
+    ) -> io::Result<Self> {
+        let mut exporter = Self {
+            writer,
+            checksum: Crc32::new(),
+            has_keys: key_bound.is_some(),
+            has_values: value_bound.is_some(),
+        };
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.push(FORMAT_VERSION);
+        header.push(kind as u8);
+        encode_bound(key_bound, &mut header);
+        encode_bound(value_bound, &mut header);
+        header.extend_from_slice(&len.to_le_bytes());
+        debug_assert_eq!(header.len(), HEADER_SIZE);
+        exporter.write(&header)?;
+        Ok(exporter)
+    }
+
+    /// Writes an entry and its checksum. The fields the structure doesn't have are ignored.
+    pub fn write_entry(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        if self.has_keys {
+            self.write_field(key)?;
+        }
+        if self.has_values {
+            self.write_field(value)?;
+        }
+        let checksum = self.checksum.value();
+        self.writer.write_all(&checksum.to_le_bytes())
+    }
+
+    /// Writes a key or a value.
+    fn write_field(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(bytes.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "fields larger than 4 GiB cannot be exported",
+            )
+        })?;
+        self.write(&len.to_le_bytes())?;
+        self.write(bytes)
+    }
+
+    /// Writes the checksum, which ends the stream.
+    pub fn finish(mut self) -> io::Result<()> {
+        let checksum = self.checksum.value();
+        self.writer.write_all(&checksum.to_le_bytes())?;
+        self.writer.flush()
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.checksum.update(bytes);
+        self.writer.write_all(bytes)
+    }
+}
+
+/// Reads a structure from a stream.
+pub(crate) struct Importer<R: Read> {
+    reader: R,
+    checksum: Crc32,
+    key_bound: Option<Bound>,
+    value_bound: Option<Bound>,
+    len: u64,
+    /// The index of the entry being read.
+    index: u64,
+}
+
+impl<R: Read> Importer<R> {
+    /// Reads the header of a stream, checking that its entries can be imported into a
+    /// structure of the given kind with the given bounds.
+    ///
+    /// A bound is `None` if the structure has no such field.
+    pub fn new(
+        reader: R,
+        kind: StructureKind,
+        key_bound: Option<&Bound>,
+        value_bound: Option<&Bound>,
+    ) -> Result<Self, ImportError> {
+        let mut importer = Self {
+            reader,
+            checksum: Crc32::new(),
+            key_bound: None,
+            value_bound: None,
+            len: 0,
+            index: 0,
+        };
+        let mut header = [0; HEADER_SIZE];
+        importer.read(&mut header)?;
+
+        let magic = [header[0], header[1], header[2]];
+        if &magic != MAGIC {
+            return Err(ImportError::BadMagic(magic));
+        }
+        if header[3] != FORMAT_VERSION {
+            return Err(ImportError::UnsupportedVersion(header[3]));
+        }
+        if StructureKind::from_u8(header[4]) != Some(kind) {
+            return Err(ImportError::KindMismatch {
+                expected: kind,
+                actual: header[4],
+            });
+        }
+
+        importer.key_bound =
+            decode_bound(&header[5..11]).ok_or(ImportError::IncompatibleKeyBound)?;
+        if !fits(importer.key_bound.as_ref(), key_bound) {
+            return Err(ImportError::IncompatibleKeyBound);
+        }
+        importer.value_bound =
+            decode_bound(&header[11..17]).ok_or(ImportError::IncompatibleValueBound)?;
+        if !fits(importer.value_bound.as_ref(), value_bound) {
+            return Err(ImportError::IncompatibleValueBound);
+        }
+
+        importer.len = u64::from_le_bytes(header[17..25].try_into().unwrap());
+        Ok(importer)
+    }
+
+    /// Returns the number of entries in the stream.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Reads the next entry as a key and a value, and checks it against its checksum. The
+    /// fields the structure doesn't have are empty.
+    pub fn read_entry(&mut self) -> Result<(Vec<u8>, Vec<u8>), ImportError> {
+        debug_assert!(self.index < self.len);
+        let key = match self.key_bound.as_ref().map(size_limit) {
+            Some(limit) => self.read_field(limit)?,
+            None => vec![],
+        };
+        let value = match self.value_bound.as_ref().map(size_limit) {
+            Some(limit) => self.read_field(limit)?,
+            None => vec![],
+        };
+        self.check_checksum()?;
+        self.index += 1;
+        Ok((key, value))
+    }
+
+    /// Returns the error for an invalid entry that was the last one read.
+    pub fn invalid_entry(&self) -> ImportError {
+        ImportError::InvalidEntry(self.index - 1)
+    }
+
+    /// Reads the checksum, which ends the stream, and checks it against the contents.
+    pub fn finish(mut self) -> Result<(), ImportError> {
+        debug_assert_eq!(self.index, self.len);
+        self.check_checksum()
+    }
+
+    /// Reads a checksum and checks it against the bytes read so far.
+    fn check_checksum(&mut self) -> Result<(), ImportError> {
+        let actual = self.checksum.value();
+        let mut stored = [0; 4];
+        self.reader.read_exact(&mut stored)?;
+        let stored = u32::from_le_bytes(stored);
+        if stored != actual {
+            return Err(ImportError::ChecksumMismatch { stored, actual });
+        }
+        Ok(())
+    }
+
+    /// Reads a key or a value, checking its length against the size limit of its bound.
+    fn read_field(&mut self, limit: Option<(u32, bool)>) -> Result<Vec<u8>, ImportError> {
+        let mut len = [0; 4];
+        self.read(&mut len)?;
+        let len = u32::from_le_bytes(len);
+        if let Some((max_size, is_fixed_size)) = limit {
+            if len > max_size || (is_fixed_size && len != max_size) {
+                return Err(ImportError::InvalidEntry(self.index));
+            }
+        }
+        let len = len as usize;
+
+        // The length is not trusted until the checksum is checked, so the buffer grows
+        // with the bytes that are actually read.
+        let mut bytes = vec![];
+        (&mut self.reader)
+            .take(len as u64)
+            .read_to_end(&mut bytes)?;
+        if bytes.len() < len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        self.checksum.update(&bytes);
+        Ok(bytes)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.reader.read_exact(buf)?;
+        self.checksum.update(buf);
+        Ok(())
+    }
+}
+
+/// Returns the maximum size and the fixed size flag of a bounded type.
+fn size_limit(bound: &Bound) -> Option<(u32, bool)> {
+    match bound {
+        Bound::Unbounded => None,
+        Bound::Bounded {
+            max_size,
+            is_fixed_size,
+        } => Some((*max_size, *is_fixed_size)),
+    }
+}
+
+/// Appends the 6-byte encoding of a bound to `buf`.
+fn encode_bound(bound: Option<&Bound>, buf: &mut Vec<u8>) {
+    let (tag, max_size, is_fixed_size) = match bound {
+        None => (0, 0, false),
+        Some(Bound::Unbounded) => (1, 0, false),
+        Some(Bound::Bounded {
+            max_size,
+            is_fixed_size,
+        }) => (2, *max_size, *is_fixed_size),
+    };
+    buf.push(tag);
+    buf.extend_from_slice(&max_size.to_le_bytes());
+    buf.push(is_fixed_size as u8);
+}
+
+/// Decodes a bound, returning `None` if the encoding is invalid.
+fn decode_bound(bytes: &[u8]) -> Option<Option<Bound>> {
+    let max_size = u32::from_le_bytes(bytes[1..5].try_into().unwrap());
+    let is_fixed_size = match bytes[5] {
+        0 => false,
+        1 => true,
+        _ => return None,
+    };
+    match (bytes[0], max_size, is_fixed_size) {
+        (0, 0, false) => Some(None),
+        (1, 0, false) => Some(Some(Bound::Unbounded)),
+        (2, max_size, is_fixed_size) => Some(Some(Bound::Bounded {
+            max_size,
+            is_fixed_size,
+        })),
+        _ => None,
+    }
+}
+
+/// Returns true if every field that satisfies the `stream` bound satisfies the `target`
+/// bound too.
+fn fits(stream: Option<&Bound>, target: Option<&Bound>) -> bool {
+    match (stream, target) {
+        (None, None) => true,
+        (None, Some(_)) | (Some(_), None) => false,
+        (Some(_), Some(Bound::Unbounded)) => true,
+        (Some(Bound::Unbounded), Some(Bound::Bounded { .. })) => false,
+        (
+            Some(Bound::Bounded {
+                max_size,
+                is_fixed_size,
+            }),
+            Some(Bound::Bounded {
+                max_size: target_max_size,
+                is_fixed_size: target_is_fixed_size,
+            }),
+        ) => {
+            if *target_is_fixed_size {
+                *is_fixed_size && max_size == target_max_size
+            } else {
+                max_size <= target_max_size
+            }
+        }
+    }
+}
+
+/// The CRC-32 checksum used by zlib, PNG and Ethernet.
+struct Crc32(u32);
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+impl Crc32 {
+    fn new() -> Self {
+        Self(!0)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = CRC32_TABLE[((self.0 ^ *byte as u32) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    fn value(&self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BTreeMap, BTreeSet, Cell, Log, MinHeap, Storable, VectorMemory};
+
+    #[test]
+    fn crc32_check_value() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.value(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn bound_compatibility() {
+        let bounded = |max_size, is_fixed_size| Bound::Bounded {
+            max_size,
+            is_fixed_size,
+        };
+        assert!(fits(Some(&bounded(8, true)), Some(&Bound::Unbounded)));
+        assert!(fits(Some(&bounded(8, false)), Some(&bounded(10, false))));
+        assert!(fits(Some(&bounded(8, true)), Some(&bounded(8, true))));
+        assert!(!fits(Some(&bounded(8, false)), Some(&bounded(8, true))));
+        assert!(!fits(Some(&bounded(10, false)), Some(&bounded(8, false))));
+        assert!(!fits(Some(&Bound::Unbounded), Some(&bounded(8, false))));
+        assert!(!fits(None, Some(&Bound::Unbounded)));
+
+        for bound in [None, Some(Bound::Unbounded), Some(bounded(42, true))] {
+            let mut buf = vec![];
+            encode_bound(bound.as_ref(), &mut buf);
+            assert_eq!(decode_bound(&buf), Some(bound));
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mem = VectorMemory::default;
+
+        let mut map = BTreeMap::new(mem());
+        for i in 0..1_000u64 {
+            map.insert(i, format!("{i}"));
+        }
+        let mut stream = vec![];
+        map.export(&mut stream).unwrap();
+        let copy: BTreeMap<u64, String, _> = BTreeMap::import(mem(), stream.as_slice()).unwrap();
+        assert!(copy
+            .iter()
+            .map(|e| e.into_pair())
+            .eq(map.iter().map(|e| e.into_pair())));
+
+        let set = BTreeSet::from_sorted_iter(mem(), (0..100u32).map(|i| i * 3));
+        let mut stream = vec![];
+        set.export(&mut stream).unwrap();
+        let copy: BTreeSet<u32, _> = BTreeSet::import(mem(), stream.as_slice()).unwrap();
+        assert!(copy.iter().eq(set.iter()));
+
+        let vec = crate::Vec::new(mem());
+        for i in 0..100u64 {
+            vec.push(&i);
+        }
+        let mut stream = vec![];
+        vec.export(&mut stream).unwrap();
+        let copy: crate::Vec<u64, _> = crate::Vec::import(mem(), stream.as_slice()).unwrap();
+        assert!(copy.iter().eq(vec.iter()));
+
+        let log = Log::new(mem(), mem());
+        for i in 0..100usize {
+            log.append(&vec![i as u8; i]).unwrap();
+        }
+        let mut stream = vec![];
+        log.export(&mut stream).unwrap();
+        let copy: Log<Vec<u8>, _, _> = Log::import(mem(), mem(), stream.as_slice()).unwrap();
+        assert!(copy.iter().eq(log.iter()));
+
+        let mut heap = MinHeap::new(mem());
+        for i in (0..100u64).rev() {
+            heap.push(&(i * 7 % 100));
+        }
+        let mut stream = vec![];
+        heap.export(&mut stream).unwrap();
+        let copy: MinHeap<u64, _> = MinHeap::import(mem(), stream.as_slice()).unwrap();
+        assert!(copy.iter().eq(heap.iter()));
+
+        let cell = Cell::new(mem(), "value".to_string());
+        let mut stream = vec![];
+        cell.export(&mut stream).unwrap();
+        let copy: Cell<String, _> = Cell::import(mem(), stream.as_slice()).unwrap();
+        assert_eq!(copy.get(), cell.get());
+    }
+
+    #[test]
+    fn import_errors() {
+        let mut map = BTreeMap::new(VectorMemory::default());
+        for i in 0..10u64 {
+            map.insert(i, i);
+        }
+        let mut stream = vec![];
+        map.export(&mut stream).unwrap();
+        let import =
+            |stream: &[u8]| BTreeMap::<u64, u64, _>::import(VectorMemory::default(), stream);
+
+        assert!(import(&stream).is_ok());
+        assert!(matches!(
+            BTreeSet::<u64, _>::import(VectorMemory::default(), stream.as_slice()),
+            Err(ImportError::KindMismatch {
+                expected: StructureKind::BTreeSet,
+                actual: 1
+            })
+        ));
+        assert!(matches!(
+            BTreeMap::<u32, u64, _>::import(VectorMemory::default(), stream.as_slice()),
+            Err(ImportError::IncompatibleKeyBound)
+        ));
+        assert!(
+            BTreeMap::<u64, String, _>::import(VectorMemory::default(), stream.as_slice()).is_ok()
+        );
+
+        let mut bad_magic = stream.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(
+            import(&bad_magic),
+            Err(ImportError::BadMagic(magic)) if &magic == b"XSX"
+        ));
+
+        let mut bad_version = stream.clone();
+        bad_version[3] = 2;
+        assert!(matches!(
+            import(&bad_version),
+            Err(ImportError::UnsupportedVersion(2))
+        ));
+
+        assert!(matches!(
+            import(&stream[..stream.len() - 1]),
+            Err(ImportError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof
+        ));
+
+        // Flip a byte of the last value, before its checksum and the stream's checksum.
+        let mut corrupted = stream.clone();
+        let idx = stream.len() - 9;
+        corrupted[idx] ^= 1;
+        assert!(matches!(
+            import(&corrupted),
+            Err(ImportError::ChecksumMismatch { .. })
+        ));
+
+        // Keys that aren't in ascending order.
+        let mut unsorted = vec![];
+        let bound = u64::BOUND;
+        let mut exporter = Exporter::new(
+            &mut unsorted,
+            StructureKind::BTreeMap,
+            Some(&bound),
+            Some(&bound),
+            2,
+        )
+        .unwrap();
+        exporter
+            .write_entry(&1u64.to_bytes(), &1u64.to_bytes())
+            .unwrap();
+        exporter
+            .write_entry(&0u64.to_bytes(), &0u64.to_bytes())
+            .unwrap();
+        exporter.finish().unwrap();
+        assert!(matches!(
+            import(&unsorted),
+            Err(ImportError::InvalidEntry(1))
+        ));
+
+        // Lengths that don't match the declared bounds.
+        let mut oversized = stream.clone();
+        oversized[HEADER_SIZE] = 9;
+        assert!(matches!(
+            import(&oversized),
+            Err(ImportError::InvalidEntry(0))
+        ));
+    }
+
+    #[test]
+    fn corrupted_entries_are_not_decoded() {
+        let mem = VectorMemory::default;
+        // Replaces the last byte of the last entry with a byte that isn't valid UTF-8, which
+        // would make `String::from_bytes` panic.
+        let corrupt = |mut stream: Vec<u8>| {
+            let idx = stream.len() - 9;
+            stream[idx] = 0xFF;
+            stream
+        };
+        let is_checksum_mismatch = |result: Result<(), ImportError>| {
+            matches!(result, Err(ImportError::ChecksumMismatch { .. }))
+        };
+
+        let mut map = BTreeMap::new(mem());
+        map.insert("key".to_string(), "value".to_string());
+        let mut stream = vec![];
+        map.export(&mut stream).unwrap();
+        let stream = corrupt(stream);
+        assert!(is_checksum_mismatch(
+            BTreeMap::<String, String, _>::import(mem(), stream.as_slice()).map(|_| ())
+        ));
+
+        let set = BTreeSet::from_sorted_iter(mem(), ["a".to_string(), "b".to_string()]);
+        let mut stream = vec![];
+        set.export(&mut stream).unwrap();
+        let stream = corrupt(stream);
+        assert!(is_checksum_mismatch(
+            BTreeSet::<String, _>::import(mem(), stream.as_slice()).map(|_| ())
+        ));
+
+        let log = Log::new(mem(), mem());
+        log.append(&"entry".to_string()).unwrap();
+        let mut stream = vec![];
+        log.export(&mut stream).unwrap();
+        let stream = corrupt(stream);
+        assert!(is_checksum_mismatch(
+            Log::<String, _, _>::import(mem(), mem(), stream.as_slice()).map(|_| ())
+        ));
+
+        let cell = Cell::new(mem(), "value".to_string());
+        let mut stream = vec![];
+        cell.export(&mut stream).unwrap();
+        let stream = corrupt(stream);
+        assert!(is_checksum_mismatch(
+            Cell::<String, _>::import(mem(), stream.as_slice()).map(|_| ())
+        ));
+    }
+}
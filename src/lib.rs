@@ -3,7 +3,9 @@ mod base_vec;
 pub mod btreemap;
 pub mod cell;
 pub use cell::{Cell as StableCell, Cell};
+pub mod btreemultimap;
 pub mod btreeset;
+pub mod export;
 pub mod file_mem;
 #[cfg(target_arch = "wasm32")]
 mod ic0_memory; // Memory API for canisters.
@@ -14,12 +16,14 @@ pub mod reader;
 pub mod storable;
 #[cfg(test)]
 mod tests;
+pub mod ttl_map;
 mod types;
 pub mod vec;
 pub mod vec_mem;
 pub mod writer;
 
 pub use btreemap::{BTreeMap, BTreeMap as StableBTreeMap};
+pub use btreemultimap::{BTreeMultimap, BTreeMultimap as StableBTreeMultimap};
 pub use btreeset::{BTreeSet, BTreeSet as StableBTreeSet};
 pub use file_mem::FileMemory;
 #[cfg(target_arch = "wasm32")]
@@ -30,6 +34,7 @@ use std::error;
 use std::fmt::{Display, Formatter};
 use std::mem::MaybeUninit;
 pub use storable::Storable;
+pub use ttl_map::{TtlMap, TtlMap as StableTtlMap};
 use types::Address;
 pub use vec::{Vec as StableVec, Vec};
 pub use vec_mem::VectorMemory;
@@ -170,6 +175,33 @@ impl Display for GrowFailed {
 
 impl error::Error for GrowFailed {}
 
+/// The progress of an incremental clear, such as [`BTreeMap::clear_incremental`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ClearProgress {
+    /// The number of elements removed by the call.
+    pub removed: u64,
+    /// The number of elements that are left to remove.
+    pub remaining: u64,
+}
+
+impl ClearProgress {
+    /// Returns true if the structure is empty.
+    pub fn is_done(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+/// Overwrites `len` bytes starting at the specified offset with zeros.
+fn write_zeros<M: Memory>(m: &M, offset: u64, len: u64) {
+    const CHUNK: [u8; 4096] = [0; 4096];
+    let mut written = 0;
+    while written < len {
+        let n = (len - written).min(CHUNK.len() as u64);
+        write(m, offset + written, &CHUNK[..n as usize]);
+        written += n;
+    }
+}
+
 /// Writes the bytes at the specified offset, growing the memory size if needed.
 fn safe_write<M: Memory>(memory: &M, offset: u64, bytes: &[u8]) -> Result<(), GrowFailed> {
     let last_byte = offset
@@ -54,10 +54,15 @@
 //! ----------------------------------------
 //! Unallocated space
 //! ```
-use crate::{read_to_vec, read_u64, safe_write, write_u64, Address, GrowFailed, Memory, Storable};
+use crate::export::{Exporter, ImportError, Importer, StructureKind};
+use crate::{
+    read_to_vec, read_u64, safe_write, write_u64, write_zeros, Address, ClearProgress, GrowFailed,
+    Memory, Storable,
+};
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::fmt;
+use std::io;
 use std::marker::PhantomData;
 use std::thread::LocalKey;
 
@@ -230,6 +235,63 @@ impl<T: Storable, INDEX: Memory, DATA: Memory> Log<T, INDEX, DATA> {
         }
     }
 
+    /// Creates a new log in the specified memories from a stream written by
+    /// [`export`](Self::export), overwriting their previous contents.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{DefaultMemoryImpl, Log};
+    ///
+    /// let log: Log<String, _, _> =
+    ///     Log::new(DefaultMemoryImpl::default(), DefaultMemoryImpl::default());
+    /// log.append(&"hello".to_string()).unwrap();
+    ///
+    /// let mut stream = vec![];
+    /// log.export(&mut stream).unwrap();
+    ///
+    /// let copy: Log<String, _, _> = Log::import(
+    ///     DefaultMemoryImpl::default(),
+    ///     DefaultMemoryImpl::default(),
+    ///     stream.as_slice(),
+    /// )
+    /// .unwrap();
+    /// assert_eq!(copy.get(0), Some("hello".to_string()));
+    /// ```
+    pub fn import<R: io::Read>(
+        index_memory: INDEX,
+        data_memory: DATA,
+        reader: R,
+    ) -> Result<Self, ImportError> {
+        let mut importer = Importer::new(reader, StructureKind::Log, None, Some(&T::BOUND))?;
+        let log = Self::new(index_memory, data_memory);
+        for _ in 0..importer.len() {
+            let (_, value) = importer.read_entry()?;
+            log.append_bytes(&value)
+                .expect("Failed to append entry to the log");
+        }
+        importer.finish()?;
+        Ok(log)
+    }
+
+    /// Writes the entries of the log to `writer`, in the [portable format](crate::export).
+    pub fn export<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        let mut exporter = Exporter::new(
+            writer,
+            StructureKind::Log,
+            None,
+            Some(&T::BOUND),
+            self.len(),
+        )?;
+        let mut buf = vec![];
+        for idx in 0..self.len() {
+            self.read_entry(idx, &mut buf)
+                .expect("the entry must exist");
+            exporter.write_entry(&[], &buf)?;
+        }
+        exporter.finish()
+    }
+
     /// Writes the stable log header to memory.
     fn write_header(memory: &impl Memory, header: &HeaderV1) {
         if memory.size() < 1 {
@@ -346,6 +408,14 @@ impl<T: Storable, INDEX: Memory, DATA: Memory> Log<T, INDEX, DATA> {
     ///
     /// POST-CONDITION: Ok(idx) = log.append(E) ⇒ log.get(idx) = Some(E)
     pub fn append(&self, item: &T) -> Result<u64, WriteError> {
+        let bytes = item.to_bytes();
+        let idx = self.append_bytes(&bytes)?;
+        debug_assert_eq!(self.get(idx).unwrap().to_bytes(), bytes);
+        Ok(idx)
+    }
+
+    /// Appends an already encoded entry to the log.
+    fn append_bytes(&self, bytes: &[u8]) -> Result<u64, WriteError> {
         let idx = self.len();
         let data_offset = if idx == 0 {
             0
@@ -353,7 +423,6 @@ impl<T: Storable, INDEX: Memory, DATA: Memory> Log<T, INDEX, DATA> {
             read_u64(&self.index_memory, self.index_entry_offset(idx - 1))
         };
 
-        let bytes = item.to_bytes();
         let new_offset = data_offset
             .checked_add(bytes.len() as u64)
             .expect("address overflow");
@@ -365,7 +434,7 @@ impl<T: Storable, INDEX: Memory, DATA: Memory> Log<T, INDEX, DATA> {
         debug_assert!(new_offset >= data_offset);
 
         // NB. we attempt to write the data first so we won't need to undo changes to the index if the write fails.
-        safe_write(&self.data_memory, entry_offset, &bytes[..])?;
+        safe_write(&self.data_memory, entry_offset, bytes)?;
 
         // NB. append to index first as it might need to grow the index memory.
         safe_write(
@@ -376,11 +445,58 @@ impl<T: Storable, INDEX: Memory, DATA: Memory> Log<T, INDEX, DATA> {
         // update number of entries
         write_u64(&self.index_memory, Address::from(HEADER_OFFSET), idx + 1);
 
-        debug_assert_eq!(self.get(idx).unwrap().to_bytes(), bytes);
-
         Ok(idx)
     }
 
+    /// Removes up to `max_entries` entries from the end of the log, overwriting their index
+    /// entries and data with zeros, and reports how many entries are left.
+    ///
+    /// Clearing a large log in a single message can exceed the instruction limit. Calling
+    /// this method repeatedly, e.g. from a timer, spreads the work over several messages.
+    /// The log remains valid between the calls, and new entries reuse the freed space.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{DefaultMemoryImpl, Log};
+    ///
+    /// let log: Log<Vec<u8>, _, _> =
+    ///     Log::new(DefaultMemoryImpl::default(), DefaultMemoryImpl::default());
+    /// for i in 0..10 {
+    ///     log.append(&vec![i; 100]).unwrap();
+    /// }
+    ///
+    /// while !log.clear_incremental(3).is_done() {}
+    /// assert!(log.is_empty());
+    /// assert_eq!(log.log_size_bytes(), 0);
+    /// ```
+    pub fn clear_incremental(&self, max_entries: u64) -> ClearProgress {
+        let len = self.len();
+        let removed = len.min(max_entries);
+        let remaining = len - removed;
+        if removed > 0 {
+            let data_start = if remaining == 0 {
+                0
+            } else {
+                read_u64(&self.index_memory, self.index_entry_offset(remaining - 1))
+            };
+            let data_end = self.log_size_bytes();
+
+            write_u64(&self.index_memory, Address::from(HEADER_OFFSET), remaining);
+            write_zeros(
+                &self.data_memory,
+                HEADER_OFFSET + data_start,
+                data_end - data_start,
+            );
+            write_zeros(
+                &self.index_memory,
+                self.index_entry_offset(remaining).get(),
+                removed * std::mem::size_of::<u64>() as u64,
+            );
+        }
+        ClearProgress { removed, remaining }
+    }
+
     /// Returns the offset and the length of the specified entry.
     fn entry_meta(&self, idx: u64) -> Option<(u64, usize)> {
         if self.len() <= idx {
@@ -261,3 +261,28 @@ fn test_thread_local_iter() {
     assert_eq!(new_iter().skip(4).count(), 0);
     assert_eq!(new_iter().skip(usize::MAX).count(), 0);
 }
+
+#[test]
+fn test_clear_incremental() {
+    let log = Log::<Vec<u8>, _, _>::new(VectorMemory::default(), VectorMemory::default());
+    for i in 0..10u8 {
+        log.append(&vec![i; i as usize + 1]).unwrap();
+    }
+
+    let progress = log.clear_incremental(4);
+    assert_eq!((progress.removed, progress.remaining), (4, 6));
+    assert_eq!(log.len(), 6);
+    assert_eq!(log.log_size_bytes(), (1..=6).sum::<u64>());
+    assert_eq!(log.get(5), Some(vec![5; 6]));
+    assert_eq!(log.get(6), None);
+
+    // New entries reuse the freed space.
+    assert_eq!(log.append(&b"new".to_vec()), Ok(6));
+    assert_eq!(log.get(6), Some(b"new".to_vec()));
+
+    let progress = log.clear_incremental(100);
+    assert_eq!((progress.removed, progress.remaining), (7, 0));
+    assert!(log.is_empty());
+    assert_eq!(log.log_size_bytes(), 0);
+    assert_eq!(log.clear_incremental(1).removed, 0);
+}
@@ -1,7 +1,10 @@
 use crate::base_vec::BaseVec;
+use crate::export::{ImportError, Importer, StructureKind};
 use crate::storable::Storable;
-use crate::Memory;
+use crate::{ClearProgress, Memory};
+use std::borrow::Cow;
 use std::fmt;
+use std::io;
 
 #[cfg(test)]
 mod tests;
@@ -48,6 +51,33 @@ where
             .expect("Failed to initialize a heap")
     }
 
+    /// Creates a new heap in the specified memory from a stream written by
+    /// [`export`](Self::export), overwriting any data the memory contains.
+    ///
+    /// The items are pushed in the order of the stream, which is the order of the exported
+    /// heap's underlying vector, so a heap is rebuilt with the same layout without moving
+    /// any item.
+    ///
+    /// Complexity: O(n * max_size(T)) for streams written by [`export`](Self::export),
+    /// where n is the number of items in the stream.
+    pub fn import<R: io::Read>(memory: M, reader: R) -> Result<Self, ImportError> {
+        let mut importer = Importer::new(reader, StructureKind::MinHeap, None, Some(&T::BOUND))?;
+        let mut heap = Self::new(memory);
+        for _ in 0..importer.len() {
+            let (_, value) = importer.read_entry()?;
+            heap.push(&T::from_bytes(Cow::Owned(value)));
+        }
+        importer.finish()?;
+        Ok(heap)
+    }
+
+    /// Writes the items of the heap to `writer`, in the [portable format](crate::export).
+    ///
+    /// Complexity: O(len * max_size(T))
+    pub fn export<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        self.0.export(writer, StructureKind::MinHeap)
+    }
+
     /// Returns the number of items in the heap.
     ///
     /// Complexity: O(1)
@@ -99,6 +129,20 @@ where
         self.0.get(0)
     }
 
+    /// Removes up to `max_items` items from the heap, overwriting their slots with zeros,
+    /// and reports how many items are left.
+    ///
+    /// The items are removed from the end of the underlying vector, so the remaining items
+    /// still form a heap, and calling this method repeatedly, e.g. from a timer, spreads
+    /// the work of clearing a large heap over several messages.
+    ///
+    /// Complexity: O(max_items * max_size(T))
+    pub fn clear_incremental(&mut self, max_items: u64) -> ClearProgress {
+        let progress = self.0.clear_incremental(max_items);
+        debug_assert_eq!(Ok(()), self.check_invariant());
+        progress
+    }
+
     /// Returns an iterator visiting all values in the underlying vector, in arbitrary order.
     pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
         self.0.iter()
@@ -122,3 +122,28 @@ fn test_failure_incompatible_version() {
     mem.write(0, b"SMH\x0f\x08\x00\x00\x00\x00\x00\x00\x00\x01");
     StableMinHeap::<u64, M>::init(mem);
 }
+
+#[test]
+fn clear_incremental() {
+    let mut h = StableMinHeap::<u64, M>::new(M::default());
+    for i in (0..20).rev() {
+        h.push(&i);
+    }
+
+    let mut remaining = h.len();
+    loop {
+        let progress = h.clear_incremental(3);
+        assert_eq!(progress.removed, remaining.min(3));
+        remaining = progress.remaining;
+        assert_eq!(h.len(), remaining);
+
+        // What is left is still a heap.
+        let mut items: Vec<u64> = h.iter().collect();
+        items.sort();
+        assert_eq!(h.peek(), items.first().copied());
+        if progress.is_done() {
+            break;
+        }
+    }
+    assert_eq!(h.pop(), None);
+}
@@ -5,6 +5,7 @@ use std::convert::{TryFrom, TryInto};
 use std::fmt;
 
 mod tuples;
+pub use tuples::TuplePrefix;
 
 #[cfg(test)]
 mod tests;
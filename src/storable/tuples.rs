@@ -2,6 +2,7 @@ use crate::storable::{
     bounds, bytes_to_store_size, bytes_to_store_size_bounded, Bound, Bounds, Storable,
 };
 use std::borrow::Cow;
+use std::cmp::Ordering;
 
 impl<A, B> Storable for (A, B)
 where
@@ -343,3 +344,34 @@ where
     debug_assert_eq!(offset, output_size);
     bytes
 }
+
+/// A tuple whose leading components form a prefix of type `P`, which can be used to find
+/// the keys of a [`BTreeMap`](crate::BTreeMap) that start with given components, with
+/// [`BTreeMap::prefix_range`](crate::BTreeMap::prefix_range).
+///
+/// `(A, B)` has the prefix `A`, and `(A, B, C)` has the prefixes `A` and `(A, B)`.
+pub trait TuplePrefix<P> {
+    /// Compares the prefix of the tuple with the given prefix.
+    ///
+    /// This must be consistent with the tuple's `Ord`: the tuples with the same prefix are
+    /// contiguous, and ordered as their prefixes.
+    fn cmp_prefix(&self, prefix: &P) -> Ordering;
+}
+
+impl<A: Ord, B> TuplePrefix<A> for (A, B) {
+    fn cmp_prefix(&self, prefix: &A) -> Ordering {
+        self.0.cmp(prefix)
+    }
+}
+
+impl<A: Ord, B, C> TuplePrefix<A> for (A, B, C) {
+    fn cmp_prefix(&self, prefix: &A) -> Ordering {
+        self.0.cmp(prefix)
+    }
+}
+
+impl<A: Ord, B: Ord, C> TuplePrefix<(A, B)> for (A, B, C) {
+    fn cmp_prefix(&self, prefix: &(A, B)) -> Ordering {
+        self.0.cmp(&prefix.0).then_with(|| self.1.cmp(&prefix.1))
+    }
+}
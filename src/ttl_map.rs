@@ -0,0 +1,378 @@
+//! This module implements a map with expiring entries, based on two B-Trees in stable
+//! memory.
+
+use crate::{storable::Bound, BTreeMap, Memory, Storable};
+use std::borrow::Cow;
+
+// The expiry of entries that never expire.
+const NEVER: u64 = u64::MAX;
+
+/// A value of a [`TtlMap`] along with its expiry.
+struct Expiring<V> {
+    at: u64,
+    value: V,
+}
+
+impl<V> Expiring<V> {
+    /// Returns `true` if the value hasn't expired at time `now`. Values that never expire
+    /// haven't, even at `u64::MAX`.
+    fn is_live(&self, now: u64) -> bool {
+        self.at == NEVER || self.at > now
+    }
+}
+
+impl<V: Storable> Storable for Expiring<V> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.at.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.value.to_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = self.at.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.value.into_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            at: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            value: V::from_bytes(Cow::Borrowed(&bytes[8..])),
+        }
+    }
+
+    const BOUND: Bound = match V::BOUND {
+        Bound::Bounded {
+            max_size,
+            is_fixed_size,
+        } => Bound::Bounded {
+            max_size: 8 + max_size,
+            is_fixed_size,
+        },
+        Bound::Unbounded => Bound::Unbounded,
+    };
+}
+
+/// A key of the expiry index of a [`TtlMap`]: the expiry of an entry followed by its key,
+/// so that the index is sorted by expiry.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct ExpiryKey<K> {
+    at: u64,
+    key: K,
+}
+
+impl<K: Storable> Storable for ExpiryKey<K> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.at.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.key.to_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = self.at.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.key.into_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            at: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            key: K::from_bytes(Cow::Borrowed(&bytes[8..])),
+        }
+    }
+
+    const BOUND: Bound = match K::BOUND {
+        Bound::Bounded {
+            max_size,
+            is_fixed_size,
+        } => Bound::Bounded {
+            max_size: 8 + max_size,
+            is_fixed_size,
+        },
+        Bound::Unbounded => Bound::Unbounded,
+    };
+}
+
+/// A map whose entries expire, stored in stable memory.
+///
+/// # Overview
+///
+/// Each entry of a `TtlMap` has an expiry, given as a timestamp in any unit (e.g. the
+/// nanoseconds returned by `ic_cdk::api::time`), and entries that never expire can be
+/// inserted with [`insert`](Self::insert). An entry is expired when its expiry is at or
+/// before the current time, which is passed to the methods that read the map: expired
+/// entries are hidden from them.
+///
+/// The entries are stored in a [`BTreeMap`] along with their expiry, and the entries that
+/// expire are indexed by expiry in a second `BTreeMap`, which the map keeps consistent with
+/// the first. Expired entries are deleted by [`purge_expired`](Self::purge_expired), a
+/// bounded number at a time, e.g. from a timer.
+///
+/// # Examples
+///
+/// ```rust
+/// use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+/// use ic_stable_structures::{DefaultMemoryImpl, TtlMap};
+///
+/// let mem_mgr = MemoryManager::init(DefaultMemoryImpl::default());
+/// let mut sessions: TtlMap<u64, String, _> =
+///     TtlMap::init(mem_mgr.get(MemoryId::new(0)), mem_mgr.get(MemoryId::new(1)));
+///
+/// sessions.insert_with_expiry(1, "alice".to_string(), 100);
+/// sessions.insert_with_expiry(2, "bob".to_string(), 200);
+/// assert_eq!(sessions.get(&1, 50), Some("alice".to_string()));
+/// assert_eq!(sessions.get(&1, 150), None);
+///
+/// // Delete the expired entries, at most 10 at a time.
+/// assert_eq!(sessions.purge_expired(150, 10), 1);
+/// assert_eq!(sessions.len(), 1);
+/// assert_eq!(sessions.next_expiry(), Some(200));
+/// ```
+pub struct TtlMap<K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    entries: BTreeMap<K, Expiring<V>, M>,
+    expiries: BTreeMap<ExpiryKey<K>, (), M>,
+}
+
+impl<K, V, M> TtlMap<K, V, M>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: Memory,
+{
+    /// Initializes a `TtlMap`.
+    ///
+    /// If the memories provided already contain a `TtlMap`, then that map is loaded.
+    /// Otherwise, a new `TtlMap` instance is created.
+    pub fn init(entries_memory: M, expiries_memory: M) -> Self {
+        Self {
+            entries: BTreeMap::init(entries_memory),
+            expiries: BTreeMap::init(expiries_memory),
+        }
+    }
+
+    /// Creates a new instance of a `TtlMap`, overwriting the contents of the memories.
+    pub fn new(entries_memory: M, expiries_memory: M) -> Self {
+        Self {
+            entries: BTreeMap::new(entries_memory),
+            expiries: BTreeMap::new(expiries_memory),
+        }
+    }
+
+    /// Loads the `TtlMap` from memory.
+    pub fn load(entries_memory: M, expiries_memory: M) -> Self {
+        Self {
+            entries: BTreeMap::load(entries_memory),
+            expiries: BTreeMap::load(expiries_memory),
+        }
+    }
+
+    /// Inserts an entry that expires at the given time, replacing the entry of the key, if
+    /// any, and its expiry.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `at` is `u64::MAX`, which is reserved for entries that never expire.
+    pub fn insert_with_expiry(&mut self, key: K, value: V, at: u64) {
+        assert_ne!(
+            at, NEVER,
+            "u64::MAX is reserved for entries that never expire."
+        );
+        self.insert_helper(key, value, at);
+    }
+
+    /// Inserts an entry that never expires, replacing the entry of the key, if any.
+    pub fn insert(&mut self, key: K, value: V) {
+        self.insert_helper(key, value, NEVER);
+    }
+
+    /// Returns the value of the key if it hasn't expired at time `now`.
+    pub fn get(&self, key: &K, now: u64) -> Option<V> {
+        self.get_with_expiry(key, now).map(|(value, _)| value)
+    }
+
+    /// Returns the value of the key and its expiry, or `None` for entries that never expire,
+    /// if it hasn't expired at time `now`.
+    pub fn get_with_expiry(&self, key: &K, now: u64) -> Option<(V, Option<u64>)> {
+        self.entries
+            .get(key)
+            .filter(|entry| entry.is_live(now))
+            .map(|entry| (entry.value, (entry.at != NEVER).then_some(entry.at)))
+    }
+
+    /// Returns `true` if the key has a value that hasn't expired at time `now`.
+    pub fn contains_key(&self, key: &K, now: u64) -> bool {
+        self.get_with_expiry(key, now).is_some()
+    }
+
+    /// Removes the entry of the key, returning its value, which may have expired.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.remove_expiry(key.clone(), entry.at);
+        Some(entry.value)
+    }
+
+    /// Deletes up to `budget` entries that have expired at time `now`, in the order they
+    /// expired, and returns the number of deleted entries.
+    ///
+    /// Expired entries remain in memory until they are purged. If the returned number is
+    /// `budget`, more expired entries may remain.
+    pub fn purge_expired(&mut self, now: u64, budget: u64) -> u64 {
+        let mut purged = 0;
+        while purged < budget {
+            let expired = match self.expiries.first_key_value() {
+                Some((expiry, ())) if expiry.at <= now => expiry,
+                _ => break,
+            };
+            self.expiries.remove(&expired);
+            self.entries.remove(&expired.key);
+            purged += 1;
+        }
+        purged
+    }
+
+    /// Returns the earliest expiry of the entries, including the expired entries that
+    /// haven't been purged yet.
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.expiries
+            .first_key_value()
+            .map(|(expiry, ())| expiry.at)
+    }
+
+    /// Returns an iterator over the entries that haven't expired at time `now`, sorted by
+    /// key.
+    pub fn iter(&self, now: u64) -> impl DoubleEndedIterator<Item = (K, V)> + '_ {
+        self.entries.iter().filter_map(move |entry| {
+            let (key, entry) = entry.into_pair();
+            entry.is_live(now).then_some((key, entry.value))
+        })
+    }
+
+    /// Returns the number of entries in the map, including the expired entries that haven't
+    /// been purged yet.
+    pub fn len(&self) -> u64 {
+        self.entries.len()
+    }
+
+    /// Returns `true` if the map contains no entries, including expired ones.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Removes all the entries from the map.
+    pub fn clear(&mut self) {
+        self.entries.clear_new();
+        self.expiries.clear_new();
+    }
+
+    /// Returns the underlying memories of the entries and of the expiry index.
+    pub fn into_memories(self) -> (M, M) {
+        (self.entries.into_memory(), self.expiries.into_memory())
+    }
+
+    fn insert_helper(&mut self, key: K, value: V, at: u64) {
+        if let Some(previous) = self.entries.insert(key.clone(), Expiring { at, value }) {
+            self.remove_expiry(key.clone(), previous.at);
+        }
+        if at != NEVER {
+            self.expiries.insert(ExpiryKey { at, key }, ());
+        }
+    }
+
+    fn remove_expiry(&mut self, key: K, at: u64) {
+        if at != NEVER {
+            self.expiries.remove(&ExpiryKey { at, key });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn make_memory() -> Rc<RefCell<Vec<u8>>> {
+        Rc::new(RefCell::new(Vec::new()))
+    }
+
+    #[test]
+    fn expired_entries_are_hidden_and_purged() {
+        let mut map: TtlMap<u64, u64, _> = TtlMap::new(make_memory(), make_memory());
+        for i in 0..100 {
+            map.insert_with_expiry(i, i * 10, 1_000 - i);
+        }
+        map.insert(100, 1);
+
+        assert_eq!(map.get(&10, 989), Some(100));
+        assert_eq!(map.get(&10, 990), None);
+        assert_eq!(map.get_with_expiry(&10, 0), Some((100, Some(990))));
+        assert_eq!(map.get_with_expiry(&100, u64::MAX - 1), Some((1, None)));
+        assert_eq!(map.iter(950).count(), 51);
+        assert_eq!(map.next_expiry(), Some(901));
+
+        // Entries expiring at or before 950 are purged in chunks, earliest first.
+        assert_eq!(map.purge_expired(950, 20), 20);
+        assert!(map.contains_key(&79, 0));
+        assert!(!map.contains_key(&80, 0));
+        assert_eq!(map.purge_expired(950, 20), 20);
+        assert_eq!(map.purge_expired(950, 20), 10);
+        assert_eq!(map.purge_expired(950, 20), 0);
+        assert_eq!(map.len(), 51);
+        assert_eq!(map.next_expiry(), Some(951));
+        assert_eq!(map.expiries.len(), 50);
+    }
+
+    #[test]
+    fn replacing_and_removing_entries_updates_the_index() {
+        let mut map: TtlMap<String, Vec<u8>, _> = TtlMap::new(make_memory(), make_memory());
+        let key = "nonce".to_string();
+
+        map.insert_with_expiry(key.clone(), vec![1], 10);
+        map.insert_with_expiry(key.clone(), vec![2], 20);
+        assert_eq!(map.expiries.len(), 1);
+        assert_eq!(map.get(&key, 15), Some(vec![2]));
+
+        // An entry that never expires isn't purged.
+        map.insert(key.clone(), vec![3]);
+        assert!(map.expiries.is_empty());
+        assert_eq!(map.purge_expired(u64::MAX, 10), 0);
+        assert_eq!(map.get(&key, 1_000), Some(vec![3]));
+
+        map.insert_with_expiry(key.clone(), vec![4], 30);
+        assert_eq!(map.remove(&key), Some(vec![4]));
+        assert!(map.expiries.is_empty());
+        assert_eq!(map.remove(&key), None);
+    }
+
+    #[test]
+    fn entries_that_never_expire_are_visible_at_any_time() {
+        let mut map: TtlMap<u64, u64, _> = TtlMap::new(make_memory(), make_memory());
+        map.insert(1, 1);
+        map.insert_with_expiry(2, 2, u64::MAX - 1);
+
+        assert_eq!(map.get_with_expiry(&1, u64::MAX), Some((1, None)));
+        assert!(map.contains_key(&1, u64::MAX));
+        assert!(!map.contains_key(&2, u64::MAX));
+        assert!(map.iter(u64::MAX).eq([(1, 1)]));
+        assert_eq!(map.purge_expired(u64::MAX, 10), 1);
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn survives_reload() {
+        let (entries_mem, expiries_mem) = (make_memory(), make_memory());
+        let mut map: TtlMap<u64, u64, _> = TtlMap::init(entries_mem, expiries_mem);
+        map.insert_with_expiry(1, 1, 10);
+        map.insert_with_expiry(2, 2, 20);
+
+        let (entries_mem, expiries_mem) = map.into_memories();
+        let mut map: TtlMap<u64, u64, _> = TtlMap::init(entries_mem, expiries_mem);
+        assert_eq!(map.purge_expired(15, 10), 1);
+        assert!(map.iter(15).eq([(2, 2)]));
+    }
+}
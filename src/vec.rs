@@ -2,9 +2,11 @@
 
 use crate::base_vec::BaseVec;
 pub use crate::base_vec::InitError;
+use crate::export::{ImportError, StructureKind};
 use crate::storable::Storable;
-use crate::Memory;
+use crate::{ClearProgress, Memory};
 use std::fmt;
+use std::io;
 
 #[cfg(test)]
 mod tests;
@@ -38,6 +40,37 @@ impl<T: Storable, M: Memory> Vec<T, M> {
             .expect("Failed to initialize a vector")
     }
 
+    /// Creates a new vector in the specified memory from a stream written by
+    /// [`export`](Self::export), overwriting any data the memory contains.
+    ///
+    /// Complexity: O(n * max_size(T)), where n is the number of items in the stream.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{DefaultMemoryImpl, Vec};
+    ///
+    /// let vec: Vec<u64, _> = Vec::new(DefaultMemoryImpl::default());
+    /// vec.push(&1);
+    /// vec.push(&2);
+    ///
+    /// let mut stream = std::vec::Vec::new();
+    /// vec.export(&mut stream).unwrap();
+    ///
+    /// let copy: Vec<u64, _> = Vec::import(DefaultMemoryImpl::default(), stream.as_slice()).unwrap();
+    /// assert_eq!(copy.get(1), Some(2));
+    /// ```
+    pub fn import<R: io::Read>(memory: M, reader: R) -> Result<Self, ImportError> {
+        BaseVec::<T, M>::import(memory, MAGIC, reader, StructureKind::Vec).map(Self)
+    }
+
+    /// Writes the items of the vector to `writer`, in the [portable format](crate::export).
+    ///
+    /// Complexity: O(len * max_size(T))
+    pub fn export<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        self.0.export(writer, StructureKind::Vec)
+    }
+
     /// Returns the underlying memory instance.
     pub fn into_memory(self) -> M {
         self.0.into_memory()
@@ -89,6 +122,34 @@ impl<T: Storable, M: Memory> Vec<T, M> {
         self.0.pop()
     }
 
+    /// Removes up to `max_items` items from the end of the vector, overwriting their slots
+    /// with zeros, and reports how many items are left.
+    ///
+    /// Clearing a large vector in a single message can exceed the instruction limit.
+    /// Calling this method repeatedly, e.g. from a timer, spreads the work over several
+    /// messages. The vector remains valid between the calls.
+    ///
+    /// Complexity: O(max_items * max_size(T))
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use ic_stable_structures::{DefaultMemoryImpl, Vec};
+    ///
+    /// let vec: Vec<u64, _> = Vec::new(DefaultMemoryImpl::default());
+    /// for i in 0..10 {
+    ///     vec.push(&i);
+    /// }
+    ///
+    /// let progress = vec.clear_incremental(4);
+    /// assert_eq!((progress.removed, progress.remaining), (4, 6));
+    /// while !vec.clear_incremental(4).is_done() {}
+    /// assert!(vec.is_empty());
+    /// ```
+    pub fn clear_incremental(&self, max_items: u64) -> ClearProgress {
+        self.0.clear_incremental(max_items)
+    }
+
     pub fn iter(&self) -> impl DoubleEndedIterator<Item = T> + '_ {
         self.0.iter()
     }
@@ -302,3 +302,24 @@ fn set_last_element_to_large_blob() {
     // Store a large blob that would require growing the memory.
     sv.set(0, &Blob::try_from(vec![1; 65536].as_slice()).unwrap());
 }
+
+#[test]
+fn clear_incremental() {
+    let sv = StableVec::<u64, M>::new(M::default());
+    for i in 0..10 {
+        sv.push(&i);
+    }
+
+    let progress = sv.clear_incremental(4);
+    assert_eq!((progress.removed, progress.remaining), (4, 6));
+    assert_eq!(sv.to_vec(), (0..6).collect::<Vec<_>>());
+
+    let progress = sv.clear_incremental(100);
+    assert_eq!((progress.removed, progress.remaining), (6, 0));
+    assert!(progress.is_done());
+    assert!(sv.is_empty());
+
+    // The vector is reusable once cleared.
+    sv.push(&42);
+    assert_eq!(sv.to_vec(), vec![42]);
+}
@@ -139,6 +139,65 @@ fn api_conformance_btreemap() {
         Vec::new()
     };
     assert_eq!(stable_result, std_result);
+
+    // Entry.
+    // Note: stable.entry(k).or_insert(v) returns an owned V, std returns &mut V.
+    // and_modify writes the modified value back to the map.
+    for i in 0..2 * n {
+        stable
+            .entry(i)
+            .and_modify(|v| v.push('!'))
+            .or_insert(i.to_string());
+        std.entry(i)
+            .and_modify(|v| v.push('!'))
+            .or_insert(i.to_string());
+    }
+    let stable_items: std::vec::Vec<_> = stable.iter().map(|e| e.into_pair()).collect();
+    let std_items: std::vec::Vec<_> = std.iter().map(|(k, v)| (*k, v.clone())).collect();
+    assert_eq!(stable_items, std_items);
+
+    // extract_if and retain.
+    // Note: the stable predicate receives `&V` rather than `&mut V`.
+    // std's extract_if is unstable, so it is simulated with a filter followed by retain.
+    let stable_extracted: std::vec::Vec<_> = stable.extract_if(|k, _| k % 3 == 0).collect();
+    let std_extracted: std::vec::Vec<_> = std
+        .iter()
+        .filter(|(k, _)| *k % 3 == 0)
+        .map(|(k, v)| (*k, v.clone()))
+        .collect();
+    std.retain(|k, _| k % 3 != 0);
+    assert_eq!(stable_extracted, std_extracted);
+
+    stable.retain(|k, _| k % 2 == 0);
+    std.retain(|k, _| k % 2 == 0);
+    let stable_items: std::vec::Vec<_> = stable.iter().map(|e| e.into_pair()).collect();
+    let std_items: std::vec::Vec<_> = std.iter().map(|(k, v)| (*k, v.clone())).collect();
+    assert_eq!(stable_items, std_items);
+
+    // split_off and append.
+    // Note: stable.split_off takes the memory for the new map.
+    let mut stable_tail = stable.split_off(&n, make_memory());
+    let mut std_tail = std.split_off(&n);
+    let stable_items: std::vec::Vec<_> = stable_tail.iter().map(|e| e.into_pair()).collect();
+    let std_items: std::vec::Vec<_> = std_tail.iter().map(|(k, v)| (*k, v.clone())).collect();
+    assert_eq!(stable_items, std_items);
+    assert_eq!(stable.len(), std.len() as u64);
+
+    stable.append(&mut stable_tail);
+    std.append(&mut std_tail);
+    assert!(stable_tail.is_empty());
+    let stable_items: std::vec::Vec<_> = stable.iter().map(|e| e.into_pair()).collect();
+    let std_items: std::vec::Vec<_> = std.iter().map(|(k, v)| (*k, v.clone())).collect();
+    assert_eq!(stable_items, std_items);
+
+    // remove_range (std doesn't have it; simulate with retain).
+    let removed = stable.remove_range(2..n - 2);
+    let before = std.len();
+    std.retain(|k, _| !(2..n - 2).contains(k));
+    assert_eq!(removed, (before - std.len()) as u64);
+    let stable_items: std::vec::Vec<_> = stable.iter().map(|e| e.into_pair()).collect();
+    let std_items: std::vec::Vec<_> = std.iter().map(|(k, v)| (*k, v.clone())).collect();
+    assert_eq!(stable_items, std_items);
 }
 
 #[test]
@@ -243,10 +302,29 @@ fn api_conformance_btreeset() {
     let std_inter: std::vec::Vec<_> = std.intersection(&std2).copied().collect();
     assert_eq!(stable_inter, std_inter);
 
+    // difference
+    let stable_diff: std::vec::Vec<_> = stable.difference(&stable2).collect();
+    let std_diff: std::vec::Vec<_> = std.difference(&std2).copied().collect();
+    assert_eq!(stable_diff, std_diff);
+
     // symmetric_difference
     let stable_diff: std::vec::Vec<_> = stable.symmetric_difference(&stable2).collect();
     let std_diff: std::vec::Vec<_> = std.symmetric_difference(&std2).copied().collect();
     assert_eq!(stable_diff, std_diff);
+
+    // extract_if and retain.
+    // Note: std's extract_if is unstable, so it is simulated with a filter followed by retain.
+    let stable_extracted: std::vec::Vec<_> = stable.extract_if(|k| k % 4 == 0).collect();
+    let std_extracted: std::vec::Vec<_> = std.iter().filter(|k| *k % 4 == 0).copied().collect();
+    std.retain(|k| k % 4 != 0);
+    assert_eq!(stable_extracted, std_extracted);
+    assert!(stable.iter().eq(std.iter().copied()));
+
+    stable2.retain(|k| k % 2 == 0);
+    std2.retain(|k| k % 2 == 0);
+    let stable_items: std::vec::Vec<_> = stable2.iter().collect();
+    let std_items: std::vec::Vec<_> = std2.iter().copied().collect();
+    assert_eq!(stable_items, std_items);
 }
 
 #[test]